@@ -72,6 +72,11 @@ fn criterion_benchmark(c: &mut Criterion) {
         engine_config: EngineConfig {
             cache_enabled: false,
             compaction_enabled: true,
+            compaction_interval: 1,
+            max_cache_entries: None,
+            max_earley_set_size: None,
+            max_total_items: None,
+            deterministic: false,
         },
         ..Default::default()
     };
@@ -3,7 +3,7 @@ use std::{fs::File, io::BufReader, path::Path, time::Duration};
 use ahash::AHashMap;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use kbnf::{
-    engine::{Engine, EngineConfig},
+    engine::{Engine, EngineConfig, EnginePool},
     vocabulary::{Token, Vocabulary},
     EngineLike,
 };
@@ -54,7 +54,7 @@ pub fn read_rwkv_world_vocab(path: impl AsRef<Path>) -> Result<Vocabulary, ReadR
     Ok(Vocabulary::new(id_to_token, id_to_token_string).unwrap())
 }
 
-fn run_an_engine(engine: &mut Engine, iteration: usize, token_id: u32, logits: &mut [f32]) {
+fn run_an_engine(engine: &mut Engine, iteration: usize, token_id: u32, _logits: &mut [f32]) {
     for _ in 0..iteration {
         let _ = engine.try_accept_new_token(token_id).unwrap();
         engine.compute_allowed_token_ids();
@@ -76,6 +76,11 @@ fn criterion_benchmark(c: &mut Criterion) {
         engine_config: EngineConfig {
             cache_enabled: false,
             compaction_enabled: true,
+            compaction_interval: 1,
+            max_cache_entries: None,
+            max_earley_set_size: None,
+            max_total_items: None,
+            deterministic: false,
         },
         ..Default::default()
     };
@@ -128,6 +133,21 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("right recursion 5 iterations(no cache)", |b| {
         b.iter(|| run_an_engine(black_box(&mut engine), 5, 124, &mut logits))
     });
+    for compaction_interval in [4, 16] {
+        let config = kbnf::config::Config {
+            engine_config: EngineConfig {
+                compaction_interval,
+                ..no_cache_config.engine_config
+            },
+            ..no_cache_config.clone()
+        };
+        let mut engine =
+            Engine::with_config("start::=C'\n';C::='{'|'{' C;", vocab.clone(), config).unwrap();
+        c.bench_function(
+            format!("right recursion 100 iterations(no cache, compaction interval {compaction_interval})"),
+            |b| b.iter(|| run_an_engine(black_box(&mut engine), 100, 124, &mut logits)),
+        );
+    }
     let mut engine = Engine::with_config(
         "start::=C'\n';C::=C'{'|'{';",
         vocab.clone(),
@@ -154,10 +174,122 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("always match regex 3 iterations(no cache)", |b| {
         b.iter(|| run_an_engine(black_box(&mut engine), 3, 113, &mut logits))
     });
+    // The eager regex cache is disabled so every token is checked by the Earley recognizer.
+    let per_token_config = kbnf::config::Config {
+        regex_config: kbnf::config::RegexConfig {
+            min_tokens_required_for_eager_regex_cache: None,
+            ..no_cache_config.regex_config
+        },
+        ..no_cache_config.clone()
+    };
+    let mut engine = Engine::with_config(
+        "start::=#\".+\"'\n';",
+        vocab.clone(),
+        per_token_config.clone(),
+    )
+    .unwrap();
+    c.bench_function(
+        "always match regex 3 iterations(no cache, no eager regex cache)",
+        |b| b.iter(|| run_an_engine(black_box(&mut engine), 3, 113, &mut logits)),
+    );
+    // The prefilter is a necessary condition of the grammar, which prunes the tokens before the Earley recognizer.
+    let json_like = r#"start ::= "[" (value ("," value)*)? "]";
+value ::= #"-?[0-9]+" | #"-?[0-9]+[.][0-9]+" | #"-?[0-9]+[eE][0-9]+" | "true" | "false" | "null" | start;"#;
+    let tokens: Vec<u32> = ["[", "[", "[", "[", "[", "[", "[", "[", "1", ","]
+        .iter()
+        .map(|x| vocab.token_id(&Token(x.as_bytes().into())).unwrap())
+        .collect();
+    for (name, prefilter_regex) in [("", None), (", prefilter", Some(r"[\[\]0-9,.eE\-a-z]*"))] {
+        let config = kbnf::config::Config {
+            prefilter_regex: prefilter_regex.map(str::to_string),
+            ..per_token_config.clone()
+        };
+        let mut engine = Engine::with_config(json_like, vocab.clone(), config).unwrap();
+        c.bench_function(
+            format!("JSON-like arrays 10 tokens(no cache, no eager regex cache{name})"),
+            |b| {
+                b.iter(|| {
+                    for &token_id in &tokens {
+                        black_box(&mut engine)
+                            .try_accept_new_token(token_id)
+                            .unwrap();
+                        engine.compute_allowed_token_ids();
+                    }
+                    engine.reset();
+                })
+            },
+        );
+    }
+    #[cfg(feature = "rayon")]
+    for num_threads in [1, 2, 4, 8] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        c.bench_function(
+            format!(
+                "always match regex 3 iterations(no cache, no eager regex cache, {num_threads} threads)"
+            ),
+            |b| {
+                b.iter(|| {
+                    pool.install(|| run_an_engine(black_box(&mut engine), 3, 113, &mut logits))
+                })
+            },
+        );
+    }
+    let prototype = Engine::new("start::=('{'start'}')?;", vocab.clone()).unwrap();
+    c.bench_function("10k engine creations 3 iterations", |b| {
+        b.iter(|| {
+            for _ in 0..10_000 {
+                let mut engine = prototype.clone();
+                run_an_engine(black_box(&mut engine), 3, 124, &mut logits);
+            }
+        })
+    });
+    let pool = EnginePool::new(prototype);
+    c.bench_function("10k engine creations 3 iterations(pool)", |b| {
+        b.iter(|| {
+            for _ in 0..10_000 {
+                let mut engine = pool.acquire();
+                run_an_engine(black_box(&mut engine), 3, 124, &mut logits);
+            }
+        })
+    });
     let mut engine = Engine::new("start::=#e'.+\n\n';", vocab.clone()).unwrap();
     c.bench_function("simple except! 3 iterations", |b| {
         b.iter(|| run_an_engine(black_box(&mut engine), 3, 113, &mut logits))
     });
+    let prompt = "lorem ipsum dolor sit amet ".repeat(152).into_bytes(); // about 4KB
+    let mut engine = Engine::new("start::=(#'[a-z]+' ' ')+;", vocab.clone()).unwrap();
+    c.bench_function("accept 4KB prompt", |b| {
+        b.iter(|| {
+            engine.try_accept_new_bytes(black_box(&prompt)).unwrap();
+            engine.reset();
+        })
+    });
+    c.bench_function("prefill 4KB prompt", |b| {
+        b.iter(|| {
+            engine.prefill(black_box(&prompt)).unwrap();
+            engine.reset();
+        })
+    });
+    for input in [
+        "start::='a';",
+        "start::=#'[a-z]+';",
+        "start::=#'[^a-z]+';",
+        "start::=#'(?s:.)+';",
+    ] {
+        let mut engine = Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed_percentage = engine
+            .allowed_token_ids_from_last_computation()
+            .count_ones(..)
+            * 100
+            / vocab.vocab_size();
+        c.bench_function(format!("mask logits({allowed_percentage}% allowed)"), |b| {
+            b.iter(|| engine.mask_logits(black_box(&mut logits)).unwrap())
+        });
+    }
 }
 
 criterion_group!(benches, criterion_benchmark);
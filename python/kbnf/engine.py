@@ -4,8 +4,9 @@ import typing
 import importlib
 import sys
 _torch_fast_mask_enabled = sys.maxsize.bit_length() == 63
-from .kbnf import InternalEngine, AcceptTokenResult, Vocabulary,Config
+from .kbnf import InternalEngine, AcceptTokenResult, Vocabulary,Config, EngineCheckpoint, ParseNode, AcceptMetrics, HealResult
 _slice_converters = []
+_batch_slice_converters = []
 _fast_mask_logits = []
 
 def _try_register_slice_converter(module_name:str,
@@ -18,6 +19,16 @@ def _try_register_slice_converter(module_name:str,
     except ImportError:
         pass
 
+def _try_register_batch_slice_converter(module_name:str,
+                        obtain_converter:typing.Callable[[types.ModuleType],
+                                                        typing.Callable[[typing.Any],
+                                                                        typing.Optional[typing.Tuple[typing.Any,int,int,int]]]]):
+    try:
+        module = importlib.import_module(module_name)
+        _batch_slice_converters.append(obtain_converter(module))
+    except ImportError:
+        pass
+
 def _try_register_fast_mask_logits(module_name:str,
                         fast_mask_logits:typing.Callable[[types.ModuleType],
                                                         typing.Callable[[typing.Any, "Engine"],
@@ -88,6 +99,34 @@ def _numpy_slice_converter(module:types.ModuleType):
         return None
     return convert_slice
 
+def _torch_batch_slice_converter(module:types.ModuleType):
+    def convert_batch_slice(tensor:typing.Any)->typing.Optional[typing.Tuple[typing.Any,int,int,int]]:
+        if isinstance(tensor, module.Tensor):
+            assert tensor.dim() == 2,\
+            f"Only tensors with shape (batch, n) are supported, while the actual tensor shape is {tensor.shape}"
+            tensor = tensor.to(device="cpu",dtype=module.float32)
+            if tensor.stride(1) != 1 or tensor.stride(0) < tensor.shape[1]:
+                tensor = tensor.contiguous()
+            ptr = tensor.data_ptr()
+            assert ptr % 4 == 0, f"The tensor data pointer which points to {ptr} is not aligned to 4 bytes"
+            return tensor, ptr, tensor.shape[1], tensor.stride(0)
+        return None
+    return convert_batch_slice
+
+def _numpy_batch_slice_converter(module:types.ModuleType):
+    def convert_batch_slice(array:typing.Any)->typing.Optional[typing.Tuple[typing.Any,int,int,int]]:
+        if isinstance(array, module.ndarray):
+            assert array.ndim == 2,\
+            f"Only array with shape (batch, n) are supported, while the actual array shape is {array.shape}"
+            if (array.dtype != module.float32 or not array.flags["WRITEABLE"] or not array.flags["ALIGNED"]
+                or array.strides[1] != 4 or array.strides[0] % 4 != 0 or array.strides[0] < 4 * array.shape[1]):
+                array = array.astype(module.float32, order="C")
+            ptr = array.ctypes.data
+            assert ptr % 4 == 0, f"The array data pointer which points to {ptr} is not aligned to 4 bytes"
+            return array, ptr, array.shape[1], array.strides[0] // 4
+        return None
+    return convert_batch_slice
+
 def _convert_logits_to_slice(logits:typing.Any)->typing.Tuple[typing.Any,int,int]:
     for converter in _slice_converters:
         converted = converter(logits)
@@ -95,6 +134,13 @@ def _convert_logits_to_slice(logits:typing.Any)->typing.Tuple[typing.Any,int,int
             return converted
     raise TypeError(f"Unsupported type of logits: {type(logits)}")
 
+def _convert_batch_logits_to_slice(logits:typing.Any)->typing.Tuple[typing.Any,int,int,int]:
+    for converter in _batch_slice_converters:
+        converted = converter(logits)
+        if converted is not None:
+            return converted
+    raise TypeError(f"Unsupported type of logits: {type(logits)}")
+
 def _mask_logits_fast(logits:typing.Any,engine:"Engine")->typing.Optional[typing.Any]:
     for masker in _fast_mask_logits:
         masked = masker(logits, engine)
@@ -107,11 +153,41 @@ class Engine:
         self._internal = InternalEngine(kbnf_syntax_grammar_str, vocabulary, config)
         self._cache = {}
 
+    @classmethod
+    def with_start_symbol(cls, kbnf_syntax_grammar_str:str, vocabulary, start_symbol:str)->"Engine":
+        engine = cls.__new__(cls)
+        engine._internal = InternalEngine.with_start_symbol(kbnf_syntax_grammar_str, vocabulary, start_symbol)
+        engine._cache = {}
+        return engine
+
+    @classmethod
+    def from_gbnf(cls, gbnf_grammar_str:str, vocabulary, config=None)->"Engine":
+        engine = cls.__new__(cls)
+        engine._internal = InternalEngine.from_gbnf(gbnf_grammar_str, vocabulary, config)
+        engine._cache = {}
+        return engine
+
+    @classmethod
+    def from_regex(cls, pattern:str, vocabulary, early_end:bool=False)->"Engine":
+        engine = cls.__new__(cls)
+        engine._internal = InternalEngine.from_regex(pattern, vocabulary, early_end)
+        engine._cache = {}
+        return engine
+
     def try_accept_new_token(self, token_id:int)->AcceptTokenResult:
         return self._internal.try_accept_new_token(token_id)
+
+    def try_accept_token_str(self, token_str:str)->AcceptTokenResult:
+        return self._internal.try_accept_token_str(token_str)
     
     def try_accept_new_bytes(self, _bytes:bytes)->AcceptTokenResult:
         return self._internal.try_accept_new_bytes(_bytes)
+
+    def prefill(self, _bytes:bytes)->AcceptTokenResult:
+        return self._internal.prefill(_bytes)
+
+    def try_accept_new_str(self, s:str)->AcceptTokenResult:
+        return self._internal.try_accept_new_str(s)
     
     def get_allowed_token_ids_from_last_computation(self)->typing.List[int]:
         return self._internal.get_allowed_token_ids_from_last_computation()
@@ -119,9 +195,18 @@ class Engine:
     def compute_allowed_token_ids(self)->typing.List[int]:
         return self._internal.compute_allowed_token_ids()
     
+    def allowed_token_ids_until(self, limit:int)->typing.List[int]:
+        return self._internal.allowed_token_ids_until(limit)
+    
+    def any_allowed_token(self)->typing.Optional[int]:
+        return self._internal.any_allowed_token()
+    
     def get_disallowed_token_ids_from_last_computation(self)->typing.List[int]:
         return self._internal.get_disallowed_token_ids_from_last_computation()
     
+    def get_allowed_token_ids_as_ranges(self)->typing.List[typing.Tuple[int, int]]:
+        return self._internal.get_allowed_token_ids_as_ranges()
+    
     def check_if_token_is_allowed(self, token_id:int)->bool:
         return self._internal.check_if_token_is_allowed(token_id)
     
@@ -140,14 +225,92 @@ class Engine:
     def write_allowed_token_ids_to_buffer(self, ptr:int, length:int)->None:
         self._internal.write_allowed_token_ids_to_buffer(ptr, length)
 
+    def write_mask_to_buffer(self, ptr:int, length:int)->None:
+        self._internal.write_mask_to_buffer(ptr, length)
+
+    def allowed_first_bytes(self)->bytes:
+        return self._internal.allowed_first_bytes()
+
+    def fast_forward(self)->bytes:
+        return self._internal.fast_forward()
+
+    def shortest_completion(self, max_len:int)->typing.Optional[bytes]:
+        return self._internal.shortest_completion(max_len)
+
+    def parse_tree(self)->typing.Optional[ParseNode]:
+        return self._internal.parse_tree()
+
+    def captures(self)->typing.Dict[str, bytes]:
+        return self._internal.captures()
+
+    def last_rejection(self)->typing.Optional[typing.Tuple[int, bytes]]:
+        return self._internal.last_rejection()
+
+    def last_accept_metrics(self)->AcceptMetrics:
+        return self._internal.last_accept_metrics()
+
+    def explain_rejection(self, token_id:int)->typing.Optional[typing.Tuple[int, typing.List[typing.Tuple[str, str, bytes]]]]:
+        return self._internal.explain_rejection(token_id)
+
+    def can_finish(self)->bool:
+        return self._internal.can_finish()
+
     def is_finished(self)->bool:
         return self._internal.is_finished()
+
+    def is_dead(self)->bool:
+        return self._internal.is_dead()
     
     def get_vocab(self)->Vocabulary:
         return self._internal.get_vocab()
 
     def reset(self)->None:
         self._internal.reset()
+
+    def clone_fresh(self)->"Engine":
+        new_engine = Engine.__new__(Engine)
+        new_engine._internal = self._internal.clone_fresh()
+        new_engine._cache = {}
+        return new_engine
+
+    def rewind(self)->None:
+        self._internal.rewind()
+
+    def checkpoint(self)->EngineCheckpoint:
+        return self._internal.checkpoint()
+
+    def restore(self, checkpoint:EngineCheckpoint)->None:
+        self._internal.restore(checkpoint)
+
+    def allowed_token_strings(self)->typing.List[typing.Tuple[int, str]]:
+        return self._internal.allowed_token_strings()
+
+    def state_fingerprint(self)->str:
+        return self._internal.state_fingerprint()
+
+    def ambiguity_warnings(self)->typing.List[str]:
+        return self._internal.ambiguity_warnings()
+
+    def set_completion_hook(self, callback: typing.Optional[typing.Callable[[str, int, int], None]])->None:
+        self._internal.set_completion_hook(callback)
+
+    def replay(self)->None:
+        self._internal.replay()
+
+    def accepted_bytes(self)->bytes:
+        return self._internal.accepted_bytes()
+
+    def cache_len(self)->int:
+        return self._internal.cache_len()
+
+    def clear_cache(self)->None:
+        self._internal.clear_cache()
+
+    def rollback(self, n:int)->None:
+        self._internal.rollback(n)
+
+    def heal_last_token(self)->HealResult:
+        return self._internal.heal_last_token()
     
     def mask_logits(self, logits):
         """
@@ -187,6 +350,115 @@ This method may raise the following exceptions:
         logits, ptr, size = _convert_logits_to_slice(logits)
         self._internal.mask_logits(ptr, size)
         return logits
+
+    def mask_logits_np(self, logits):
+        """
+Masks the NumPy logits array in-place based on last computed token IDs, like [`Engine::mask_logits`].
+
+Unlike [`Engine::mask_logits`], the array is never converted or copied,
+so an array that cannot be updated in-place is rejected instead.
+
+# Arguments
+
+* `logits`: The `numpy.ndarray` of logits to be masked, which must meet all of the following conditions:
+    * The shape is `(n,)`.
+    * The data type is `float32`.
+    * The underlying data buffer is C-contiguous, aligned and writeable.
+
+# Returns
+
+The same array as the input logits.
+
+# Exceptions
+
+This method may raise the following exceptions:
+    * TypeError: When the logits is not a `numpy.ndarray` of `float32`.
+    * ValueError: When the logits shape or memory layout is not supported, or the logits length is too short.
+        """
+        numpy = importlib.import_module("numpy")
+        if not isinstance(logits, numpy.ndarray) or logits.dtype != numpy.float32:
+            raise TypeError(f"Only numpy.ndarray of float32 is supported, while the actual type is {type(logits)}"
+                            f"{f' of {logits.dtype}' if isinstance(logits, numpy.ndarray) else ''}")
+        if logits.ndim != 1:
+            raise ValueError(f"Only array with shape (n) is supported, while the actual array shape is {logits.shape}")
+        if not logits.flags["C_CONTIGUOUS"] or not logits.flags["ALIGNED"] or not logits.flags["WRITEABLE"]:
+            raise ValueError("Only C-contiguous, aligned and writeable array is supported")
+        self._internal.mask_logits(logits.ctypes.data, logits.shape[0])
+        return logits
+
+    def allowed_token_ids_np(self):
+        """
+Gets the allowed token IDs since last computation as a `numpy.ndarray` of `uint32` in ascending order,
+which avoids constructing a list for large vocabularies.
+
+# Returns
+
+A new `numpy.ndarray` with shape `(n,)`, where `n` is the number of allowed token IDs.
+        """
+        numpy = importlib.import_module("numpy")
+        token_ids = numpy.empty(self._internal.get_number_of_allowed_token_ids(), dtype=numpy.uintp)
+        if token_ids.size > 0:
+            self._internal.write_allowed_token_ids_to_buffer(token_ids.ctypes.data, token_ids.size)
+        return token_ids.astype(numpy.uint32)
+
+    @staticmethod
+    def mask_logits_batch(engines:typing.Sequence["Engine"], logits):
+        """
+Computes the allowed token IDs of each engine and masks the corresponding row of the logits in one call.
+
+With the `rayon` feature enabled, the engines are processed in parallel.
+
+# Arguments
+
+* `engines`: The engines, one for each row of the logits. An engine cannot appear more than once.
+* `logits`: The logits to be masked. `numpy.ndarray` is supported by default.
+`torch.Tensor` is supported if PyTorch is installed. The shape of the logits should be `(len(engines), n)`.
+The logits will be updated in-place if all of the following conditions are met:
+    * The logits data type is `float32`.
+    * The elements in each row are contiguous AND on CPU.
+    * The data pointer is aligned to 4 bytes.
+
+# Returns
+
+The masked logits. The returned logits is the same object as the input logits if the input logits is updated in-place.
+Otherwise, a new object with the same type as the input logits is returned.
+
+# Exceptions
+
+This method may raise the following exceptions:
+    * TypeError: When the logits type is not supported.
+    * AssertionError: When the logits shape is not supported or the memory allocator returns an unaligned pointer.
+    * ValueError: When a row of the logits is too short.
+        """
+        logits, ptr, length, stride = _convert_batch_logits_to_slice(logits)
+        assert logits.shape[0] == len(engines),\
+        f"The logits have {logits.shape[0]} rows, while {len(engines)} engines are given"
+        if len(engines) == 0:
+            return logits
+        InternalEngine.mask_logits_batch([engine._internal for engine in engines], ptr, length, stride)
+        return logits
+
+    def bias_logits_by_grammar_prior(self, logits):
+        """
+Adds the logarithm of the priors of the weighted alternatives(`"A" %0.9`) to the logits.
+
+Only the weighted alternatives that start at current states are considered.
+A token consistent with some of them is biased by the logarithm of their priors' sum,
+while other tokens are left unchanged.
+
+# Arguments
+
+* `logits`: The logits to be biased. The supported types and the in-place conditions are the same as `mask_logits`.
+
+# Returns
+
+The biased logits.
+The returned logits is the same object as the input logits if the input logits is updated in-place.
+Otherwise, a new object with the same type as the input logits is returned.
+        """
+        logits, ptr, size = _convert_logits_to_slice(logits)
+        self._internal.bias_logits_by_grammar_prior(ptr, size)
+        return logits
     
     def update_logits(self, token_id:int, logits)->typing.Tuple[typing.Any,AcceptTokenResult]:
         """
@@ -218,6 +490,11 @@ This method may raise the following exceptions:
         logits, ptr, size = _convert_logits_to_slice(logits)
         result = self._internal.update_logits(token_id, ptr, size)
         return logits, result
+
+    def update_logits_for_token_str(self, token_str:str, logits)->typing.Tuple[typing.Any,AcceptTokenResult]:
+        logits, ptr, size = _convert_logits_to_slice(logits)
+        result = self._internal.update_logits_for_token_str(token_str, ptr, size)
+        return logits, result
     
     def __repr__(self)->str:
         return f"Engine({self._internal.__repr__()}, {self._cache})"
@@ -239,5 +516,7 @@ This method may raise the following exceptions:
 
 _try_register_slice_converter("torch", _torch_slice_converter)
 _try_register_slice_converter("numpy", _numpy_slice_converter)
+_try_register_batch_slice_converter("torch", _torch_batch_slice_converter)
+_try_register_batch_slice_converter("numpy", _numpy_batch_slice_converter)
 if _torch_fast_mask_enabled:
     _try_register_fast_mask_logits("torch", _torch_fast_mask_logits)
\ No newline at end of file
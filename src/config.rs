@@ -18,6 +18,34 @@ pub struct InternalConfig {
     pub engine_config: EngineConfig,
     /// The start nonterminal of the grammar.
     pub start_nonterminal: String,
+    /// The EOS token ID, which is allowed exactly when the engine is finished.
+    pub eos_token_id: Option<u32>,
+    /// Whether to mask all the logits except the EOS token's after the engine is finished.
+    pub mask_after_finish: bool,
+    /// Whether accepting more tokens or bytes after the engine is finished is an error.
+    pub finished_is_terminal: bool,
+    /// Whether to analyze the grammar for ambiguity when the engine is created.
+    pub collect_ambiguity_warnings: bool,
+    /// The token IDs that are accepted as no-ops and never allowed.
+    pub ignored_token_ids: Vec<u32>,
+    /// Whether the engine can finish before accepting any bytes when the start nonterminal is nullable.
+    pub allow_empty_match: bool,
+    /// Whether to record the detail of the rejected bytes.
+    pub detailed_errors: bool,
+    /// Whether to count the work done by each accept.
+    pub collect_accept_metrics: bool,
+    /// Whether to record the accepted bytes.
+    pub track_accepted_bytes: bool,
+    /// Whether healing the last token restricts the allowed token IDs to the heal candidates.
+    pub token_healing: bool,
+    /// The regex of the bytes that may be skipped between any two symbols of a concatenation.
+    pub implicit_whitespace: Option<String>,
+    /// Whether the newlines expected by the terminals and the regexes also match `\r\n` and a lone `\r`.
+    pub normalize_newlines: bool,
+    /// The regex that every prefix of the output must be able to extend to a match of.
+    pub prefilter_regex: Option<String>,
+    /// The maximum nesting depth of the middle recursion in the grammar.
+    pub max_recursion_depth: Option<usize>,
 }
 /// The configuration of the [`Engine`](crate::engine::Engine) struct. This should suffice most scenarios.
 #[cfg_attr(feature = "python", pyclass)]
@@ -29,7 +57,9 @@ pub struct Config {
     pub regex_config: RegexConfig,
     /// The configuration of the engine.
     pub engine_config: EngineConfig,
-    /// The start nonterminal of the grammar.
+    /// The start nonterminal of the grammar, which seeds the initial Earley set of the engine.
+    /// It may be any nonterminal defined in the grammar,
+    /// so one grammar string can provide multiple entry points.
     /// The default is `start`.
     pub start_nonterminal: String,
     /// The length of the expected output in bytes.
@@ -40,6 +70,129 @@ pub struct Config {
     pub expected_output_length: usize,
     /// The configuration of the terminals compression.
     pub compression_config: CompressionConfig,
+    /// The token ID of the EOS token, which the model emits to stop the generation.
+    /// When set, the EOS token is allowed exactly when the engine is finished,
+    /// and accepting it in this case returns [`AcceptTokenResult::Finished`](crate::AcceptTokenResult::Finished).
+    /// The default is `None`.
+    pub eos_token_id: Option<u32>,
+    /// Whether [`EngineLike::update_logits`](crate::EngineLike::update_logits) masks all the logits
+    /// except the EOS token's when the token finishes the engine, so the model is forced to emit EOS.
+    /// It has no effect unless [`Config::eos_token_id`] is set.
+    /// The default is `false`, where the logits are not updated after the engine is finished.
+    #[serde(default)]
+    pub mask_after_finish: bool,
+    /// Whether accepting tokens, bytes or strings after the engine is finished is an error.
+    /// When unset, the engine stays finished and such accepts are no-ops returning
+    /// [`AcceptTokenResult::Finished`](crate::AcceptTokenResult::Finished) without changing the states,
+    /// so a generation loop may keep feeding the tokens without checking each result.
+    /// [`EngineLike::update_logits`](crate::EngineLike::update_logits) then masks all the logits except the EOS token's
+    /// once the engine is finished, regardless of [`Config::mask_after_finish`], or all of them without [`Config::eos_token_id`].
+    /// The default is `true`, where [`AcceptTokenError::Finished`](crate::engine_like::AcceptTokenError::Finished) is returned.
+    #[serde(default = "default_true")]
+    pub finished_is_terminal: bool,
+    /// The configuration of the eager cache of the engine's states.
+    #[serde(default = "default_eager_cache_config")]
+    pub eager_cache_config: EagerCacheConfig,
+    /// Whether to run [`Grammar::analyze_ambiguity`](crate::grammar::Grammar::analyze_ambiguity) when the engine is created.
+    /// Each warning is logged with [`log::warn!`] and can be retrieved with
+    /// [`Engine::ambiguity_warnings`](crate::engine::Engine::ambiguity_warnings).
+    /// The default is `false`.
+    #[serde(default)]
+    pub collect_ambiguity_warnings: bool,
+    /// The token IDs that the model may emit but are meaningless to the grammar, e.g. BOS or padding tokens.
+    /// Accepting an ignored token ID does not change the states of the engine,
+    /// even if the token ID is not in the vocabulary or the engine is finished.
+    /// The ignored token IDs are never allowed, which takes precedence over [`Config::eos_token_id`].
+    /// The default is empty.
+    #[serde(default)]
+    pub ignored_token_ids: Vec<u32>,
+    /// Whether the grammar may match the empty string.
+    /// When set and the start nonterminal is nullable, the engine [can finish](crate::EngineLike::can_finish)
+    /// right after creation or [reset](crate::EngineLike::reset) without being [finished](crate::EngineLike::is_finished),
+    /// so the caller decides whether to stop, e.g. by accepting the EOS token, or to continue with more input.
+    /// A grammar that only matches the empty string is accepted as well, where only the EOS token is allowed.
+    /// The default is `false`, where a grammar that only matches the empty string is rejected
+    /// with [`CreateEngineError::EmptyGrammarError`](crate::engine::CreateEngineError::EmptyGrammarError)
+    /// and the empty string is never a match.
+    #[serde(default)]
+    pub allow_empty_match: bool,
+    /// Whether to record where the bytes of a rejected token or rejected bytes diverge from the grammar,
+    /// which can be retrieved by [`EngineLike::last_rejection`](crate::EngineLike::last_rejection).
+    /// The rejected prefix is accepted again to record the detail, so it only costs time when the bytes are rejected.
+    /// The default is `false`, where [`AcceptTokenError::Rejected`](crate::engine_like::AcceptTokenError::Rejected)
+    /// is the only information about a rejection.
+    #[serde(default)]
+    pub detailed_errors: bool,
+    /// Whether to count the work done by each accept, like the Earley items created and the completions,
+    /// which can be retrieved by [`EngineLike::last_accept_metrics`](crate::EngineLike::last_accept_metrics).
+    /// This is useful to tune a grammar that is slow to accept tokens, e.g. to find out how ambiguous it is.
+    /// The counters are a few additions per byte.
+    /// The default is `false`, where the metrics are all zeros.
+    #[serde(default)]
+    pub collect_accept_metrics: bool,
+    /// Whether to record the bytes accepted since the last reset, which can be retrieved by
+    /// [`EngineLike::accepted_bytes`](crate::EngineLike::accepted_bytes).
+    /// The recorded bytes are also needed by [`EngineLike::rollback`](crate::EngineLike::rollback),
+    /// [`EngineLike::heal_last_token`](crate::EngineLike::heal_last_token), [`EngineLike::replay`](crate::EngineLike::replay),
+    /// [`EngineLike::parse_tree`](crate::EngineLike::parse_tree) and [`EngineLike::captures`](crate::EngineLike::captures).
+    /// When unset, the memory of the accepted bytes is saved, the accepted bytes are always empty,
+    /// the rollbacks and the replays return an error, and no derivation or capture is found.
+    /// The default is `true`.
+    #[serde(default = "default_true")]
+    pub track_accepted_bytes: bool,
+    /// Whether [`EngineLike::heal_last_token`](crate::EngineLike::heal_last_token) also restricts the allowed token IDs
+    /// to the heal candidates, so [`EngineLike::mask_logits`](crate::EngineLike::mask_logits) right after healing
+    /// only lets the model re-pick a token extending the rolled-back one.
+    /// The restriction lasts until the allowed token IDs are computed again.
+    /// The default is `false`, where healing only returns the candidates and the allowed token IDs are left unrestricted.
+    #[serde(default)]
+    pub token_healing: bool,
+    /// The regex of one skippable piece of whitespace, e.g. `[ \t\r\n]`, which may repeat any number of times
+    /// between any two symbols of a concatenation, including the repetitions of a symbol, without writing it in the grammar.
+    ///
+    /// This changes the language accepted by the grammar: `start ::= "a" "b";` accepts `"a \n b"` with `Some("[ \t\n]".to_string())`.
+    /// No whitespace is skipped inside terminals and regexes, or before and after the whole output.
+    /// The default is `None`, where only the whitespace written in the grammar is accepted.
+    pub implicit_whitespace: Option<String>,
+    /// Whether a newline(`\n`) in terminals and regexes also matches `\r\n` and a lone `\r`,
+    /// so grammars written with `\n` accept the output with Windows or classic Mac OS line endings.
+    ///
+    /// This changes the language accepted by the grammar, as if each `\n` were written as `#"\r\n?|\n"`:
+    /// `start ::= "abc\n";` accepts `"abc\r\n"`, `"abc\r"` and `"abc\n"`, while `"abc\r\r"` is still rejected.
+    /// A `\r` followed by `\n` may be matched both as a whole line ending and as a lone `\r`,
+    /// which the engine tracks at the same time until the following bytes rule one of them out.
+    /// The accepted bytes, like [`EngineLike::accepted_bytes`](crate::EngineLike::accepted_bytes), are kept as they are.
+    /// The newlines inside character classes like `[^a]` or `\s`, regex complements(`#ex`) and substrings are not affected.
+    /// The default is `false`.
+    #[serde(default)]
+    pub normalize_newlines: bool,
+    /// A cheap necessary condition of the grammar as a regex, e.g. `[\[{][^\x00-\x1F]*` for a JSON-like grammar,
+    /// which runs in lockstep with the grammar so the bytes it rejects are pruned before the grammar is checked.
+    ///
+    /// A byte is allowed only if both the grammar and the prefilter accept it, where the prefilter accepts the bytes
+    /// as long as they are a prefix of some string matched by the regex from the start of the output.
+    /// The allowed token IDs are unchanged when every output of the grammar satisfies the regex;
+    /// otherwise the outputs rejected by the regex are rejected as well.
+    /// The prefilter never decides whether the engine is finished.
+    /// The cache of the allowed token IDs and the eager regex cache are not used with a prefilter,
+    /// since they do not depend on the state of the prefilter.
+    /// The default is `None`, where only the grammar is checked.
+    pub prefilter_regex: Option<String>,
+    /// The maximum nesting depth of the middle recursion, e.g. the braces in `start ::= ("{" start "}")?;`,
+    /// so the model cannot nest the output arbitrarily deep.
+    ///
+    /// A nonterminal referenced in the middle of one of its own alternations, i.e. neither first nor last,
+    /// directly or through other nonterminals, nests one level deeper at each such reference.
+    /// The depth is the number of the alternations on the path that nest, i.e. hold such a reference
+    /// or leave the nested part out like `"{" "}"`, so the outermost level is at depth 1
+    /// and with `Some(3)` the grammar above accepts `"{{{}}}"` but not `"{{{{}}}}"`.
+    /// The other alternations add no depth, so `value ::= obj | 'x'; obj ::= '[' value ']';` accepts `"[[[x]]]"` as well.
+    /// The left and right recursion, like the repetitions, are not bounded since they do not nest.
+    /// The grammar is expanded with one copy of the recursive nonterminals per depth before the engine is created,
+    /// which are named `__kbnf_depth_<depth>_<nonterminal>` in [`EngineLike::parse_tree`](crate::EngineLike::parse_tree).
+    /// `Some(0)` is treated as `Some(1)`.
+    /// The default is `None`, where the recursion is unbounded.
+    pub max_recursion_depth: Option<usize>,
 }
 /// The type of the Finite State Automaton to be used.
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
@@ -58,10 +211,18 @@ pub enum Fsa {
 #[cfg_attr(feature = "wasm", wasm_bindgen(inspectable))]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy)]
 pub struct RegexConfig {
-    /// The maximum memory usage in bytes allowed when compiling the regex.
-    /// If the memory usage exceeds this limit, an error will be returned.
+    /// The maximum memory usage in bytes allowed when compiling the regex,
+    /// which limits both the DFA and the auxiliary memory used during its construction.
+    /// If the memory usage exceeds this limit,
+    /// [`CreateGrammarError::RegexTooLargeToCompile`](crate::grammar::CreateGrammarError::RegexTooLargeToCompile) will be returned.
     /// The default is `None`, which means no limit for dfa.
     pub max_memory_usage: Option<usize>,
+    /// The maximum size in bytes of each DFA compiled from a regex.
+    /// If a DFA exceeds this limit,
+    /// [`CreateGrammarError::RegexTooLargeToCompile`](crate::grammar::CreateGrammarError::RegexTooLargeToCompile) will be returned.
+    /// When [`RegexConfig::max_memory_usage`] is also set, the smaller limit applies.
+    /// The default is `None`, which means no limit.
+    pub dfa_size_limit: Option<usize>,
     /// The type of the Finite State Automaton to be used.
     /// The default is [`Fsa::Dfa`].
     pub fsa_type: Fsa,
@@ -69,6 +230,17 @@ pub struct RegexConfig {
     /// `None` means that the cache will be disabled.
     /// The default is `Some(1000)`.
     pub min_tokens_required_for_eager_regex_cache: Option<usize>,
+    /// Whether to compile the regexes in byte mode, where `.` and negated classes like `[^a]` match any single byte
+    /// instead of any UTF-8 encoded character.
+    ///
+    /// This is useful when the vocabulary contains tokens that are not valid UTF-8 on their own, like a token of `0xFF`,
+    /// which can never be allowed by a regex otherwise. The tradeoff is that the regexes may then match invalid UTF-8,
+    /// and Unicode features like `\p{L}` or non-ASCII characters in character classes are rejected,
+    /// while `\w`, `\d` and `\s` only match ASCII characters. Non-ASCII characters outside of classes still match their UTF-8 encodings.
+    /// `#char` is not affected.
+    /// The default is `false`.
+    #[serde(default)]
+    pub dot_matches_bytes: bool,
 }
 
 /// The configuration of regular expressions.
@@ -81,33 +253,141 @@ pub struct CompressionConfig {
     pub min_terminals: usize,
 }
 
+/// The configuration of the eager cache, which precomputes the allowed token IDs of the engine's states when the engine is created.
+///
+/// Unlike the eager regex cache, which only helps when the dot sits on a regex,
+/// the eager cache eliminates the cold-start penalty of pure context-free grammars.
+/// It takes effect only when [`EngineConfig::cache_enabled`] is true.
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all, set_all))]
+#[cfg_attr(feature = "wasm", wasm_bindgen(inspectable))]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy)]
+pub struct EagerCacheConfig {
+    /// The maximum number of states to be precomputed, including the initial state.
+    /// After the initial state, the states reachable by one allowed token are precomputed in the order of token IDs
+    /// until the limit is reached.
+    /// `None` means that the eager cache will be disabled.
+    /// The default is `None`.
+    pub max_states: Option<usize>,
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             regex_config: RegexConfig {
                 max_memory_usage: None,
+                dfa_size_limit: None,
                 fsa_type: Fsa::Dfa,
                 min_tokens_required_for_eager_regex_cache: Some(1000),
+                dot_matches_bytes: false,
             },
             engine_config: EngineConfig {
                 cache_enabled: true,
                 compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: None,
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
             },
             start_nonterminal: "start".to_string(),
             compression_config: CompressionConfig { min_terminals: 5 },
             expected_output_length: u32::MAX as usize,
+            eos_token_id: None,
+            mask_after_finish: false,
+            finished_is_terminal: true,
+            eager_cache_config: EagerCacheConfig { max_states: None },
+            collect_ambiguity_warnings: false,
+            ignored_token_ids: Vec::new(),
+            allow_empty_match: false,
+            detailed_errors: false,
+            collect_accept_metrics: false,
+            track_accepted_bytes: true,
+            token_healing: false,
+            implicit_whitespace: None,
+            normalize_newlines: false,
+            prefilter_regex: None,
+            max_recursion_depth: None,
         }
     }
 }
+// The defaults of the fields added after the first release, which are the same as in `Config::default`,
+// so the configurations serialized before the fields were added can still be deserialized.
+fn default_true() -> bool {
+    true
+}
+fn default_eager_cache_config() -> EagerCacheConfig {
+    EagerCacheConfig { max_states: None }
+}
+pub(crate) fn default_compaction_interval() -> usize {
+    1
+}
+impl RegexConfig {
+    /// Converts the configuration to the configuration of the DFA builder.
+    pub(crate) fn dfa_config(&self) -> kbnf_regex_automata::dfa::dense::Config {
+        kbnf_regex_automata::dfa::dense::Config::new()
+            .dfa_size_limit(match (self.max_memory_usage, self.dfa_size_limit) {
+                (Some(x), Some(y)) => Some(x.min(y)),
+                (x, y) => x.or(y),
+            })
+            .determinize_size_limit(self.max_memory_usage)
+            .start_kind(kbnf_regex_automata::dfa::StartKind::Both)
+    }
+    /// Converts the configuration to the syntax configuration of the regexes.
+    pub(crate) fn syntax_config(&self) -> kbnf_regex_automata::util::syntax::Config {
+        kbnf_regex_automata::util::syntax::Config::new()
+            .unicode(!self.dot_matches_bytes)
+            .utf8(!self.dot_matches_bytes)
+    }
+}
 impl Config {
+    /// Creates a configuration for vocabularies with more than about 100k tokens.
+    ///
+    /// Each cache entry holds one bit per token, so the cache of allowed token IDs is capped at 1024 entries,
+    /// about 16 MB for a vocabulary of 128k tokens, at the cost of recomputing the evicted states.
+    /// The eager regex cache only keeps the regex states that accept at least 5000 tokens,
+    /// which still covers the permissive regexes like `#"[a-z ]+"` where most of the time is spent.
+    pub fn for_large_vocab() -> Self {
+        let mut config = Self::default();
+        config.engine_config.max_cache_entries = Some(1024);
+        config
+            .regex_config
+            .min_tokens_required_for_eager_regex_cache = Some(5000);
+        config
+    }
+    /// Creates a configuration that keeps the memory usage of the engine small.
+    ///
+    /// The eager regex cache is disabled and the cache of allowed token IDs is capped at 64 entries,
+    /// so the allowed token IDs are computed again more often. The Earley sets are compacted after every token.
+    pub fn low_memory() -> Self {
+        let mut config = Self::default();
+        config.engine_config.max_cache_entries = Some(64);
+        config.engine_config.compaction_interval = 1;
+        config
+            .regex_config
+            .min_tokens_required_for_eager_regex_cache = None;
+        config
+    }
+    /// Creates a configuration that trades memory and engine creation time for the speed of computing
+    /// the allowed token IDs.
+    ///
+    /// The cache of allowed token IDs is unbounded, the eager regex cache keeps the regex states that accept
+    /// at least 100 tokens, and up to 256 states are precomputed by the [eager cache](EagerCacheConfig)
+    /// when the engine is created. Build with the `rayon` feature to also compute the allowed token IDs in parallel.
+    pub fn max_throughput() -> Self {
+        let mut config = Self::default();
+        config.engine_config.cache_enabled = true;
+        config.engine_config.max_cache_entries = None;
+        config
+            .regex_config
+            .min_tokens_required_for_eager_regex_cache = Some(100);
+        config.eager_cache_config.max_states = Some(256);
+        config
+    }
     /// Converts the configuration to the internal configuration.
     pub fn internal_config(self) -> InternalConfig {
         let regex_config = match self.regex_config.fsa_type {
-            Fsa::Dfa => FiniteStateAutomatonConfig::Dfa(
-                kbnf_regex_automata::dfa::dense::Config::new()
-                    .dfa_size_limit(self.regex_config.max_memory_usage)
-                    .start_kind(kbnf_regex_automata::dfa::StartKind::Both),
-            ),
+            Fsa::Dfa => FiniteStateAutomatonConfig::Dfa(self.regex_config.dfa_config()),
         };
         let compression_config = kbnf_syntax::config::CompressionConfig {
             min_terminals: self.compression_config.min_terminals,
@@ -120,6 +400,20 @@ impl Config {
             compression_config,
             engine_config: self.engine_config,
             start_nonterminal: self.start_nonterminal,
+            eos_token_id: self.eos_token_id,
+            mask_after_finish: self.mask_after_finish,
+            finished_is_terminal: self.finished_is_terminal,
+            collect_ambiguity_warnings: self.collect_ambiguity_warnings,
+            ignored_token_ids: self.ignored_token_ids,
+            allow_empty_match: self.allow_empty_match,
+            detailed_errors: self.detailed_errors,
+            collect_accept_metrics: self.collect_accept_metrics,
+            track_accepted_bytes: self.track_accepted_bytes,
+            token_healing: self.token_healing,
+            implicit_whitespace: self.implicit_whitespace,
+            normalize_newlines: self.normalize_newlines,
+            prefilter_regex: self.prefilter_regex,
+            max_recursion_depth: self.max_recursion_depth,
         }
     }
 }
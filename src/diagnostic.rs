@@ -0,0 +1,180 @@
+//! The diagnostic module that locates the errors in KBNF grammar strings.
+use std::fmt::Display;
+
+use nom::error::{VerboseError, VerboseErrorKind};
+
+use crate::preprocessor;
+
+/// The location of an error in a KBNF grammar string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The byte offset of the error in the grammar string.
+    pub offset: usize,
+    /// The line of the error, starting from 1.
+    pub line: usize,
+    /// The column of the error in characters, starting from 1.
+    pub column: usize,
+    /// The line of the grammar string that contains the error, without the line break.
+    pub snippet: String,
+}
+
+impl SourceLocation {
+    /// Locate the byte offset in the grammar string.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offset is not on a character boundary of the grammar string.
+    pub fn new(grammar: &str, offset: usize) -> Self {
+        let line_start = grammar[..offset].rfind('\n').map(|x| x + 1).unwrap_or(0);
+        let line_end = grammar[offset..]
+            .find('\n')
+            .map(|x| offset + x)
+            .unwrap_or(grammar.len());
+        Self {
+            offset,
+            line: grammar[..line_start].matches('\n').count() + 1,
+            column: grammar[line_start..offset].chars().count() + 1,
+            snippet: grammar[line_start..line_end]
+                .trim_end_matches('\r')
+                .to_string(),
+        }
+    }
+}
+
+/// The structured diagnostic of an error in a KBNF grammar string,
+/// which can be obtained from [`CreateGrammarError::diagnostic`](crate::grammar::CreateGrammarError::diagnostic).
+///
+/// The [`Display`] implementation shows the message followed by the offending line with a caret under the error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarDiagnostic {
+    /// The human-readable message of the error.
+    pub message: String,
+    /// The location of the error, or `None` if the error cannot be located in the grammar string,
+    /// e.g. when it occurs in the rules created by desugaring the syntax extensions.
+    pub location: Option<SourceLocation>,
+    /// The nonterminal at fault for semantic errors like an undefined nonterminal.
+    pub nonterminal: Option<String>,
+}
+
+impl Display for GrammarDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(location) = &self.location {
+            let line = location.line.to_string();
+            write!(
+                f,
+                "\n{} --> line {}, column {}\n{line} | {}\n{} | {}^",
+                " ".repeat(line.len()),
+                location.line,
+                location.column,
+                location.snippet,
+                " ".repeat(line.len()),
+                " ".repeat(location.column - 1),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl GrammarDiagnostic {
+    /// Translate the error of the parser into a diagnostic.
+    ///
+    /// The parser works on the preprocessed grammar string, so the location is mapped back to the grammar string
+    /// when the unparsed input can be found there.
+    pub(crate) fn from_parsing_error(
+        grammar: &str,
+        preprocessed: &str,
+        error: &nom::Err<VerboseError<String>>,
+    ) -> Self {
+        let errors = match error {
+            nom::Err::Error(e) | nom::Err::Failure(e) => &e.errors,
+            nom::Err::Incomplete(_) => {
+                return Self {
+                    message: "the grammar is incomplete.".to_string(),
+                    location: None,
+                    nonterminal: None,
+                }
+            }
+        };
+        // The error with the least unparsed input is the furthest position the parser reached.
+        let Some((remaining, kind)) = errors.iter().min_by_key(|(x, _)| x.len()) else {
+            return Self {
+                message: "the grammar cannot be parsed.".to_string(),
+                location: None,
+                nonterminal: None,
+            };
+        };
+        let context = errors.iter().find_map(|(_, kind)| match kind {
+            VerboseErrorKind::Context(x) => Some(*x),
+            _ => None,
+        });
+        let found = match remaining.chars().next() {
+            Some(c) => format!("`{c}`"),
+            None => "the end of the grammar".to_string(),
+        };
+        let message = match (context, kind) {
+            (Some(context), _) => format!("{context}; found {found}."),
+            (None, VerboseErrorKind::Char(c)) => format!("expected `{c}`, but found {found}."),
+            (None, _) => format!("unexpected {found}."),
+        };
+        Self {
+            message,
+            location: Self::locate_remaining(grammar, preprocessed, remaining)
+                .map(|x| SourceLocation::new(grammar, x)),
+            nonterminal: None,
+        }
+    }
+
+    /// Translate the semantic error into a diagnostic, where the nonterminal at fault is located at its first use.
+    pub(crate) fn from_semantic_error(
+        grammar: &str,
+        error: &kbnf_syntax::semantic_error::SemanticError,
+    ) -> Self {
+        use kbnf_syntax::semantic_error::SemanticError;
+        let nonterminal = match error {
+            SemanticError::UndefinedNonterminal(x)
+            | SemanticError::InvalidExceptedNonterminal(x) => Some(x.clone()),
+            _ => None,
+        };
+        Self {
+            message: error.to_string(),
+            location: nonterminal
+                .as_deref()
+                .and_then(|x| preprocessor::find_nonterminal(grammar, x))
+                .map(|x| SourceLocation::new(grammar, x)),
+            nonterminal,
+        }
+    }
+
+    /// Create the diagnostic of a grammar that only matches the empty string or nothing,
+    /// where the start nonterminal is located at its first occurrence.
+    pub(crate) fn from_empty_grammar(grammar: &str, start_nonterminal: &str) -> Self {
+        Self {
+            message: format!(
+                "the start nonterminal `{start_nonterminal}` matches only the empty string or nothing."
+            ),
+            location: preprocessor::find_nonterminal(grammar, start_nonterminal)
+                .map(|x| SourceLocation::new(grammar, x)),
+            nonterminal: Some(start_nonterminal.to_string()),
+        }
+    }
+
+    fn locate_remaining(grammar: &str, preprocessed: &str, remaining: &str) -> Option<usize> {
+        if !preprocessed.ends_with(remaining) {
+            return None;
+        }
+        if grammar.ends_with(remaining) {
+            return Some(grammar.len() - remaining.len());
+        }
+        // The desugared rules differ from the grammar string, so the rest of the line is searched instead.
+        let line = remaining.split('\n').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            return None;
+        }
+        let mut matches = grammar.match_indices(line);
+        match (matches.next(), matches.next()) {
+            (Some((offset, _)), None) => Some(offset),
+            _ => None,
+        }
+    }
+}
@@ -1,6 +1,8 @@
 //! The main module that contains the [`Engine`] struct and its related types.
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
 
+use ahash::AHashMap;
+use kbnf_regex_automata::dfa::dense;
 use kbnf_syntax::simplified_grammar::SimplifiedGrammar;
 #[cfg(feature = "python")]
 use pyo3::pyclass;
@@ -9,7 +11,14 @@ use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
 use crate::{
-    config::Config, engine_base::EngineBase, engine_like::EngineLike, grammar::Grammar, utils,
+    config::{Config, InternalConfig, RegexConfig},
+    diagnostic::GrammarDiagnostic,
+    engine_base::{
+        CompletionHook, EngineBase, EngineBaseCheckpoint, RestoreCheckpointError, SharedCacheBase,
+    },
+    engine_like::EngineLike,
+    grammar::{AmbiguityWarning, Grammar, NonterminalID},
+    utils,
     vocabulary::Vocabulary,
 };
 
@@ -29,6 +38,36 @@ pub struct EngineConfig {
     /// speeds up the engine in most cases. In particular, cache usually requires compaction to be effective.
     /// It is enabled by default.
     pub compaction_enabled: bool,
+    /// The minimum number of bytes accepted between two compactions when the compaction is enabled.
+    /// The compaction only happens after the last byte of accepted tokens or bytes, so it is amortized
+    /// over several tokens when the interval is larger than the token lengths, while the Earley sets grow in between.
+    /// `0` is treated as `1`. The default is `1`, which compacts after every accepted token or bytes.
+    #[serde(default = "crate::config::default_compaction_interval")]
+    pub compaction_interval: usize,
+    /// The maximum number of entries in the cache. When the cache is full,
+    /// the least recently used entry is evicted to bound the memory usage of long-running engines,
+    /// where each entry holds the allowed token IDs of a state.
+    /// `None` means that the cache can grow without bound.
+    /// The default is `None`.
+    pub max_cache_entries: Option<usize>,
+    /// The maximum number of items in an Earley set, which bounds the work of accepting a byte.
+    /// When a byte makes an Earley set exceed the limit, the accepted token or bytes are reverted and
+    /// [`AcceptTokenError::ResourceLimitExceeded`](crate::engine_like::AcceptTokenError::ResourceLimitExceeded) is returned,
+    /// which allows accepting untrusted grammars and inputs without exhausting the memory.
+    /// `None` means no limit. The default is `None`.
+    pub max_earley_set_size: Option<usize>,
+    /// The maximum number of items in all the Earley sets, checked in the same way as [`EngineConfig::max_earley_set_size`].
+    /// The Earley sets removed by the compaction do not count towards the limit.
+    /// `None` means no limit. The default is `None`.
+    pub max_total_items: Option<usize>,
+    /// Whether the hash maps and sets of the engine use fixed seeds instead of random ones.
+    /// The order of the Earley items then only depends on the grammar and the input,
+    /// so the engine behaves the same and its `Debug` output is byte-for-byte identical across runs,
+    /// which is useful for reproducible benchmarks and tests.
+    /// The hashing is slightly weaker against collision attacks in deterministic mode.
+    /// The default is `false`.
+    #[serde(default)]
+    pub deterministic: bool,
 }
 #[derive(Debug, Clone)]
 /// An enum that represents the common type combinations of [`EngineBase`].
@@ -48,6 +87,183 @@ pub(crate) enum EngineUnion {
 pub struct Engine {
     union: EngineUnion,
 }
+#[derive(Debug, Clone)]
+/// The grammar of an [`Engine`], returned by [`Engine::grammar`].
+///
+/// The variants correspond to the sizes of the nonterminal IDs(TI) chosen by [`Engine::with_config`].
+pub enum EngineGrammar {
+    /// The grammar with [u8] IDs.
+    U8(Arc<Grammar<u8>>),
+    /// The grammar with [u16] IDs.
+    U16(Arc<Grammar<u16>>),
+}
+impl EngineGrammar {
+    /// Get the nonterminal string from the grammar, e.g. of the ID passed to the hook set by [`Engine::set_completion_hook`].
+    pub fn nonterminal_str(&self, nonterminal_id: NonterminalID<usize>) -> Option<&str> {
+        match self {
+            EngineGrammar::U8(grammar) => {
+                grammar.nonterminal_str(NonterminalID(u8::try_from(nonterminal_id.0).ok()?))
+            }
+            EngineGrammar::U16(grammar) => {
+                grammar.nonterminal_str(NonterminalID(u16::try_from(nonterminal_id.0).ok()?))
+            }
+        }
+    }
+}
+#[derive(Debug, Clone)]
+/// An enum that represents the checkpoints of the variants of [`EngineUnion`].
+pub(crate) enum EngineCheckpointUnion {
+    U8U8U8U8U32(EngineBaseCheckpoint<u8, u8, u8, u8, u32>),
+    U8U8U16U16U16(EngineBaseCheckpoint<u8, u8, u16, u16, u16>),
+    U16U16U32U32U32(EngineBaseCheckpoint<u16, u16, u32, u32, u32>),
+}
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Debug, Clone)]
+/// The opaque snapshot of the states of an [`Engine`], created by [`Engine::checkpoint`].
+///
+/// The grammar and the vocabulary are shared with the engine, and the cache is not included.
+pub struct EngineCheckpoint {
+    union: EngineCheckpointUnion,
+}
+#[derive(Debug, Clone)]
+/// An enum that represents the shared caches of the variants of [`EngineUnion`].
+pub(crate) enum SharedCacheUnion {
+    U8U8U8U8U32(SharedCacheBase<u8, u8, u8, u8, u32>),
+    U8U8U16U16U16(SharedCacheBase<u8, u8, u16, u16, u16>),
+    U16U16U32U32U32(SharedCacheBase<u16, u16, u32, u32, u32>),
+}
+#[derive(Debug, Clone, Default)]
+/// The cache of allowed token IDs shared by multiple [`Engine`]s, possibly across threads,
+/// which is attached by [`Engine::with_shared_cache`].
+///
+/// Cloning the shared cache or an engine with it attached shares the same entries.
+/// The cache holds at most [`EngineConfig::max_cache_entries`] of the first engine attached to it,
+/// and evicts the least recently used entry like the cache of an engine.
+/// The cache is keyed by the Earley sets, which only identify the same states in engines of the same grammar.
+/// Hence all the engines sharing a cache MUST be created from the same grammar string and config;
+/// otherwise the engines may see the allowed token IDs of unrelated states.
+pub struct SharedCache {
+    // The variant is decided by the first engine attached to the cache.
+    union: Arc<OnceLock<SharedCacheUnion>>,
+}
+
+impl SharedCache {
+    /// Creates an empty shared cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Gets the number of entries in the cache.
+    pub fn len(&self) -> usize {
+        match self.union.get() {
+            Some(SharedCacheUnion::U8U8U8U8U32(cache)) => cache.len(),
+            Some(SharedCacheUnion::U8U8U16U16U16(cache)) => cache.len(),
+            Some(SharedCacheUnion::U16U16U32U32U32(cache)) => cache.len(),
+            None => 0,
+        }
+    }
+    /// Checks whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Removes all the entries of the cache for all the engines sharing it.
+    pub fn clear(&self) {
+        match self.union.get() {
+            Some(SharedCacheUnion::U8U8U8U8U32(cache)) => cache.clear(),
+            Some(SharedCacheUnion::U8U8U16U16U16(cache)) => cache.clear(),
+            Some(SharedCacheUnion::U16U16U32U32U32(cache)) => cache.clear(),
+            None => {}
+        }
+    }
+}
+/// A pool of [`Engine`]s cloned from the same prototype, which reuses the working memory of the returned engines.
+///
+/// The engines created per request allocate their scratch buffers, like the items to be completed
+/// and the deduplication buffer, from scratch as they grow. An engine acquired from the pool is a previously
+/// returned engine whose buffers are cleared but retain their capacity, or a clone of the prototype if none is idle.
+/// The grammar and the vocabulary are shared with the prototype either way.
+///
+/// The pool can be shared across threads, e.g. behind an [`Arc`].
+#[derive(Debug)]
+pub struct EnginePool {
+    prototype: Engine,
+    idle_engines: Mutex<Vec<Engine>>,
+}
+
+impl EnginePool {
+    /// Creates an empty pool, where the new engines are clones of the prototype reset to the initial states.
+    pub fn new(prototype: Engine) -> Self {
+        Self {
+            prototype,
+            idle_engines: Mutex::new(Vec::new()),
+        }
+    }
+    /// Acquires an engine in the initial states, which is returned to the pool when the guard is dropped.
+    pub fn acquire(&self) -> PooledEngine<'_> {
+        let engine = self.lock_idle_engines().pop().unwrap_or_else(|| {
+            let mut engine = self.prototype.clone();
+            engine.reset();
+            engine
+        });
+        PooledEngine {
+            engine: Some(engine),
+            pool: self,
+        }
+    }
+    /// Gets the number of idle engines in the pool.
+    pub fn idle_len(&self) -> usize {
+        self.lock_idle_engines().len()
+    }
+    /// Drops all the idle engines along with their working memory.
+    pub fn clear(&self) {
+        self.lock_idle_engines().clear();
+    }
+    fn lock_idle_engines(&self) -> std::sync::MutexGuard<'_, Vec<Engine>> {
+        // Only complete engines are pushed or popped, so the poisoned lock is still usable.
+        self.idle_engines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// An [`Engine`] acquired by [`EnginePool::acquire`], which is reset and returned to the pool on drop.
+#[derive(Debug)]
+pub struct PooledEngine<'a> {
+    // It is only `None` after the engine is detached or returned.
+    engine: Option<Engine>,
+    pool: &'a EnginePool,
+}
+
+impl PooledEngine<'_> {
+    /// Takes the engine out of the pool, so it is not returned when dropped.
+    pub fn detach(mut self) -> Engine {
+        self.engine.take().expect("the engine is only taken once")
+    }
+}
+
+impl std::ops::Deref for PooledEngine<'_> {
+    type Target = Engine;
+
+    fn deref(&self) -> &Engine {
+        self.engine.as_ref().expect("the engine is only taken once")
+    }
+}
+
+impl std::ops::DerefMut for PooledEngine<'_> {
+    fn deref_mut(&mut self) -> &mut Engine {
+        self.engine.as_mut().expect("the engine is only taken once")
+    }
+}
+
+impl Drop for PooledEngine<'_> {
+    fn drop(&mut self) {
+        if let Some(mut engine) = self.engine.take() {
+            // Resetting clears the buffers without deallocating them.
+            engine.reset();
+            self.pool.lock_idle_engines().push(engine);
+        }
+    }
+}
 #[derive(Debug, thiserror::Error)]
 /// Represents the error type for the [`Engine`] creation.
 pub enum CreateEngineError {
@@ -59,13 +275,68 @@ pub enum CreateEngineError {
     GrammarError(#[from] crate::grammar::CreateGrammarError),
     #[error("The grammar after simplification is empty.
     This usually means that the grammar only contains empty terminals and/or self recursions like A::=A;")]
-    /// The grammar is empty.
-    EmptyGrammarError,
+    /// The grammar is empty. The diagnostic names the start nonterminal.
+    EmptyGrammarError(Box<GrammarDiagnostic>),
     #[error("The grammar and/or config's value range is not supported by the Engine.\n
     This usually means that the grammar has more than 65536 nonterminals,
     at least one nonterminal has more than 65536 alternations or repetitions, and/or the expected output length is more than 2^32.")]
     /// The grammar and/or config's value range is not supported by the Engine.
     InvalidInputError,
+    #[error("The prefilter regex cannot be compiled: {0}")]
+    /// The [`Config::prefilter_regex`] is malformed or too large to compile.
+    InvalidPrefilterRegexError(String),
+    #[error("The shared cache is already used by engines of a different grammar.")]
+    /// The shared cache is already attached to engines whose grammar needs different integer types.
+    MismatchedSharedCacheError,
+}
+
+impl CreateEngineError {
+    /// Get the structured diagnostic of the error, which locates the error in the grammar string.
+    ///
+    /// See [`CreateGrammarError::diagnostic`](crate::grammar::CreateGrammarError::diagnostic) for more information.
+    pub fn diagnostic(&self) -> Option<&GrammarDiagnostic> {
+        match self {
+            CreateEngineError::GrammarError(e) => e.diagnostic(),
+            CreateEngineError::EmptyGrammarError(diagnostic) => Some(diagnostic),
+            _ => None,
+        }
+    }
+}
+
+/// Create the [`EngineUnion`] variant from a compiled grammar with the options of the [`InternalConfig`].
+macro_rules! create_engine_union {
+    (
+        $variant:ident,
+        $grammar:expr,
+        $vocabulary:expr,
+        $internal_config:expr,
+        $empty_match:expr,
+        $prefilter:expr,
+        $max_eager_cached_states:expr
+    ) => {{
+        EngineBase::new(
+            $vocabulary.clone(),
+            $grammar,
+            $internal_config.engine_config,
+        )
+        .and_then(|engine| {
+            Ok(EngineUnion::$variant(
+                engine
+                    .with_eos_token_id($internal_config.eos_token_id)?
+                    .with_mask_after_finish($internal_config.mask_after_finish)
+                    .with_finished_is_terminal($internal_config.finished_is_terminal)
+                    .with_ambiguity_warnings($internal_config.collect_ambiguity_warnings)
+                    .with_ignored_token_ids($internal_config.ignored_token_ids.iter().copied())
+                    .with_empty_match($empty_match)
+                    .with_detailed_errors($internal_config.detailed_errors)
+                    .with_accept_metrics($internal_config.collect_accept_metrics)
+                    .with_track_accepted_bytes($internal_config.track_accepted_bytes)
+                    .with_token_healing($internal_config.token_healing)
+                    .with_prefilter($prefilter.clone())
+                    .with_eager_cache($max_eager_cached_states),
+            ))
+        })
+    }};
 }
 
 impl Engine {
@@ -84,6 +355,7 @@ impl Engine {
     /// # Errors
     ///
     /// Returns an [`CreateEngineError`] when the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    /// [`CreateEngineError::diagnostic`] locates the errors in the grammar string.
     pub fn new(
         kbnf_syntax_grammar_str: &str,
         vocabulary: Vocabulary,
@@ -92,6 +364,38 @@ impl Engine {
         Self::with_config(kbnf_syntax_grammar_str, vocabulary, config)
     }
 
+    /// Create a new [`Engine`] from an KBNF grammar string and a [`Vocabulary`],
+    /// where `start_symbol` replaces the default [`Config::start_nonterminal`].
+    ///
+    /// This is useful when a grammar string defines multiple top-level formats,
+    /// so each [`Engine`] can pick its entry point without maintaining separate grammar strings.
+    ///
+    /// # Arguments
+    ///
+    /// * `kbnf_syntax_grammar_str` - The KBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `start_symbol` - The nonterminal that the engine starts from.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the start symbol is not defined in the grammar,
+    /// the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    pub fn with_start_symbol(
+        kbnf_syntax_grammar_str: &str,
+        vocabulary: Vocabulary,
+        start_symbol: &str,
+    ) -> Result<Engine, CreateEngineError> {
+        let config = Config {
+            start_nonterminal: start_symbol.to_string(),
+            ..Default::default()
+        };
+        Self::with_config(kbnf_syntax_grammar_str, vocabulary, config)
+    }
+
     fn check_id_length(grammar: &SimplifiedGrammar, value: usize) -> bool {
         grammar.interned_strings.terminals.len() <= value
             && grammar.interned_strings.nonterminals.len() <= value
@@ -111,6 +415,12 @@ impl Engine {
     /// # Errors
     ///
     /// Returns an [`CreateEngineError`] when the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    /// A grammar that only matches the empty string is not empty when [`Config::allow_empty_match`] is set.
+    /// [`CreateEngineError::diagnostic`] locates the errors in the grammar string.
+    ///
+    /// The integer types of the underlying [`EngineBase`] are selected as the smallest ones that fit the grammar
+    /// and [`Config::expected_output_length`], so a grammar too large for 16-bit state IDs uses 32-bit ones
+    /// instead of failing. [`Engine::state_id_width`] tells the selected size.
     pub fn with_config(
         kbnf_syntax_grammar_str: &str,
         vocabulary: Vocabulary,
@@ -118,62 +428,396 @@ impl Engine {
     ) -> Result<Engine, CreateEngineError> {
         let tsp = config.expected_output_length;
         let regex_config = config.regex_config;
+        let max_eager_cached_states = config.eager_cache_config.max_states;
         let internal_config = config.internal_config();
-        let grammar =
-            utils::construct_kbnf_syntax_grammar(kbnf_syntax_grammar_str, internal_config.clone())?;
+        let (mut grammar, analysis) = utils::construct_kbnf_syntax_grammar_and_analysis(
+            kbnf_syntax_grammar_str,
+            internal_config.clone(),
+        )?;
+        let empty_match = internal_config.allow_empty_match && analysis.start_nullable;
         if grammar.is_empty() {
-            return Err(CreateEngineError::EmptyGrammarError);
+            if !empty_match {
+                return Err(CreateEngineError::EmptyGrammarError(Box::new(
+                    GrammarDiagnostic::from_empty_grammar(
+                        kbnf_syntax_grammar_str,
+                        &internal_config.start_nonterminal,
+                    ),
+                )));
+            }
+            // The grammar only matches the empty string, which is replaced by a regex matching nothing
+            // since the engine cannot be created from a grammar without rules.
+            grammar = utils::construct_kbnf_syntax_grammar(
+                &format!("{} ::= #'[a&&b]';", internal_config.start_nonterminal),
+                internal_config.clone(),
+            )?;
         }
         let td = utils::find_max_dotted_position_from_kbnf_syntax_grammar(&grammar);
         let tp = utils::find_max_production_id_from_kbnf_syntax_grammar(&grammar);
         let ts = utils::find_max_state_id_from_kbnf_syntax_grammar(&grammar);
-        let engine = if Self::check_id_length(&grammar, u8::MAX.into())
-            && td <= u8::MAX.into()
-            && tp <= u8::MAX.into()
-            && tsp <= u8::MAX.into()
-            && ts <= u32::MAX as usize
-        {
-            let grammar: Grammar<u8> = Grammar::new(grammar, &vocabulary, regex_config)?;
-            let grammar = Arc::new(grammar);
-            let vocabulary = Arc::new(vocabulary);
-            EngineUnion::U8U8U8U8U32(EngineBase::new(
-                vocabulary,
-                grammar,
-                internal_config.engine_config,
-            )?)
-        } else if Self::check_id_length(&grammar, u8::MAX.into())
+        let vocabulary = Arc::new(vocabulary);
+        let prefilter = Self::compile_prefilter(&internal_config, &regex_config)?;
+        macro_rules! create_engine {
+            ($variant:ident, $ti:ty, $grammar:expr) => {{
+                let grammar: Grammar<$ti> = Grammar::new($grammar, &vocabulary, regex_config)?
+                    .with_analysis(analysis.clone());
+                create_engine_union!(
+                    $variant,
+                    Arc::new(grammar),
+                    vocabulary,
+                    internal_config,
+                    empty_match,
+                    prefilter,
+                    max_eager_cached_states
+                )
+            }};
+        }
+        let fits_u16_state_id = Self::check_id_length(&grammar, u8::MAX.into())
             && td <= u8::MAX.into()
             && tp <= u16::MAX.into()
             && tsp <= u16::MAX.into()
-            && ts <= u16::MAX as usize
-        {
-            let grammar: Grammar<u8> = Grammar::new(grammar, &vocabulary, regex_config)?;
-            let grammar = Arc::new(grammar);
-            let vocabulary = Arc::new(vocabulary);
-            EngineUnion::U8U8U16U16U16(EngineBase::new(
-                vocabulary,
-                grammar,
-                internal_config.engine_config,
-            )?)
-        } else if Self::check_id_length(&grammar, u16::MAX.into())
+            && ts <= u16::MAX as usize;
+        let fits_u32_state_id = Self::check_id_length(&grammar, u16::MAX.into())
             && td <= u16::MAX.into()
             && tp <= u32::MAX as usize
             && tsp <= u32::MAX as usize
+            && ts <= u32::MAX as usize;
+        let engine = if Self::check_id_length(&grammar, u8::MAX.into())
+            && td <= u8::MAX.into()
+            && tp <= u8::MAX.into()
+            && tsp <= u8::MAX.into()
             && ts <= u32::MAX as usize
         {
-            let grammar: Grammar<u16> = Grammar::new(grammar, &vocabulary, regex_config)?;
-            let grammar = Arc::new(grammar);
-            let vocabulary = Arc::new(vocabulary);
-            EngineUnion::U16U16U32U32U32(EngineBase::new(
-                vocabulary,
-                grammar,
-                internal_config.engine_config,
-            )?)
+            create_engine!(U8U8U8U8U32, u8, grammar)?
+        } else if fits_u16_state_id {
+            // The number of states is estimated from the simplified grammar, which may be exceeded
+            // after Grammar::new lowers the raw bytes, so the larger StateID(TS) is used instead.
+            match create_engine!(U8U8U16U16U16, u8, grammar.clone()) {
+                Err(e) if e.exceeds_state_id_size() => {
+                    create_engine!(U16U16U32U32U32, u16, grammar)?
+                }
+                engine => engine?,
+            }
+        } else if fits_u32_state_id {
+            create_engine!(U16U16U32U32U32, u16, grammar)?
         } else {
             return Err(CreateEngineError::InvalidInputError);
         };
         Ok(Self { union: engine })
     }
+    /// Create a new [`Engine`] from a compiled grammar, a [`Vocabulary`], and a [`Config`],
+    /// which skips parsing and compiling the grammar string.
+    ///
+    /// The grammar can be obtained from [`Engine::grammar`] or deserialized by [`Grammar::from_bytes`],
+    /// so multiple engines can share one compiled grammar.
+    /// The fields of the config that only affect the grammar compilation, i.e. [`Config::start_nonterminal`],
+    /// [`Config::compression_config`], [`Config::implicit_whitespace`], [`Config::normalize_newlines`],
+    /// [`Config::max_recursion_depth`] and [`Config::regex_config`] except for the prefilter, are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `grammar` - The compiled grammar.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `config` - The [`Config`] object.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the grammar and/or config's value range is not supported by the Engine,
+    /// or [`Config::prefilter_regex`] cannot be compiled.
+    /// Unlike [`Engine::with_config`], the size of the nonterminal IDs(TI) is fixed by the grammar,
+    /// so a grammar with [u8] IDs too large for 16-bit state IDs is not retried with larger ones.
+    pub fn from_grammar(
+        grammar: EngineGrammar,
+        vocabulary: Vocabulary,
+        config: Config,
+    ) -> Result<Engine, CreateEngineError> {
+        let tsp = config.expected_output_length;
+        let regex_config = config.regex_config;
+        let max_eager_cached_states = config.eager_cache_config.max_states;
+        let internal_config = config.internal_config();
+        let vocabulary = Arc::new(vocabulary);
+        let prefilter = Self::compile_prefilter(&internal_config, &regex_config)?;
+        macro_rules! create_engine {
+            ($variant:ident, $grammar:expr) => {{
+                let empty_match = internal_config.allow_empty_match
+                    && $grammar.is_nullable($grammar.get_start_nonterminal_id());
+                create_engine_union!(
+                    $variant,
+                    $grammar,
+                    vocabulary,
+                    internal_config,
+                    empty_match,
+                    prefilter,
+                    max_eager_cached_states
+                )
+            }};
+        }
+        let engine = match grammar {
+            EngineGrammar::U8(grammar) => {
+                let (td, tp) = grammar.max_dotted_position_and_production_id();
+                if td <= u8::MAX.into() && tp <= u8::MAX.into() && tsp <= u8::MAX.into() {
+                    create_engine!(U8U8U8U8U32, grammar)?
+                } else if td <= u8::MAX.into() && tp <= u16::MAX.into() && tsp <= u16::MAX.into() {
+                    create_engine!(U8U8U16U16U16, grammar)?
+                } else {
+                    return Err(CreateEngineError::InvalidInputError);
+                }
+            }
+            EngineGrammar::U16(grammar) => {
+                let (td, tp) = grammar.max_dotted_position_and_production_id();
+                if td <= u16::MAX.into() && tp <= u32::MAX as usize && tsp <= u32::MAX as usize {
+                    create_engine!(U16U16U32U32U32, grammar)?
+                } else {
+                    return Err(CreateEngineError::InvalidInputError);
+                }
+            }
+        };
+        Ok(Self { union: engine })
+    }
+    fn compile_prefilter(
+        internal_config: &InternalConfig,
+        regex_config: &RegexConfig,
+    ) -> Result<Option<dense::DFA<Vec<u32>>>, CreateEngineError> {
+        internal_config
+            .prefilter_regex
+            .as_deref()
+            .map(|regex| {
+                dense::Builder::new()
+                    .configure(regex_config.dfa_config())
+                    .syntax(regex_config.syntax_config())
+                    .build(regex)
+                    .map_err(|e| CreateEngineError::InvalidPrefilterRegexError(e.to_string()))
+            })
+            .transpose()
+    }
+    /// Create a new [`Engine`] from a llama.cpp GBNF grammar string, a [`Vocabulary`], and a [`Config`].
+    ///
+    /// The GBNF grammar is translated into KBNF as in [`Grammar::from_gbnf`],
+    /// so the `root` rule becomes the `start` nonterminal of the default [`Config::start_nonterminal`].
+    ///
+    /// # Arguments
+    ///
+    /// * `gbnf_grammar_str` - The GBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `config` - The [`Config`] object.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the GBNF grammar is malformed, the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    pub fn from_gbnf(
+        gbnf_grammar_str: &str,
+        vocabulary: Vocabulary,
+        config: Config,
+    ) -> Result<Engine, CreateEngineError> {
+        let kbnf_grammar_str = crate::gbnf::gbnf_to_kbnf(gbnf_grammar_str)
+            .map_err(crate::grammar::CreateGrammarError::GbnfParsingError)?;
+        Self::with_config(&kbnf_grammar_str, vocabulary, config)
+    }
+    /// Create a new [`Engine`] that matches a single regex, wrapped into a grammar by [`regex_to_kbnf`](crate::grammar::regex_to_kbnf).
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regex.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `early_end` - Whether the regex ends as soon as it matches, like `#e"..."`.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the regex is invalid or matches only the empty string or nothing.
+    pub fn from_regex(
+        pattern: &str,
+        vocabulary: Vocabulary,
+        early_end: bool,
+    ) -> Result<Engine, CreateEngineError> {
+        Self::new(
+            &crate::grammar::regex_to_kbnf(pattern, early_end),
+            vocabulary,
+        )
+    }
+    /// Create a new [`Engine`] from an KBNF grammar string, a [`Vocabulary`], and a [`Config`],
+    /// which computes the allowed token IDs with the [`SharedCache`] instead of its own cache.
+    ///
+    /// The engines created from the same grammar string and config can share a cache, e.g. one engine per worker thread,
+    /// so the allowed token IDs of the same states are computed only once.
+    /// See [`SharedCache`] for the invariant that all the engines sharing a cache must satisfy.
+    ///
+    /// # Arguments
+    ///
+    /// * `kbnf_syntax_grammar_str` - The KBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `config` - The [`Config`] object.
+    /// * `shared_cache` - The [`SharedCache`] object.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the engine cannot be created as in [`Engine::with_config`],
+    /// or [`CreateEngineError::MismatchedSharedCacheError`] when the shared cache is used by engines of a different grammar.
+    pub fn with_shared_cache(
+        kbnf_syntax_grammar_str: &str,
+        vocabulary: Vocabulary,
+        config: Config,
+        shared_cache: &SharedCache,
+    ) -> Result<Engine, CreateEngineError> {
+        let max_cache_entries = config.engine_config.max_cache_entries;
+        let engine = Self::with_config(kbnf_syntax_grammar_str, vocabulary, config)?;
+        let union = match engine.union {
+            EngineUnion::U8U8U8U8U32(engine) => match shared_cache.union.get_or_init(|| {
+                SharedCacheUnion::U8U8U8U8U32(SharedCacheBase::with_max_entries(max_cache_entries))
+            }) {
+                SharedCacheUnion::U8U8U8U8U32(cache) => {
+                    EngineUnion::U8U8U8U8U32(engine.with_shared_cache(cache.clone()))
+                }
+                _ => return Err(CreateEngineError::MismatchedSharedCacheError),
+            },
+            EngineUnion::U8U8U16U16U16(engine) => match shared_cache.union.get_or_init(|| {
+                SharedCacheUnion::U8U8U16U16U16(SharedCacheBase::with_max_entries(
+                    max_cache_entries,
+                ))
+            }) {
+                SharedCacheUnion::U8U8U16U16U16(cache) => {
+                    EngineUnion::U8U8U16U16U16(engine.with_shared_cache(cache.clone()))
+                }
+                _ => return Err(CreateEngineError::MismatchedSharedCacheError),
+            },
+            EngineUnion::U16U16U32U32U32(engine) => match shared_cache.union.get_or_init(|| {
+                SharedCacheUnion::U16U16U32U32U32(SharedCacheBase::with_max_entries(
+                    max_cache_entries,
+                ))
+            }) {
+                SharedCacheUnion::U16U16U32U32U32(cache) => {
+                    EngineUnion::U16U16U32U32U32(engine.with_shared_cache(cache.clone()))
+                }
+                _ => return Err(CreateEngineError::MismatchedSharedCacheError),
+            },
+        };
+        Ok(Self { union })
+    }
+    /// Creates a checkpoint of the current states, which can be restored by [`Engine::restore`] later.
+    ///
+    /// Only the mutable states are copied. The grammar and the vocabulary are shared, and the cache is not included,
+    /// so a checkpoint is much cheaper than cloning the engine.
+    /// The last rejection and the last accept metrics are part of the states and restored as well.
+    /// This is useful to explore multiple continuations from the same states, e.g. in beam search.
+    pub fn checkpoint(&self) -> EngineCheckpoint {
+        let union = match &self.union {
+            EngineUnion::U8U8U8U8U32(engine) => {
+                EngineCheckpointUnion::U8U8U8U8U32(engine.checkpoint())
+            }
+            EngineUnion::U8U8U16U16U16(engine) => {
+                EngineCheckpointUnion::U8U8U16U16U16(engine.checkpoint())
+            }
+            EngineUnion::U16U16U32U32U32(engine) => {
+                EngineCheckpointUnion::U16U16U32U32U32(engine.checkpoint())
+            }
+        };
+        EngineCheckpoint { union }
+    }
+    /// Restores the states from the checkpoint. The cache of the engine is preserved.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RestoreCheckpointError::MismatchedEngine`] when the checkpoint is not created by the engine
+    /// or its clones. The engine is not modified in this case.
+    pub fn restore(&mut self, checkpoint: &EngineCheckpoint) -> Result<(), RestoreCheckpointError> {
+        match (&mut self.union, &checkpoint.union) {
+            (EngineUnion::U8U8U8U8U32(engine), EngineCheckpointUnion::U8U8U8U8U32(checkpoint)) => {
+                engine.restore(checkpoint)
+            }
+            (
+                EngineUnion::U8U8U16U16U16(engine),
+                EngineCheckpointUnion::U8U8U16U16U16(checkpoint),
+            ) => engine.restore(checkpoint),
+            (
+                EngineUnion::U16U16U32U32U32(engine),
+                EngineCheckpointUnion::U16U16U32U32U32(checkpoint),
+            ) => engine.restore(checkpoint),
+            _ => Err(RestoreCheckpointError::MismatchedEngine),
+        }
+    }
+    /// Gets the allowed token IDs since last computation paired with their token strings in ascending order of token IDs,
+    /// which is useful for debugging grammars.
+    ///
+    /// Like [`EngineLike::allowed_token_ids_from_last_computation`], the result only changes after the allowed token IDs are computed.
+    pub fn allowed_token_strings(&self) -> Vec<(u32, String)> {
+        match &self.union {
+            EngineUnion::U8U8U8U8U32(engine) => engine.allowed_token_strings(),
+            EngineUnion::U8U8U16U16U16(engine) => engine.allowed_token_strings(),
+            EngineUnion::U16U16U32U32U32(engine) => engine.allowed_token_strings(),
+        }
+    }
+    /// Gets a human-readable fingerprint of the current states,
+    /// which is identical across runs and platforms for the same grammar, vocabulary and sequence of accepted tokens.
+    ///
+    /// See [`EngineBase::state_fingerprint`] for the contents of the fingerprint.
+    pub fn state_fingerprint(&self) -> String {
+        match &self.union {
+            EngineUnion::U8U8U8U8U32(engine) => engine.state_fingerprint(),
+            EngineUnion::U8U8U16U16U16(engine) => engine.state_fingerprint(),
+            EngineUnion::U16U16U32U32U32(engine) => engine.state_fingerprint(),
+        }
+    }
+    /// Gets the size in bytes of the state IDs(TS) of the underlying [`EngineBase`],
+    /// which [`Engine::with_config`] selects from the terminal lengths, the regex states and the substrings of the grammar.
+    pub fn state_id_width(&self) -> usize {
+        match &self.union {
+            EngineUnion::U8U8U8U8U32(_) => std::mem::size_of::<u32>(),
+            EngineUnion::U8U8U16U16U16(_) => std::mem::size_of::<u16>(),
+            EngineUnion::U16U16U32U32U32(_) => std::mem::size_of::<u32>(),
+        }
+    }
+    /// Get the compiled grammar of the engine,
+    /// which can be inspected or passed to [`Engine::from_grammar`] to create another engine without recompiling it.
+    pub fn grammar(&self) -> EngineGrammar {
+        match &self.union {
+            EngineUnion::U8U8U8U8U32(engine) => EngineGrammar::U8(engine.grammar()),
+            EngineUnion::U8U8U16U16U16(engine) => EngineGrammar::U8(engine.grammar()),
+            EngineUnion::U16U16U32U32U32(engine) => EngineGrammar::U16(engine.grammar()),
+        }
+    }
+    /// Get the ambiguity warnings of the grammar,
+    /// which are empty unless [`Config::collect_ambiguity_warnings`] is set.
+    pub fn ambiguity_warnings(&self) -> &[AmbiguityWarning] {
+        match &self.union {
+            EngineUnion::U8U8U8U8U32(engine) => engine.ambiguity_warnings(),
+            EngineUnion::U8U8U16U16U16(engine) => engine.ambiguity_warnings(),
+            EngineUnion::U16U16U32U32U32(engine) => engine.ambiguity_warnings(),
+        }
+    }
+    /// Sets the hook invoked with the ID, the start byte offset and the end byte offset of each nonterminal
+    /// completed when accepting tokens or bytes, which replaces the previous hook.
+    /// The name of the nonterminal can be resolved by [`EngineGrammar::nonterminal_str`] of [`Engine::grammar`].
+    ///
+    /// See [`EngineBase::set_completion_hook`] for when the hook is invoked.
+    pub fn set_completion_hook(&mut self, hook: CompletionHook) {
+        match &mut self.union {
+            EngineUnion::U8U8U8U8U32(engine) => engine.set_completion_hook(hook),
+            EngineUnion::U8U8U16U16U16(engine) => engine.set_completion_hook(hook),
+            EngineUnion::U16U16U32U32U32(engine) => engine.set_completion_hook(hook),
+        }
+    }
+    /// Removes the hook set by [`Engine::set_completion_hook`].
+    pub fn clear_completion_hook(&mut self) {
+        match &mut self.union {
+            EngineUnion::U8U8U8U8U32(engine) => engine.clear_completion_hook(),
+            EngineUnion::U8U8U16U16U16(engine) => engine.clear_completion_hook(),
+            EngineUnion::U16U16U32U32U32(engine) => engine.clear_completion_hook(),
+        }
+    }
 }
 
 macro_rules! match_engine_union {
@@ -186,7 +830,19 @@ macro_rules! match_engine_union {
     }
 }
 
-impl crate::engine_like::sealed::Sealed for Engine {}
+impl crate::engine_like::sealed::Sealed for Engine {
+    fn begin_transaction(&mut self) -> crate::engine_like::sealed::TransactionCheckpoint {
+        match_engine_union!(crate::engine_like::sealed::Sealed::begin_transaction[&mut self.union])
+    }
+
+    fn end_transaction(
+        &mut self,
+        checkpoint: crate::engine_like::sealed::TransactionCheckpoint,
+        commit: bool,
+    ) {
+        match_engine_union!(crate::engine_like::sealed::Sealed::end_transaction[&mut self.union, checkpoint, commit])
+    }
+}
 
 impl EngineLike for Engine {
     fn try_accept_new_token(
@@ -196,6 +852,21 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::try_accept_new_token[&mut self.union, token_id])
     }
 
+    fn try_accept_token_str(
+        &mut self,
+        token_str: &str,
+    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        match_engine_union!(EngineLike::try_accept_token_str[&mut self.union, token_str])
+    }
+
+    fn try_accept_new_tokens(
+        &mut self,
+        token_ids: &[u32],
+    ) -> Result<crate::engine_like::AcceptTokenResult, (usize, crate::engine_like::AcceptTokenError)>
+    {
+        match_engine_union!(EngineLike::try_accept_new_tokens[&mut self.union, token_ids])
+    }
+
     fn try_accept_new_bytes(
         &mut self,
         bytes: &[u8],
@@ -203,14 +874,50 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::try_accept_new_bytes[&mut self.union, bytes])
     }
 
+    fn prefill(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<crate::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        match_engine_union!(EngineLike::prefill[&mut self.union, bytes])
+    }
+
+    fn try_accept_new_str(
+        &mut self,
+        s: &str,
+    ) -> Result<crate::AcceptTokenResult, crate::engine_like::AcceptStrError> {
+        match_engine_union!(EngineLike::try_accept_new_str[&mut self.union, s])
+    }
+
     fn compute_allowed_token_ids(&mut self) {
         match_engine_union!(EngineLike::compute_allowed_token_ids[&mut self.union])
     }
 
+    fn compute_allowed_token_ids_deadline(
+        &mut self,
+        deadline: std::time::Instant,
+    ) -> crate::engine_like::ComputeOutcome {
+        match_engine_union!(EngineLike::compute_allowed_token_ids_deadline[&mut self.union, deadline])
+    }
+
+    fn allowed_token_ids_until(&mut self, limit: usize) -> Vec<u32> {
+        match_engine_union!(EngineLike::allowed_token_ids_until[&mut self.union, limit])
+    }
+
+    fn any_allowed_token(&mut self) -> Option<u32> {
+        match_engine_union!(EngineLike::any_allowed_token[&mut self.union])
+    }
+
     fn mask_logits(&self, logits: &mut [f32]) -> Result<(), crate::engine_like::MaskLogitsError> {
         match_engine_union!(EngineLike::mask_logits[&self.union, logits])
     }
 
+    fn mask_logits_buffer(
+        &self,
+        logits: &mut dyn crate::engine_like::LogitsMut,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        match_engine_union!(EngineLike::mask_logits_buffer[&self.union, logits])
+    }
+
     fn update_logits(
         &mut self,
         token_id: u32,
@@ -219,10 +926,37 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::update_logits[&mut self.union, token_id, logits])
     }
 
+    fn update_logits_for_token_str(
+        &mut self,
+        token_str: &str,
+        logits: &mut [f32],
+    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::UpdateLogitsError> {
+        match_engine_union!(EngineLike::update_logits_for_token_str[&mut self.union, token_str, logits])
+    }
+
+    fn bias_logits_by_grammar_prior(
+        &self,
+        logits: &mut [f32],
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        match_engine_union!(EngineLike::bias_logits_by_grammar_prior[&self.union, logits])
+    }
+
     fn allowed_token_ids_from_last_computation(&self) -> &fixedbitset_stack::FixedBitSet {
         match_engine_union!(EngineLike::allowed_token_ids_from_last_computation[&self.union])
     }
 
+    fn allowed_token_ids_snapshot(&self) -> crate::engine_like::AllowedTokens {
+        match_engine_union!(EngineLike::allowed_token_ids_snapshot[&self.union])
+    }
+
+    fn allowed_token_count(&self) -> usize {
+        match_engine_union!(EngineLike::allowed_token_count[&self.union])
+    }
+
+    fn disallowed_token_count(&self) -> usize {
+        match_engine_union!(EngineLike::disallowed_token_count[&self.union])
+    }
+
     fn write_disallowed_token_ids_to_buffer(
         &self,
         buffer: &mut [usize],
@@ -237,14 +971,122 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::write_allowed_token_ids_to_buffer[&self.union, buffer])
     }
 
+    fn write_mask_to_buffer(
+        &self,
+        buffer: &mut [f32],
+    ) -> Result<(), crate::engine_like::WriteBufferError> {
+        match_engine_union!(EngineLike::write_mask_to_buffer[&self.union, buffer])
+    }
+
+    fn allowed_token_ids_as_ranges(&self) -> Vec<(u32, u32)> {
+        match_engine_union!(EngineLike::allowed_token_ids_as_ranges[&self.union])
+    }
+
+    fn allowed_first_bytes(&mut self) -> &crate::utils::ByteSet {
+        match_engine_union!(EngineLike::allowed_first_bytes[&mut self.union])
+    }
+
+    fn fast_forward(&mut self) -> Vec<u8> {
+        match_engine_union!(EngineLike::fast_forward[&mut self.union])
+    }
+
+    fn shortest_completion(&self, max_len: usize) -> Option<Vec<u8>> {
+        match_engine_union!(EngineLike::shortest_completion[&self.union, max_len])
+    }
+
+    fn parse_tree(&self) -> Option<crate::engine_like::ParseNode> {
+        match_engine_union!(EngineLike::parse_tree[&self.union])
+    }
+
+    fn captures(&self) -> AHashMap<String, Vec<u8>> {
+        match_engine_union!(EngineLike::captures[&self.union])
+    }
+
+    fn last_rejection(&self) -> Option<&crate::engine_like::RejectionDetail> {
+        match_engine_union!(EngineLike::last_rejection[&self.union])
+    }
+
+    fn last_accept_metrics(&self) -> crate::engine_like::AcceptMetrics {
+        match_engine_union!(EngineLike::last_accept_metrics[&self.union])
+    }
+
+    fn explain_rejection(&self, token_id: u32) -> Option<crate::engine_like::RejectionExplanation> {
+        match_engine_union!(EngineLike::explain_rejection[&self.union, token_id])
+    }
+
+    fn in_progress_items(&self) -> Vec<crate::engine_like::InProgressItem> {
+        match_engine_union!(EngineLike::in_progress_items[&self.union])
+    }
+
+    fn can_finish(&self) -> bool {
+        match_engine_union!(EngineLike::can_finish[&self.union])
+    }
+
     fn is_finished(&self) -> bool {
         match_engine_union!(EngineLike::is_finished[&self.union])
     }
 
+    fn is_dead(&self) -> bool {
+        match_engine_union!(EngineLike::is_dead[&self.union])
+    }
+
+    fn transaction(&mut self) -> crate::engine_like::EngineTransaction<'_> {
+        crate::engine_like::EngineTransaction::new(self)
+    }
+
+    fn try_accept_new_token_speculative(
+        &mut self,
+        token_id: u32,
+    ) -> Result<crate::engine_like::SpeculativeToken<'_>, crate::engine_like::AcceptTokenError>
+    {
+        crate::engine_like::SpeculativeToken::new(self, token_id)
+    }
+
     fn reset(&mut self) {
         match_engine_union!(EngineLike::reset[&mut self.union])
     }
 
+    fn clone_fresh(&self) -> Self {
+        let union = match &self.union {
+            EngineUnion::U8U8U8U8U32(engine) => EngineUnion::U8U8U8U8U32(engine.clone_fresh()),
+            EngineUnion::U8U8U16U16U16(engine) => EngineUnion::U8U8U16U16U16(engine.clone_fresh()),
+            EngineUnion::U16U16U32U32U32(engine) => {
+                EngineUnion::U16U16U32U32U32(engine.clone_fresh())
+            }
+        };
+        Self { union }
+    }
+
+    fn rewind(&mut self) {
+        match_engine_union!(EngineLike::rewind[&mut self.union])
+    }
+
+    fn rollback(&mut self, n: usize) -> Result<(), crate::engine_like::RollbackError> {
+        match_engine_union!(EngineLike::rollback[&mut self.union, n])
+    }
+
+    fn heal_last_token(
+        &mut self,
+    ) -> Result<crate::engine_like::HealResult, crate::engine_like::RollbackError> {
+        match_engine_union!(EngineLike::heal_last_token[&mut self.union])
+    }
+
+    fn replay(&mut self) -> Result<(), crate::engine_like::AcceptTokenError> {
+        match_engine_union!(EngineLike::replay[&mut self.union])
+    }
+
+    fn accepted_bytes(&self) -> &[u8] {
+        match_engine_union!(EngineLike::accepted_bytes[&self.union])
+    }
+
+    fn cache_len(&self) -> usize {
+        match_engine_union!(EngineLike::cache_len[&self.union])
+    }
+
+    fn clear_cache(&mut self) {
+        match_engine_union!(EngineLike::clear_cache[&mut self.union])
+    }
+
     fn into_boxed_engine(self) -> Box<dyn EngineLike> {
         match_engine_union!(EngineLike::into_boxed_engine[self.union])
     }
@@ -252,3 +1094,60 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::vocab[&self.union])
     }
 }
+
+/// Computes the allowed token IDs of each engine and masks the corresponding row of the logits.
+///
+/// The logits are a contiguous buffer of shape `[engines.len(), row_length]` in row-major order,
+/// where `row_length` is `logits.len() / engines.len()`.
+/// Each row is masked as in [`EngineLike::mask_logits`] after [`EngineLike::compute_allowed_token_ids`] is called on its engine.
+///
+/// With the `rayon` feature enabled, the engines are processed in parallel in the current rayon thread pool.
+///
+/// # Errors
+///
+/// Returns [`MaskLogitsError::InvalidLogitsLength`](crate::engine_like::MaskLogitsError::InvalidLogitsLength)
+/// when the logits cannot be split into rows of the same length,
+/// or when a row is shorter than the vocabulary size of its engine.
+/// Neither the engines nor the logits are updated in this case.
+pub fn mask_logits_batch(
+    engines: &mut [&mut Engine],
+    logits: &mut [f32],
+) -> Result<(), crate::engine_like::MaskLogitsError> {
+    if engines.is_empty() && logits.is_empty() {
+        return Ok(());
+    }
+    if engines.is_empty() || !logits.len().is_multiple_of(engines.len()) {
+        return Err(crate::engine_like::MaskLogitsError::InvalidLogitsLength);
+    }
+    let row_length = logits.len() / engines.len();
+    mask_logits_rows(engines, logits.chunks_mut(row_length.max(1)).collect())
+}
+
+/// Computes the allowed token IDs of each engine and masks the corresponding row, which may not be contiguous.
+pub(crate) fn mask_logits_rows(
+    engines: &mut [&mut Engine],
+    mut rows: Vec<&mut [f32]>,
+) -> Result<(), crate::engine_like::MaskLogitsError> {
+    if engines.len() != rows.len()
+        || engines.iter().zip(rows.iter()).any(|(engine, row)| {
+            // The capacity of the allowed token IDs is the vocabulary size.
+            row.len() < engine.allowed_token_ids_from_last_computation().len()
+        })
+    {
+        return Err(crate::engine_like::MaskLogitsError::InvalidLogitsLength);
+    }
+    let mask = |(engine, row): (&mut &mut Engine, &mut &mut [f32])| {
+        engine.compute_allowed_token_ids();
+        engine.mask_logits(row)
+    };
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        engines
+            .par_iter_mut()
+            .zip(rows.par_iter_mut())
+            .try_for_each(mask)
+    }
+    #[cfg(not(feature = "rayon"))]
+    engines.iter_mut().zip(rows.iter_mut()).try_for_each(mask)
+}
@@ -4,6 +4,7 @@ use fixedbitset_stack::FixedBitSet;
 use jaggedarray::jagged_array::JaggedArray;
 use jaggedarray::jagged_array::JaggedArrayViewTrait;
 use jaggedarray::JaggedArrayMutViewTrait;
+use kbnf_regex_automata::dfa::dense;
 use kbnf_regex_automata::dfa::Automaton;
 use kbnf_regex_automata::util::primitives::StateID;
 use kbnf_syntax::regex::FiniteStateAutomaton;
@@ -12,24 +13,53 @@ use num::{
     traits::{ConstOne, ConstZero, NumAssign, NumOps},
     Num,
 };
+use std::cell::Cell;
 use std::fmt::Debug;
 use std::hint::unreachable_unchecked;
 use std::slice;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
 
 use crate::engine::EngineConfig;
+use crate::engine_like::AcceptMetrics;
+use crate::engine_like::ComputeOutcome;
 use crate::engine_like::EngineLike;
+use crate::engine_like::RejectionDetail;
 use crate::engine_like::WriteBufferError;
 use crate::grammar::RegexType;
+use crate::json;
+use crate::token_trie::TokenTrie;
 use crate::utils;
 use crate::utils::dispatch_by_dfa_state_status;
 use crate::utils::ByteSet;
-use crate::vocabulary::TokenIterItem;
 use crate::AcceptTokenResult;
 use crate::{
-    grammar::{Grammar, HIRNode, NonterminalID},
+    grammar::{
+        AmbiguityWarning, BoundedSubstrings, Grammar, HIRNode, LengthPrefixed, NonterminalID,
+    },
     vocabulary::Vocabulary,
 };
+/// The hook set by [`EngineBase::set_completion_hook`], which is invoked with the ID,
+/// the start byte offset and the end byte offset of each completed nonterminal.
+///
+/// The name of the nonterminal can be resolved by [`Grammar::nonterminal_str`]
+/// or [`EngineGrammar::nonterminal_str`](crate::engine::EngineGrammar::nonterminal_str).
+pub type CompletionHook = Box<dyn FnMut(NonterminalID<usize>, usize, usize) + Send>;
+/// The hook of an engine, which is not carried into the clones since a boxed closure cannot be cloned.
+struct CompletionHookSlot(Option<Mutex<CompletionHook>>);
+impl Clone for CompletionHookSlot {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+impl CompletionHookSlot {
+    fn hook_mut(&mut self) -> Option<&mut CompletionHook> {
+        // A panic in the hook never leaves the hook half-updated, so the poisoned hook is still usable.
+        self.0
+            .as_mut()
+            .map(|hook| hook.get_mut().unwrap_or_else(PoisonError::into_inner))
+    }
+}
 type EarleySets<TN, TD, TP, TSP, TS> = JaggedArray<EarleyItem<TN, TD, TP, TSP, TS>, Vec<usize>, 2>;
 const USIZE_WIDTH: usize = std::mem::size_of::<usize>();
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -121,7 +151,12 @@ where
                     }
                 },
                 HIRNode::Nonterminal(_) => String::new(),
-                HIRNode::Substrings(_) => {
+                HIRNode::Substrings(_)
+                | HIRNode::LengthPrefixed(_)
+                | HIRNode::BoundedSubstrings(_)
+                | HIRNode::ByteSetComplement(_)
+                | HIRNode::AnyChar
+                | HIRNode::Json => {
                     format!("[{}]", self.state_id.as_())
                 }
             }
@@ -300,9 +335,138 @@ pub enum CreateEngineBaseError {
     )]
     /// The substrings length exceeds the maximum substrings length allowed by the current size of StateID(TS).
     SubstringsTooLarge(usize, usize),
+    #[error(
+        "Length-prefixed regex length {0} exceeds {1}, the maximum length-prefixed regex length allowed by current size of StateID(TS).
+     Consider reducing regex states or use larger StateID(TS)."
+    )]
+    /// The regex length in a length-prefixed node exceeds the maximum regex length allowed by the current size of StateID(TS).
+    LengthPrefixedTooLarge(usize, usize),
+    #[error(
+        "Bounded substrings state bits {0} exceeds {1}, the number of bits of current StateID(TS).
+     Consider reducing substrings length or use larger StateID(TS)."
+    )]
+    /// The bits needed by the state of a bounded substrings node exceed the bits of the current StateID(TS).
+    BoundedSubstringsTooLarge(usize, usize),
+    #[error(
+        "JSON state bits {0} exceeds {1}, the number of bits of current StateID(TS).
+     Consider using larger StateID(TS)."
+    )]
+    /// The bits needed by the state of a JSON node exceed the bits of the current StateID(TS).
+    JsonTooLarge(usize, usize),
+    #[error("EOS token ID {0} is not less than the vocabulary size {1}.")]
+    /// The EOS token ID is out of the vocabulary.
+    InvalidEosTokenID(u32, usize),
+}
+impl CreateEngineBaseError {
+    /// Whether the error is caused by a grammar too large for the current size of StateID(TS).
+    pub(crate) fn exceeds_state_id_size(&self) -> bool {
+        matches!(
+            self,
+            Self::TerminalTooLong(..)
+                | Self::RegexTooLarge(..)
+                | Self::SubstringsTooLarge(..)
+                | Self::LengthPrefixedTooLarge(..)
+                | Self::BoundedSubstringsTooLarge(..)
+                | Self::JsonTooLarge(..)
+        )
+    }
+}
+/// The error type for errors in restoring an engine from a checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RestoreCheckpointError {
+    #[error("The checkpoint is created by an engine with a different grammar or vocabulary.")]
+    /// The checkpoint is created by an engine with a different grammar or vocabulary.
+    /// Only the checkpoints created by the engine itself or its clones can be restored.
+    MismatchedEngine,
+}
+/// The states before an accepted token or bytes, which are used to roll back the acceptance.
+#[derive(Debug, Clone, Copy)]
+struct AcceptMarker {
+    earley_sets_len: usize,
+    finished: bool,
+    accepted_bytes_len: usize,
+    prefilter_state: StateID,
+}
+/// The cache of the allowed token IDs of the weighted alternatives, see [`EngineLike::bias_logits_by_grammar_prior`].
+type PriorCache<TI, TD, TP, TSP, TS> =
+    utils::LruCache<(NonterminalID<TI>, EarleySets<TI, TD, TP, TSP, TS>), FixedBitSet>;
+/// The DFA of a prefilter regex, see [`EngineBase::with_prefilter`].
+#[derive(Debug)]
+struct Prefilter {
+    dfa: dense::DFA<Vec<u32>>,
+    /// The indices of the match states that accept neither more bytes nor the end of input.
+    ///
+    /// The DFA reports a match one byte after its end, so the byte entering such a state
+    /// only ends the match before it and is rejected.
+    exhausted_match_states: FixedBitSet,
+}
+
+impl Prefilter {
+    fn new(dfa: dense::DFA<Vec<u32>>) -> Self {
+        let is_rejected = |state| {
+            dfa.is_special_state(state) && (dfa.is_dead_state(state) || dfa.is_quit_state(state))
+        };
+        let mut exhausted_match_states = FixedBitSet::with_capacity(dfa.state_len());
+        for state in dfa.states() {
+            let id = state.id();
+            if dfa.is_match_state(id)
+                && !dfa.is_match_state(dfa.next_eoi_state(id))
+                && (0..=u8::MAX).all(|byte| is_rejected(dfa.next_state(id, byte)))
+            {
+                exhausted_match_states.insert(id.as_usize() >> dfa.stride2());
+            }
+        }
+        Self {
+            dfa,
+            exhausted_match_states,
+        }
+    }
+
+    /// Feeds a byte to the prefilter, returning the next state or `None` if the byte is rejected.
+    #[inline]
+    fn feed(&self, state: StateID, byte: u8) -> Option<StateID> {
+        let dfa = &self.dfa;
+        let state = dfa.next_state(state, byte);
+        let rejected = dfa.is_special_state(state)
+            && (dfa.is_dead_state(state)
+                || dfa.is_quit_state(state)
+                || dfa.is_match_state(state)
+                    && self
+                        .exhausted_match_states
+                        .contains(state.as_usize() >> dfa.stride2()));
+        (!rejected).then_some(state)
+    }
+}
+/// The end positions memoized when reconstructing a derivation in [`EngineLike::parse_tree`].
+struct DerivationMemo<'a> {
+    bytes: &'a [u8],
+    /// The end positions keyed by the nonterminal ID and the start position.
+    nonterminal_ends: AHashMap<(usize, usize), FixedBitSet>,
+    /// The end positions of the terminal-like nodes keyed by the nonterminal ID, the production index,
+    /// the dot position and the start position.
+    node_ends: AHashMap<(usize, usize, usize, usize), FixedBitSet>,
+    /// The nonterminals and start positions evaluated in the current iteration of the fixpoint.
+    evaluated: AHashSet<(usize, usize)>,
+    changed: bool,
+    /// The nonterminals and spans being derived, which are skipped to avoid cycles like `a ::= b; b ::= a;`.
+    deriving: AHashSet<(usize, usize, usize)>,
+    /// The regex IDs and spans of the regexes with named capture groups in the derivation so far, from left to right.
+    capture_spans: Vec<(usize, usize, usize)>,
 }
+/// The decoded state of a length-prefixed node.
+#[derive(Debug, Clone, Copy)]
+struct LengthPrefixedState {
+    /// Whether the numeric regex has matched and the content regex is being matched.
+    in_content: bool,
+    dfa_state: StateID,
+    /// The length read so far when matching the numeric regex,
+    /// or the remaining length when matching the content regex.
+    count: usize,
+}
+#[allow(clippy::type_complexity)]
 #[derive(Clone)]
-struct StagedChanges<TI, TSP>
+/// The low-level engine struct that implements the Earley recognizer with Leo optimization and Earley sets compaction.
+pub struct EngineBase<TI, TD, TP, TSP, TS>
 where
     TI: Num
         + AsPrimitive<usize>
@@ -316,16 +480,78 @@ where
         + num::Bounded
         + std::convert::TryFrom<usize>
         + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
     TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
 {
+    vocabulary: Arc<Vocabulary>,
+    grammar: Arc<Grammar<TI>>,
+    allowed_first_bytes: ByteSet,
+    allowed_token_ids: FixedBitSet,
+    earley_sets: EarleySets<TI, TD, TP, TSP, TS>,
+    cache: utils::LruCache<EarleySets<TI, TD, TP, TSP, TS>, FixedBitSet>,
+    // The allowed token IDs of the weighted alternatives, keyed by the nonterminal and the restricted Earley sets.
+    // Shared by the clones, since they only depend on the states and `bias_logits_by_grammar_prior` borrows the engine immutably.
+    prior_cache: Arc<Mutex<PriorCache<TI, TD, TP, TSP, TS>>>,
+    to_be_completed_items: AHashSet<ToBeCompletedItem<TI, TSP>>,
+    to_be_completed_items_buffer: AHashSet<ToBeCompletedItem<TI, TSP>>,
+    deduplication_buffer: AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
+    // Maybe a smallvec will be better. Profiling is needed to make a decision.
+    // I feel like copying the item is better than add a reference to the item since the item is relatively small(<=16 bytes)
+    // Memory pool actually makes the performance worse. Maybe it will be better if there is a lot of postdot items for a single Dotted.
+    postdot_items: AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
     postdot_items_since_last_commit: AHashSet<Dotted<TI, TSP>>,
-    earley_sets_len_since_last_commit: usize,
+    // Maybe we could do a tree-like search to broaden the definition of leo items later.
+    column_to_postdot_nonterminals: AHashMap<TSP, AHashSet<NonterminalID<TI>>>,
+    leo_items: AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+    leo_items_buffer: Vec<ToBeCompletedItem<TI, TSP>>,
+    already_predicted_nonterminals: FixedBitSet,
+    finished: bool,
+    // The bytes accepted since the last reset, which are preserved by rewind.
+    accepted_bytes: Vec<u8>,
+    // One marker per accepted token or bytes since the last reset or rewind.
+    accept_markers: Vec<AcceptMarker>,
+    // Whether to record the accepted bytes and the markers, which rollback, replay and the derivation need.
+    track_accepted_bytes: bool,
+    eos_token_id: Option<u32>,
+    ignored_token_ids: AHashSet<u32>,
+    mask_after_finish: bool,
+    // Whether accepting after the engine is finished is an error rather than a no-op.
+    finished_is_terminal: bool,
+    // Whether the grammar matches the empty string, so the engine can finish before accepting any bytes.
+    empty_match: bool,
+    detailed_errors: bool,
+    last_rejection: Option<RejectionDetail>,
+    collect_accept_metrics: bool,
+    accept_metrics: AcceptMetrics,
+    // Whether healing the last token restricts the allowed token IDs to the heal candidates.
+    token_healing: bool,
+    // The DFA of the prefilter regex and its state after the bytes accepted since the last reset or rewind.
+    // The state is unused without a prefilter.
+    prefilter: Option<Arc<Prefilter>>,
+    prefilter_state: StateID,
+    completion_hook: CompletionHookSlot,
+    // The byte offset of each Earley set since the last reset or rewind,
+    // which differs from the index of the Earley set after the compaction.
+    column_offsets: Vec<usize>,
+    // The number of bytes accepted since the last compaction.
+    uncompacted_bytes: usize,
+    ambiguity_warnings: Vec<AmbiguityWarning>,
+    shared_cache: Option<SharedCacheBase<TI, TD, TP, TSP, TS>>,
+    config: EngineConfig,
 }
 
 #[allow(clippy::type_complexity)]
 #[derive(Clone)]
-/// The low-level engine struct that implements the Earley recognizer with Leo optimization and Earley sets compaction.
-pub struct EngineBase<TI, TD, TP, TSP, TS>
+/// The snapshot of the mutable states of an [`EngineBase`], created by [`EngineBase::checkpoint`].
+///
+/// The grammar and the vocabulary are shared with the engine, and the cache is not included.
+pub struct EngineBaseCheckpoint<TI, TD, TP, TSP, TS>
 where
     TI: Num
         + AsPrimitive<usize>
@@ -353,22 +579,254 @@ where
     allowed_first_bytes: ByteSet,
     allowed_token_ids: FixedBitSet,
     earley_sets: EarleySets<TI, TD, TP, TSP, TS>,
-    cache: AHashMap<EarleySets<TI, TD, TP, TSP, TS>, FixedBitSet>,
-    to_be_completed_items: AHashSet<ToBeCompletedItem<TI, TSP>>,
-    to_be_completed_items_buffer: AHashSet<ToBeCompletedItem<TI, TSP>>,
-    deduplication_buffer: AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
-    // Maybe a smallvec will be better. Profiling is needed to make a decision.
-    // I feel like copying the item is better than add a reference to the item since the item is relatively small(<=16 bytes)
-    // Memory pool actually makes the performance worse. Maybe it will be better if there is a lot of postdot items for a single Dotted.
     postdot_items: AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
-    postdot_items_since_last_commit: AHashSet<Dotted<TI, TSP>>,
-    // Maybe we could do a tree-like search to broaden the definition of leo items later.
     column_to_postdot_nonterminals: AHashMap<TSP, AHashSet<NonterminalID<TI>>>,
     leo_items: AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
-    leo_items_buffer: Vec<ToBeCompletedItem<TI, TSP>>,
-    already_predicted_nonterminals: FixedBitSet,
     finished: bool,
-    config: EngineConfig,
+    accepted_bytes: Vec<u8>,
+    accept_markers: Vec<AcceptMarker>,
+    prefilter_state: StateID,
+    column_offsets: Vec<usize>,
+    uncompacted_bytes: usize,
+    last_rejection: Option<RejectionDetail>,
+    accept_metrics: AcceptMetrics,
+}
+
+impl<TI, TD, TP, TSP, TS> Debug for EngineBaseCheckpoint<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + Eq
+        + std::hash::Hash
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EngineBaseCheckpoint")
+            .field("earley_sets_len", &self.earley_sets.len())
+            .field("finished", &self.finished)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The cache of allowed token IDs shared by multiple [`EngineBase`]s, possibly across threads,
+/// which is attached by [`EngineBase::with_shared_cache`].
+///
+/// The cache is keyed by the Earley sets, which only identify the same states in engines of the same grammar.
+/// Hence all the engines sharing a cache MUST be created from the same grammar string and config;
+/// otherwise the engines may see the allowed token IDs of unrelated states.
+///
+/// Like the cache of an engine, the least recently used entry is evicted when the cache holds more entries than its capacity.
+#[allow(clippy::type_complexity)]
+pub struct SharedCacheBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + Eq
+        + std::hash::Hash
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
+{
+    // A lookup marks the entry as recently used, so even the lookups take the lock exclusively.
+    // The lock is only held to copy the allowed token IDs, which is much cheaper than computing them.
+    entries: Arc<Mutex<utils::LruCache<EarleySets<TI, TD, TP, TSP, TS>, FixedBitSet>>>,
+}
+
+impl<TI, TD, TP, TSP, TS> Clone for SharedCacheBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + Eq
+        + std::hash::Hash
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<TI, TD, TP, TSP, TS> Default for SharedCacheBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + Eq
+        + std::hash::Hash
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
+{
+    fn default() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(utils::LruCache::new(None))),
+        }
+    }
+}
+
+impl<TI, TD, TP, TSP, TS> Debug for SharedCacheBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + Eq
+        + std::hash::Hash
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedCacheBase")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<TI, TD, TP, TSP, TS> SharedCacheBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + Eq
+        + std::hash::Hash
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
+{
+    /// Creates an empty shared cache without a capacity.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Creates an empty shared cache that holds at most `max_entries` entries, or unlimited entries if it is `None`.
+    pub fn with_max_entries(max_entries: Option<usize>) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(utils::LruCache::new(max_entries))),
+        }
+    }
+    /// Gets the number of entries in the cache.
+    pub fn len(&self) -> usize {
+        // A panic never leaves the map half-updated, so the poisoned lock is still usable.
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+    /// Checks whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Removes all the entries of the cache for all the engines sharing it.
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Calls `f` with the allowed token IDs of the Earley sets under the lock if they are cached.
+    fn get<R>(
+        &self,
+        earley_sets: &EarleySets<TI, TD, TP, TSP, TS>,
+        f: impl FnOnce(&FixedBitSet) -> R,
+    ) -> Option<R> {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(earley_sets)
+            .map(f)
+    }
+
+    /// Inserts the allowed token IDs of the Earley sets unless another engine has inserted them.
+    fn insert(
+        &self,
+        earley_sets: &EarleySets<TI, TD, TP, TSP, TS>,
+        allowed_token_ids: &FixedBitSet,
+    ) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.get(earley_sets).is_none() {
+            entries.insert(earley_sets.clone(), allowed_token_ids.clone());
+        }
+    }
 }
 
 impl<TI, TD, TP, TSP, TS> Debug for EngineBase<TI, TD, TP, TSP, TS>
@@ -412,12 +870,15 @@ where
             )
             .field(
                 "cache",
-                &utils::get_deterministic_display_form_from_hash_map(&self.cache, |(k, v)| {
-                    (
-                        self.get_display_form_from_earley_sets(k),
-                        (self.get_display_form_from_token_ids(v),),
-                    )
-                }),
+                &utils::get_deterministic_display_form_from_hash_map(
+                    self.cache.iter(),
+                    |(k, v)| {
+                        (
+                            self.get_display_form_from_earley_sets(k),
+                            (self.get_display_form_from_token_ids(v),),
+                        )
+                    },
+                ),
             )
             .field("to_be_completed_items", {
                 &utils::get_deterministic_display_form_from_hash_set(
@@ -552,15 +1013,19 @@ where
         Self::validate_ts_size_for_terminals(&grammar)?;
         Self::validate_ts_size_for_regexes(&grammar)?;
         Self::validate_ts_size_for_suffix_automata(&grammar)?;
+        Self::validate_ts_size_for_length_prefixed(&grammar)?;
+        Self::validate_ts_size_for_bounded_substrings(&grammar)?;
+        Self::validate_ts_size_for_json(&grammar)?;
         // Init fields
-        let allowed_first_bytes = ByteSet::with_capacity(u8::MAX as usize);
+        let allowed_first_bytes = ByteSet::with_capacity(256);
         let allowed_token_ids = FixedBitSet::with_capacity(vocabulary.vocab_size());
         let earley_sets = JaggedArray::new();
-        let cache = AHashMap::default();
-        let to_be_completed_items = AHashSet::default();
+        let cache = utils::LruCache::new(config.max_cache_entries);
+        let prior_cache = Arc::new(Mutex::new(utils::LruCache::new(config.max_cache_entries)));
+        let to_be_completed_items = utils::new_hash_set(config.deterministic);
         let already_predicted_nonterminals =
             FixedBitSet::with_capacity(grammar.nonterminals_size());
-        let postdot_items = AHashMap::default();
+        let postdot_items = utils::new_hash_map(config.deterministic);
         let mut engine = Self {
             vocabulary,
             grammar,
@@ -568,24 +1033,667 @@ where
             allowed_token_ids,
             earley_sets,
             cache,
+            prior_cache,
             to_be_completed_items,
             already_predicted_nonterminals,
             config,
             postdot_items,
-            leo_items: AHashMap::default(),
+            leo_items: utils::new_hash_map(config.deterministic),
             finished: false,
-            to_be_completed_items_buffer: AHashSet::default(),
+            accepted_bytes: Vec::new(),
+            accept_markers: Vec::new(),
+            track_accepted_bytes: true,
+            eos_token_id: None,
+            ignored_token_ids: utils::new_hash_set(config.deterministic),
+            mask_after_finish: false,
+            finished_is_terminal: true,
+            empty_match: false,
+            detailed_errors: false,
+            last_rejection: None,
+            collect_accept_metrics: false,
+            accept_metrics: AcceptMetrics::default(),
+            token_healing: false,
+            prefilter: None,
+            prefilter_state: StateID::ZERO,
+            completion_hook: CompletionHookSlot(None),
+            column_offsets: Vec::new(),
+            uncompacted_bytes: 0,
+            ambiguity_warnings: Vec::new(),
+            shared_cache: None,
+            to_be_completed_items_buffer: utils::new_hash_set(config.deterministic),
             leo_items_buffer: Vec::new(),
-            postdot_items_since_last_commit: AHashSet::default(),
-            deduplication_buffer: AHashSet::default(),
-            column_to_postdot_nonterminals: AHashMap::default(),
+            postdot_items_since_last_commit: utils::new_hash_set(config.deterministic),
+            deduplication_buffer: utils::new_hash_set(config.deterministic),
+            column_to_postdot_nonterminals: utils::new_hash_map(config.deterministic),
         };
-        engine.reset();
+        engine.initialize_states();
         Ok(engine)
     }
-
-    fn get_display_form_from_earley_sets(
-        &self,
+    /// Sets the EOS token ID, which is allowed exactly when the engine is finished.
+    ///
+    /// Accepting the EOS token returns [`AcceptTokenResult::Finished`] if the engine is finished,
+    /// and is rejected otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CreateEngineBaseError::InvalidEosTokenID`] if the token ID is not less than the vocabulary size.
+    pub fn with_eos_token_id(
+        mut self,
+        eos_token_id: Option<u32>,
+    ) -> Result<Self, CreateEngineBaseError> {
+        if let Some(id) = eos_token_id {
+            let vocab_size = self.vocabulary.vocab_size();
+            if id as usize >= vocab_size {
+                return Err(CreateEngineBaseError::InvalidEosTokenID(id, vocab_size));
+            }
+        }
+        self.eos_token_id = eos_token_id;
+        Ok(self)
+    }
+    /// Sets the token IDs that are accepted as no-ops and never allowed, e.g. BOS or padding tokens.
+    ///
+    /// The token IDs need not be in the vocabulary. An ignored EOS token ID is ignored rather than treated as the EOS token.
+    pub fn with_ignored_token_ids(
+        mut self,
+        ignored_token_ids: impl IntoIterator<Item = u32>,
+    ) -> Self {
+        self.ignored_token_ids = ignored_token_ids.into_iter().collect();
+        self
+    }
+    /// Sets whether [`EngineLike::update_logits`] masks all the logits except the EOS token's
+    /// when the token finishes the engine. It has no effect unless the EOS token ID is set.
+    pub fn with_mask_after_finish(mut self, mask_after_finish: bool) -> Self {
+        self.mask_after_finish = mask_after_finish;
+        self
+    }
+    /// Sets whether accepting tokens, bytes or strings after the engine is finished returns
+    /// [`AcceptTokenError::Finished`](crate::engine_like::AcceptTokenError::Finished).
+    /// When unset, such accepts return [`AcceptTokenResult::Finished`] without changing the states.
+    pub fn with_finished_is_terminal(mut self, finished_is_terminal: bool) -> Self {
+        self.finished_is_terminal = finished_is_terminal;
+        self
+    }
+    /// Sets whether the grammar matches the empty string, e.g. when the start nonterminal is nullable.
+    ///
+    /// When set, the engine can finish before accepting any bytes since the last reset or rewind,
+    /// where the EOS token is allowed and accepted, while the engine is not finished and still accepts more bytes.
+    pub fn with_empty_match(mut self, empty_match: bool) -> Self {
+        self.empty_match = empty_match;
+        self
+    }
+    /// Sets whether to record the [`RejectionDetail`] of rejected bytes, which can be retrieved by [`EngineLike::last_rejection`].
+    ///
+    /// The rejected prefix is accepted again on a copy of the states to find the allowed first bytes,
+    /// so it only costs time when the bytes are rejected.
+    pub fn with_detailed_errors(mut self, detailed_errors: bool) -> Self {
+        self.detailed_errors = detailed_errors;
+        self
+    }
+    /// Sets whether to record the accepted bytes, which [`EngineLike::rollback`], [`EngineLike::replay`]
+    /// and the derivation like [`EngineLike::parse_tree`] need.
+    pub fn with_track_accepted_bytes(mut self, track_accepted_bytes: bool) -> Self {
+        self.track_accepted_bytes = track_accepted_bytes;
+        self
+    }
+    /// Sets whether to count the work of each accept, which can be retrieved by [`EngineLike::last_accept_metrics`].
+    pub fn with_accept_metrics(mut self, collect_accept_metrics: bool) -> Self {
+        self.collect_accept_metrics = collect_accept_metrics;
+        self
+    }
+    /// Sets whether [`EngineLike::heal_last_token`] restricts the allowed token IDs to the heal candidates.
+    pub fn with_token_healing(mut self, token_healing: bool) -> Self {
+        self.token_healing = token_healing;
+        self
+    }
+    /// Sets the DFA of the prefilter regex, which runs in lockstep with the grammar,
+    /// so a byte is accepted only if both the grammar and the DFA anchored at the start of the output accept it.
+    ///
+    /// The rejected bytes of the DFA are pruned from the allowed first bytes and the traversal of the vocabulary
+    /// before the grammar is checked. The cache is not used with a prefilter,
+    /// since the allowed token IDs depend on the state of the DFA as well. The states are reinitialized afterwards.
+    pub fn with_prefilter(mut self, prefilter: Option<dense::DFA<Vec<u32>>>) -> Self {
+        self.prefilter = prefilter.map(|dfa| Arc::new(Prefilter::new(dfa)));
+        self.initialize_states();
+        self
+    }
+    /// Sets the hook invoked with the ID, the start byte offset and the end byte offset of each nonterminal
+    /// completed when accepting tokens or bytes, which replaces the previous hook.
+    ///
+    /// The offsets are into the bytes accepted since the last reset or rewind, like the offsets of [`EngineLike::parse_tree`],
+    /// regardless of the compaction.
+    /// The nonterminals inlined by the grammar simplification never complete,
+    /// so only the nonterminals marked with `@preserve` are guaranteed to reach the hook.
+    /// The hook is not invoked when computing the allowed token IDs or when bytes are accepted again by
+    /// [`EngineLike::rollback`]. It is not carried into the clones of the engine or [`EngineLike::clone_fresh`].
+    pub fn set_completion_hook(&mut self, hook: CompletionHook) {
+        self.completion_hook = CompletionHookSlot(Some(Mutex::new(hook)));
+    }
+    /// Removes the hook set by [`EngineBase::set_completion_hook`].
+    pub fn clear_completion_hook(&mut self) {
+        self.completion_hook = CompletionHookSlot(None);
+    }
+    /// Sets whether to analyze the grammar for ambiguity with [`Grammar::analyze_ambiguity`].
+    /// Each warning is logged with [`log::warn!`] and stored in the engine.
+    pub fn with_ambiguity_warnings(mut self, collect: bool) -> Self {
+        self.ambiguity_warnings = if collect {
+            self.grammar.analyze_ambiguity()
+        } else {
+            Vec::new()
+        };
+        for warning in &self.ambiguity_warnings {
+            log::warn!("Ambiguity in the grammar: {warning}");
+        }
+        self
+    }
+    /// Get the ambiguity warnings of the grammar,
+    /// which are empty unless they are collected with [`EngineBase::with_ambiguity_warnings`].
+    pub fn ambiguity_warnings(&self) -> &[AmbiguityWarning] {
+        &self.ambiguity_warnings
+    }
+    /// Get the grammar of the engine, which can be shared with other engines without recompiling it.
+    pub fn grammar(&self) -> Arc<Grammar<TI>> {
+        self.grammar.clone()
+    }
+    /// Gets the allowed token IDs since last computation paired with their token strings, which is useful for debugging grammars.
+    pub fn allowed_token_strings(&self) -> Vec<(u32, String)> {
+        self.allowed_token_ids
+            .ones()
+            .filter_map(|id| {
+                let id = id as u32;
+                self.vocabulary
+                    .token_string(id)
+                    .map(|string| (id, string.to_string()))
+            })
+            .collect()
+    }
+    /// Gets a human-readable fingerprint of the current states,
+    /// which is identical across runs and platforms for the same grammar, vocabulary and sequence of accepted tokens.
+    ///
+    /// The fingerprint contains the Earley sets, the allowed first bytes, the allowed token IDs since last computation
+    /// and whether the engine is finished. The items in each Earley set are sorted, so the fingerprint does not depend on
+    /// the iteration order of the hash maps. The cache and the buffers are excluded since they depend on the history of the engine.
+    /// This is useful for snapshot tests.
+    pub fn state_fingerprint(&self) -> String {
+        #[derive(Debug)]
+        #[allow(dead_code)] // The fields are only read by the Debug implementation.
+        struct StateFingerprint {
+            earley_sets: Vec<Vec<EarleyItemDebugStruct>>,
+            allowed_first_bytes: Vec<usize>,
+            allowed_token_ids: Vec<(u32, u32)>,
+            finished: bool,
+        }
+        let mut earley_sets = self.get_display_form_from_earley_sets(&self.earley_sets);
+        for set in earley_sets.iter_mut() {
+            set.sort();
+        }
+        format!(
+            "{:#?}",
+            StateFingerprint {
+                earley_sets,
+                allowed_first_bytes: utils::get_display_form_from_bitset_on_stack(
+                    &self.allowed_first_bytes
+                ),
+                allowed_token_ids: utils::get_ranges_from_bitset(&self.allowed_token_ids),
+                finished: self.finished,
+            }
+        )
+    }
+    fn productions_len(&self, nonterminal: usize) -> usize {
+        let dotted_productions = self.grammar.rules().view::<1, 2>([nonterminal]);
+        if dotted_productions.len() == 0 {
+            return 0;
+        }
+        dotted_productions.view::<1, 1>([0]).len()
+    }
+    fn production_len(&self, nonterminal: usize, production: usize) -> usize {
+        let dotted_productions = self.grammar.rules().view::<1, 2>([nonterminal]);
+        (0..dotted_productions.len())
+            .take_while(|&dot| production < dotted_productions.view::<1, 1>([dot]).len())
+            .count()
+    }
+    /// Finds the end positions of the nonterminal starting at `start`.
+    ///
+    /// The nonterminal being evaluated, e.g. due to left recursion, uses the end positions found so far,
+    /// so the evaluation must be repeated until [`DerivationMemo::changed`] is false.
+    fn derivable_nonterminal_ends(
+        &self,
+        memo: &mut DerivationMemo,
+        nonterminal: usize,
+        start: usize,
+    ) -> FixedBitSet {
+        let key = (nonterminal, start);
+        if !memo.evaluated.insert(key) {
+            return memo
+                .nonterminal_ends
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| FixedBitSet::with_capacity(memo.bytes.len() + 1));
+        }
+        let mut ends = FixedBitSet::with_capacity(memo.bytes.len() + 1);
+        for production in 0..self.productions_len(nonterminal) {
+            ends.union_with(&self.derivable_sequence_ends(memo, nonterminal, production, 0, start));
+        }
+        let len = memo.bytes.len();
+        let entry = memo
+            .nonterminal_ends
+            .entry(key)
+            .or_insert_with(|| FixedBitSet::with_capacity(len + 1));
+        if !ends.is_subset(entry) {
+            entry.union_with(&ends);
+            memo.changed = true;
+        }
+        entry.clone()
+    }
+    /// Finds the end positions of the production from the dot position to its end, starting at `start`.
+    fn derivable_sequence_ends(
+        &self,
+        memo: &mut DerivationMemo,
+        nonterminal: usize,
+        production: usize,
+        dot: usize,
+        start: usize,
+    ) -> FixedBitSet {
+        let mut positions = FixedBitSet::with_capacity(memo.bytes.len() + 1);
+        positions.insert(start);
+        for dot in dot..self.production_len(nonterminal, production) {
+            let mut next_positions = FixedBitSet::with_capacity(memo.bytes.len() + 1);
+            for position in positions.ones() {
+                next_positions.union_with(&self.derivable_node_ends(
+                    memo,
+                    nonterminal,
+                    production,
+                    dot,
+                    position,
+                ));
+            }
+            if next_positions.is_clear() {
+                return next_positions;
+            }
+            positions = next_positions;
+        }
+        positions
+    }
+    /// Finds the end positions of the node after the dot starting at `start`.
+    fn derivable_node_ends(
+        &self,
+        memo: &mut DerivationMemo,
+        nonterminal: usize,
+        production: usize,
+        dot: usize,
+        start: usize,
+    ) -> FixedBitSet {
+        let node = self.grammar.rules()[[nonterminal, dot, production]];
+        if let HIRNode::Nonterminal(id) = node {
+            return self.derivable_nonterminal_ends(memo, id.0.as_(), start);
+        }
+        let key = (nonterminal, production, dot, start);
+        if let Some(ends) = memo.node_ends.get(&key) {
+            return ends.clone();
+        }
+        // The terminal-like node is matched by scanning the bytes with an item whose dot is before the node,
+        // which is advanced or completed once the node matches.
+        let mut ends = FixedBitSet::with_capacity(memo.bytes.len() + 1);
+        let mut items = vec![EarleyItem {
+            nonterminal_id: NonterminalID(nonterminal.as_()),
+            dot_position: dot.as_(),
+            production_index: production.as_(),
+            start_position: TSP::ZERO,
+            state_id: Self::initialize_state_id_based_on_node(&self.grammar, node),
+        }];
+        let mut earley_sets = JaggedArray::new();
+        let mut to_be_completed_items = utils::new_hash_set(self.config.deterministic);
+        for (i, &byte) in memo.bytes[start..].iter().enumerate() {
+            earley_sets.clear();
+            earley_sets.new_row::<0>();
+            for &item in &items {
+                earley_sets.push_to_last_row(item);
+            }
+            to_be_completed_items.clear();
+            Self::scan(
+                &self.grammar,
+                &mut earley_sets,
+                &mut to_be_completed_items,
+                byte,
+            );
+            let scanned_items = earley_sets.view::<1, 1>([1]).as_slice();
+            if !to_be_completed_items.is_empty()
+                || scanned_items.iter().any(|x| x.dot_position.as_() > dot)
+            {
+                ends.insert(start + i + 1);
+            }
+            items.clear();
+            items.extend(
+                scanned_items
+                    .iter()
+                    .copied()
+                    .filter(|x| x.dot_position.as_() == dot),
+            );
+            if items.is_empty() {
+                break;
+            }
+        }
+        memo.node_ends.insert(key, ends.clone());
+        ends
+    }
+    fn derive_nonterminal(
+        &self,
+        memo: &mut DerivationMemo,
+        nonterminal: usize,
+        start: usize,
+        end: usize,
+    ) -> Option<crate::engine_like::ParseNode> {
+        let key = (nonterminal, start, end);
+        // A derivation without the same nonterminal spanning the same bytes twice on a path always exists if any derivation exists.
+        if !memo.deriving.insert(key) {
+            return None;
+        }
+        let children = (0..self.productions_len(nonterminal)).find_map(|production| {
+            self.derive_sequence(memo, nonterminal, production, 0, start, end)
+        });
+        memo.deriving.remove(&key);
+        Some(crate::engine_like::ParseNode {
+            nonterminal: self
+                .grammar
+                .nonterminal_str(NonterminalID(nonterminal.as_()))
+                .unwrap_or_default()
+                .to_string(),
+            start,
+            end,
+            children: children?,
+        })
+    }
+    fn derive_sequence(
+        &self,
+        memo: &mut DerivationMemo,
+        nonterminal: usize,
+        production: usize,
+        dot: usize,
+        start: usize,
+        end: usize,
+    ) -> Option<Vec<crate::engine_like::ParseNode>> {
+        if dot == self.production_len(nonterminal, production) {
+            return (start == end).then(Vec::new);
+        }
+        let node = self.grammar.rules()[[nonterminal, dot, production]];
+        let positions = self.derivable_node_ends(memo, nonterminal, production, dot, start);
+        let capture_spans_len = memo.capture_spans.len();
+        for position in positions.ones().filter(|&x| x <= end) {
+            if !self
+                .derivable_sequence_ends(memo, nonterminal, production, dot + 1, position)
+                .contains(end)
+            {
+                continue;
+            }
+            let child = match node {
+                HIRNode::Nonterminal(id) => {
+                    match self.derive_nonterminal(memo, id.0.as_(), start, position) {
+                        Some(child) => Some(child),
+                        None => continue,
+                    }
+                }
+                HIRNode::RegexString(id) | HIRNode::EarlyEndRegexString(id)
+                    if self.grammar.capture_regex(id).is_some() =>
+                {
+                    memo.capture_spans.push((id.0.as_(), start, position));
+                    None
+                }
+                _ => None,
+            };
+            if let Some(rest) =
+                self.derive_sequence(memo, nonterminal, production, dot + 1, position, end)
+            {
+                let mut children: Vec<_> = child.into_iter().collect();
+                children.extend(rest);
+                return Some(children);
+            }
+            // The spans in the abandoned derivation are not captured.
+            memo.capture_spans.truncate(capture_spans_len);
+        }
+        None
+    }
+    /// Reconstructs one valid derivation of the bytes consumed by the current states from the start nonterminal.
+    fn derive_consumed_bytes(
+        &self,
+        memo: &mut DerivationMemo,
+    ) -> Option<crate::engine_like::ParseNode> {
+        if !self.track_accepted_bytes {
+            return None;
+        }
+        let start_nonterminal = self.grammar.get_start_nonterminal_id().0.as_();
+        // The end positions are a least fixpoint since the nonterminals may be left-recursive.
+        let mut ends = FixedBitSet::with_capacity(0);
+        while memo.changed {
+            memo.changed = false;
+            memo.evaluated.clear();
+            ends = self.derivable_nonterminal_ends(memo, start_nonterminal, 0);
+        }
+        let end = memo.bytes.len();
+        if !ends.contains(end) {
+            return None;
+        }
+        self.derive_nonterminal(memo, start_nonterminal, 0, end)
+    }
+    fn derivation_memo(&self) -> DerivationMemo<'_> {
+        // The bytes preserved by rewinding are not consumed by the current states.
+        let consumed_bytes_start = self
+            .accept_markers
+            .first()
+            .map_or(self.accepted_bytes.len(), |x| x.accepted_bytes_len);
+        DerivationMemo {
+            bytes: &self.accepted_bytes[consumed_bytes_start..],
+            nonterminal_ends: AHashMap::default(),
+            node_ends: AHashMap::default(),
+            evaluated: AHashSet::default(),
+            changed: true,
+            deriving: AHashSet::default(),
+            capture_spans: Vec::new(),
+        }
+    }
+    /// Attaches the shared cache, which replaces the cache of the engine when computing the allowed token IDs.
+    /// The entries already in the cache of the engine, e.g. the ones added by [`EngineBase::with_eager_cache`], are moved into the shared cache.
+    ///
+    /// See [`SharedCacheBase`] for the invariant that all the engines sharing a cache must satisfy.
+    pub fn with_shared_cache(mut self, shared_cache: SharedCacheBase<TI, TD, TP, TSP, TS>) -> Self {
+        for (earley_sets, allowed_token_ids) in self.cache.iter() {
+            shared_cache.insert(earley_sets, allowed_token_ids);
+        }
+        self.cache.clear();
+        self.shared_cache = Some(shared_cache);
+        self
+    }
+    fn apply_special_token_ids(&mut self) {
+        if let Some(id) = self.eos_token_id {
+            // The EOS token only terminates the generation, even if its bytes are accepted by the grammar.
+            self.allowed_token_ids
+                .set(id as usize, self.finished || self.matches_empty());
+        }
+        for &id in &self.ignored_token_ids {
+            if (id as usize) < self.allowed_token_ids.len() {
+                self.allowed_token_ids.set(id as usize, false);
+            }
+        }
+    }
+    /// The result of accepting anything after the engine is finished, which depends on `finished_is_terminal`.
+    fn accept_after_finish(
+        &self,
+    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        if self.finished_is_terminal {
+            Err(crate::engine_like::AcceptTokenError::Finished)
+        } else {
+            Ok(crate::engine_like::AcceptTokenResult::Finished)
+        }
+    }
+    /// Records the detail of the rejection of `bytes` at `byte_index`,
+    /// where the prefix before the index is accepted again on a copy of the states.
+    fn record_rejection(&mut self, bytes: &[u8], byte_index: usize, is_token: bool) {
+        let mut engine = self.accept_rejected_prefix(bytes, byte_index, is_token);
+        engine.update_allowed_first_bytes();
+        self.last_rejection = Some(RejectionDetail {
+            byte_index,
+            allowed_first_bytes: engine.allowed_first_bytes,
+        });
+    }
+    /// Accepts the prefix of the rejected `bytes` before `byte_index` on a copy of the states without the cache.
+    fn accept_rejected_prefix(&self, bytes: &[u8], byte_index: usize, is_token: bool) -> Self {
+        let mut engine = self.clone_without_cache();
+        let ptr = &mut engine.column_to_postdot_nonterminals
+            as *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>;
+        // The prefix is accepted before, so it is never rejected.
+        let _ = Self::accept_bytes(
+            &engine.grammar,
+            &mut engine.earley_sets,
+            &mut engine.to_be_completed_items,
+            &mut engine.to_be_completed_items_buffer,
+            &mut engine.leo_items,
+            &mut engine.leo_items_buffer,
+            &mut engine.postdot_items,
+            &mut engine.postdot_items_since_last_commit,
+            &mut engine.already_predicted_nonterminals,
+            &mut engine.deduplication_buffer,
+            ptr,
+            &engine.config,
+            &mut engine.finished,
+            bytes[..byte_index].iter().copied(),
+            is_token,
+            true,
+            None,
+            &mut engine.uncompacted_bytes,
+            engine
+                .prefilter
+                .as_deref()
+                .map(|prefilter| (prefilter, &mut engine.prefilter_state)),
+            None,
+        );
+        engine
+    }
+    /// Whether the engine matches the empty string at current states, i.e. no bytes are accepted yet.
+    fn matches_empty(&self) -> bool {
+        // Each accepted byte adds an Earley set that the compaction never removes, so the untracked accepts are still noticed.
+        self.empty_match
+            && if self.track_accepted_bytes {
+                self.accept_markers.is_empty()
+            } else {
+                self.earley_sets.len() == 1
+            }
+    }
+    fn accept_marker(&self) -> AcceptMarker {
+        AcceptMarker {
+            earley_sets_len: self.earley_sets.len(),
+            finished: self.finished,
+            accepted_bytes_len: self.accepted_bytes.len(),
+            prefilter_state: self.prefilter_state,
+        }
+    }
+    /// Records the byte offsets of the Earley sets added by accepting `len` bytes after `earley_sets_len` Earley sets.
+    ///
+    /// The compaction after the last byte only keeps the Earley sets still referenced and the last one,
+    /// so the offsets of the removed ones are removed as well.
+    fn update_column_offsets(
+        column_offsets: &mut Vec<usize>,
+        earley_sets: &EarleySets<TI, TD, TP, TSP, TS>,
+        earley_sets_len: usize,
+        len: usize,
+    ) {
+        let last_offset = column_offsets[earley_sets_len - 1];
+        column_offsets.extend(last_offset + 1..=last_offset + len);
+        let last_column = column_offsets.len() - 1;
+        column_offsets.drain(earley_sets.len() - 1..last_column);
+    }
+    /// Truncates the states to the marker, which is only valid if no compaction happened since the marker.
+    fn truncate_to_marker(&mut self, marker: AcceptMarker) {
+        let len = marker.earley_sets_len;
+        self.accepted_bytes.truncate(marker.accepted_bytes_len);
+        self.earley_sets.truncate::<0>(len);
+        self.column_offsets.truncate(len);
+        self.postdot_items
+            .retain(|dotted, _| dotted.column.as_() < len);
+        self.leo_items.retain(|dotted, _| dotted.column.as_() < len);
+        self.column_to_postdot_nonterminals
+            .retain(|column, _| column.as_() < len);
+        self.postdot_items_since_last_commit.clear();
+        self.finished = marker.finished;
+        self.prefilter_state = marker.prefilter_state;
+    }
+    /// Tracks the columns of the postdot items since the Earley set `len`,
+    /// which are not tracked when the bytes are accepted with the compaction suspended.
+    fn track_postdot_columns_since(&mut self, len: usize) {
+        for dotted in self.postdot_items.keys() {
+            if dotted.column.as_() >= len {
+                self.column_to_postdot_nonterminals
+                    .entry(dotted.column)
+                    .or_default()
+                    .insert(dotted.postdot_nonterminal_id);
+            }
+        }
+    }
+    /// Creates a checkpoint of the current states, which can be restored by [`EngineBase::restore`] later.
+    ///
+    /// Only the mutable states are copied. The grammar and the vocabulary are shared, and the cache is not included,
+    /// so a checkpoint is much cheaper than cloning the engine.
+    pub fn checkpoint(&self) -> EngineBaseCheckpoint<TI, TD, TP, TSP, TS> {
+        EngineBaseCheckpoint {
+            vocabulary: self.vocabulary.clone(),
+            grammar: self.grammar.clone(),
+            allowed_first_bytes: self.allowed_first_bytes.clone(),
+            allowed_token_ids: self.allowed_token_ids.clone(),
+            earley_sets: self.earley_sets.clone(),
+            postdot_items: self.postdot_items.clone(),
+            column_to_postdot_nonterminals: self.column_to_postdot_nonterminals.clone(),
+            leo_items: self.leo_items.clone(),
+            finished: self.finished,
+            accepted_bytes: self.accepted_bytes.clone(),
+            accept_markers: self.accept_markers.clone(),
+            prefilter_state: self.prefilter_state,
+            column_offsets: self.column_offsets.clone(),
+            uncompacted_bytes: self.uncompacted_bytes,
+            last_rejection: self.last_rejection.clone(),
+            accept_metrics: self.accept_metrics,
+        }
+    }
+    /// Restores the states from the checkpoint. The cache of the engine is preserved.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RestoreCheckpointError::MismatchedEngine`] when the checkpoint is not created by the engine
+    /// or its clones. The engine is not modified in this case.
+    pub fn restore(
+        &mut self,
+        checkpoint: &EngineBaseCheckpoint<TI, TD, TP, TSP, TS>,
+    ) -> Result<(), RestoreCheckpointError> {
+        if !Arc::ptr_eq(&self.grammar, &checkpoint.grammar)
+            || !Arc::ptr_eq(&self.vocabulary, &checkpoint.vocabulary)
+        {
+            return Err(RestoreCheckpointError::MismatchedEngine);
+        }
+        // clone_from reuses the allocations, while the rows after the checkpoint are dropped
+        self.earley_sets.clone_from(&checkpoint.earley_sets);
+        self.postdot_items.clone_from(&checkpoint.postdot_items);
+        self.column_to_postdot_nonterminals
+            .clone_from(&checkpoint.column_to_postdot_nonterminals);
+        self.leo_items.clone_from(&checkpoint.leo_items);
+        self.allowed_first_bytes
+            .clone_from(&checkpoint.allowed_first_bytes);
+        self.allowed_token_ids
+            .clone_from(&checkpoint.allowed_token_ids);
+        self.accepted_bytes.clone_from(&checkpoint.accepted_bytes);
+        self.accept_markers.clone_from(&checkpoint.accept_markers);
+        self.finished = checkpoint.finished;
+        self.prefilter_state = checkpoint.prefilter_state;
+        self.column_offsets.clone_from(&checkpoint.column_offsets);
+        self.uncompacted_bytes = checkpoint.uncompacted_bytes;
+        self.last_rejection.clone_from(&checkpoint.last_rejection);
+        self.accept_metrics = checkpoint.accept_metrics;
+        self.to_be_completed_items.clear();
+        self.to_be_completed_items_buffer.clear();
+        self.leo_items_buffer.clear();
+        self.postdot_items_since_last_commit.clear();
+        self.deduplication_buffer.clear();
+        self.already_predicted_nonterminals.clear();
+        Ok(())
+    }
+
+    fn get_display_form_from_earley_sets(
+        &self,
         sets: &EarleySets<TI, TD, TP, TSP, TS>,
     ) -> Vec<Vec<EarleyItemDebugStruct>> {
         let mut res = Vec::with_capacity(sets.len());
@@ -650,11 +1758,66 @@ where
         }
         Ok(())
     }
+    fn validate_ts_size_for_length_prefixed(
+        grammar: &Grammar<TI>,
+    ) -> Result<(), CreateEngineBaseError> {
+        // At least one bit is needed for the phase and another one for the length.
+        let max: usize = 2usize.saturating_pow(Self::STATE_ID_TYPE_BIT - 2) - 1;
+        for length_prefixed in grammar.id_to_length_prefixed() {
+            let len = length_prefixed
+                .numeric()
+                .state_len()
+                .max(length_prefixed.content().state_len());
+            if len > max {
+                return Err(CreateEngineBaseError::LengthPrefixedTooLarge(len, max));
+            }
+        }
+        Ok(())
+    }
+    fn validate_ts_size_for_bounded_substrings(
+        grammar: &Grammar<TI>,
+    ) -> Result<(), CreateEngineBaseError> {
+        for bounded_substrings in grammar.id_to_bounded_substrings() {
+            let bits = Self::bounded_substrings_node_bits(bounded_substrings)
+                + (usize::BITS - bounded_substrings.max().leading_zeros());
+            if bits > Self::STATE_ID_TYPE_BIT {
+                return Err(CreateEngineBaseError::BoundedSubstringsTooLarge(
+                    bits as usize,
+                    Self::STATE_ID_TYPE_BIT as usize,
+                ));
+            }
+        }
+        Ok(())
+    }
+    fn validate_ts_size_for_json(grammar: &Grammar<TI>) -> Result<(), CreateEngineBaseError> {
+        let rules = grammar.rules();
+        let has_json = (0..rules.len()).any(|i| {
+            let view = rules.view::<1, 2>([i]);
+            (0..view.len()).any(|j| {
+                view.view::<1, 1>([j])
+                    .as_slice()
+                    .iter()
+                    .any(|x| matches!(x, HIRNode::Json))
+            })
+        });
+        if has_json && json::STATE_BITS > Self::STATE_ID_TYPE_BIT {
+            return Err(CreateEngineBaseError::JsonTooLarge(
+                json::STATE_BITS as usize,
+                Self::STATE_ID_TYPE_BIT as usize,
+            ));
+        }
+        Ok(())
+    }
     /// Run prediction stage of Earley algorithm on last Earley set and current `already_predicted_nonterminals` content
+    /// Predicts the last Earley set.
+    ///
+    /// If `suppress_atomic` is true, the atomic nonterminals are not predicted,
+    /// since no token may straddle their start.
     fn predict(
         grammar: &Grammar<TI>,
         earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
         already_predicted_nonterminals: &mut FixedBitSet,
+        suppress_atomic: bool,
     ) {
         let earley_set_index = earley_sets.len() - 1;
         let mut earley_set_len =
@@ -671,6 +1834,10 @@ where
                 )
             };
             if let HIRNode::Nonterminal(nonterminal_id) = node {
+                if suppress_atomic && grammar.is_atomic_nonterminal(nonterminal_id) {
+                    i += 1;
+                    continue;
+                }
                 earley_set_len += Self::predict_nonterminal(
                     grammar,
                     earley_sets,
@@ -707,12 +1874,13 @@ where
                 match fsa {
                     FiniteStateAutomaton::Dfa(dfa) => {
                         // SAFETY: start_error will not happen since that will result in an error in Grammar::new() method
-                        let start = unsafe{dfa
-                            .start_state(
+                        let start = unsafe {
+                            dfa.start_state(
                                 &kbnf_regex_automata::util::start::Config::new()
                                     .anchored(kbnf_regex_automata::Anchored::No),
                             )
-                            .unwrap_unchecked()};
+                            .unwrap_unchecked()
+                        };
                         Self::from_dfa_state_id_to_state_id(start, dfa.stride2())
                     }
                 }
@@ -720,6 +1888,23 @@ where
             HIRNode::Substrings(_) => {
                 Self::from_suffix_automaton_node_id_to_state_id(general_sam::SAM_ROOT_NODE_ID)
             }
+            HIRNode::LengthPrefixed(id) => {
+                let length_prefixed = grammar.length_prefixed(id);
+                Self::from_length_prefixed_state_to_state_id(
+                    length_prefixed,
+                    LengthPrefixedState {
+                        in_content: false,
+                        dfa_state: length_prefixed.numeric_start(),
+                        count: 0,
+                    },
+                )
+            }
+            HIRNode::BoundedSubstrings(id) => Self::from_bounded_substrings_state_to_state_id(
+                grammar.bounded_substrings(id),
+                general_sam::SAM_ROOT_NODE_ID,
+                0,
+            ),
+            HIRNode::Json => Self::from_index_to_state_id(json::START),
             _ => TS::ZERO,
         }
     }
@@ -768,50 +1953,99 @@ where
         let earley_set_index = self.earley_sets.len() - 1;
         let earley_set = self.earley_sets.view::<1, 1>([earley_set_index]).as_slice();
         for item in earley_set.iter().copied() {
-            let node = *self.grammar.node(
-                item.nonterminal_id,
-                item.dot_position,
-                item.production_index,
-            );
-            match node {
-                HIRNode::Terminal(terminal_id) => {
-                    self.allowed_first_bytes
-                        .insert(self.grammar.terminal(terminal_id)[item.state_id.as_()].as_());
+            Self::add_first_bytes_of_item(&self.grammar, item, &mut self.allowed_first_bytes);
+        }
+        // The bytes absent from the vocabulary can never be produced by any token.
+        self.allowed_first_bytes
+            .intersect_with(&self.vocabulary.covered_bytes);
+        if let Some(prefilter) = self.prefilter.as_deref() {
+            for byte in 0..=u8::MAX {
+                if prefilter.feed(self.prefilter_state, byte).is_none() {
+                    self.allowed_first_bytes.set(byte as usize, false);
                 }
-                HIRNode::RegexString(regex_id) | HIRNode::EarlyEndRegexString(regex_id) => {
-                    if let Some(first_bytes) = self.grammar.first_bytes_from_regex(
-                        regex_id,
-                        Self::from_state_id_to_dfa_state_id(
-                            item.state_id,
-                            match self.grammar.regex(regex_id) {
-                                FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
-                            },
-                        ),
-                    ) {
-                        self.allowed_first_bytes.union_with(first_bytes);
-                    }
+            }
+        }
+    }
+
+    /// Adds the bytes accepted by the node after the dot of the item at its current state to `first_bytes`.
+    fn add_first_bytes_of_item(
+        grammar: &Grammar<TI>,
+        item: EarleyItem<TI, TD, TP, TSP, TS>,
+        first_bytes: &mut ByteSet,
+    ) {
+        let node = *grammar.node(
+            item.nonterminal_id,
+            item.dot_position,
+            item.production_index,
+        );
+        match node {
+            HIRNode::Terminal(terminal_id) => {
+                first_bytes.insert(grammar.terminal(terminal_id)[item.state_id.as_()].as_());
+            }
+            HIRNode::RegexString(regex_id) | HIRNode::EarlyEndRegexString(regex_id) => {
+                if let Some(bytes) = grammar.first_bytes_from_regex(
+                    regex_id,
+                    Self::from_state_id_to_dfa_state_id(
+                        item.state_id,
+                        match grammar.regex(regex_id) {
+                            FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
+                        },
+                    ),
+                ) {
+                    first_bytes.union_with(bytes);
                 }
-                HIRNode::RegexComplement(regex_id) => {
-                    if let Some(first_bytes) = self.grammar.complement_first_bytes_from_regex(
-                        regex_id,
-                        Self::from_state_id_to_dfa_state_id(
-                            item.state_id,
-                            match self.grammar.regex(regex_id) {
-                                FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
-                            },
-                        ),
-                    ) {
-                        self.allowed_first_bytes.union_with(first_bytes);
-                    }
+            }
+            HIRNode::RegexComplement(regex_id) => {
+                if let Some(bytes) = grammar.complement_first_bytes_from_regex(
+                    regex_id,
+                    Self::from_state_id_to_dfa_state_id(
+                        item.state_id,
+                        match grammar.regex(regex_id) {
+                            FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
+                        },
+                    ),
+                ) {
+                    first_bytes.union_with(bytes);
                 }
-                HIRNode::Substrings(_) => {
-                    let first_bytes = self
-                        .grammar
-                        .first_bytes_from_suffix_automaton(item.state_id.as_());
-                    self.allowed_first_bytes.union_with(first_bytes);
+            }
+            HIRNode::Substrings(_) => {
+                first_bytes
+                    .union_with(grammar.first_bytes_from_suffix_automaton(item.state_id.as_()));
+            }
+            HIRNode::LengthPrefixed(id) => {
+                let length_prefixed = grammar.length_prefixed(id);
+                for byte in 0..=u8::MAX {
+                    let mut acceptable = false;
+                    if Self::feed_length_prefixed(length_prefixed, item.state_id, byte, |_| {
+                        acceptable = true
+                    }) || acceptable
+                    {
+                        first_bytes.insert(byte as usize);
+                    }
                 }
-                _ => {}
             }
+            HIRNode::BoundedSubstrings(id) => {
+                let bounded_substrings = grammar.bounded_substrings(id);
+                // The items reaching the maximum length are never added.
+                let (node_id, _) = Self::from_state_id_to_bounded_substrings_state(
+                    bounded_substrings,
+                    item.state_id,
+                );
+                first_bytes.union_with(bounded_substrings.first_bytes(node_id));
+            }
+            HIRNode::ByteSetComplement(id) => match item.state_id.as_() {
+                0 => first_bytes.union_with(grammar.byte_set_complement(id)),
+                state => first_bytes.union_with(&utils::utf8_continuation_bytes(state)),
+            },
+            HIRNode::AnyChar => match item.state_id.as_() {
+                0 => {
+                    first_bytes.insert_range(0..0x80);
+                    first_bytes.insert_range(utils::UTF8_LEADING_BYTES);
+                }
+                state => first_bytes.union_with(&utils::utf8_continuation_bytes(state)),
+            },
+            HIRNode::Json => first_bytes.union_with(&json::first_bytes(item.state_id.as_())),
+            _ => {}
         }
     }
     #[inline]
@@ -918,6 +2152,172 @@ where
         unsafe { std::mem::transmute((state_id.as_() << stride2) as u32) }
     }
     #[inline]
+    fn length_prefixed_state_bits(length_prefixed: &LengthPrefixed) -> u32 {
+        let len = length_prefixed
+            .numeric()
+            .state_len()
+            .max(length_prefixed.content().state_len());
+        usize::BITS - len.leading_zeros()
+    }
+    #[inline]
+    /// The state id of a length-prefixed node stores the phase in the highest bit,
+    /// the DFA state in the lowest bits and the length in the bits between.
+    fn from_length_prefixed_state_to_state_id(
+        length_prefixed: &LengthPrefixed,
+        state: LengthPrefixedState,
+    ) -> TS {
+        let bits = Self::length_prefixed_state_bits(length_prefixed);
+        let stride2 = if state.in_content {
+            length_prefixed.content().stride2()
+        } else {
+            length_prefixed.numeric().stride2()
+        };
+        // SAFETY: StateID is a u32 due to #[repr(transparent)] attribute
+        let id: u32 = unsafe { std::mem::transmute(state.dfa_state) };
+        let phase = (state.in_content as usize) << (Self::STATE_ID_TYPE_BIT - 1);
+        // SAFETY: Self::feed_length_prefixed ensures the length fits in the bits between
+        (phase | (state.count << bits) | (id >> stride2) as usize).as_()
+    }
+    #[inline]
+    fn from_state_id_to_length_prefixed_state(
+        length_prefixed: &LengthPrefixed,
+        state_id: TS,
+    ) -> LengthPrefixedState {
+        let bits = Self::length_prefixed_state_bits(length_prefixed);
+        let state_id = state_id.as_();
+        let phase_bit = Self::STATE_ID_TYPE_BIT - 1;
+        let in_content = (state_id >> phase_bit) & 1 == 1;
+        let stride2 = if in_content {
+            length_prefixed.content().stride2()
+        } else {
+            length_prefixed.numeric().stride2()
+        };
+        let index = state_id & ((1 << bits) - 1);
+        LengthPrefixedState {
+            in_content,
+            // SAFETY: StateID is a u32 due to #[repr(transparent)] attribute
+            dfa_state: unsafe { std::mem::transmute::<u32, StateID>((index << stride2) as u32) },
+            count: (state_id & ((1 << phase_bit) - 1)) >> bits,
+        }
+    }
+    /// Feeds one byte to the length-prefixed node in the given state.
+    /// `add_state` is called with each state the node can be in after the byte.
+    ///
+    /// Returns whether the node is completed by the byte.
+    fn feed_length_prefixed(
+        length_prefixed: &LengthPrefixed,
+        state_id: TS,
+        byte: u8,
+        mut add_state: impl FnMut(TS),
+    ) -> bool {
+        let state = Self::from_state_id_to_length_prefixed_state(length_prefixed, state_id);
+        let max_count = (1usize
+            << (Self::STATE_ID_TYPE_BIT - 1 - Self::length_prefixed_state_bits(length_prefixed)))
+            - 1;
+        let mut completed = false;
+        if state.in_content {
+            let dfa = length_prefixed.content();
+            let dfa_state = dfa.next_state(state.dfa_state, byte);
+            let count = state.count - 1;
+            let mut add = || {
+                if count != 0 {
+                    add_state(Self::from_length_prefixed_state_to_state_id(
+                        length_prefixed,
+                        LengthPrefixedState {
+                            in_content: true,
+                            dfa_state,
+                            count,
+                        },
+                    ));
+                }
+            };
+            dispatch_by_dfa_state_status!(
+                dfa_state,
+                dfa,
+                accept=>{
+                    completed = count == 0;
+                    add();
+                },
+                reject=>{},
+                in_progress=>{add();}
+            );
+        } else {
+            let count = if byte.is_ascii_digit() {
+                state
+                    .count
+                    .checked_mul(10)
+                    .and_then(|x| x.checked_add((byte - b'0') as usize))
+            } else {
+                Some(state.count)
+            };
+            let Some(count) = count.filter(|&x| x <= max_count) else {
+                // The length cannot be stored in the state id
+                return false;
+            };
+            let dfa = length_prefixed.numeric();
+            let dfa_state = dfa.next_state(state.dfa_state, byte);
+            let numeric_state = Self::from_length_prefixed_state_to_state_id(
+                length_prefixed,
+                LengthPrefixedState {
+                    in_content: false,
+                    dfa_state,
+                    count,
+                },
+            );
+            dispatch_by_dfa_state_status!(
+                dfa_state,
+                dfa,
+                accept=>{
+                    add_state(numeric_state);
+                    // The numeric regex may end here
+                    let content = length_prefixed.content();
+                    let content_start = length_prefixed.content_start();
+                    if count == 0 {
+                        completed = content.is_match_state(content.next_eoi_state(content_start));
+                    } else {
+                        add_state(Self::from_length_prefixed_state_to_state_id(
+                            length_prefixed,
+                            LengthPrefixedState {
+                                in_content: true,
+                                dfa_state: content_start,
+                                count,
+                            },
+                        ));
+                    }
+                },
+                reject=>{},
+                in_progress=>{add_state(numeric_state);}
+            );
+        }
+        completed
+    }
+    #[inline]
+    fn bounded_substrings_node_bits(bounded_substrings: &BoundedSubstrings) -> u32 {
+        let max_node_id = bounded_substrings.suffix_automaton().num_of_nodes() - 1;
+        usize::BITS - max_node_id.leading_zeros()
+    }
+    #[inline]
+    /// The state id of a bounded substrings node stores the matched length in the highest bits
+    /// and the node of the suffix automaton in the lowest bits.
+    fn from_bounded_substrings_state_to_state_id(
+        bounded_substrings: &BoundedSubstrings,
+        node_id: usize,
+        len: usize,
+    ) -> TS {
+        // SAFETY: Self::validate_ts_size_for_bounded_substrings ensures the state fits in TS
+        ((len << Self::bounded_substrings_node_bits(bounded_substrings)) | node_id).as_()
+    }
+    #[inline]
+    /// Returns the node of the suffix automaton and the matched length.
+    fn from_state_id_to_bounded_substrings_state(
+        bounded_substrings: &BoundedSubstrings,
+        state_id: TS,
+    ) -> (usize, usize) {
+        let bits = Self::bounded_substrings_node_bits(bounded_substrings);
+        let state = state_id.as_();
+        (state & ((1 << bits) - 1), state >> bits)
+    }
+    #[inline]
     fn from_suffix_automaton_node_id_to_state_id(node_id: usize) -> TS {
         node_id.as_()
     }
@@ -937,8 +2337,9 @@ where
         let earley_set_len =
             unsafe { earley_sets.view_unchecked::<1, 1>([earley_set_index]).len() };
         earley_sets.new_row::<0>();
-        // Each regex or excepted will add at most two item to the next Earley set
-        earley_sets.buffer_reserve(earley_set_len * 2);
+        // Each regex or excepted will add at most two item to the next Earley set,
+        // while each length-prefixed node will add at most three items
+        earley_sets.buffer_reserve(earley_set_len * 3);
         // SAFETY: earley_set_index is guaranteed to be valid since earley_sets is never empty
         // SAFETY: earley_set is guaranteed to be valid during the loop since we have preallocated enough memory to avoid reallocation
         let earley_set = unsafe {
@@ -1090,9 +2491,115 @@ where
                         unsafe { earley_sets.push_to_last_row_unchecked(item) };
                     }
                 }
-                HIRNode::Nonterminal(_) => {}
-            }
-        }
+                HIRNode::BoundedSubstrings(bounded_substrings_id) => {
+                    let bounded_substrings = grammar.bounded_substrings(bounded_substrings_id);
+                    let (node_id, len) = Self::from_state_id_to_bounded_substrings_state(
+                        bounded_substrings,
+                        item.state_id,
+                    );
+                    let mut state = bounded_substrings.suffix_automaton().get_state(node_id);
+                    state.feed([byte]);
+                    if !state.is_nil() {
+                        let len = len + 1;
+                        if len >= bounded_substrings.min() {
+                            // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                            unsafe {
+                                Self::advance_item_normal_unchecked(
+                                    grammar,
+                                    earley_sets,
+                                    to_be_completed_items,
+                                    item,
+                                )
+                            };
+                        }
+                        if len < bounded_substrings.max() {
+                            item.state_id = Self::from_bounded_substrings_state_to_state_id(
+                                bounded_substrings,
+                                state.node_id,
+                                len,
+                            );
+                            // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                            unsafe { earley_sets.push_to_last_row_unchecked(item) };
+                        }
+                    }
+                }
+                HIRNode::LengthPrefixed(length_prefixed_id) => {
+                    let length_prefixed = grammar.length_prefixed(length_prefixed_id);
+                    let completed = Self::feed_length_prefixed(
+                        length_prefixed,
+                        item.state_id,
+                        byte,
+                        |state_id| {
+                            // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                            unsafe {
+                                earley_sets
+                                    .push_to_last_row_unchecked(EarleyItem { state_id, ..item })
+                            };
+                        },
+                    );
+                    if completed {
+                        // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                        unsafe {
+                            Self::advance_item_normal_unchecked(
+                                grammar,
+                                earley_sets,
+                                to_be_completed_items,
+                                item,
+                            )
+                        };
+                    }
+                }
+                HIRNode::ByteSetComplement(_) | HIRNode::AnyChar => {
+                    let next = match node {
+                        HIRNode::ByteSetComplement(byte_set_id) => utils::feed_utf8_char(
+                            grammar.byte_set_complement(byte_set_id),
+                            item.state_id.as_(),
+                            byte,
+                        ),
+                        _ => utils::feed_any_utf8_char(item.state_id.as_(), byte),
+                    };
+                    match next {
+                        Some(0) => {
+                            // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                            unsafe {
+                                Self::advance_item_normal_unchecked(
+                                    grammar,
+                                    earley_sets,
+                                    to_be_completed_items,
+                                    item,
+                                )
+                            };
+                        }
+                        Some(state) => {
+                            item.state_id = Self::from_index_to_state_id(state);
+                            // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                            unsafe { earley_sets.push_to_last_row_unchecked(item) };
+                        }
+                        None => {}
+                    }
+                }
+                HIRNode::Json => {
+                    if let Some(state) = json::feed(item.state_id.as_(), byte) {
+                        if json::is_complete(state) {
+                            // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                            unsafe {
+                                Self::advance_item_normal_unchecked(
+                                    grammar,
+                                    earley_sets,
+                                    to_be_completed_items,
+                                    item,
+                                )
+                            };
+                        }
+                        // The recognizer always accepts more bytes, at least the trailing whitespace.
+                        item.state_id = Self::from_index_to_state_id(state);
+                        // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                        unsafe { earley_sets.push_to_last_row_unchecked(item) };
+                    }
+                }
+                HIRNode::Nonterminal(_) => {}
+            }
+        }
     }
     fn update_postdot_items(
         grammar: &Grammar<TI>,
@@ -1148,12 +2655,16 @@ where
         }
         for v in postdot_items.values_mut() {
             if let &mut PostDotItems::LeoEligible(item) = v {
-                if !Self::item_should_be_completed(
-                    grammar,
-                    item.nonterminal_id,
-                    item.dot_position + TD::ONE,
-                    item.production_index,
-                ) {
+                // The Leo items complete their nonterminals implicitly,
+                // so atomic nonterminals are excluded to allow suppressing their completions.
+                if grammar.is_atomic_nonterminal(item.nonterminal_id)
+                    || !Self::item_should_be_completed(
+                        grammar,
+                        item.nonterminal_id,
+                        item.dot_position + TD::ONE,
+                        item.production_index,
+                    )
+                {
                     // not a leo item
                     *v = PostDotItems::NormalItems(vec![item]);
                 }
@@ -1165,6 +2676,7 @@ where
         leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
         postdot_items: &AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
         mut topmost_item: ToBeCompletedItem<TI, TSP>,
+        mut completed: impl FnMut(ToBeCompletedItem<TI, TSP>),
     ) -> Option<ToBeCompletedItem<TI, TSP>> {
         loop {
             let dotted = Dotted {
@@ -1200,6 +2712,8 @@ where
         } else {
             leo_items.reserve(leo_items_buffer.len());
             for leo_item in leo_items_buffer.iter().copied() {
+                // The items below the topmost item are completed implicitly by the Leo optimization.
+                completed(leo_item);
                 // Very interestingly, this is faster than leo_items_buffer.drain()
                 let dotted = Dotted {
                     postdot_nonterminal_id: leo_item.nonterminal_id,
@@ -1261,13 +2775,25 @@ where
         postdot_items: &AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
         deduplication_buffer: &mut AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
         finished: &mut bool,
+        suppress_atomic: bool,
+        mut completed: impl FnMut(ToBeCompletedItem<TI, TSP>, usize),
     ) {
+        let end_position = earley_sets.len() - 1;
         to_be_completed_items_buffer.clear();
         while !to_be_completed_items.is_empty() {
             for item in to_be_completed_items.drain() {
-                if let Some(topmost_item) =
-                    Self::try_leo_complete_item(leo_items_buffer, leo_items, postdot_items, item)
-                {
+                if suppress_atomic && grammar.is_atomic_nonterminal(item.nonterminal_id) {
+                    // No token may straddle the end of an atomic nonterminal
+                    continue;
+                }
+                if let Some(topmost_item) = Self::try_leo_complete_item(
+                    leo_items_buffer,
+                    leo_items,
+                    postdot_items,
+                    item,
+                    |x| completed(x, end_position),
+                ) {
+                    completed(topmost_item, end_position);
                     Self::earley_complete_one_item(
                         grammar,
                         topmost_item,
@@ -1277,6 +2803,7 @@ where
                         finished,
                     );
                 } else {
+                    completed(item, end_position);
                     Self::earley_complete_one_item(
                         grammar,
                         item,
@@ -1325,18 +2852,312 @@ where
         earley_sets.view::<1, 1>([earley_sets.len() - 1]).is_empty()
             && to_be_completed_items.is_empty()
     }
+    #[inline]
+    fn is_completable_partial_regex(
+        grammar: &Grammar<TI>,
+        item: EarleyItem<TI, TD, TP, TSP, TS>,
+    ) -> bool {
+        // SAFETY: item comes from the Earley sets or Self::advance_item, both of which guarantee the validity.
+        let node = unsafe {
+            *grammar.node_unchecked(
+                item.nonterminal_id,
+                item.dot_position,
+                item.production_index,
+            )
+        };
+        match node {
+            HIRNode::RegexString(regex_id) | HIRNode::EarlyEndRegexString(regex_id)
+                if grammar.is_partial_regex(regex_id) =>
+            {
+                match grammar.regex(regex_id) {
+                    FiniteStateAutomaton::Dfa(dfa) => {
+                        let state_id =
+                            Self::from_state_id_to_dfa_state_id(item.state_id, dfa.stride2());
+                        utils::check_dfa_state_status(state_id, dfa)
+                            != utils::FsaStateStatus::Reject
+                    }
+                }
+            }
+            _ => false,
+        }
+    }
+    /// Checks whether the start nonterminal could be completed at the last Earley set
+    /// if every partial regex in a live state were completed.
+    ///
+    /// The Earley sets are not modified. Instead, the completions are simulated with local buffers.
+    fn can_finish_with_partial_regexes(&self) -> bool {
+        let earley_set_index = self.earley_sets.len() - 1;
+        let column: TSP = earley_set_index.as_();
+        // Items that are virtually advanced over their current nodes
+        let mut to_be_advanced_items = Vec::new();
+        let mut visited_items = AHashSet::default();
+        let mut completed_items = AHashSet::default();
+        let mut to_be_completed_items = AHashSet::default();
+        // Items in the last Earley set that wait for a nonterminal to be completed.
+        let mut waiting_items: AHashMap<NonterminalID<TI>, Vec<EarleyItem<TI, TD, TP, TSP, TS>>> =
+            AHashMap::default();
+        let mut predicted_nonterminals =
+            FixedBitSet::with_capacity(self.grammar.nonterminals_size());
+        let mut to_be_visited_items: Vec<_> = self
+            .earley_sets
+            .view::<1, 1>([earley_set_index])
+            .as_slice()
+            .to_vec();
+        loop {
+            while let Some(item) = to_be_visited_items.pop() {
+                if !visited_items.insert(item) {
+                    continue;
+                }
+                if Self::is_completable_partial_regex(&self.grammar, item) {
+                    to_be_advanced_items.push(item);
+                    continue;
+                }
+                // SAFETY: item comes from the Earley sets or Self::advance_item, both of which guarantee the validity.
+                let node = unsafe {
+                    *self.grammar.node_unchecked(
+                        item.nonterminal_id,
+                        item.dot_position,
+                        item.production_index,
+                    )
+                };
+                if let HIRNode::Nonterminal(nonterminal_id) = node {
+                    waiting_items.entry(nonterminal_id).or_default().push(item);
+                    if completed_items.contains(&ToBeCompletedItem {
+                        nonterminal_id,
+                        start_position: column,
+                    }) {
+                        to_be_advanced_items.push(item);
+                    }
+                    let nid = nonterminal_id.0.as_();
+                    if !predicted_nonterminals.contains(nid) {
+                        predicted_nonterminals.insert(nid);
+                        // SAFETY: nonterminal_id comes from the grammar and 0 is always a valid dot position.
+                        let productions =
+                            unsafe { self.grammar.rules().view_unchecked::<2, 1>([nid, 0]) }
+                                .as_slice();
+                        for (j, node) in productions.iter().copied().enumerate() {
+                            to_be_visited_items.push(EarleyItem {
+                                nonterminal_id,
+                                dot_position: TD::ZERO,
+                                production_index: j.as_(),
+                                start_position: column,
+                                state_id: Self::initialize_state_id_based_on_node(
+                                    &self.grammar,
+                                    node,
+                                ),
+                            });
+                        }
+                    }
+                }
+            }
+            while let Some(item) = to_be_advanced_items.pop() {
+                Self::advance_item(
+                    &self.grammar,
+                    &mut to_be_completed_items,
+                    |new_item| to_be_visited_items.push(new_item),
+                    item,
+                );
+            }
+            for mut item in to_be_completed_items.drain() {
+                let dotted = Dotted {
+                    postdot_nonterminal_id: item.nonterminal_id,
+                    column: item.start_position,
+                };
+                if let Some(leo_item) = self.leo_items.get(&dotted) {
+                    item = *leo_item;
+                }
+                if !completed_items.insert(item) {
+                    continue;
+                }
+                if item.nonterminal_id == self.grammar.get_start_nonterminal_id()
+                    && item.start_position == TSP::ZERO
+                {
+                    return true;
+                }
+                if item.start_position == column {
+                    if let Some(items) = waiting_items.get(&item.nonterminal_id) {
+                        to_be_advanced_items.extend_from_slice(items);
+                    }
+                } else if let Some(postdot) = self.postdot_items.get(&Dotted {
+                    postdot_nonterminal_id: item.nonterminal_id,
+                    column: item.start_position,
+                }) {
+                    match postdot {
+                        PostDotItems::LeoEligible(leo_item) => to_be_advanced_items.push(*leo_item),
+                        PostDotItems::NormalItems(items) => {
+                            to_be_advanced_items.extend_from_slice(items)
+                        }
+                    }
+                }
+            }
+            if to_be_visited_items.is_empty() && to_be_advanced_items.is_empty() {
+                return false;
+            }
+        }
+    }
+    /// Creates a copy of the engine without cache whose last Earley set only contains
+    /// the items derived from the nonterminal predicted at the last Earley set.
+    ///
+    /// The tokens allowed by the copy are hence the tokens consistent with the nonterminal.
+    fn restrict_to_nonterminal_at_last_earley_set(
+        &self,
+        nonterminal_id: NonterminalID<TI>,
+    ) -> Self {
+        let earley_set_index = self.earley_sets.len() - 1;
+        let column: TSP = earley_set_index.as_();
+        let items = self
+            .earley_sets
+            .view::<1, 1>([earley_set_index])
+            .as_slice()
+            .to_vec();
+        let mut nonterminals = FixedBitSet::with_capacity(self.grammar.nonterminals_size());
+        nonterminals.insert(nonterminal_id.0.as_());
+        let mut updated = true;
+        while updated {
+            updated = false;
+            for item in items.iter() {
+                if item.start_position != column
+                    || !nonterminals.contains(item.nonterminal_id.0.as_())
+                {
+                    continue;
+                }
+                if let HIRNode::Nonterminal(nonterminal_id) = *self.grammar.node(
+                    item.nonterminal_id,
+                    item.dot_position,
+                    item.production_index,
+                ) {
+                    if !nonterminals.put(nonterminal_id.0.as_()) {
+                        updated = true;
+                    }
+                }
+            }
+        }
+        let mut earley_sets = self.earley_sets.clone();
+        earley_sets.truncate::<0>(earley_set_index);
+        earley_sets.new_row::<0>();
+        for item in items {
+            if item.start_position == column && nonterminals.contains(item.nonterminal_id.0.as_()) {
+                earley_sets.push_to_last_row(item);
+            }
+        }
+        // Items outside of the nonterminal should not be advanced, except the ones waiting for the nonterminal itself.
+        let mut postdot_items = self.postdot_items.clone();
+        postdot_items.retain(|dotted, _| {
+            dotted.column != column || dotted.postdot_nonterminal_id == nonterminal_id
+        });
+        Self::update_postdot_items(
+            &self.grammar,
+            &mut earley_sets,
+            &mut postdot_items,
+            &mut utils::new_hash_set(self.config.deterministic),
+            |_| {},
+        );
+        Self {
+            vocabulary: self.vocabulary.clone(),
+            grammar: self.grammar.clone(),
+            allowed_first_bytes: self.allowed_first_bytes.clone(),
+            allowed_token_ids: self.allowed_token_ids.clone(),
+            earley_sets,
+            cache: utils::LruCache::new(None),
+            prior_cache: self.prior_cache.clone(),
+            to_be_completed_items: utils::new_hash_set(self.config.deterministic),
+            to_be_completed_items_buffer: utils::new_hash_set(self.config.deterministic),
+            deduplication_buffer: utils::new_hash_set(self.config.deterministic),
+            postdot_items,
+            postdot_items_since_last_commit: utils::new_hash_set(self.config.deterministic),
+            column_to_postdot_nonterminals: self.column_to_postdot_nonterminals.clone(),
+            leo_items: self.leo_items.clone(),
+            leo_items_buffer: Vec::new(),
+            already_predicted_nonterminals: self.already_predicted_nonterminals.clone(),
+            finished: self.finished,
+            accepted_bytes: Vec::new(),
+            accept_markers: Vec::new(),
+            track_accepted_bytes: self.track_accepted_bytes,
+            eos_token_id: self.eos_token_id,
+            ignored_token_ids: self.ignored_token_ids.clone(),
+            mask_after_finish: self.mask_after_finish,
+            finished_is_terminal: self.finished_is_terminal,
+            empty_match: false,
+            detailed_errors: false,
+            last_rejection: None,
+            collect_accept_metrics: false,
+            accept_metrics: AcceptMetrics::default(),
+            token_healing: self.token_healing,
+            prefilter: self.prefilter.clone(),
+            prefilter_state: self.prefilter_state,
+            completion_hook: CompletionHookSlot(None),
+            column_offsets: self.column_offsets.clone(),
+            uncompacted_bytes: 0,
+            ambiguity_warnings: self.ambiguity_warnings.clone(),
+            shared_cache: None,
+            config: EngineConfig {
+                cache_enabled: false,
+                ..self.config
+            },
+        }
+    }
+    /// Clones the engine without its cache, which is useful for exploring the states without modifying the engine.
+    fn clone_without_cache(&self) -> Self {
+        Self {
+            vocabulary: self.vocabulary.clone(),
+            grammar: self.grammar.clone(),
+            allowed_first_bytes: self.allowed_first_bytes.clone(),
+            allowed_token_ids: self.allowed_token_ids.clone(),
+            earley_sets: self.earley_sets.clone(),
+            cache: utils::LruCache::new(None),
+            prior_cache: self.prior_cache.clone(),
+            to_be_completed_items: utils::new_hash_set(self.config.deterministic),
+            to_be_completed_items_buffer: utils::new_hash_set(self.config.deterministic),
+            deduplication_buffer: utils::new_hash_set(self.config.deterministic),
+            postdot_items: self.postdot_items.clone(),
+            postdot_items_since_last_commit: utils::new_hash_set(self.config.deterministic),
+            column_to_postdot_nonterminals: self.column_to_postdot_nonterminals.clone(),
+            leo_items: self.leo_items.clone(),
+            leo_items_buffer: Vec::new(),
+            already_predicted_nonterminals: FixedBitSet::with_capacity(
+                self.already_predicted_nonterminals.len(),
+            ),
+            finished: self.finished,
+            accepted_bytes: self.accepted_bytes.clone(),
+            accept_markers: self.accept_markers.clone(),
+            track_accepted_bytes: self.track_accepted_bytes,
+            eos_token_id: self.eos_token_id,
+            ignored_token_ids: self.ignored_token_ids.clone(),
+            mask_after_finish: self.mask_after_finish,
+            finished_is_terminal: self.finished_is_terminal,
+            empty_match: self.empty_match,
+            detailed_errors: false,
+            last_rejection: None,
+            collect_accept_metrics: false,
+            accept_metrics: AcceptMetrics::default(),
+            token_healing: self.token_healing,
+            prefilter: self.prefilter.clone(),
+            prefilter_state: self.prefilter_state,
+            completion_hook: CompletionHookSlot(None),
+            column_offsets: self.column_offsets.clone(),
+            uncompacted_bytes: self.uncompacted_bytes,
+            ambiguity_warnings: self.ambiguity_warnings.clone(),
+            shared_cache: None,
+            config: EngineConfig {
+                cache_enabled: false,
+                ..self.config
+            },
+        }
+    }
     /// Compact the Earley sets by removing the Earley sets that are not reachable from the last Earley set
     fn compact(
         earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
         leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
         postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
         column_to_postdot_nonterminals: &mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>,
+        predicted_items_start: usize,
     ) {
         let earley_set_index = earley_sets.len() - 1;
         let mut view = earley_sets.view_mut::<1, 1>([earley_set_index]);
         let earley_set = view.as_slice_mut();
         let mut max_start_position = 0;
-        for item in earley_set.iter_mut() {
+        // The predicted items start at the last Earley set, so they never keep the other Earley sets reachable.
+        for item in earley_set[..predicted_items_start].iter_mut() {
             let mut start_position = item.start_position.as_();
             if let Some(leo_item) = leo_items
                 .get(&Dotted {
@@ -1366,6 +3187,10 @@ where
             return;
         }
         earley_sets.remove_rows(max_start_position + 1..earley_set_index);
+        let mut view = earley_sets.view_mut::<1, 1>([max_start_position + 1]);
+        for item in view.as_slice_mut()[predicted_items_start..].iter_mut() {
+            item.start_position = (max_start_position + 1).as_();
+        }
         for index in max_start_position + 1..earley_set_index {
             if let Some(nonterminals) = column_to_postdot_nonterminals.remove(&index.as_()) {
                 for nonterminal in nonterminals.into_iter() {
@@ -1380,6 +3205,10 @@ where
         }
     }
 
+    /// Accepts one byte and creates the next Earley set.
+    ///
+    /// `mid_token` indicates whether more bytes of the same token follow the byte,
+    /// in which case no atomic nonterminal is completed or predicted in the next Earley set.
     fn accept_byte(
         grammar: &Grammar<TI>,
         earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
@@ -1395,12 +3224,17 @@ where
         deduplication_buffer: &mut AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
         previous_earley_set_length: usize,
         finished: &mut bool,
+        completed: impl FnMut(ToBeCompletedItem<TI, TSP>, usize),
         compact: impl FnOnce(
             &mut EarleySets<TI, TD, TP, TSP, TS>,
             &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
             &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+            usize,
         ),
         byte: u8,
+        mid_token: bool,
+        max_earley_set_size: usize,
+        max_total_items: usize,
     ) -> Result<(), crate::engine_like::AcceptTokenError> {
         Self::scan(grammar, earley_sets, to_be_completed_items, byte); // scan the current Earley set and creates the next Earley set
         if Self::is_rejected(earley_sets, to_be_completed_items) {
@@ -1415,6 +3249,7 @@ where
             );
             return Err(crate::engine_like::AcceptTokenError::Rejected);
         }
+        let suppress_atomic = mid_token && grammar.has_atomic_nonterminals();
         Self::complete(
             grammar,
             earley_sets,
@@ -1425,9 +3260,34 @@ where
             postdot_items,
             deduplication_buffer,
             finished,
+            suppress_atomic,
+            completed,
         ); // complete the next Earley set
-        compact(earley_sets, leo_items, postdot_items);
-        Self::predict(grammar, earley_sets, already_predicted_nonterminals); // predict the next Earley set
+        let earley_set_index = earley_sets.len() - 1;
+        let predicted_items_start = earley_sets.view::<1, 1>([earley_set_index]).len();
+        Self::predict(
+            grammar,
+            earley_sets,
+            already_predicted_nonterminals,
+            suppress_atomic,
+        ); // predict the next Earley set
+
+        // The limits are checked before the compaction, which cannot be reverted.
+        if earley_sets.view::<1, 1>([earley_set_index]).len() > max_earley_set_size
+            || earley_sets.buffer_len() > max_total_items
+        {
+            Self::revert_change(
+                earley_sets,
+                postdot_items,
+                added_postdot_items,
+                leo_items,
+                remove_column_to_postdot_nonterminal_operation,
+                previous_earley_set_length,
+                finished,
+            );
+            return Err(crate::engine_like::AcceptTokenError::ResourceLimitExceeded);
+        }
+        compact(earley_sets, leo_items, postdot_items, predicted_items_start);
         Self::update_postdot_items(
             grammar,
             earley_sets,
@@ -1438,70 +3298,374 @@ where
         Ok(())
     }
 
-    fn add_tokens_from_eager_regex_cache(&mut self) -> bool {
-        let cache = &self.grammar.regex_to_token_ids;
-        let last_earley_set_index = self.earley_sets.len() - 1;
-        let last_earley_set = self
-            .earley_sets
-            .view::<1, 1>([last_earley_set_index])
-            .as_slice();
-        let mut changed = false;
-        for item in last_earley_set.iter().copied() {
-            let node = *self.grammar.node(
-                item.nonterminal_id,
-                item.dot_position,
-                item.production_index,
-            );
-            let regex_id;
-            let regex_type;
-            match node {
-                HIRNode::RegexString(id) => {
-                    regex_id = id;
-                    regex_type = RegexType::Normal;
-                }
-                HIRNode::EarlyEndRegexString(id) => {
-                    regex_id = id;
-                    regex_type = RegexType::Early;
-                }
-                HIRNode::RegexComplement(id) => {
-                    regex_id = id;
-                    regex_type = RegexType::Complement;
+    /// Clears the states except the accepted bytes and initializes the first Earley set.
+    fn initialize_states(&mut self) {
+        self.accept_markers.clear();
+        self.last_rejection = None;
+        self.uncompacted_bytes = 0;
+        self.earley_sets.clear();
+        self.to_be_completed_items.clear();
+        self.to_be_completed_items_buffer.clear();
+        self.leo_items.clear();
+        self.leo_items_buffer.clear();
+        self.postdot_items.clear();
+        self.postdot_items_since_last_commit.clear();
+        self.deduplication_buffer.clear();
+        self.column_to_postdot_nonterminals.clear();
+        self.already_predicted_nonterminals.clear();
+        self.finished = false;
+        self.prefilter_state = self
+            .prefilter
+            .as_deref()
+            .map_or(StateID::ZERO, |prefilter| {
+                // SAFETY: the DFA is built with the anchored start states and no look-behind byte is given,
+                // so getting the start state never fails.
+                unsafe {
+                    prefilter
+                        .dfa
+                        .start_state(
+                            &kbnf_regex_automata::util::start::Config::new()
+                                .anchored(kbnf_regex_automata::Anchored::Yes),
+                        )
+                        .unwrap_unchecked()
                 }
-                _ => continue,
-            }
-            let dfa = self.grammar.regex(regex_id);
-            let stride2 = match dfa {
-                FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
-            };
-            let state_id = Self::from_state_id_to_dfa_state_id(item.state_id, stride2);
-            if let Some(token_ids) = cache.get(&(regex_id, state_id, regex_type)) {
-                self.allowed_token_ids.union_with(token_ids);
-                changed = true;
-            }
-        }
-        changed
-    }
-
-    fn accept_bytes(
-        grammar: &Grammar<TI>,
-        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
-        to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
-        to_be_completed_items_buffer: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
-        leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
-        leo_items_buffer: &mut Vec<ToBeCompletedItem<TI, TSP>>,
-        postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
-        added_postdot_items: &mut AHashSet<Dotted<TI, TSP>>,
-        already_predicted_nonterminals: &mut FixedBitSet,
+            });
+        self.allowed_token_ids.clear();
+        self.allowed_first_bytes.clear();
+        self.earley_sets.new_row::<0>();
+        self.column_offsets.clear();
+        self.column_offsets.push(0);
+        Self::predict_nonterminal(
+            &self.grammar,
+            &mut self.earley_sets,
+            &mut self.already_predicted_nonterminals,
+            self.grammar.get_start_nonterminal_id(),
+            0,
+        ); // init the first Earley set
+        Self::predict(
+            &self.grammar,
+            &mut self.earley_sets,
+            &mut self.already_predicted_nonterminals,
+            false,
+        ); // run a full prediction for the first earley set
+        Self::update_postdot_items(
+            &self.grammar,
+            &mut self.earley_sets,
+            &mut self.postdot_items,
+            // We will never need to revert the engine's state since it is the initialization
+            &mut utils::new_hash_set(self.config.deterministic),
+            |_| {}, // column zero should never be removed
+        );
+    }
+
+    /// Adds the allowed tokens starting with `byte` to `allowed_token_ids`,
+    /// stopping as soon as `remaining` tokens are added. `remaining` is decreased by the number of added tokens.
+    ///
+    /// The tokens are visited by a depth-first traversal of the subtree of `byte` in the vocabulary's trie,
+    /// so each byte shared by several tokens is fed only once and its Earley set is reused by all of them.
+    /// A subtree is skipped as soon as its byte is rejected by the prefilter or the grammar,
+    /// or when all its tokens are already allowed by the eager regex cache.
+    ///
+    /// `added_postdot_items_stack` holds the postdot items added by each byte on the current path,
+    /// which are removed when the traversal backtracks over the byte.
+    /// The states are reverted before returning, so the calls for different first bytes are independent of each other.
+    ///
+    /// Returns `false` if `deadline` passes before the traversal is done, in which case the untested tokens are not added.
+    fn add_allowed_tokens_from_first_byte(
+        grammar: &Grammar<TI>,
+        vocabulary: &Vocabulary,
+        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
+        to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
+        to_be_completed_items_buffer: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
+        leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        leo_items_buffer: &mut Vec<ToBeCompletedItem<TI, TSP>>,
+        postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+        postdot_items_since_last_commit: &mut AHashSet<Dotted<TI, TSP>>,
+        already_predicted_nonterminals: &mut FixedBitSet,
+        deduplication_buffer: &mut AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
+        finished: &mut bool,
+        allowed_token_ids: &mut FixedBitSet,
+        added_postdot_items_stack: &mut Vec<AHashSet<Dotted<TI, TSP>>>,
+        eager_cache: bool,
+        remaining: &mut usize,
+        deadline: Option<Instant>,
+        prefilter: Option<&Prefilter>,
+        prefilter_state: StateID,
+        byte: u8,
+    ) -> bool {
+        let trie = vocabulary.token_trie();
+        let Some(first_node) = trie.child(TokenTrie::ROOT, byte) else {
+            return true;
+        };
+        let end = trie.subtree_end(first_node);
+        // The accepted nodes on the current path with the Earley set length and the finished flag before them,
+        // and the state of the prefilter after them.
+        let mut path: Vec<(usize, usize, bool, StateID)> = Vec::new();
+        let mut node = first_node;
+        let mut timed_out = false;
+        while node < end {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                timed_out = true;
+                break;
+            }
+            let parent_prefilter_state = path.last().map_or(prefilter_state, |x| x.3);
+            let next_prefilter_state = match prefilter {
+                Some(prefilter) => prefilter.feed(parent_prefilter_state, trie.byte(node)),
+                None => Some(parent_prefilter_state),
+            };
+            let skipped = next_prefilter_state.is_none()
+                || eager_cache
+                    && trie
+                        .subtree_token_ids(node)
+                        .iter()
+                        .all(|&token_id| allowed_token_ids.contains(token_id as usize));
+            let earley_sets_len = earley_sets.len();
+            let was_finished = *finished;
+            let has_children = trie.has_children(node);
+            if !skipped
+                && Self::accept_byte(
+                    grammar,
+                    earley_sets,
+                    to_be_completed_items,
+                    to_be_completed_items_buffer,
+                    leo_items,
+                    leo_items_buffer,
+                    postdot_items,
+                    postdot_items_since_last_commit,
+                    |_| {},
+                    |_| {},
+                    already_predicted_nonterminals,
+                    deduplication_buffer,
+                    earley_sets_len,
+                    finished,
+                    |_, _| {},
+                    |_, _, _, _| {},
+                    trie.byte(node),
+                    // The Earley set is only used by the longer tokens
+                    has_children,
+                    usize::MAX,
+                    usize::MAX,
+                )
+                .is_ok()
+            {
+                for &token_id in trie.token_ids(node) {
+                    if !allowed_token_ids.put(token_id as usize) {
+                        *remaining -= 1;
+                    }
+                }
+                if *remaining == 0 {
+                    Self::revert_change(
+                        earley_sets,
+                        postdot_items,
+                        postdot_items_since_last_commit,
+                        leo_items,
+                        |_| {},
+                        earley_sets_len,
+                        finished,
+                    );
+                    *finished = was_finished;
+                    break;
+                }
+                if has_children {
+                    // The postdot items of this byte are kept until the traversal backtracks over it.
+                    if added_postdot_items_stack.len() == path.len() {
+                        added_postdot_items_stack.push(AHashSet::with_hasher(
+                            postdot_items_since_last_commit.hasher().clone(),
+                        ));
+                    }
+                    std::mem::swap(
+                        postdot_items_since_last_commit,
+                        &mut added_postdot_items_stack[path.len()],
+                    );
+                    // The node is skipped unless its byte is accepted by the prefilter.
+                    let prefilter_state = next_prefilter_state.unwrap_or(StateID::ZERO);
+                    path.push((node, earley_sets_len, was_finished, prefilter_state));
+                    node += 1;
+                    continue;
+                }
+                Self::revert_change(
+                    earley_sets,
+                    postdot_items,
+                    postdot_items_since_last_commit,
+                    leo_items,
+                    |_| {},
+                    earley_sets_len,
+                    finished,
+                );
+                *finished = was_finished;
+            }
+            node = trie.subtree_end(node);
+            // Backtrack over the nodes whose subtrees are exhausted.
+            while let Some(&(parent, earley_sets_len, was_finished, _)) = path.last() {
+                if node < trie.subtree_end(parent) {
+                    break;
+                }
+                path.pop();
+                Self::revert_change(
+                    earley_sets,
+                    postdot_items,
+                    &mut added_postdot_items_stack[path.len()],
+                    leo_items,
+                    |_| {},
+                    earley_sets_len,
+                    finished,
+                );
+                *finished = was_finished;
+            }
+        }
+        // The traversal stops early when enough tokens are added or the deadline passes,
+        // so the remaining path is reverted.
+        while let Some((_, earley_sets_len, was_finished, _)) = path.pop() {
+            Self::revert_change(
+                earley_sets,
+                postdot_items,
+                &mut added_postdot_items_stack[path.len()],
+                leo_items,
+                |_| {},
+                earley_sets_len,
+                finished,
+            );
+            *finished = was_finished;
+        }
+        !timed_out
+    }
+
+    fn add_tokens_from_eager_regex_cache(&mut self) -> bool {
+        let cache = &self.grammar.regex_to_token_ids;
+        let last_earley_set_index = self.earley_sets.len() - 1;
+        let last_earley_set = self
+            .earley_sets
+            .view::<1, 1>([last_earley_set_index])
+            .as_slice();
+        let mut changed = false;
+        for item in last_earley_set.iter().copied() {
+            let node = *self.grammar.node(
+                item.nonterminal_id,
+                item.dot_position,
+                item.production_index,
+            );
+            let regex_id;
+            let regex_type;
+            match node {
+                HIRNode::RegexString(id) => {
+                    regex_id = id;
+                    regex_type = RegexType::Normal;
+                }
+                HIRNode::EarlyEndRegexString(id) => {
+                    regex_id = id;
+                    regex_type = RegexType::Early;
+                }
+                HIRNode::RegexComplement(id) => {
+                    regex_id = id;
+                    regex_type = RegexType::Complement;
+                }
+                _ => continue,
+            }
+            let dfa = self.grammar.regex(regex_id);
+            let stride2 = match dfa {
+                FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
+            };
+            let state_id = Self::from_state_id_to_dfa_state_id(item.state_id, stride2);
+            if let Some(token_ids) = cache.get(&(regex_id, state_id, regex_type)) {
+                self.allowed_token_ids.union_with(token_ids);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Adds the work of accepting one byte to the metrics, where `earley_sets_len` is the number of
+    /// the Earley sets before the byte and `completions` counts the completions since the last call.
+    fn record_accept_metrics(
+        metrics: &mut AcceptMetrics,
+        earley_sets: &EarleySets<TI, TD, TP, TSP, TS>,
+        earley_sets_len: usize,
+        accepted: bool,
+        completions: &Cell<usize>,
+    ) {
+        metrics.bytes_scanned += 1;
+        metrics.completions += completions.take();
+        if accepted {
+            metrics.items_created += earley_sets.view::<1, 1>([earley_sets.len() - 1]).len();
+            metrics.earley_sets_compacted += earley_sets_len + 1 - earley_sets.len();
+        }
+    }
+
+    fn accept_bytes(
+        grammar: &Grammar<TI>,
+        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
+        to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
+        to_be_completed_items_buffer: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
+        leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        leo_items_buffer: &mut Vec<ToBeCompletedItem<TI, TSP>>,
+        postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+        added_postdot_items: &mut AHashSet<Dotted<TI, TSP>>,
+        already_predicted_nonterminals: &mut FixedBitSet,
         deduplication_buffer: &mut AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
         column_to_postdot_nonterminals: *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>,
         config: &EngineConfig,
         finished: &mut bool,
         bytes: impl Iterator<Item = u8>,
-    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        is_token: bool,
+        token_continues: bool,
+        mut completion_hook: Option<(&mut CompletionHook, &[usize])>,
+        uncompacted_bytes: &mut usize,
+        prefilter: Option<(&Prefilter, &mut StateID)>,
+        mut metrics: Option<&mut AcceptMetrics>,
+    ) -> Result<crate::engine_like::AcceptTokenResult, (usize, crate::engine_like::AcceptTokenError)>
+    {
         let len = earley_sets.len();
+        // The state of the prefilter is only updated when all the bytes are accepted.
+        let mut prefilter = prefilter.map(|(prefilter, state)| (prefilter, *state, state));
+        let mut bytes = bytes.enumerate().peekable();
+        let max_earley_set_size = config.max_earley_set_size.unwrap_or(usize::MAX);
+        let max_total_items = config.max_total_items.unwrap_or(usize::MAX);
+        let collect_metrics = metrics.is_some();
+        let completions = Cell::new(0);
+        let mut completed = |item: ToBeCompletedItem<TI, TSP>, end_position: usize| {
+            if collect_metrics {
+                completions.set(completions.get() + 1);
+            }
+            if let Some((hook, column_offsets)) = &mut completion_hook {
+                // The Earley sets added by the bytes are not compacted until the last byte is completed.
+                let offset = |column: usize| match column_offsets.get(column) {
+                    Some(&offset) => offset,
+                    None => column_offsets[len - 1] + column + 1 - len,
+                };
+                hook(
+                    NonterminalID(item.nonterminal_id.0.as_()),
+                    offset(item.start_position.as_()),
+                    offset(end_position),
+                );
+            }
+        };
         if config.compaction_enabled {
-            for byte in bytes {
-                Self::accept_byte(
+            let mut compacted = false;
+            while let Some((index, byte)) = bytes.next() {
+                if let Some((prefilter, state, _)) = &mut prefilter {
+                    let Some(next_state) = prefilter.feed(*state, byte) else {
+                        Self::revert_change(
+                            earley_sets,
+                            postdot_items,
+                            added_postdot_items,
+                            leo_items,
+                            |column| {
+                                // SAFETY: no other reference to the map is alive here
+                                unsafe { &mut *column_to_postdot_nonterminals }.remove(&column);
+                            },
+                            len,
+                            finished,
+                        );
+                        return Err((index, crate::engine_like::AcceptTokenError::Rejected));
+                    };
+                    *state = next_state;
+                }
+                let is_last_byte = bytes.peek().is_none() && !token_continues;
+                let should_compact =
+                    is_last_byte && *uncompacted_bytes + index + 1 >= config.compaction_interval;
+                compacted |= should_compact;
+                let earley_sets_len = earley_sets.len();
+                let result = Self::accept_byte(
                     grammar,
                     earley_sets,
                     to_be_completed_items,
@@ -1531,19 +3695,63 @@ where
                     deduplication_buffer,
                     len,
                     finished,
-                    |earley_sets, leo_items, postdot_items| {
-                        // SAFETY: this closure will only be called in `accept_byte`
-                        // and never run simultaneously with the closures above
-                        Self::compact(earley_sets, leo_items, postdot_items, unsafe {
-                            &mut *column_to_postdot_nonterminals
-                        })
+                    &mut completed,
+                    |earley_sets, leo_items, postdot_items, predicted_items_start| {
+                        // The compaction cannot be reverted, so it is deferred to the last byte,
+                        // after which no byte can be rejected.
+                        if should_compact {
+                            // SAFETY: this closure will only be called in `accept_byte`
+                            // and never run simultaneously with the closures above
+                            Self::compact(
+                                earley_sets,
+                                leo_items,
+                                postdot_items,
+                                unsafe { &mut *column_to_postdot_nonterminals },
+                                predicted_items_start,
+                            )
+                        }
                     },
                     byte,
-                )?;
+                    is_token && (bytes.peek().is_some() || token_continues),
+                    max_earley_set_size,
+                    max_total_items,
+                );
+                if let Some(metrics) = metrics.as_deref_mut() {
+                    Self::record_accept_metrics(
+                        metrics,
+                        earley_sets,
+                        earley_sets_len,
+                        result.is_ok(),
+                        &completions,
+                    );
+                }
+                result.map_err(|e| (index, e))?;
             }
+            *uncompacted_bytes = if compacted {
+                0
+            } else {
+                // No Earley set is removed without the compaction.
+                *uncompacted_bytes + earley_sets.len() - len
+            };
         } else {
-            for byte in bytes {
-                Self::accept_byte(
+            while let Some((index, byte)) = bytes.next() {
+                if let Some((prefilter, state, _)) = &mut prefilter {
+                    let Some(next_state) = prefilter.feed(*state, byte) else {
+                        Self::revert_change(
+                            earley_sets,
+                            postdot_items,
+                            added_postdot_items,
+                            leo_items,
+                            |_| {},
+                            len,
+                            finished,
+                        );
+                        return Err((index, crate::engine_like::AcceptTokenError::Rejected));
+                    };
+                    *state = next_state;
+                }
+                let earley_sets_len = earley_sets.len();
+                let result = Self::accept_byte(
                     grammar,
                     earley_sets,
                     to_be_completed_items,
@@ -1558,12 +3766,29 @@ where
                     deduplication_buffer,
                     len,
                     finished,
-                    |_, _, _| {},
+                    &mut completed,
+                    |_, _, _, _| {},
                     byte,
-                )?;
+                    is_token && (bytes.peek().is_some() || token_continues),
+                    max_earley_set_size,
+                    max_total_items,
+                );
+                if let Some(metrics) = metrics.as_deref_mut() {
+                    Self::record_accept_metrics(
+                        metrics,
+                        earley_sets,
+                        earley_sets_len,
+                        result.is_ok(),
+                        &completions,
+                    );
+                }
+                result.map_err(|e| (index, e))?;
             }
         }
         Self::commit_change(added_postdot_items);
+        if let Some((_, state, prefilter_state)) = prefilter {
+            *prefilter_state = state;
+        }
         if *finished {
             Ok(crate::engine_like::AcceptTokenResult::Finished)
         } else {
@@ -1572,36 +3797,145 @@ where
     }
 }
 
-impl<TI, TD, TP, TSP, TS> crate::engine_like::sealed::Sealed for EngineBase<TI, TD, TP, TSP, TS>
+#[cfg(feature = "rayon")]
+#[allow(clippy::type_complexity)]
+/// The copies of the mutable states owned by a thread in [`EngineBase::add_allowed_tokens_in_parallel`].
+struct ParallelScratch<TI, TD, TP, TSP, TS>
 where
     TI: Num
         + AsPrimitive<usize>
         + ConstOne
         + ConstZero
-        + NumOps
-        + NumAssign
-        + std::cmp::PartialOrd
+        + Eq
+        + std::hash::Hash
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
         + num::Bounded
         + std::convert::TryFrom<usize>
-        + Debug
+        + NumAssign,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>,
+{
+    earley_sets: EarleySets<TI, TD, TP, TSP, TS>,
+    to_be_completed_items: AHashSet<ToBeCompletedItem<TI, TSP>>,
+    to_be_completed_items_buffer: AHashSet<ToBeCompletedItem<TI, TSP>>,
+    leo_items: AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+    leo_items_buffer: Vec<ToBeCompletedItem<TI, TSP>>,
+    postdot_items: AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+    postdot_items_since_last_commit: AHashSet<Dotted<TI, TSP>>,
+    already_predicted_nonterminals: FixedBitSet,
+    deduplication_buffer: AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
+    finished: bool,
+    allowed_token_ids: FixedBitSet,
+    added_postdot_items_stack: Vec<AHashSet<Dotted<TI, TSP>>>,
+}
+
+#[cfg(feature = "rayon")]
+#[allow(clippy::type_complexity)]
+impl<TI, TD, TP, TSP, TS> EngineBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
         + Eq
         + std::hash::Hash
-        + PartialEq,
+        + PartialEq
+        + std::fmt::Debug
+        + PartialOrd
+        + num::Bounded
+        + num::traits::NumAssignOps
+        + std::convert::TryFrom<usize>,
     TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
     TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
     TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
     TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TI: Send + Sync,
+    TD: Send + Sync,
+    TP: Send + Sync,
+    TSP: Send + Sync,
+    TS: Send + Sync,
     usize: num::traits::AsPrimitive<TI>
         + num::traits::AsPrimitive<TD>
         + num::traits::AsPrimitive<TP>
         + num::traits::AsPrimitive<TSP>
         + num::traits::AsPrimitive<TS>,
 {
+    /// Adds the allowed tokens of every allowed first byte to `self.allowed_token_ids` in parallel.
+    ///
+    /// The first bytes are sharded across the threads of the current rayon thread pool.
+    /// Each shard owns copies of the mutable states, and the allowed tokens of the shards are unioned together.
+    fn add_allowed_tokens_in_parallel(&mut self, eager_cache: bool) {
+        use rayon::prelude::*;
+        let first_bytes: Vec<u8> = self.allowed_first_bytes.ones().map(|x| x as u8).collect();
+        let grammar = &*self.grammar;
+        let vocabulary = &*self.vocabulary;
+        let prefilter = self.prefilter.as_deref();
+        let prefilter_state = self.prefilter_state;
+        let this = &*self;
+        let allowed_token_ids = first_bytes
+            .par_iter()
+            .fold(
+                || ParallelScratch {
+                    earley_sets: this.earley_sets.clone(),
+                    to_be_completed_items: this.to_be_completed_items.clone(),
+                    to_be_completed_items_buffer: this.to_be_completed_items_buffer.clone(),
+                    leo_items: this.leo_items.clone(),
+                    leo_items_buffer: this.leo_items_buffer.clone(),
+                    postdot_items: this.postdot_items.clone(),
+                    postdot_items_since_last_commit: this.postdot_items_since_last_commit.clone(),
+                    already_predicted_nonterminals: this.already_predicted_nonterminals.clone(),
+                    deduplication_buffer: this.deduplication_buffer.clone(),
+                    finished: this.finished,
+                    allowed_token_ids: this.allowed_token_ids.clone(),
+                    added_postdot_items_stack: Vec::new(),
+                },
+                |mut scratch, &byte| {
+                    let mut remaining = usize::MAX;
+                    Self::add_allowed_tokens_from_first_byte(
+                        grammar,
+                        vocabulary,
+                        &mut scratch.earley_sets,
+                        &mut scratch.to_be_completed_items,
+                        &mut scratch.to_be_completed_items_buffer,
+                        &mut scratch.leo_items,
+                        &mut scratch.leo_items_buffer,
+                        &mut scratch.postdot_items,
+                        &mut scratch.postdot_items_since_last_commit,
+                        &mut scratch.already_predicted_nonterminals,
+                        &mut scratch.deduplication_buffer,
+                        &mut scratch.finished,
+                        &mut scratch.allowed_token_ids,
+                        &mut scratch.added_postdot_items_stack,
+                        eager_cache,
+                        &mut remaining,
+                        None,
+                        prefilter,
+                        prefilter_state,
+                        byte,
+                    );
+                    scratch
+                },
+            )
+            .map(|scratch| scratch.allowed_token_ids)
+            .reduce_with(|mut x, y| {
+                x.union_with(&y);
+                x
+            });
+        if let Some(allowed_token_ids) = allowed_token_ids {
+            self.allowed_token_ids = allowed_token_ids;
+        }
+    }
 }
 
-#[allow(clippy::type_complexity)]
-#[allow(clippy::too_many_arguments)]
-impl<TI, TD, TP, TSP, TS> EngineLike for EngineBase<TI, TD, TP, TSP, TS>
+impl<TI, TD, TP, TSP, TS> EngineBase<TI, TD, TP, TSP, TS>
 where
     TI: Num
         + AsPrimitive<usize>
@@ -1618,31 +3952,403 @@ where
     TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
     TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
     TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    // Required by the EngineLike implementation.
+    TI: Send + Sync,
+    TD: Send + Sync,
+    TP: Send + Sync,
+    TSP: Send + Sync,
+    TS: Send + Sync,
     usize: num::traits::AsPrimitive<TI>
         + num::traits::AsPrimitive<TD>
         + num::traits::AsPrimitive<TP>
         + num::traits::AsPrimitive<TSP>
         + num::traits::AsPrimitive<TS>,
 {
-    fn try_accept_new_token(
-        &mut self,
-        token_id: u32,
-    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
-        if self.is_finished() {
-            return Err(crate::engine_like::AcceptTokenError::Finished);
+    /// Finds at most `limit` allowed token IDs except the EOS token and the ignored tokens,
+    /// which backs [`EngineLike::allowed_token_ids_until`].
+    fn allowed_token_ids_except_eos_until(&mut self, limit: usize) -> Vec<u32> {
+        if limit == 0 {
+            return Vec::new();
         }
-        let token = match self.vocabulary.token(token_id) {
-            Some(token) => token,
-            None => return Err(crate::engine_like::AcceptTokenError::UnknownTokenID),
+        // The EOS token is only allowed when the engine is finished, and the ignored tokens are never allowed.
+        let eos_token_id = self.eos_token_id;
+        let ignored_token_ids = self.ignored_token_ids.clone();
+        let is_allowed = |id: &usize| {
+            Some(*id as u32) != eos_token_id && !ignored_token_ids.contains(&(*id as u32))
         };
-        let token_iter = token.0.iter().copied();
-        let ptr = &mut self.column_to_postdot_nonterminals as *mut _;
-        Self::accept_bytes(
-            &self.grammar,
-            &mut self.earley_sets,
-            &mut self.to_be_completed_items,
-            &mut self.to_be_completed_items_buffer,
-            &mut self.leo_items,
+        let excluded = self.ignored_token_ids.len() + 1;
+        let collect_cached = |allowed_ids: &FixedBitSet| {
+            allowed_ids
+                .ones()
+                .filter(is_allowed)
+                .take(limit)
+                .map(|x| x as u32)
+                .collect()
+        };
+        let cached = match &self.shared_cache {
+            // The allowed token IDs depend on the state of the prefilter, which is not cached.
+            _ if self.prefilter.is_some() => None,
+            Some(shared_cache) => shared_cache.get(&self.earley_sets, collect_cached),
+            None if self.config.cache_enabled => {
+                self.cache.get(&self.earley_sets).map(collect_cached)
+            }
+            None => None,
+        };
+        if let Some(allowed_token_ids) = cached {
+            return allowed_token_ids;
+        }
+        let vocab_size = self.allowed_token_ids.len();
+        let last_allowed_token_ids = std::mem::replace(
+            &mut self.allowed_token_ids,
+            FixedBitSet::with_capacity(vocab_size),
+        );
+        let last_allowed_first_bytes = self.allowed_first_bytes.clone();
+        // More tokens are found in case the EOS token or the ignored tokens are among them.
+        let complete = self.add_allowed_token_ids_until(limit.saturating_add(excluded), None);
+        if complete && self.prefilter.is_none() {
+            match &self.shared_cache {
+                Some(shared_cache) => {
+                    shared_cache.insert(&self.earley_sets, &self.allowed_token_ids)
+                }
+                None if self.config.cache_enabled => self
+                    .cache
+                    .insert(self.earley_sets.clone(), self.allowed_token_ids.clone()),
+                None => {}
+            }
+        }
+        let allowed_token_ids =
+            std::mem::replace(&mut self.allowed_token_ids, last_allowed_token_ids);
+        self.allowed_first_bytes = last_allowed_first_bytes;
+        allowed_token_ids
+            .ones()
+            .filter(is_allowed)
+            .take(limit)
+            .map(|x| x as u32)
+            .collect()
+    }
+    /// Precomputes the allowed token IDs of the initial state and the states reachable by one token
+    /// into the cache, which eliminates the cold-start penalty of the first computations.
+    ///
+    /// At most `max_states` states are precomputed, including the initial state.
+    /// It does nothing if `max_states` is `None` or the cache is disabled.
+    /// The engine is reset afterwards.
+    pub fn with_eager_cache(mut self, max_states: Option<usize>) -> Self {
+        let Some(max_states) = max_states else {
+            return self;
+        };
+        if !self.config.cache_enabled || max_states == 0 || self.prefilter.is_some() {
+            return self;
+        }
+        let max_states = self
+            .cache
+            .capacity()
+            .map_or(max_states, |capacity| capacity.min(max_states));
+        self.compute_allowed_token_ids();
+        let initial_allowed_token_ids = self.allowed_token_ids.clone();
+        for token_id in initial_allowed_token_ids.ones() {
+            if self.cache.len() >= max_states {
+                break;
+            }
+            if let Ok(AcceptTokenResult::Ongoing) = self.try_accept_new_token(token_id as u32) {
+                self.compute_allowed_token_ids();
+            }
+            self.reset();
+        }
+        self.reset();
+        self
+    }
+
+    /// Randomly walks from current states by accepting the allowed first bytes one by one,
+    /// which backs [`Grammar::sample`].
+    ///
+    /// Before each byte, the walk tries to finish with the probability of the bytes accepted so far over `max_len`
+    /// by appending the shortest completion, so it ends in time even in recursive grammars.
+    /// The bytes after which no completion fits in `max_len` are skipped.
+    /// They are rolled back, which is cheap only when the compaction is disabled.
+    pub(crate) fn sample(
+        &mut self,
+        rng: &mut impl FnMut() -> u64,
+        max_len: usize,
+    ) -> Option<Vec<u8>> {
+        let mut sample = Vec::new();
+        loop {
+            if self.is_finished() {
+                return Some(sample);
+            }
+            let finishing = sample.len() >= max_len
+                || (rng() % (max_len as u64).saturating_add(1)) < sample.len() as u64;
+            if finishing {
+                let completion = self.shortest_completion(max_len.saturating_sub(sample.len()))?;
+                sample.extend(completion);
+                return Some(sample);
+            }
+            self.update_allowed_first_bytes();
+            let mut candidates: Vec<u8> =
+                self.allowed_first_bytes.ones().map(|x| x as u8).collect();
+            loop {
+                if candidates.is_empty() {
+                    return self.can_finish().then_some(sample);
+                }
+                // The first bytes may be allowed by an item that gets rejected later, e.g. inside excepted regexes.
+                let byte = candidates.swap_remove((rng() % candidates.len() as u64) as usize);
+                if self.try_accept_new_bytes(&[byte]).is_err() {
+                    continue;
+                }
+                // The byte is kept only if the walk can still finish within `max_len`.
+                if self
+                    .shortest_completion(max_len - sample.len() - 1)
+                    .is_some()
+                {
+                    sample.push(byte);
+                    break;
+                }
+                // SAFETY: the byte has just been accepted
+                unsafe { self.rollback(1).unwrap_unchecked() };
+            }
+        }
+    }
+
+    /// Computes the allowed token IDs like [`EngineLike::compute_allowed_token_ids`],
+    /// but stops when `deadline` passes, in which case the untested tokens are disallowed and the result is not cached.
+    fn compute_allowed_token_ids_before(&mut self, deadline: Option<Instant>) -> ComputeOutcome {
+        self.allowed_token_ids.clear();
+        if self.is_finished() {
+            self.apply_special_token_ids();
+            return ComputeOutcome::Complete;
+        }
+        let cached = match &self.shared_cache {
+            // The allowed token IDs depend on the state of the prefilter, which is not cached.
+            _ if self.prefilter.is_some() => false,
+            Some(shared_cache) => shared_cache
+                .get(&self.earley_sets, |allowed_ids| {
+                    self.allowed_token_ids.union_with(allowed_ids)
+                })
+                .is_some(),
+            None if self.config.cache_enabled => self
+                .cache
+                .get(&self.earley_sets)
+                .map(|allowed_ids| self.allowed_token_ids.union_with(allowed_ids))
+                .is_some(),
+            None => false,
+        };
+        if self.collect_accept_metrics {
+            self.accept_metrics.cache_hit = Some(cached);
+        }
+        if cached {
+            self.apply_special_token_ids();
+            return ComputeOutcome::Complete;
+        }
+        if !self.add_allowed_token_ids_until(usize::MAX, deadline) {
+            self.apply_special_token_ids();
+            return ComputeOutcome::TimedOut;
+        }
+        match &self.shared_cache {
+            _ if self.prefilter.is_some() => {}
+            Some(shared_cache) => shared_cache.insert(&self.earley_sets, &self.allowed_token_ids),
+            None if self.config.cache_enabled => self
+                .cache
+                .insert(self.earley_sets.clone(), self.allowed_token_ids.clone()),
+            None => {}
+        }
+        self.apply_special_token_ids();
+        ComputeOutcome::Complete
+    }
+
+    /// Adds the allowed token IDs of current states to `self.allowed_token_ids`,
+    /// stopping as soon as at least `limit` tokens are added or `deadline` passes.
+    ///
+    /// Returns whether all the allowed token IDs are added, which is required before caching them.
+    /// The EOS token is not handled here.
+    fn add_allowed_token_ids_until(&mut self, limit: usize, deadline: Option<Instant>) -> bool {
+        let mut eager_cache = false;
+        // The tokens in the eager regex cache are not checked by the prefilter.
+        if !self.grammar.regex_to_token_ids.is_empty() && self.prefilter.is_none() {
+            eager_cache = self.add_tokens_from_eager_regex_cache();
+        }
+        let mut remaining = limit;
+        if eager_cache && limit != usize::MAX {
+            remaining = remaining.saturating_sub(self.allowed_token_ids.count_ones(..));
+            if remaining == 0 {
+                return false;
+            }
+        }
+        self.update_allowed_first_bytes();
+        // The shards cannot stop each other early, so only the full computation is parallelized.
+        #[cfg(feature = "rayon")]
+        let parallel = limit == usize::MAX && deadline.is_none();
+        #[cfg(not(feature = "rayon"))]
+        let parallel = false;
+        if parallel {
+            #[cfg(feature = "rayon")]
+            self.add_allowed_tokens_in_parallel(eager_cache);
+        } else {
+            let mut added_postdot_items_stack = Vec::new();
+            for byte in self.allowed_first_bytes.ones() {
+                let completed = Self::add_allowed_tokens_from_first_byte(
+                    &self.grammar,
+                    &self.vocabulary,
+                    &mut self.earley_sets,
+                    &mut self.to_be_completed_items,
+                    &mut self.to_be_completed_items_buffer,
+                    &mut self.leo_items,
+                    &mut self.leo_items_buffer,
+                    &mut self.postdot_items,
+                    &mut self.postdot_items_since_last_commit,
+                    &mut self.already_predicted_nonterminals,
+                    &mut self.deduplication_buffer,
+                    &mut self.finished,
+                    &mut self.allowed_token_ids,
+                    &mut added_postdot_items_stack,
+                    eager_cache,
+                    &mut remaining,
+                    deadline,
+                    self.prefilter.as_deref(),
+                    self.prefilter_state,
+                    byte as u8,
+                );
+                if remaining == 0 || !completed {
+                    return false;
+                }
+            }
+        }
+        Self::commit_change(&mut self.postdot_items_since_last_commit);
+        true
+    }
+}
+
+impl<TI, TD, TP, TSP, TS> crate::engine_like::sealed::Sealed for EngineBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + Debug
+        + Eq
+        + std::hash::Hash
+        + PartialEq,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>
+        + num::traits::AsPrimitive<TS>,
+{
+    fn begin_transaction(&mut self) -> crate::engine_like::sealed::TransactionCheckpoint {
+        let checkpoint = crate::engine_like::sealed::TransactionCheckpoint {
+            earley_sets_len: self.earley_sets.len(),
+            finished: self.finished,
+            accepted_bytes_len: self.accepted_bytes.len(),
+            accept_markers_len: self.accept_markers.len(),
+            compaction_enabled: self.config.compaction_enabled,
+            allowed_token_ids: self.allowed_token_ids.clone(),
+            allowed_first_bytes: self.allowed_first_bytes.clone(),
+            prefilter_state: self.prefilter_state,
+        };
+        // Without compaction, the Earley sets before the checkpoint are never modified.
+        self.config.compaction_enabled = false;
+        checkpoint
+    }
+
+    fn end_transaction(
+        &mut self,
+        checkpoint: crate::engine_like::sealed::TransactionCheckpoint,
+        commit: bool,
+    ) {
+        let len = checkpoint.earley_sets_len;
+        self.config.compaction_enabled = checkpoint.compaction_enabled;
+        if commit {
+            if self.config.compaction_enabled {
+                // The columns added in the transaction are not tracked since the compaction is suspended.
+                self.track_postdot_columns_since(len);
+            }
+            return;
+        }
+        self.truncate_to_marker(AcceptMarker {
+            earley_sets_len: len,
+            finished: checkpoint.finished,
+            accepted_bytes_len: checkpoint.accepted_bytes_len,
+            prefilter_state: checkpoint.prefilter_state,
+        });
+        self.accept_markers.truncate(checkpoint.accept_markers_len);
+        self.allowed_token_ids = checkpoint.allowed_token_ids;
+        self.allowed_first_bytes = checkpoint.allowed_first_bytes;
+    }
+}
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+impl<TI, TD, TP, TSP, TS> EngineLike for EngineBase<TI, TD, TP, TSP, TS>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + num::Bounded
+        + std::convert::TryFrom<usize>
+        + Debug,
+    TI: Eq + std::hash::Hash + PartialEq,
+    TD: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TSP: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    TS: Num + AsPrimitive<usize> + ConstOne + ConstZero + Eq + std::hash::Hash + PartialEq,
+    // Required to share the states across threads when the `rayon` feature is enabled.
+    TI: Send + Sync,
+    TD: Send + Sync,
+    TP: Send + Sync,
+    TSP: Send + Sync,
+    TS: Send + Sync,
+    usize: num::traits::AsPrimitive<TI>
+        + num::traits::AsPrimitive<TD>
+        + num::traits::AsPrimitive<TP>
+        + num::traits::AsPrimitive<TSP>
+        + num::traits::AsPrimitive<TS>,
+{
+    fn try_accept_new_token(
+        &mut self,
+        token_id: u32,
+    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        self.accept_metrics = AcceptMetrics::default();
+        if self.ignored_token_ids.contains(&token_id) {
+            return Ok(if self.is_finished() {
+                crate::engine_like::AcceptTokenResult::Finished
+            } else {
+                crate::engine_like::AcceptTokenResult::Ongoing
+            });
+        }
+        if Some(token_id) == self.eos_token_id {
+            return if self.is_finished() || self.matches_empty() {
+                Ok(crate::engine_like::AcceptTokenResult::Finished)
+            } else {
+                Err(crate::engine_like::AcceptTokenError::Rejected)
+            };
+        }
+        if self.is_finished() {
+            return self.accept_after_finish();
+        }
+        let token = match self.vocabulary.token(token_id) {
+            Some(token) => token,
+            None => return Err(crate::engine_like::AcceptTokenError::UnknownTokenID),
+        };
+        let token_iter = token.0.iter().copied();
+        let ptr = &mut self.column_to_postdot_nonterminals as *mut _;
+        let marker = self.accept_marker();
+        let result = Self::accept_bytes(
+            &self.grammar,
+            &mut self.earley_sets,
+            &mut self.to_be_completed_items,
+            &mut self.to_be_completed_items_buffer,
+            &mut self.leo_items,
             &mut self.leo_items_buffer,
             &mut self.postdot_items,
             &mut self.postdot_items_since_last_commit,
@@ -1652,19 +4358,76 @@ where
             &self.config,
             &mut self.finished,
             token_iter,
-        )
+            true,
+            false,
+            self.completion_hook
+                .hook_mut()
+                .map(|hook| (hook, &self.column_offsets[..])),
+            &mut self.uncompacted_bytes,
+            self.prefilter
+                .as_deref()
+                .map(|prefilter| (prefilter, &mut self.prefilter_state)),
+            self.collect_accept_metrics
+                .then_some(&mut self.accept_metrics),
+        );
+        match result {
+            Ok(result) => {
+                Self::update_column_offsets(
+                    &mut self.column_offsets,
+                    &self.earley_sets,
+                    marker.earley_sets_len,
+                    token.0.len(),
+                );
+                if self.track_accepted_bytes {
+                    self.accept_markers.push(marker);
+                    self.accepted_bytes.extend_from_slice(&token.0);
+                }
+                self.last_rejection = None;
+                Ok(result)
+            }
+            Err((byte_index, error)) => {
+                if self.detailed_errors && error == crate::engine_like::AcceptTokenError::Rejected {
+                    let token = token.0.clone();
+                    self.record_rejection(&token, byte_index, true);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    fn try_accept_token_str(
+        &mut self,
+        token_str: &str,
+    ) -> Result<AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        let token_id = self
+            .vocabulary
+            .token_id_from_str(token_str)
+            .ok_or(crate::engine_like::AcceptTokenError::UnknownTokenStr)?;
+        self.try_accept_new_token(token_id)
+    }
+
+    fn try_accept_new_tokens(
+        &mut self,
+        token_ids: &[u32],
+    ) -> Result<AcceptTokenResult, (usize, crate::engine_like::AcceptTokenError)> {
+        let mut transaction = self.transaction();
+        let result = transaction.try_accept_new_tokens(token_ids)?;
+        transaction.commit();
+        Ok(result)
     }
 
     fn try_accept_new_bytes(
         &mut self,
         bytes: &[u8],
     ) -> Result<AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        self.accept_metrics = AcceptMetrics::default();
         if self.is_finished() {
-            return Err(crate::engine_like::AcceptTokenError::Finished);
+            return self.accept_after_finish();
         }
         let ptr = &mut self.column_to_postdot_nonterminals
             as *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>;
-        Self::accept_bytes(
+        let marker = self.accept_marker();
+        let result = Self::accept_bytes(
             &self.grammar,
             &mut self.earley_sets,
             &mut self.to_be_completed_items,
@@ -1679,30 +4442,110 @@ where
             &self.config,
             &mut self.finished,
             bytes.iter().copied(),
-        )
+            false,
+            false,
+            self.completion_hook
+                .hook_mut()
+                .map(|hook| (hook, &self.column_offsets[..])),
+            &mut self.uncompacted_bytes,
+            self.prefilter
+                .as_deref()
+                .map(|prefilter| (prefilter, &mut self.prefilter_state)),
+            self.collect_accept_metrics
+                .then_some(&mut self.accept_metrics),
+        );
+        match result {
+            Ok(result) => {
+                Self::update_column_offsets(
+                    &mut self.column_offsets,
+                    &self.earley_sets,
+                    marker.earley_sets_len,
+                    bytes.len(),
+                );
+                if self.track_accepted_bytes {
+                    self.accept_markers.push(marker);
+                    self.accepted_bytes.extend_from_slice(bytes);
+                }
+                self.last_rejection = None;
+                Ok(result)
+            }
+            Err((byte_index, error)) => {
+                if self.detailed_errors && error == crate::engine_like::AcceptTokenError::Rejected {
+                    self.record_rejection(bytes, byte_index, false);
+                }
+                Err(error)
+            }
+        }
     }
 
-    fn compute_allowed_token_ids(&mut self) {
-        self.allowed_token_ids.clear();
+    fn prefill(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        self.accept_metrics = AcceptMetrics::default();
         if self.is_finished() {
-            return;
+            return self.accept_after_finish();
         }
-        if self.config.cache_enabled {
-            if let Some(allowed_ids) = self.cache.get(&self.earley_sets) {
-                self.allowed_token_ids.union_with(allowed_ids);
-                return;
+        let Some((&last_byte, bytes_before)) = bytes.split_last() else {
+            return self.try_accept_new_bytes(bytes);
+        };
+        let marker = self.accept_marker();
+        let uncompacted_bytes = self.uncompacted_bytes;
+        // Each byte creates one Earley set, whose size is estimated by the last one.
+        let earley_set_len = self
+            .earley_sets
+            .view::<1, 1>([self.earley_sets.len() - 1])
+            .len();
+        self.earley_sets
+            .reserve([bytes.len(), bytes.len() * earley_set_len]);
+        let compaction_enabled = self.config.compaction_enabled;
+        let ptr = &mut self.column_to_postdot_nonterminals
+            as *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>;
+        // The bytes before the last one are accepted with the compaction suspended,
+        // so the columns of the postdot items are tracked only once afterwards.
+        let config = EngineConfig {
+            compaction_enabled: false,
+            ..self.config
+        };
+        let mut result = Self::accept_bytes(
+            &self.grammar,
+            &mut self.earley_sets,
+            &mut self.to_be_completed_items,
+            &mut self.to_be_completed_items_buffer,
+            &mut self.leo_items,
+            &mut self.leo_items_buffer,
+            &mut self.postdot_items,
+            &mut self.postdot_items_since_last_commit,
+            &mut self.already_predicted_nonterminals,
+            &mut self.deduplication_buffer,
+            ptr,
+            &config,
+            &mut self.finished,
+            bytes_before.iter().copied(),
+            false,
+            false,
+            self.completion_hook
+                .hook_mut()
+                .map(|hook| (hook, &self.column_offsets[..])),
+            &mut self.uncompacted_bytes,
+            self.prefilter
+                .as_deref()
+                .map(|prefilter| (prefilter, &mut self.prefilter_state)),
+            self.collect_accept_metrics
+                .then_some(&mut self.accept_metrics),
+        );
+        if result.is_ok() {
+            Self::update_column_offsets(
+                &mut self.column_offsets,
+                &self.earley_sets,
+                marker.earley_sets_len,
+                bytes_before.len(),
+            );
+            if compaction_enabled {
+                self.track_postdot_columns_since(marker.earley_sets_len);
+                self.uncompacted_bytes += bytes_before.len();
             }
-        }
-        let mut eager_cache = false;
-        if !self.grammar.regex_to_token_ids.is_empty() {
-            eager_cache = self.add_tokens_from_eager_regex_cache();
-        }
-        let original_earley_set_len = self.earley_sets.len();
-        self.update_allowed_first_bytes();
-        let mut invalid_next_bytes = ByteSet::with_capacity(256);
-        for byte in self.allowed_first_bytes.ones() {
-            invalid_next_bytes.clear();
-            Self::accept_byte(
+            result = Self::accept_bytes(
                 &self.grammar,
                 &mut self.earley_sets,
                 &mut self.to_be_completed_items,
@@ -1711,183 +4554,174 @@ where
                 &mut self.leo_items_buffer,
                 &mut self.postdot_items,
                 &mut self.postdot_items_since_last_commit,
-                |_| {},
-                |_| {},
                 &mut self.already_predicted_nonterminals,
                 &mut self.deduplication_buffer,
-                original_earley_set_len,
+                ptr,
+                &self.config,
                 &mut self.finished,
-                |_, _, _| {},
-                byte as u8,
+                std::iter::once(last_byte),
+                false,
+                false,
+                self.completion_hook
+                    .hook_mut()
+                    .map(|hook| (hook, &self.column_offsets[..])),
+                &mut self.uncompacted_bytes,
+                self.prefilter
+                    .as_deref()
+                    .map(|prefilter| (prefilter, &mut self.prefilter_state)),
+                self.collect_accept_metrics
+                    .then_some(&mut self.accept_metrics),
             )
-            .unwrap();
-            let mut staged_changes = StagedChanges {
-                earley_sets_len_since_last_commit: original_earley_set_len,
-                postdot_items_since_last_commit: self.postdot_items_since_last_commit.clone(),
-            };
-            let len = self.earley_sets.len();
-            Self::commit_change(&mut self.postdot_items_since_last_commit);
-            let mut current_token_id: usize = usize::MAX;
-            let mut token_iter = self.vocabulary.normal_tokens_from_first_byte(byte as u8);
-            let mut rejected = true;
-            let mut accepted = false;
-            let mut second_byte_unseen = false;
-            while let Some(token_byte) = token_iter.next() {
-                match token_byte {
-                    TokenIterItem::TokenByte(token_byte) => {
-                        let token_byte = token_byte.get();
-                        if second_byte_unseen
-                        // SAFETY: invalid_next_bytes preallocates 256 bytes on the stack
-                            && unsafe { invalid_next_bytes.contains_unchecked(token_byte.into()) }
-                        {
-                            rejected = true;
-                            token_iter.next_token();
-                            continue;
-                        }
-                        if Self::accept_byte(
-                            &self.grammar,
-                            &mut self.earley_sets,
-                            &mut self.to_be_completed_items,
-                            &mut self.to_be_completed_items_buffer,
-                            &mut self.leo_items,
-                            &mut self.leo_items_buffer,
-                            &mut self.postdot_items,
-                            &mut self.postdot_items_since_last_commit,
-                            |_| {},
-                            |_| {},
-                            &mut self.already_predicted_nonterminals,
-                            &mut self.deduplication_buffer,
-                            len,
-                            &mut self.finished,
-                            |_, _, _| {},
-                            token_byte,
-                        )
-                        .is_err()
-                        // The token is rejected
-                        {
-                            if second_byte_unseen {
-                                // SAFETY: invalid_next_bytes preallocates 256 bytes on the stack
-                                unsafe { invalid_next_bytes.insert_unchecked(token_byte.into()) };
-                            }
-                            rejected = true;
-                            token_iter.next_token();
-                        }
-                        second_byte_unseen = false;
-                    }
-                    TokenIterItem::NewToken => {
-                        // The token is accepted
-                        second_byte_unseen = true;
-                        if !accepted && !rejected {
-                            Self::revert_change(
-                                &mut self.earley_sets,
-                                &mut self.postdot_items,
-                                &mut self.postdot_items_since_last_commit,
-                                &mut self.leo_items,
-                                |_| {},
-                                len,
-                                &mut self.finished,
-                            );
-                            self.allowed_token_ids.insert(current_token_id);
-                        }
-                        current_token_id = token_iter.current_token_id();
-                        rejected = false;
-                        accepted = eager_cache && self.allowed_token_ids.contains(current_token_id);
-                        if accepted {
-                            token_iter.next_token();
-                        }
-                    }
+            .map_err(|(_, error)| (bytes_before.len(), error));
+        }
+        match result {
+            Ok(result) => {
+                Self::update_column_offsets(
+                    &mut self.column_offsets,
+                    &self.earley_sets,
+                    marker.earley_sets_len + bytes_before.len(),
+                    1,
+                );
+                if self.track_accepted_bytes {
+                    self.accept_markers.push(marker);
+                    self.accepted_bytes.extend_from_slice(bytes);
                 }
+                self.last_rejection = None;
+                Ok(result)
             }
-            // reach the end of the token iterator, revert the last token's change
-            Self::revert_change(
-                &mut self.earley_sets,
-                &mut self.postdot_items,
-                &mut self.postdot_items_since_last_commit,
-                &mut self.leo_items,
-                |_| {},
-                len,
-                &mut self.finished,
-            );
-            if !rejected && !accepted {
-                self.allowed_token_ids.insert(current_token_id);
-            }
-            Self::revert_change(
-                &mut self.earley_sets,
-                &mut self.postdot_items,
-                &mut staged_changes.postdot_items_since_last_commit,
-                &mut self.leo_items,
-                |_| {},
-                staged_changes.earley_sets_len_since_last_commit,
-                &mut self.finished,
-            )
-        }
-        for (token_id, token) in self.vocabulary.tokens_containing_separators() {
-            let mut accepted = true;
-            for byte in token.0.iter().copied() {
-                if Self::accept_byte(
-                    &self.grammar,
-                    &mut self.earley_sets,
-                    &mut self.to_be_completed_items,
-                    &mut self.to_be_completed_items_buffer,
-                    &mut self.leo_items,
-                    &mut self.leo_items_buffer,
-                    &mut self.postdot_items,
-                    &mut self.postdot_items_since_last_commit,
-                    |_| {},
-                    |_| {},
-                    &mut self.already_predicted_nonterminals,
-                    &mut self.deduplication_buffer,
-                    original_earley_set_len,
-                    &mut self.finished,
-                    |_, _, _| {},
-                    byte,
-                )
-                .is_err()
-                // The token is rejected
-                {
-                    accepted = false;
-                    break;
+            Err((byte_index, error)) => {
+                // The rejected byte is never compacted, so the states can be truncated.
+                self.truncate_to_marker(marker);
+                self.uncompacted_bytes = uncompacted_bytes;
+                if self.detailed_errors && error == crate::engine_like::AcceptTokenError::Rejected {
+                    self.record_rejection(bytes, byte_index, false);
                 }
+                Err(error)
             }
-            if accepted {
-                self.allowed_token_ids.insert(token_id as usize);
-                Self::revert_change(
-                    &mut self.earley_sets,
-                    &mut self.postdot_items,
-                    &mut self.postdot_items_since_last_commit,
-                    &mut self.leo_items,
-                    |_| {},
-                    original_earley_set_len,
-                    &mut self.finished,
-                );
+        }
+    }
+
+    fn try_accept_new_str(
+        &mut self,
+        s: &str,
+    ) -> Result<AcceptTokenResult, crate::engine_like::AcceptStrError> {
+        if self.is_finished() {
+            return self
+                .accept_after_finish()
+                .map_err(|_| crate::engine_like::AcceptStrError::Finished);
+        }
+        self.try_accept_new_bytes(s.as_bytes()).map_err(|e| {
+            if e == crate::engine_like::AcceptTokenError::ResourceLimitExceeded {
+                return crate::engine_like::AcceptStrError::ResourceLimitExceeded;
             }
+            // The rejected bytes are fed again character by character to locate the rejection,
+            // which only costs time on the error path.
+            let mut engine = self.clone_without_cache();
+            let mut buffer = [0; 4];
+            let char_index = s
+                .chars()
+                .position(|c| {
+                    engine
+                        .try_accept_new_bytes(c.encode_utf8(&mut buffer).as_bytes())
+                        .is_err()
+                })
+                .unwrap_or(0);
+            crate::engine_like::AcceptStrError::Rejected { char_index }
+        })
+    }
+
+    fn compute_allowed_token_ids(&mut self) {
+        self.compute_allowed_token_ids_before(None);
+    }
+
+    fn compute_allowed_token_ids_deadline(&mut self, deadline: Instant) -> ComputeOutcome {
+        self.compute_allowed_token_ids_before(Some(deadline))
+    }
+
+    fn allowed_token_ids_until(&mut self, limit: usize) -> Vec<u32> {
+        if limit == 0 {
+            return Vec::new();
         }
-        Self::commit_change(&mut self.postdot_items_since_last_commit);
-        if self.config.cache_enabled {
-            self.cache
-                .insert(self.earley_sets.clone(), self.allowed_token_ids.clone());
+        if self.is_finished() {
+            return self
+                .eos_token_id
+                .filter(|id| !self.ignored_token_ids.contains(id))
+                .into_iter()
+                .collect();
+        }
+        match self
+            .eos_token_id
+            .filter(|id| self.matches_empty() && !self.ignored_token_ids.contains(id))
+        {
+            Some(eos_token_id) => {
+                let mut allowed_token_ids = self.allowed_token_ids_except_eos_until(limit - 1);
+                let index = allowed_token_ids.partition_point(|&x| x < eos_token_id);
+                allowed_token_ids.insert(index, eos_token_id);
+                allowed_token_ids
+            }
+            None => self.allowed_token_ids_except_eos_until(limit),
         }
     }
 
+    fn any_allowed_token(&mut self) -> Option<u32> {
+        self.allowed_token_ids_until(1).first().copied()
+    }
+
     fn mask_logits(&self, logits: &mut [f32]) -> Result<(), crate::engine_like::MaskLogitsError> {
-        let vocab_size = self.vocabulary.vocab_size();
+        // The capacity of self.allowed_token_ids == vocab_size, which avoids scanning the vocabulary.
+        let vocab_size = self.allowed_token_ids.len();
         let logits_len = logits.len();
         if logits_len < vocab_size {
             return Err(crate::engine_like::MaskLogitsError::InvalidLogitsLength);
         }
         if self.allowed_token_ids.count_zeroes(..) > logits_len / 2 {
-            let mut mask = vec![f32::NEG_INFINITY; logits_len];
-            for token_id in self.allowed_token_ids.ones() {
-                // SAFETY: the capacity of self.allowed_token_ids == vocab_size and we have checked logits_len >= vocab_size
-                unsafe { *mask.get_unchecked_mut(token_id) = *logits.get_unchecked(token_id) };
+            // The logits beyond the vocabulary are masked as well when most tokens are disallowed.
+            logits[vocab_size..].fill(f32::NEG_INFINITY);
+        }
+        // Each block is either skipped when all its tokens are allowed, filled when none of them are allowed,
+        // or masked bit by bit without branches so the compiler can vectorize the loop.
+        const BLOCK_BITS: usize = fixedbitset_stack::Block::BITS as usize;
+        for (block, logits) in self
+            .allowed_token_ids
+            .as_slice()
+            .iter()
+            .copied()
+            .zip(logits[..vocab_size].chunks_mut(BLOCK_BITS))
+        {
+            match block {
+                fixedbitset_stack::Block::MAX => {}
+                0 => logits.fill(f32::NEG_INFINITY),
+                _ => {
+                    for (i, logit) in logits.iter_mut().enumerate() {
+                        if (block >> i) & 1 == 0 {
+                            *logit = f32::NEG_INFINITY;
+                        }
+                    }
+                }
             }
-            logits.copy_from_slice(&mask);
-        } else {
-            for token_id in self.allowed_token_ids.zeroes() {
-                // SAFETY: the capacity of self.allowed_token_ids == vocab_size and we have checked logits_len >= vocab_size
-                unsafe { *logits.get_unchecked_mut(token_id) = f32::NEG_INFINITY };
+        }
+        Ok(())
+    }
+
+    fn mask_logits_buffer(
+        &self,
+        logits: &mut dyn crate::engine_like::LogitsMut,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        if let Some(logits) = logits.as_mut_slice() {
+            return self.mask_logits(logits);
+        }
+        let vocab_size = self.allowed_token_ids.len();
+        if logits.len() < vocab_size {
+            return Err(crate::engine_like::MaskLogitsError::InvalidLogitsLength);
+        }
+        if self.allowed_token_ids.count_zeroes(..) > logits.len() / 2 {
+            for i in vocab_size..logits.len() {
+                logits.set_neg_infinity(i);
             }
         }
+        for i in self.allowed_token_ids.zeroes() {
+            logits.set_neg_infinity(i);
+        }
         Ok(())
     }
 
@@ -1903,13 +4737,27 @@ where
             crate::engine_like::AcceptTokenError::UnknownTokenID => {
                 crate::engine_like::UpdateLogitsError::UnknownTokenID
             }
+            crate::engine_like::AcceptTokenError::UnknownTokenStr => {
+                crate::engine_like::UpdateLogitsError::UnknownTokenStr
+            }
             crate::engine_like::AcceptTokenError::Rejected => {
                 crate::engine_like::UpdateLogitsError::Rejected
             }
+            crate::engine_like::AcceptTokenError::ResourceLimitExceeded => {
+                crate::engine_like::UpdateLogitsError::ResourceLimitExceeded
+            }
+            crate::engine_like::AcceptTokenError::AcceptedBytesNotTracked => {
+                unreachable!("Accepting a token never replays the accepted bytes.")
+            }
         })?;
-        if AcceptTokenResult::Finished == result {
+        // The engine that is not terminal keeps being fed after finishing, so its logits are masked as well.
+        if AcceptTokenResult::Finished == result
+            && self.finished_is_terminal
+            && !(self.mask_after_finish && self.eos_token_id.is_some())
+        {
             return Ok(crate::engine_like::AcceptTokenResult::Finished);
         }
+        // When the engine is finished, only the EOS token is allowed, or no token without the EOS token ID.
         self.compute_allowed_token_ids();
         self.mask_logits(logits).map_err(|e| match e {
             crate::engine_like::MaskLogitsError::InvalidLogitsLength => {
@@ -1919,10 +4767,34 @@ where
         Ok(result)
     }
 
+    fn update_logits_for_token_str(
+        &mut self,
+        token_str: &str,
+        logits: &mut [f32],
+    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::UpdateLogitsError> {
+        let token_id = self
+            .vocabulary
+            .token_id_from_str(token_str)
+            .ok_or(crate::engine_like::UpdateLogitsError::UnknownTokenStr)?;
+        self.update_logits(token_id, logits)
+    }
+
     fn allowed_token_ids_from_last_computation(&self) -> &FixedBitSet {
         &self.allowed_token_ids
     }
 
+    fn allowed_token_ids_snapshot(&self) -> crate::engine_like::AllowedTokens {
+        crate::engine_like::AllowedTokens::new(self.allowed_token_ids.clone())
+    }
+
+    fn allowed_token_count(&self) -> usize {
+        self.allowed_token_ids.count_ones(..)
+    }
+
+    fn disallowed_token_count(&self) -> usize {
+        self.allowed_token_ids.count_zeroes(..)
+    }
+
     fn write_disallowed_token_ids_to_buffer(
         &self,
         buffer: &mut [usize],
@@ -1949,44 +4821,505 @@ where
         Ok(())
     }
 
+    fn allowed_token_ids_as_ranges(&self) -> Vec<(u32, u32)> {
+        utils::get_ranges_from_bitset(&self.allowed_token_ids)
+    }
+
+    fn bias_logits_by_grammar_prior(
+        &self,
+        logits: &mut [f32],
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        let vocab_size = self.vocabulary.vocab_size();
+        if logits.len() < vocab_size {
+            return Err(crate::engine_like::MaskLogitsError::InvalidLogitsLength);
+        }
+        if !self.grammar.has_priors() || self.is_finished() {
+            return Ok(());
+        }
+        let earley_set_index = self.earley_sets.len() - 1;
+        let column: TSP = earley_set_index.as_();
+        let mut visited_nonterminals = FixedBitSet::with_capacity(self.grammar.nonterminals_size());
+        let mut probabilities: Vec<f32> = Vec::new();
+        // The allowed token IDs depend on the state of the prefilter, which is not cached.
+        let cache_enabled = self.config.cache_enabled && self.prefilter.is_none();
+        for item in self
+            .earley_sets
+            .view::<1, 1>([earley_set_index])
+            .as_slice()
+            .iter()
+        {
+            if item.start_position != column || item.dot_position != TD::ZERO {
+                continue;
+            }
+            let Some(prior) = self.grammar.nonterminal_prior(item.nonterminal_id) else {
+                continue;
+            };
+            if visited_nonterminals.put(item.nonterminal_id.0.as_()) {
+                continue;
+            }
+            let mut engine = self.restrict_to_nonterminal_at_last_earley_set(item.nonterminal_id);
+            if probabilities.is_empty() {
+                probabilities = vec![0.0; vocab_size];
+            }
+            let key = cache_enabled.then(|| (item.nonterminal_id, engine.earley_sets.clone()));
+            if let Some(key) = &key {
+                let mut prior_cache = self
+                    .prior_cache
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if let Some(allowed_token_ids) = prior_cache.get(key) {
+                    for token_id in allowed_token_ids.ones() {
+                        probabilities[token_id] += prior;
+                    }
+                    continue;
+                }
+            }
+            engine.compute_allowed_token_ids();
+            for token_id in engine.allowed_token_ids.ones() {
+                probabilities[token_id] += prior;
+            }
+            if let Some(key) = key {
+                self.prior_cache
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(key, engine.allowed_token_ids);
+            }
+        }
+        for (logit, probability) in logits.iter_mut().zip(probabilities) {
+            if probability > 0.0 {
+                *logit += probability.ln();
+            }
+        }
+        Ok(())
+    }
+
+    fn write_mask_to_buffer(&self, buffer: &mut [f32]) -> Result<(), WriteBufferError> {
+        if buffer.len() < self.vocabulary.vocab_size() {
+            return Err(WriteBufferError::BufferTooSmall);
+        }
+        buffer.fill(f32::NEG_INFINITY);
+        for token_id in self.allowed_token_ids.ones() {
+            // SAFETY: the capacity of self.allowed_token_ids == vocab_size and we have checked buffer.len() >= vocab_size
+            unsafe { *buffer.get_unchecked_mut(token_id) = 0.0 };
+        }
+        Ok(())
+    }
+
+    fn allowed_first_bytes(&mut self) -> &ByteSet {
+        if self.is_finished() {
+            self.allowed_first_bytes.clear();
+        } else {
+            self.update_allowed_first_bytes();
+        }
+        &self.allowed_first_bytes
+    }
+
+    fn fast_forward(&mut self) -> Vec<u8> {
+        let mut forced_bytes = Vec::new();
+        while !self.is_finished() {
+            self.update_allowed_first_bytes();
+            if self.allowed_first_bytes.count_ones(..) != 1 {
+                break;
+            }
+            // SAFETY: we have checked that exactly one byte is allowed
+            let byte = unsafe { self.allowed_first_bytes.ones().next().unwrap_unchecked() } as u8;
+            if self.try_accept_new_bytes(&[byte]).is_err() {
+                break;
+            }
+            forced_bytes.push(byte);
+        }
+        forced_bytes
+    }
+
+    fn shortest_completion(&self, max_len: usize) -> Option<Vec<u8>> {
+        if self.can_finish() {
+            return Some(Vec::new());
+        }
+        let mut engine = self.clone_without_cache();
+        // Different bytes often lead to the same Earley sets, e.g. in regexes like `[a-z]{10}`,
+        // so the states already reached by a shorter or smaller completion are skipped.
+        let mut visited = AHashSet::default();
+        visited.insert((self.earley_sets.clone(), self.prefilter_state));
+        let mut frontier = vec![(self.checkpoint(), Vec::new())];
+        for _ in 0..max_len {
+            let mut next_frontier = Vec::new();
+            for (checkpoint, completion) in frontier {
+                // SAFETY: the checkpoints are created by the engine itself
+                unsafe { engine.restore(&checkpoint).unwrap_unchecked() };
+                engine.update_allowed_first_bytes();
+                let first_bytes = engine.allowed_first_bytes.clone();
+                for byte in first_bytes.ones() {
+                    // SAFETY: the checkpoints are created by the engine itself
+                    unsafe { engine.restore(&checkpoint).unwrap_unchecked() };
+                    if engine.try_accept_new_bytes(&[byte as u8]).is_err() {
+                        continue;
+                    }
+                    let mut completion = completion.clone();
+                    completion.push(byte as u8);
+                    if engine.can_finish() {
+                        return Some(completion);
+                    }
+                    if visited.insert((engine.earley_sets.clone(), engine.prefilter_state)) {
+                        next_frontier.push((engine.checkpoint(), completion));
+                    }
+                }
+            }
+            if next_frontier.is_empty() {
+                return None;
+            }
+            frontier = next_frontier;
+        }
+        None
+    }
+
+    fn parse_tree(&self) -> Option<crate::engine_like::ParseNode> {
+        self.derive_consumed_bytes(&mut self.derivation_memo())
+    }
+
+    fn captures(&self) -> AHashMap<String, Vec<u8>> {
+        let mut captures = AHashMap::default();
+        if !self.grammar.has_capture_regexes() {
+            return captures;
+        }
+        let mut memo = self.derivation_memo();
+        if self.derive_consumed_bytes(&mut memo).is_none() {
+            return captures;
+        }
+        for &(regex_id, start, end) in &memo.capture_spans {
+            let Some(regex) = self
+                .grammar
+                .capture_regex(crate::grammar::RegexID(regex_id.as_()))
+            else {
+                continue;
+            };
+            // The later matches of the same name overwrite the earlier ones.
+            for (name, bytes) in regex.named_groups(&memo.bytes[start..end]) {
+                captures.insert(name.to_string(), bytes.to_vec());
+            }
+        }
+        captures
+    }
+
+    fn last_rejection(&self) -> Option<&crate::engine_like::RejectionDetail> {
+        self.last_rejection.as_ref()
+    }
+
+    fn last_accept_metrics(&self) -> AcceptMetrics {
+        self.accept_metrics
+    }
+
+    fn explain_rejection(&self, token_id: u32) -> Option<crate::engine_like::RejectionExplanation> {
+        if self.ignored_token_ids.contains(&token_id) || Some(token_id) == self.eos_token_id {
+            return None;
+        }
+        let token = self.vocabulary.token(token_id)?;
+        let byte_index = if self.is_finished() {
+            0
+        } else {
+            let mut engine = self.clone_without_cache();
+            let ptr = &mut engine.column_to_postdot_nonterminals
+                as *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>;
+            match Self::accept_bytes(
+                &engine.grammar,
+                &mut engine.earley_sets,
+                &mut engine.to_be_completed_items,
+                &mut engine.to_be_completed_items_buffer,
+                &mut engine.leo_items,
+                &mut engine.leo_items_buffer,
+                &mut engine.postdot_items,
+                &mut engine.postdot_items_since_last_commit,
+                &mut engine.already_predicted_nonterminals,
+                &mut engine.deduplication_buffer,
+                ptr,
+                &engine.config,
+                &mut engine.finished,
+                token.0.iter().copied(),
+                true,
+                false,
+                None,
+                &mut engine.uncompacted_bytes,
+                engine
+                    .prefilter
+                    .as_deref()
+                    .map(|prefilter| (prefilter, &mut engine.prefilter_state)),
+                None,
+            ) {
+                Ok(_) => return None,
+                Err((byte_index, _)) => byte_index,
+            }
+        };
+        let rejected_byte = *token.0.get(byte_index)?;
+        let engine = self.accept_rejected_prefix(&token.0, byte_index, true);
+        let earley_set = engine
+            .earley_sets
+            .view::<1, 1>([engine.earley_sets.len() - 1])
+            .as_slice();
+        let mut expectations: Vec<crate::engine_like::NodeExpectation> = Vec::new();
+        for item in earley_set.iter().copied() {
+            let mut expected_bytes = ByteSet::with_capacity(256);
+            Self::add_first_bytes_of_item(&engine.grammar, item, &mut expected_bytes);
+            if expected_bytes.is_clear() {
+                continue;
+            }
+            let expectation = crate::engine_like::NodeExpectation {
+                nonterminal: engine
+                    .grammar
+                    .nonterminal_str(item.nonterminal_id)
+                    .unwrap_or_default()
+                    .to_string(),
+                node: engine
+                    .grammar
+                    .node(
+                        item.nonterminal_id,
+                        item.dot_position,
+                        item.production_index,
+                    )
+                    .to_display_form(&engine.grammar),
+                expected_bytes,
+            };
+            if !expectations.contains(&expectation) {
+                expectations.push(expectation);
+            }
+        }
+        Some(crate::engine_like::RejectionExplanation {
+            byte_index,
+            rejected_byte,
+            expectations,
+        })
+    }
+
+    fn in_progress_items(&self) -> Vec<crate::engine_like::InProgressItem> {
+        use crate::engine_like::NodeKind;
+        let earley_set = self
+            .earley_sets
+            .view::<1, 1>([self.earley_sets.len() - 1])
+            .as_slice();
+        let mut items: Vec<crate::engine_like::InProgressItem> = Vec::new();
+        for item in earley_set.iter().copied() {
+            let node = *self.grammar.node(
+                item.nonterminal_id,
+                item.dot_position,
+                item.production_index,
+            );
+            let kind = match node {
+                HIRNode::Terminal(_) => NodeKind::Terminal,
+                HIRNode::RegexString(_) => NodeKind::Regex,
+                HIRNode::EarlyEndRegexString(_) => NodeKind::EarlyEndRegex,
+                HIRNode::Substrings(_) => NodeKind::Substrings,
+                HIRNode::RegexComplement(_) => NodeKind::RegexComplement,
+                HIRNode::LengthPrefixed(_) => NodeKind::LengthPrefixed,
+                HIRNode::ByteSetComplement(_) => NodeKind::ByteSetComplement,
+                HIRNode::AnyChar => NodeKind::AnyChar,
+                HIRNode::BoundedSubstrings(_) => NodeKind::BoundedSubstrings,
+                HIRNode::Json => NodeKind::Json,
+                HIRNode::Nonterminal(_) => continue,
+            };
+            // The node has not matched any bytes in its initial state.
+            if item.state_id == Self::initialize_state_id_based_on_node(&self.grammar, node) {
+                continue;
+            }
+            let in_progress = crate::engine_like::InProgressItem {
+                nonterminal: self
+                    .grammar
+                    .nonterminal_str(item.nonterminal_id)
+                    .unwrap_or_default()
+                    .to_string(),
+                node: node.to_display_form(&self.grammar),
+                kind,
+                state_id: item.state_id.as_(),
+            };
+            if !items.contains(&in_progress) {
+                items.push(in_progress);
+            }
+        }
+        items
+    }
+
+    fn can_finish(&self) -> bool {
+        self.is_finished() || self.matches_empty() || self.can_finish_with_partial_regexes()
+    }
+
     fn is_finished(&self) -> bool {
         self.finished
     }
 
+    fn is_dead(&self) -> bool {
+        Self::is_rejected(&self.earley_sets, &self.to_be_completed_items)
+    }
+
+    fn transaction(&mut self) -> crate::engine_like::EngineTransaction<'_> {
+        crate::engine_like::EngineTransaction::new(self)
+    }
+
+    fn try_accept_new_token_speculative(
+        &mut self,
+        token_id: u32,
+    ) -> Result<crate::engine_like::SpeculativeToken<'_>, crate::engine_like::AcceptTokenError>
+    {
+        crate::engine_like::SpeculativeToken::new(self, token_id)
+    }
+
     fn reset(&mut self) {
-        self.earley_sets.clear();
-        self.to_be_completed_items.clear();
-        self.to_be_completed_items_buffer.clear();
-        self.leo_items.clear();
-        self.leo_items_buffer.clear();
-        self.postdot_items.clear();
-        self.postdot_items_since_last_commit.clear();
-        self.deduplication_buffer.clear();
-        self.column_to_postdot_nonterminals.clear();
-        self.already_predicted_nonterminals.clear();
-        self.finished = false;
-        self.allowed_token_ids.clear();
-        self.allowed_first_bytes.clear();
-        self.earley_sets.new_row::<0>();
-        Self::predict_nonterminal(
-            &self.grammar,
-            &mut self.earley_sets,
-            &mut self.already_predicted_nonterminals,
-            self.grammar.get_start_nonterminal_id(),
-            0,
-        ); // init the first Earley set
-        Self::predict(
-            &self.grammar,
-            &mut self.earley_sets,
-            &mut self.already_predicted_nonterminals,
-        ); // run a full prediction for the first earley set
-        Self::update_postdot_items(
-            &self.grammar,
-            &mut self.earley_sets,
-            &mut self.postdot_items,
-            &mut AHashSet::default(), // We will never need to revert the engine's state since it is the initialization
-            |_| {},                   // column zero should never be removed
-        );
+        self.accepted_bytes.clear();
+        self.rewind();
+    }
+
+    fn clone_fresh(&self) -> Self {
+        let mut engine = Self {
+            vocabulary: self.vocabulary.clone(),
+            grammar: self.grammar.clone(),
+            allowed_first_bytes: ByteSet::with_capacity(256),
+            allowed_token_ids: FixedBitSet::with_capacity(self.vocabulary.vocab_size()),
+            earley_sets: JaggedArray::new(),
+            cache: self.cache.clone(),
+            prior_cache: self.prior_cache.clone(),
+            to_be_completed_items: utils::new_hash_set(self.config.deterministic),
+            to_be_completed_items_buffer: utils::new_hash_set(self.config.deterministic),
+            deduplication_buffer: utils::new_hash_set(self.config.deterministic),
+            postdot_items: utils::new_hash_map(self.config.deterministic),
+            postdot_items_since_last_commit: utils::new_hash_set(self.config.deterministic),
+            column_to_postdot_nonterminals: utils::new_hash_map(self.config.deterministic),
+            leo_items: utils::new_hash_map(self.config.deterministic),
+            leo_items_buffer: Vec::new(),
+            already_predicted_nonterminals: FixedBitSet::with_capacity(
+                self.already_predicted_nonterminals.len(),
+            ),
+            finished: false,
+            accepted_bytes: Vec::new(),
+            accept_markers: Vec::new(),
+            track_accepted_bytes: self.track_accepted_bytes,
+            eos_token_id: self.eos_token_id,
+            ignored_token_ids: self.ignored_token_ids.clone(),
+            mask_after_finish: self.mask_after_finish,
+            finished_is_terminal: self.finished_is_terminal,
+            empty_match: self.empty_match,
+            detailed_errors: self.detailed_errors,
+            last_rejection: None,
+            collect_accept_metrics: self.collect_accept_metrics,
+            accept_metrics: AcceptMetrics::default(),
+            token_healing: self.token_healing,
+            prefilter: self.prefilter.clone(),
+            prefilter_state: StateID::ZERO,
+            completion_hook: CompletionHookSlot(None),
+            column_offsets: Vec::new(),
+            uncompacted_bytes: 0,
+            ambiguity_warnings: self.ambiguity_warnings.clone(),
+            shared_cache: self.shared_cache.clone(),
+            config: self.config,
+        };
+        engine.initialize_states();
+        engine
+    }
+
+    fn rewind(&mut self) {
+        self.initialize_states();
+    }
+
+    fn rollback(&mut self, n: usize) -> Result<(), crate::engine_like::RollbackError> {
+        if !self.track_accepted_bytes {
+            return Err(crate::engine_like::RollbackError::AcceptedBytesNotTracked);
+        }
+        if n > self.accept_markers.len() {
+            return Err(crate::engine_like::RollbackError::NotEnoughAcceptedTokens);
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        let index = self.accept_markers.len() - n;
+        let marker = self.accept_markers[index];
+        if !self.config.compaction_enabled {
+            // Without compaction, the Earley sets before the marker are never modified.
+            self.accept_markers.truncate(index);
+            self.truncate_to_marker(marker);
+            return Ok(());
+        }
+        // The compacted Earley sets cannot be truncated, so the remaining byte groups are accepted again.
+        let accepted_bytes = std::mem::take(&mut self.accepted_bytes);
+        let mut accept_markers = std::mem::take(&mut self.accept_markers);
+        accept_markers.truncate(index);
+        let completion_hook = self.completion_hook.0.take();
+        self.rewind();
+        // The bytes preserved by rewind are not reflected in the Earley sets.
+        let preserved_len = accept_markers
+            .first()
+            .map_or(marker.accepted_bytes_len, |x| x.accepted_bytes_len);
+        self.accepted_bytes
+            .extend_from_slice(&accepted_bytes[..preserved_len]);
+        for (i, m) in accept_markers.iter().enumerate() {
+            let end = accept_markers
+                .get(i + 1)
+                .map_or(marker.accepted_bytes_len, |x| x.accepted_bytes_len);
+            // The bytes have been accepted in the same order, so they are never rejected.
+            let _ = self.try_accept_new_bytes(&accepted_bytes[m.accepted_bytes_len..end]);
+        }
+        self.completion_hook.0 = completion_hook;
+        Ok(())
+    }
+
+    fn heal_last_token(
+        &mut self,
+    ) -> Result<crate::engine_like::HealResult, crate::engine_like::RollbackError> {
+        if !self.track_accepted_bytes {
+            return Err(crate::engine_like::RollbackError::AcceptedBytesNotTracked);
+        }
+        let marker = self
+            .accept_markers
+            .last()
+            .ok_or(crate::engine_like::RollbackError::NotEnoughAcceptedTokens)?;
+        let removed_bytes = self.accepted_bytes[marker.accepted_bytes_len..].to_vec();
+        self.rollback(1)?;
+        self.compute_allowed_token_ids();
+        let trie = self.vocabulary.token_trie();
+        let mut candidate_token_ids: Vec<u32> =
+            trie.find(&removed_bytes).map_or_else(Vec::new, |node| {
+                trie.subtree_token_ids(node)
+                    .iter()
+                    .copied()
+                    .filter(|&token_id| self.allowed_token_ids.contains(token_id as usize))
+                    .collect()
+            });
+        candidate_token_ids.sort_unstable();
+        if self.token_healing {
+            self.allowed_token_ids.clear();
+            for &token_id in &candidate_token_ids {
+                self.allowed_token_ids.insert(token_id as usize);
+            }
+        }
+        Ok(crate::engine_like::HealResult {
+            removed_bytes,
+            candidate_token_ids,
+        })
+    }
+
+    fn replay(&mut self) -> Result<(), crate::engine_like::AcceptTokenError> {
+        if !self.track_accepted_bytes {
+            return Err(crate::engine_like::AcceptTokenError::AcceptedBytesNotTracked);
+        }
+        self.rewind();
+        let accepted_bytes = std::mem::take(&mut self.accepted_bytes);
+        let result = self.try_accept_new_bytes(&accepted_bytes);
+        if result.is_err() {
+            // The rejected bytes may leave the compacted Earley sets partially updated
+            self.rewind();
+            self.accepted_bytes = accepted_bytes;
+        }
+        result.map(|_| ())
+    }
+
+    fn accepted_bytes(&self) -> &[u8] {
+        &self.accepted_bytes
+    }
+
+    fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.prior_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
     }
 
     fn into_boxed_engine(self) -> Box<dyn EngineLike> {
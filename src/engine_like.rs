@@ -2,6 +2,7 @@
 
 use std::sync::Arc;
 
+use ahash::AHashMap;
 use displaydoc::Display;
 use fixedbitset_stack::FixedBitSet;
 #[cfg(feature = "python")]
@@ -9,6 +10,7 @@ use pyo3::pyclass;
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
+use crate::utils::ByteSet;
 use crate::vocabulary::Vocabulary;
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -17,10 +19,132 @@ use crate::vocabulary::Vocabulary;
 pub enum AcceptTokenError {
     /// The input token id does not exist in the vocabulary of the [`EngineLike`].
     UnknownTokenID,
+    /// The input token string matches neither a token nor a token string in the vocabulary of the [`EngineLike`].
+    UnknownTokenStr,
     /// The input token id is rejected and the [`EngineLike`]'s internal states are not updated.
     Rejected,
     /// The [`EngineLike`] is finished, as defined by its grammar. No more tokens can be accepted.
+    /// It is not returned unless [`Config::finished_is_terminal`](crate::config::Config::finished_is_terminal) is set.
     Finished,
+    /// The Earley sets exceed the limits in the [`EngineConfig`](crate::engine::EngineConfig) and the [`EngineLike`]'s internal states are not updated.
+    ResourceLimitExceeded,
+    /// The accepted bytes are not recorded, so there are no bytes to replay.
+    /// It is only returned by [`EngineLike::replay`] when [`Config::track_accepted_bytes`](crate::config::Config::track_accepted_bytes) is unset.
+    AcceptedBytesNotTracked,
+}
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+/// Represents the error when an [`EngineLike`] tries to accept a string.
+pub enum AcceptStrError {
+    /// The string is rejected at the character with index {char_index} and the [`EngineLike`]'s internal states are not updated.
+    /// A rejection in the middle of a multi-byte character is reported at the index of that character.
+    Rejected {
+        /// The index of the first rejected character in the string, counted in characters rather than bytes.
+        char_index: usize,
+    },
+    /// The [`EngineLike`] is finished, as defined by its grammar. No more strings can be accepted.
+    Finished,
+    /// The Earley sets exceed the limits in the [`EngineConfig`](crate::engine::EngineConfig) and the [`EngineLike`]'s internal states are not updated.
+    ResourceLimitExceeded,
+}
+/// The detail of the bytes rejected by an [`EngineLike`], which is recorded when
+/// [`Config::detailed_errors`](crate::config::Config::detailed_errors) is set and retrieved by [`EngineLike::last_rejection`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RejectionDetail {
+    /// The index of the rejected byte in the token or the bytes.
+    pub byte_index: usize,
+    /// The bytes allowed in place of the rejected byte, i.e. after the bytes before it are accepted.
+    pub allowed_first_bytes: ByteSet,
+}
+/// A terminal-like node of the grammar, e.g. a terminal, a regex or substrings, that matched the bytes
+/// before the rejected byte, which is reported by [`EngineLike::explain_rejection`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodeExpectation {
+    /// The name of the nonterminal whose production contains the node.
+    pub nonterminal: String,
+    /// The display form of the node, e.g. `"abc"[0]` for the terminal with ID 0.
+    pub node: String,
+    /// The bytes the node expects in place of the rejected byte.
+    pub expected_bytes: ByteSet,
+}
+/// The explanation of why a token is rejected by an [`EngineLike`], created by [`EngineLike::explain_rejection`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RejectionExplanation {
+    /// The index of the rejected byte in the token.
+    pub byte_index: usize,
+    /// The rejected byte.
+    pub rejected_byte: u8,
+    /// The nodes that matched the bytes before the rejected byte, in the order of the Earley items.
+    pub expectations: Vec<NodeExpectation>,
+}
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+/// The kind of a terminal-like node of the grammar, reported in [`InProgressItem`].
+pub enum NodeKind {
+    /// The terminal, e.g. `"abc"`.
+    Terminal,
+    /// The regex, e.g. `#"[0-9]+"`.
+    Regex,
+    /// The early end regex, e.g. `#e"[0-9]+"`.
+    EarlyEndRegex,
+    /// The substrings, e.g. `#substrs"abc"`.
+    Substrings,
+    /// The regex complement, e.g. `#ex"abc"`.
+    RegexComplement,
+    /// The length-prefixed node, e.g. `#lenprefixed(#"[0-9]+:", #".*")`.
+    LengthPrefixed,
+    /// The byte set complement, which matches one character outside a set of ASCII characters.
+    ByteSetComplement,
+    /// The any-character node, i.e. `#char`.
+    AnyChar,
+    /// The bounded substrings, e.g. `#substrs(1,2)"abc"`.
+    BoundedSubstrings,
+    /// The JSON node, i.e. `#json`.
+    Json,
+}
+/// A terminal-like node that an Earley item is in the middle of, reported by [`EngineLike::in_progress_items`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InProgressItem {
+    /// The name of the nonterminal whose production contains the node.
+    pub nonterminal: String,
+    /// The display form of the node, e.g. `"abc"[0]` for the terminal with ID 0.
+    pub node: String,
+    /// The kind of the node.
+    pub kind: NodeKind,
+    /// The state of the node. For a terminal, it is the number of bytes of the terminal matched so far.
+    /// For the other kinds, it is the opaque ID of the state of the underlying automaton,
+    /// which only tells whether two items are in the same state.
+    pub state_id: usize,
+}
+/// The work done by the last accept of an [`EngineLike`], reported by [`EngineLike::last_accept_metrics`].
+///
+/// The numbers grow with the ambiguity of the grammar, since every way to match the bytes so far
+/// keeps its own Earley items; a grammar that is slow to accept tokens usually creates many more items per byte
+/// than the bytes it scans.
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct AcceptMetrics {
+    /// The number of bytes scanned, including the rejected byte.
+    pub bytes_scanned: usize,
+    /// The number of Earley items in the Earley sets created by the accepted bytes.
+    pub items_created: usize,
+    /// The number of Earley items completed.
+    pub completions: usize,
+    /// The number of Earley sets removed by the compaction.
+    pub earley_sets_compacted: usize,
+    /// Whether the allowed token IDs computed after the accept are found in the cache,
+    /// or `None` if they are not computed yet or the engine is finished.
+    pub cache_hit: Option<bool>,
+}
+/// The last token rolled back by [`EngineLike::heal_last_token`] and the tokens that may replace it.
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct HealResult {
+    /// The bytes of the rolled-back token or bytes.
+    pub removed_bytes: Vec<u8>,
+    /// The allowed token IDs after the rollback whose bytes start with the removed bytes in ascending order,
+    /// which include the rolled-back token itself.
+    pub candidate_token_ids: Vec<u32>,
 }
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -32,6 +156,14 @@ pub enum AcceptTokenResult {
     /// The [`EngineLike`] is finished and no more tokens can be accepted.
     Finished,
 }
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+/// Represents the outcome of [`EngineLike::compute_allowed_token_ids_deadline`].
+pub enum ComputeOutcome {
+    /// All the allowed token IDs are computed.
+    Complete,
+    /// The deadline passed before all the tokens were tested, so only the tested tokens may be allowed.
+    TimedOut,
+}
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
@@ -50,6 +182,19 @@ pub enum WriteBufferError {
     BufferTooSmall,
 }
 
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+/// Represents the error when an [`EngineLike`] tries to roll back the accepted tokens.
+pub enum RollbackError {
+    /// The number of tokens to roll back exceeds the number of tokens accepted since the last reset or rewind.
+    /// The [`EngineLike`]'s internal states are not updated in this case.
+    NotEnoughAcceptedTokens,
+    /// The accepted tokens are not recorded since [`Config::track_accepted_bytes`](crate::config::Config::track_accepted_bytes) is unset.
+    /// The [`EngineLike`]'s internal states are not updated in this case.
+    AcceptedBytesNotTracked,
+}
+
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
@@ -57,15 +202,473 @@ pub enum WriteBufferError {
 pub enum UpdateLogitsError {
     /// The input token id does not exist in the vocabulary of the [`EngineLike`].
     UnknownTokenID,
+    /// The input token string matches neither a token nor a token string in the vocabulary of the [`EngineLike`].
+    UnknownTokenStr,
     /// The input token id is rejected and the [`EngineLike`]'s internal states are not updated.
     Rejected,
     /// The [`EngineLike`] is finished, as defined by its grammar. No more tokens can be accepted.
     Finished,
     /// The input logits array is not of the expected length according to the vocabulary.
     InvalidLogitsLength,
+    /// The Earley sets exceed the limits in the [`EngineConfig`](crate::engine::EngineConfig) and the [`EngineLike`]'s internal states are not updated.
+    ResourceLimitExceeded,
 }
+/// A node of the derivation reconstructed by [`EngineLike::parse_tree`].
+///
+/// The spans are byte offsets into the bytes consumed by the current states,
+/// which are the bytes accepted since the last [`EngineLike::reset`] or [`EngineLike::rewind`].
+/// Terminals, regexes and other terminal-like nodes are not represented,
+/// so a node's children are only the nonterminals directly used by its production.
+///
+/// The tree follows the simplified grammar rather than the grammar string. The nonterminals with only one symbol
+/// in their definitions are inlined by the simplification unless they are annotated with `@preserve`,
+/// and the nonterminals created when desugaring the syntax extensions, like the ones for repetitions,
+/// appear in the tree with their generated names.
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ParseNode {
+    /// The name of the nonterminal.
+    pub nonterminal: String,
+    /// The byte offset where the nonterminal's match starts, inclusive.
+    pub start: usize,
+    /// The byte offset where the nonterminal's match ends, exclusive.
+    pub end: usize,
+    /// The nonterminals used by the production in order.
+    pub children: Vec<ParseNode>,
+}
+
+impl ParseNode {
+    /// Finds the first node of the nonterminal in the tree in depth-first order, including the node itself.
+    pub fn find(&self, nonterminal: &str) -> Option<&ParseNode> {
+        if self.nonterminal == nonterminal {
+            return Some(self);
+        }
+        self.children.iter().find_map(|x| x.find(nonterminal))
+    }
+    /// Finds all the nodes of the nonterminal in the tree in depth-first order, including the node itself.
+    pub fn find_all(&self, nonterminal: &str) -> Vec<&ParseNode> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            if node.nonterminal == nonterminal {
+                nodes.push(node);
+            }
+            stack.extend(node.children.iter().rev());
+        }
+        nodes
+    }
+}
+
+/// The owned snapshot of the allowed token IDs, created by [`EngineLike::allowed_token_ids_snapshot`].
+///
+/// Unlike [`EngineLike::allowed_token_ids_from_last_computation`], the snapshot does not borrow the engine,
+/// so the engine can accept tokens while the snapshot is iterated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AllowedTokens {
+    token_ids: FixedBitSet,
+}
+
+impl AllowedTokens {
+    pub(crate) fn new(token_ids: FixedBitSet) -> Self {
+        Self { token_ids }
+    }
+    /// Whether the token ID is allowed. Token IDs outside the vocabulary are never allowed.
+    pub fn contains(&self, token_id: u32) -> bool {
+        self.token_ids.contains(token_id as usize)
+    }
+    /// Returns the number of allowed token IDs.
+    pub fn len(&self) -> usize {
+        self.token_ids.count_ones(..)
+    }
+    /// Whether no token ID is allowed.
+    pub fn is_empty(&self) -> bool {
+        self.token_ids.is_clear()
+    }
+    /// Iterates over the allowed token IDs in ascending order.
+    pub fn iter(&self) -> std::iter::Map<fixedbitset_stack::Ones<'_>, fn(usize) -> u32> {
+        self.token_ids.ones().map(|x| x as u32)
+    }
+    /// Gets the allowed token IDs as a bitset indexed by token IDs.
+    pub fn as_bitset(&self) -> &FixedBitSet {
+        &self.token_ids
+    }
+}
+
+impl IntoIterator for AllowedTokens {
+    type Item = u32;
+    type IntoIter = std::iter::Map<fixedbitset_stack::IntoOnes, fn(usize) -> u32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.token_ids.into_ones().map(|x| x as u32)
+    }
+}
+
+impl<'a> IntoIterator for &'a AllowedTokens {
+    type Item = u32;
+    type IntoIter = std::iter::Map<fixedbitset_stack::Ones<'a>, fn(usize) -> u32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A mutable buffer of logits that [`EngineLike::mask_logits_buffer`] can mask,
+/// which allows masking strided or non-contiguous buffers without copying them into a slice.
+pub trait LogitsMut {
+    /// Returns the number of logits in the buffer.
+    fn len(&self) -> usize;
+    /// Returns whether the buffer has no logits.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Returns the logit at the index.
+    ///
+    /// # Panics
+    ///
+    /// May panic if the index is out of bounds.
+    fn get(&self, index: usize) -> f32;
+    /// Sets the logit at the index to negative infinity.
+    ///
+    /// # Panics
+    ///
+    /// May panic if the index is out of bounds.
+    fn set_neg_infinity(&mut self, index: usize);
+    /// Returns the buffer as a contiguous slice if possible, in which case the faster slice masking is used.
+    fn as_mut_slice(&mut self) -> Option<&mut [f32]> {
+        None
+    }
+}
+
+impl LogitsMut for [f32] {
+    fn len(&self) -> usize {
+        <[f32]>::len(self)
+    }
+    fn get(&self, index: usize) -> f32 {
+        self[index]
+    }
+    fn set_neg_infinity(&mut self, index: usize) {
+        self[index] = f32::NEG_INFINITY;
+    }
+    fn as_mut_slice(&mut self) -> Option<&mut [f32]> {
+        Some(self)
+    }
+}
+
+impl LogitsMut for Vec<f32> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+    fn get(&self, index: usize) -> f32 {
+        self[index]
+    }
+    fn set_neg_infinity(&mut self, index: usize) {
+        self[index] = f32::NEG_INFINITY;
+    }
+    fn as_mut_slice(&mut self) -> Option<&mut [f32]> {
+        Some(self)
+    }
+}
+
+impl<T: LogitsMut + ?Sized> LogitsMut for &mut T {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+    fn get(&self, index: usize) -> f32 {
+        (**self).get(index)
+    }
+    fn set_neg_infinity(&mut self, index: usize) {
+        (**self).set_neg_infinity(index)
+    }
+    fn as_mut_slice(&mut self) -> Option<&mut [f32]> {
+        (**self).as_mut_slice()
+    }
+}
+
 pub(crate) mod sealed {
-    pub trait Sealed {}
+    use fixedbitset_stack::FixedBitSet;
+
+    /// The states recorded when an [`EngineTransaction`](super::EngineTransaction) begins.
+    #[derive(Debug, Clone)]
+    pub struct TransactionCheckpoint {
+        pub(crate) earley_sets_len: usize,
+        pub(crate) finished: bool,
+        pub(crate) accepted_bytes_len: usize,
+        pub(crate) accept_markers_len: usize,
+        pub(crate) compaction_enabled: bool,
+        pub(crate) allowed_token_ids: FixedBitSet,
+        pub(crate) allowed_first_bytes: crate::utils::ByteSet,
+        pub(crate) prefilter_state: kbnf_regex_automata::util::primitives::StateID,
+    }
+
+    pub trait Sealed {
+        /// Records the current states and suspends the compaction until the transaction ends,
+        /// so the changes made in the transaction can be reverted by truncating the Earley sets.
+        fn begin_transaction(&mut self) -> TransactionCheckpoint;
+        /// Ends the transaction, reverting the engine to the checkpoint unless `commit` is true.
+        fn end_transaction(&mut self, checkpoint: TransactionCheckpoint, commit: bool);
+    }
+}
+
+/// A guard that accepts tokens or bytes tentatively.
+///
+/// The guard is created by [`EngineLike::transaction`]. The tokens and bytes accepted through the guard
+/// update the engine as usual, but the engine reverts to the states before the transaction
+/// when the guard is dropped, unless [`EngineTransaction::commit`] is called.
+///
+/// The compaction is suspended during the transaction, so a long transaction uses more memory.
+/// The cache entries added in the transaction are kept even if the transaction is reverted, since they are still valid.
+pub struct EngineTransaction<'a> {
+    engine: &'a mut dyn EngineLike,
+    checkpoint: Option<sealed::TransactionCheckpoint>,
+}
+
+impl<'a> EngineTransaction<'a> {
+    pub(crate) fn new(engine: &'a mut dyn EngineLike) -> Self {
+        let checkpoint = engine.begin_transaction();
+        Self {
+            engine,
+            checkpoint: Some(checkpoint),
+        }
+    }
+    /// Tries to accept a new token with the given token ID in the transaction.
+    ///
+    /// See [`EngineLike::try_accept_new_token`] for more details.
+    pub fn try_accept_new_token(
+        &mut self,
+        token_id: u32,
+    ) -> Result<AcceptTokenResult, AcceptTokenError> {
+        self.engine.try_accept_new_token(token_id)
+    }
+    /// Tries to accept the tokens with the given token IDs one by one in the transaction.
+    ///
+    /// See [`EngineLike::try_accept_new_tokens`] for more details.
+    ///
+    /// # Errors
+    ///
+    /// Returns the index of the rejected token and its [`AcceptTokenError`].
+    /// The tokens before the rejected one remain accepted in the transaction.
+    pub fn try_accept_new_tokens(
+        &mut self,
+        token_ids: &[u32],
+    ) -> Result<AcceptTokenResult, (usize, AcceptTokenError)> {
+        let mut result = if self.engine.is_finished() {
+            AcceptTokenResult::Finished
+        } else {
+            AcceptTokenResult::Ongoing
+        };
+        for (index, &token_id) in token_ids.iter().enumerate() {
+            result = self
+                .engine
+                .try_accept_new_token(token_id)
+                .map_err(|error| (index, error))?;
+        }
+        Ok(result)
+    }
+    /// Tries to accept new bytes in the transaction.
+    ///
+    /// See [`EngineLike::try_accept_new_bytes`] for more details.
+    pub fn try_accept_new_bytes(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<AcceptTokenResult, AcceptTokenError> {
+        self.engine.try_accept_new_bytes(bytes)
+    }
+    /// Computes the allowed token IDs based on current states in the transaction.
+    ///
+    /// The allowed token IDs computed before the transaction are restored if the transaction is reverted.
+    pub fn compute_allowed_token_ids(&mut self) {
+        self.engine.compute_allowed_token_ids();
+    }
+    /// Gets the engine with the changes made in the transaction so far.
+    pub fn engine(&self) -> &dyn EngineLike {
+        self.engine
+    }
+    /// Commits the transaction, keeping all the changes made in it.
+    pub fn commit(mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            self.engine.end_transaction(checkpoint, true);
+        }
+    }
+}
+
+impl Drop for EngineTransaction<'_> {
+    fn drop(&mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            self.engine.end_transaction(checkpoint, false);
+        }
+    }
+}
+
+impl std::fmt::Debug for EngineTransaction<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EngineTransaction")
+            .field("checkpoint", &self.checkpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A guard of a token accepted tentatively, e.g. a draft token in speculative decoding.
+///
+/// The guard is created by [`EngineLike::try_accept_new_token_speculative`].
+/// The token is reverted by [`SpeculativeToken::abort`] or when the guard is dropped,
+/// unless [`SpeculativeToken::commit`] is called. See [`EngineTransaction`] for the tradeoffs.
+#[derive(Debug)]
+pub struct SpeculativeToken<'a> {
+    transaction: EngineTransaction<'a>,
+    result: AcceptTokenResult,
+}
+
+impl<'a> SpeculativeToken<'a> {
+    pub(crate) fn new(
+        engine: &'a mut dyn EngineLike,
+        token_id: u32,
+    ) -> Result<Self, AcceptTokenError> {
+        let mut transaction = EngineTransaction::new(engine);
+        let result = transaction.try_accept_new_token(token_id)?;
+        Ok(Self {
+            transaction,
+            result,
+        })
+    }
+    /// Gets the result of accepting the token.
+    pub fn result(&self) -> AcceptTokenResult {
+        self.result
+    }
+    /// Gets the engine with the token accepted.
+    pub fn engine(&self) -> &dyn EngineLike {
+        self.transaction.engine()
+    }
+    /// Computes the allowed token IDs after the token.
+    ///
+    /// The allowed token IDs computed before the token are restored if the token is aborted.
+    pub fn compute_allowed_token_ids(&mut self) {
+        self.transaction.compute_allowed_token_ids();
+    }
+    /// Keeps the token accepted.
+    ///
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the token.
+    pub fn commit(self) -> AcceptTokenResult {
+        self.transaction.commit();
+        self.result
+    }
+    /// Reverts the engine to the states before the token was accepted.
+    pub fn abort(self) {}
+}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+/// Represents the result after [`StreamingAcceptor`] is fed with bytes.
+pub enum StreamResult {
+    /// All the buffered bytes are accepted by the [`EngineLike`].
+    Accepted,
+    /// The bytes are acceptable so far, but a trailing UTF-8 sequence is incomplete and held back until more bytes arrive.
+    NeedMore,
+    /// The bytes are rejected and discarded. The [`EngineLike`]'s internal states and the held back bytes are not updated.
+    Rejected,
+}
+
+/// A wrapper that feeds the bytes of a stream into an [`EngineLike`] as they arrive.
+///
+/// The bytes are accumulated and only committed to the engine once they end with a complete UTF-8 sequence,
+/// so the engine never stops in the middle of a character.
+/// The incomplete trailing sequence is held back, but it is still checked against the engine,
+/// so a byte that can never be accepted is rejected immediately rather than when the character completes.
+/// Bytes that are not part of a valid UTF-8 sequence are committed as they are.
+pub struct StreamingAcceptor<'a> {
+    engine: &'a mut dyn EngineLike,
+    pending: Vec<u8>,
+}
+
+impl<'a> StreamingAcceptor<'a> {
+    /// Creates a new [`StreamingAcceptor`] that feeds the bytes into the given engine.
+    pub fn new(engine: &'a mut dyn EngineLike) -> Self {
+        Self {
+            engine,
+            pending: Vec::new(),
+        }
+    }
+    /// Feeds new bytes of the stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes that arrive, which may start or end in the middle of a UTF-8 sequence.
+    ///
+    /// # Returns
+    ///
+    /// * [`StreamResult`] - Whether the bytes are committed, held back or rejected.
+    pub fn feed(&mut self, bytes: &[u8]) -> StreamResult {
+        let old_len = self.pending.len();
+        self.pending.extend_from_slice(bytes);
+        let complete_len = self.pending.len() - Self::incomplete_suffix_len(&self.pending);
+        let (complete, incomplete) = self.pending.split_at(complete_len);
+        let accepted = if incomplete.is_empty() {
+            complete.is_empty() || self.engine.try_accept_new_bytes(complete).is_ok()
+        } else {
+            // The incomplete suffix is only checked in a transaction, so the complete part is accepted again afterwards.
+            let mut transaction = self.engine.transaction();
+            let viable = (complete.is_empty()
+                || transaction.try_accept_new_bytes(complete).is_ok())
+                && transaction.try_accept_new_bytes(incomplete).is_ok();
+            drop(transaction);
+            viable && (complete.is_empty() || self.engine.try_accept_new_bytes(complete).is_ok())
+        };
+        if !accepted {
+            self.pending.truncate(old_len);
+            return StreamResult::Rejected;
+        }
+        self.pending.drain(..complete_len);
+        if self.pending.is_empty() {
+            StreamResult::Accepted
+        } else {
+            StreamResult::NeedMore
+        }
+    }
+    /// Commits the held back bytes as they are, e.g. when the stream ends in the middle of a UTF-8 sequence.
+    ///
+    /// # Returns
+    ///
+    /// * [`StreamResult`] - [`StreamResult::Accepted`] if the held back bytes are accepted or there is none,
+    ///   and [`StreamResult::Rejected`] otherwise, in which case the held back bytes are kept.
+    pub fn flush(&mut self) -> StreamResult {
+        if self.pending.is_empty() || self.engine.try_accept_new_bytes(&self.pending).is_ok() {
+            self.pending.clear();
+            StreamResult::Accepted
+        } else {
+            StreamResult::Rejected
+        }
+    }
+    /// Gets the bytes held back until their UTF-8 sequence completes.
+    pub fn pending_bytes(&self) -> &[u8] {
+        &self.pending
+    }
+    /// Gets the engine with the committed bytes.
+    pub fn engine(&self) -> &dyn EngineLike {
+        self.engine
+    }
+    /// The length of the trailing UTF-8 sequence that a valid leading byte starts but is not completed yet.
+    fn incomplete_suffix_len(bytes: &[u8]) -> usize {
+        for (len, &byte) in bytes.iter().rev().take(4).enumerate() {
+            let expected = match byte {
+                0x80..=0xBF => continue,
+                0xC2..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF4 => 4,
+                _ => return 0,
+            };
+            return if len + 1 < expected { len + 1 } else { 0 };
+        }
+        0
+    }
+}
+
+impl std::fmt::Debug for StreamingAcceptor<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamingAcceptor")
+            .field("pending", &self.pending)
+            .finish_non_exhaustive()
+    }
 }
 
 /// A trait that defines the behavior of an [`EngineLike`] object.
@@ -89,6 +692,51 @@ pub trait EngineLike: sealed::Sealed {
         token_id: u32,
     ) -> Result<AcceptTokenResult, AcceptTokenError>;
 
+    /// Tries to accept a new token given as its string, which is resolved to the token ID by
+    /// [`Vocabulary::token_id_from_str`] on the vocabulary of the [`EngineLike`].
+    ///
+    /// # Arguments
+    ///
+    /// * `token_str` - The string of the token to be accepted.
+    ///
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the token.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AcceptTokenError::UnknownTokenStr`] when the string is not a known token,
+    /// or the errors of [`EngineLike::try_accept_new_token`] otherwise.
+    /// The [`EngineLike`] internal states are not updated in this case.
+    fn try_accept_token_str(
+        &mut self,
+        token_str: &str,
+    ) -> Result<AcceptTokenResult, AcceptTokenError>;
+
+    /// Tries to accept a sequence of tokens atomically, e.g. the tokens committed together after verification.
+    ///
+    /// The tokens are accepted one by one as in [`EngineLike::try_accept_new_token`].
+    /// If any token is rejected, the engine reverts to the states before the first token,
+    /// including the allowed token IDs from the last computation.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_ids` - The IDs of the tokens to be accepted.
+    ///
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the last token,
+    ///   or the current result if `token_ids` is empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns the index of the rejected token in `token_ids` and its [`AcceptTokenError`].
+    /// The [`EngineLike`] internal states are not updated in this case.
+    fn try_accept_new_tokens(
+        &mut self,
+        token_ids: &[u32],
+    ) -> Result<AcceptTokenResult, (usize, AcceptTokenError)>;
+
     /// Tries to accept new bytes.
     ///
     /// # Arguments
@@ -105,9 +753,89 @@ pub trait EngineLike: sealed::Sealed {
     fn try_accept_new_bytes(&mut self, bytes: &[u8])
         -> Result<AcceptTokenResult, AcceptTokenError>;
 
+    /// Accepts new bytes that are expected to be valid, like a known prompt, faster than [`EngineLike::try_accept_new_bytes`].
+    ///
+    /// The Earley sets are reserved for all the bytes up front and the compaction is suspended until the last byte,
+    /// so the bookkeeping needed by the compaction is done only once.
+    /// The allowed token IDs are not computed during the prefill.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes to be accepted.
+    ///
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`AcceptTokenError`] when the bytes are not accepted. Check the error type docs for more details.
+    /// The whole prefill is reverted in this case.
+    fn prefill(&mut self, bytes: &[u8]) -> Result<AcceptTokenResult, AcceptTokenError>;
+
+    /// Tries to accept a new string, which is the same as accepting its UTF-8 bytes,
+    /// except that the rejection is reported in characters.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The string to be accepted.
+    ///
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the string.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`AcceptStrError`] when the string is not accepted. Check the error type docs for more details.
+    fn try_accept_new_str(&mut self, s: &str) -> Result<AcceptTokenResult, AcceptStrError>;
+
     /// Computes the allowed token IDs based on current states.
     fn compute_allowed_token_ids(&mut self);
 
+    /// Computes the allowed token IDs based on current states like [`EngineLike::compute_allowed_token_ids`],
+    /// but stops testing the tokens once `deadline` passes.
+    ///
+    /// The tokens not tested yet are disallowed, so an illegal token is never allowed while some legal tokens may be missed.
+    /// Such an incomplete result is not cached. The cached results are used as usual, which are always complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - The instant after which no more tokens are tested.
+    ///
+    /// # Returns
+    ///
+    /// * [`ComputeOutcome`] - Whether all the allowed token IDs are computed.
+    fn compute_allowed_token_ids_deadline(
+        &mut self,
+        deadline: std::time::Instant,
+    ) -> ComputeOutcome;
+
+    /// Computes the allowed token IDs based on current states, but stops as soon as `limit` tokens are found.
+    ///
+    /// This is much cheaper than [`EngineLike::compute_allowed_token_ids`] when only a few candidates are needed.
+    /// The allowed token IDs from the last computation are not updated,
+    /// and the partial results are not cached, while a computation that finds all the allowed tokens is cached as usual.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of allowed token IDs to find.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u32>` - At most `limit` allowed token IDs in ascending order,
+    ///   which are not necessarily the smallest allowed token IDs.
+    fn allowed_token_ids_until(&mut self, limit: usize) -> Vec<u32>;
+
+    /// Finds any allowed token ID based on current states, stopping at the first one found.
+    ///
+    /// See [`EngineLike::allowed_token_ids_until`] for more details.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(u32)` - An allowed token ID.
+    /// * `None` - If no token is allowed.
+    fn any_allowed_token(&mut self) -> Option<u32>;
+
     /// Masks the logits based on last computed token IDs.
     /// These token IDs can also be obtained from [`EngineLike::allowed_token_ids_from_last_computation`].
     ///
@@ -124,6 +852,21 @@ pub trait EngineLike: sealed::Sealed {
     /// The logits array is not updated in this case.
     fn mask_logits(&self, logits: &mut [f32]) -> Result<(), MaskLogitsError>;
 
+    /// Masks the logits in any [`LogitsMut`] buffer, e.g. a strided view of a larger array,
+    /// in the same way as [`EngineLike::mask_logits`].
+    ///
+    /// Buffers that can be viewed as a contiguous slice are masked by [`EngineLike::mask_logits`] directly.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - A mutable reference to the logits buffer to be masked.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the buffer is shorter than the vocabulary.
+    /// The buffer is not updated in this case.
+    fn mask_logits_buffer(&self, logits: &mut dyn LogitsMut) -> Result<(), MaskLogitsError>;
+
     /// Try to accept the token ID and if succeeds, update the given logits array.
     ///
     /// # Arguments
@@ -134,6 +877,11 @@ pub trait EngineLike: sealed::Sealed {
     /// # Returns
     ///
     /// * [`AcceptTokenResult`] - The result of accepting the token.
+    ///   When the result is [`AcceptTokenResult::Finished`], the logits array is not updated,
+    ///   unless [`Config::mask_after_finish`](crate::config::Config::mask_after_finish) and the EOS token ID are set,
+    ///   in which case all the logits except the EOS token's are masked.
+    ///   Without [`Config::finished_is_terminal`](crate::config::Config::finished_is_terminal), the logits are always masked
+    ///   except the EOS token's, and all of them are masked when the EOS token ID is not set.
     ///
     /// # Errors
     ///
@@ -146,11 +894,64 @@ pub trait EngineLike: sealed::Sealed {
         logits: &mut [f32],
     ) -> Result<AcceptTokenResult, UpdateLogitsError>;
 
+    /// Try to accept the token given as its string and if succeeds, update the given logits array.
+    /// The string is resolved to the token ID by [`Vocabulary::token_id_from_str`] on the vocabulary of the [`EngineLike`].
+    ///
+    /// # Arguments
+    ///
+    /// * `token_str` - The string of the token.
+    /// * `logits` - A mutable reference to the logits array to be updated.
+    ///
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the token. See [`EngineLike::update_logits`] for more details.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`UpdateLogitsError::UnknownTokenStr`] when the string is not a known token,
+    /// or the errors of [`EngineLike::update_logits`] otherwise.
+    /// The [`EngineLike`] internal states and the logits array are not updated in this case.
+    fn update_logits_for_token_str(
+        &mut self,
+        token_str: &str,
+        logits: &mut [f32],
+    ) -> Result<AcceptTokenResult, UpdateLogitsError>;
+
+    /// Adds the logarithm of the priors of the weighted alternatives(`"A" %0.9`) to the logits.
+    ///
+    /// Only the weighted alternatives that start at current states are considered.
+    /// A token consistent with some of them is biased by the logarithm of their priors' sum,
+    /// which is positive for a weight above 1 like `%2.0`, while other tokens are left unchanged. It does not mask the logits,
+    /// so it is usually used together with [`EngineLike::mask_logits`].
+    ///
+    /// The tokens consistent with each weighted alternative are computed like [`EngineLike::compute_allowed_token_ids`]
+    /// on a copy of the states restricted to the alternative, so every call may cost one pass over the vocabulary
+    /// per weighted alternative starting at current states. When
+    /// [`EngineConfig::cache_enabled`](crate::engine::EngineConfig::cache_enabled) is set, the passes are cached by the states
+    /// like the allowed token IDs and [`EngineLike::clear_cache`] clears them as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - A mutable reference to the logits array to be biased.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the input logits array is not of the expected length according to the vocabulary.
+    /// The logits array is not updated in this case.
+    fn bias_logits_by_grammar_prior(&self, logits: &mut [f32]) -> Result<(), MaskLogitsError>;
+
     /// Gets the allowed token IDs since last computation.
     /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
     ///
     /// In other words, [`EngineLike::try_accept_new_token`] DOES NOT compute the allowed token IDs and hence DOES NOT affect its result!
     fn allowed_token_ids_from_last_computation(&self) -> &FixedBitSet;
+    /// Gets an owned snapshot of the allowed token IDs since last computation,
+    /// which can be held while the engine accepts tokens.
+    fn allowed_token_ids_snapshot(&self) -> AllowedTokens;
+    /// Gets the number of allowed token IDs since last computation.
+    fn allowed_token_count(&self) -> usize;
+    /// Gets the number of disallowed token IDs since last computation.
+    fn disallowed_token_count(&self) -> usize;
     /// Write the disallowed token IDs to the given buffer.
     fn write_disallowed_token_ids_to_buffer(
         &self,
@@ -162,10 +963,230 @@ pub trait EngineLike: sealed::Sealed {
         &self,
         buffer: &mut [usize],
     ) -> Result<(), WriteBufferError>;
+
+    /// Write the additive mask based on last computed token IDs to the given buffer.
+    /// Allowed token IDs are written as `0.0` and other entries are written as negative infinity,
+    /// so the mask can be added to the logits directly.
+    ///
+    /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteBufferError`] when the buffer is smaller than the vocabulary size.
+    /// The buffer is not updated in this case.
+    fn write_mask_to_buffer(&self, buffer: &mut [f32]) -> Result<(), WriteBufferError>;
+    /// Gets the allowed token IDs since last computation as sorted, disjoint `(start, end)` ranges,
+    /// where `start` is inclusive and `end` is exclusive.
+    ///
+    /// Consecutive allowed token IDs are coalesced into one range, so this is usually much more compact
+    /// than a list of token IDs when most of the vocabulary is allowed.
+    ///
+    /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
+    fn allowed_token_ids_as_ranges(&self) -> Vec<(u32, u32)>;
+    /// Gets the bytes allowed as the next byte at current states.
+    ///
+    /// This is computed from the current states on every call,
+    /// which is much cheaper than [`EngineLike::compute_allowed_token_ids`] when only byte granularity is needed,
+    /// like sampling raw UTF-8 bytes. The set is empty if the engine is finished.
+    fn allowed_first_bytes(&mut self) -> &ByteSet;
+    /// Accepts the bytes forced by the grammar until a branch point is reached or the engine is finished.
+    ///
+    /// A byte is forced when it is the only byte allowed by the current states.
+    /// The forced bytes do not necessarily align with the tokens in the vocabulary,
+    /// so it is up to the caller to map them to tokens or emit them directly.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The accepted forced bytes. It is empty if the current states allow zero or more than one byte.
+    fn fast_forward(&mut self) -> Vec<u8>;
+    /// Finds the shortest bytes that would let the engine finish from current states without modifying them.
+    ///
+    /// The search is breadth-first over the allowed first bytes, so the lexicographically smallest one is returned among the shortest completions.
+    /// Like [`EngineLike::fast_forward`], the completion does not necessarily align with the tokens in the vocabulary.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_len` - The maximum length of the completion in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<u8>>` - The completion, which is empty if the engine could already finish,
+    ///   or `None` if no completion within `max_len` bytes exists.
+    fn shortest_completion(&self, max_len: usize) -> Option<Vec<u8>>;
+    /// Reconstructs how the bytes consumed by the current states are derived from the start nonterminal.
+    ///
+    /// The grammar may be ambiguous, so only one valid derivation is returned:
+    /// the productions are tried in the order of the simplified grammar and the shorter spans are tried first,
+    /// from the leftmost symbol to the rightmost one.
+    /// The Earley sets do not keep the completed items, so the derivation is recomputed from the consumed bytes,
+    /// which is much slower than accepting them. Avoid calling it after every token.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ParseNode>` - The root node of the start nonterminal spanning all the consumed bytes,
+    ///   or `None` if the consumed bytes are not a complete match of the grammar
+    ///   or [`Config::track_accepted_bytes`](crate::config::Config::track_accepted_bytes) is unset.
+    fn parse_tree(&self) -> Option<ParseNode>;
+    /// Gets the bytes captured by the named groups of the regexes, like `year` in `#"(?P<year>[0-9]{4})"`,
+    /// in the derivation reconstructed by [`EngineLike::parse_tree`].
+    ///
+    /// The DFAs stepped when accepting bytes cannot track the groups, so the regexes naming any group are compiled
+    /// once more into regexes with captures when creating the grammar, which costs extra time and memory, and they are
+    /// not merged with the equivalent regexes. Accepting bytes is not slowed down. Instead, the derivation is recomputed
+    /// and each regex with groups is matched again on the bytes it spans, so this is as slow as [`EngineLike::parse_tree`].
+    ///
+    /// # Returns
+    ///
+    /// * `AHashMap<String, Vec<u8>>` - The captured bytes keyed by the group names, where a later match of a name
+    ///   overwrites the earlier ones. It is empty if the consumed bytes are not a complete match of the grammar.
+    fn captures(&self) -> AHashMap<String, Vec<u8>>;
+    /// Gets the detail of the last [`AcceptTokenError::Rejected`] returned when scanning the bytes of a token or the bytes,
+    /// which tells where the bytes diverge from the grammar.
+    ///
+    /// It is only recorded when [`Config::detailed_errors`](crate::config::Config::detailed_errors) is set,
+    /// and is cleared when a token or bytes are accepted or the engine is reset.
+    /// The rejected EOS token is not recorded since no bytes are scanned.
+    fn last_rejection(&self) -> Option<&RejectionDetail>;
+    /// Gets the work done by the last call accepting a token, bytes or a string,
+    /// e.g. [`EngineLike::try_accept_new_token`] or [`EngineLike::update_logits`],
+    /// including the accepts that are rejected.
+    ///
+    /// It is only counted when [`Config::collect_accept_metrics`](crate::config::Config::collect_accept_metrics) is set,
+    /// and is all zeros otherwise. [`EngineLike::try_accept_new_tokens`] reports the last token.
+    fn last_accept_metrics(&self) -> AcceptMetrics;
+    /// Explains why the token is rejected at current states, by scanning its bytes on a copy of the states
+    /// and collecting the nodes that expect other bytes in place of the rejected byte.
+    /// The states of the engine are not modified.
+    ///
+    /// It is intended for debugging grammars and is much slower than [`EngineLike::try_accept_new_token`].
+    /// Unlike [`EngineLike::last_rejection`], it does not require [`Config::detailed_errors`](crate::config::Config::detailed_errors).
+    ///
+    /// # Returns
+    ///
+    /// * `Option<RejectionExplanation>` - The explanation, or `None` if the token is accepted, unknown,
+    ///   the EOS token or one of the ignored tokens. A token is rejected at its first byte when the engine is finished.
+    fn explain_rejection(&self, token_id: u32) -> Option<RejectionExplanation>;
+    /// Gets the terminal-like nodes that the current states are in the middle of,
+    /// i.e. the nodes that have matched some bytes but not completed yet, in the order of the Earley items.
+    ///
+    /// A token may span only a part of a long terminal, like `"Hel"` of the terminal `"Hello, World!\n"`,
+    /// after which the terminal is reported with [`InProgressItem::state_id`] 3.
+    /// The nodes that have not matched any bytes yet, like the ones just predicted, are not reported.
+    /// The states are not modified.
+    fn in_progress_items(&self) -> Vec<InProgressItem>;
+    /// Checks if the engine could finish at current states, i.e. whether stopping the generation now is legal.
+    ///
+    /// The states are not modified and no bytes are fed, so it is cheap to call after every accepted token.
+    ///
+    /// Besides the finished engine, a partial regex(`#p""`) in any state that has not rejected the input
+    /// is considered completable, even if the regex has not matched yet.
+    /// This is useful to validate truncated outputs leniently.
+    fn can_finish(&self) -> bool;
     /// Checks if the engine is finished.
     fn is_finished(&self) -> bool;
+    /// Checks if the engine is dead, i.e. the current states have no possible continuation.
+    ///
+    /// It differs from [`EngineLike::is_finished`] and [`EngineLike::can_finish`]: an engine can be alive
+    /// while it is neither finished nor able to finish, when it requires more bytes,
+    /// and a finished engine is dead once nothing can follow the matched start nonterminal.
+    /// Since a rejected token or byte leaves the states unchanged, a live engine only dies by finishing.
+    ///
+    /// The states are not modified, so it is cheap to call before committing to more work.
+    fn is_dead(&self) -> bool;
+    /// Begins a transaction that reverts the engine to current states when dropped, unless it is committed.
+    ///
+    /// This is useful to try some tokens or bytes without cloning the engine.
+    ///
+    /// # Returns
+    ///
+    /// * [`EngineTransaction`] - The guard through which tokens and bytes are accepted tentatively.
+    fn transaction(&mut self) -> EngineTransaction<'_>;
+    /// Tries to accept a new token tentatively, which is kept only if the returned guard is committed.
+    ///
+    /// This is useful for speculative decoding, where a draft token is reverted cheaply if the verifier rejects it.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The ID of the token to be accepted.
+    ///
+    /// # Returns
+    ///
+    /// * [`SpeculativeToken`] - The guard of the accepted token.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`AcceptTokenError`] when the token is not accepted, in the same way as [`EngineLike::try_accept_new_token`].
+    fn try_accept_new_token_speculative(
+        &mut self,
+        token_id: u32,
+    ) -> Result<SpeculativeToken<'_>, AcceptTokenError>;
     /// Resets the engine to its initial state. Notably, the cache is preserved.
     fn reset(&mut self);
+    /// Creates an engine at the initial state that shares the grammar, the vocabulary and the config with this engine.
+    ///
+    /// Unlike [`Clone`], the current states are not copied, so it is cheap to create one engine per generation.
+    /// The shared cache, if any, is shared by the new engine, while the cache of this engine is copied.
+    fn clone_fresh(&self) -> Self
+    where
+        Self: Sized;
+    /// Rewinds the engine to its initial state, while the bytes accepted since the last [`EngineLike::reset`]
+    /// and the cache are preserved.
+    ///
+    /// The preserved bytes can be accepted again by [`EngineLike::replay`].
+    /// The bytes accepted after rewinding are appended to the preserved bytes.
+    fn rewind(&mut self);
+    /// Rolls back the last `n` tokens or bytes accepted by [`EngineLike::try_accept_new_token`]
+    /// and [`EngineLike::try_accept_new_bytes`] since the last [`EngineLike::reset`] or [`EngineLike::rewind`].
+    ///
+    /// Each successful call of the two methods counts as one. The cache is preserved.
+    /// When the compaction is enabled, the remaining tokens are accepted again from the initial states,
+    /// since the compacted Earley sets cannot be truncated. Disable the compaction for faster rollbacks.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RollbackError::NotEnoughAcceptedTokens`] when `n` exceeds the number of accepted tokens,
+    /// or [`RollbackError::AcceptedBytesNotTracked`] when [`Config::track_accepted_bytes`](crate::config::Config::track_accepted_bytes) is unset.
+    fn rollback(&mut self, n: usize) -> Result<(), RollbackError>;
+    /// Rolls back the last token or bytes like [`EngineLike::rollback`] with `n = 1`, and finds the allowed tokens
+    /// extending the rolled-back bytes, which is known as token healing.
+    ///
+    /// A prompt like `"Hel"` may end in the middle of the token the model would pick, like `"Hello"`.
+    /// Healing lets the sampler re-pick the last token among the candidates, so the model can extend the prompt naturally.
+    /// The allowed token IDs are computed after the rollback, which can be retrieved by
+    /// [`EngineLike::allowed_token_ids_from_last_computation`]. When
+    /// [`Config::token_healing`](crate::config::Config::token_healing) is set, they are restricted to the candidates.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RollbackError::NotEnoughAcceptedTokens`] when no tokens are accepted since the last
+    /// [`EngineLike::reset`] or [`EngineLike::rewind`], or [`RollbackError::AcceptedBytesNotTracked`] when
+    /// [`Config::track_accepted_bytes`](crate::config::Config::track_accepted_bytes) is unset.
+    fn heal_last_token(&mut self) -> Result<HealResult, RollbackError>;
+    /// Rewinds the engine and accepts the bytes accepted since the last [`EngineLike::reset`] again.
+    ///
+    /// This is useful to return to the states before [`EngineLike::rewind`],
+    /// or to check whether a logged generation is accepted by a fresh engine.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`AcceptTokenError`] when the preserved bytes are rejected,
+    /// which happens when the bytes accepted after rewinding do not continue the preserved bytes.
+    /// The engine is rewound and the preserved bytes are kept in this case.
+    /// Returns [`AcceptTokenError::AcceptedBytesNotTracked`] without rewinding when
+    /// [`Config::track_accepted_bytes`](crate::config::Config::track_accepted_bytes) is unset.
+    fn replay(&mut self) -> Result<(), AcceptTokenError>;
+    /// Gets the bytes accepted since the last [`EngineLike::reset`], regardless of how they are tokenized.
+    ///
+    /// The bytes are preserved by [`EngineLike::rewind`] and truncated by [`EngineLike::rollback`].
+    /// The EOS token does not contribute any bytes.
+    /// It is always empty when [`Config::track_accepted_bytes`](crate::config::Config::track_accepted_bytes) is unset.
+    fn accepted_bytes(&self) -> &[u8];
+    /// Gets the number of entries in the cache of allowed token IDs.
+    ///
+    /// The number is bounded by [`EngineConfig::max_cache_entries`](crate::engine::EngineConfig::max_cache_entries) if it is set.
+    fn cache_len(&self) -> usize;
+    /// Clears the cache of allowed token IDs, which frees the memory held by the cached states.
+    fn clear_cache(&mut self);
     /// Converts the engine to a boxed engine.
     fn into_boxed_engine(self) -> Box<dyn EngineLike>;
     /// Gets the vocabulary of the engine.
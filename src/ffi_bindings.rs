@@ -1,9 +1,13 @@
 #[cfg(any(feature = "python", feature = "wasm"))]
-use crate::engine::CreateEngineError;
+use crate::engine::{CreateEngineError, EngineCheckpoint};
+#[cfg(any(feature = "python", feature = "wasm"))]
+use crate::engine_base::RestoreCheckpointError;
 #[cfg(any(feature = "python", feature = "wasm"))]
 use crate::engine_like::WriteBufferError;
 #[cfg(any(feature = "python", feature = "wasm"))]
-use crate::engine_like::{AcceptTokenError, MaskLogitsError, UpdateLogitsError};
+use crate::engine_like::{
+    AcceptStrError, AcceptTokenError, MaskLogitsError, RollbackError, UpdateLogitsError,
+};
 #[cfg(any(feature = "python", feature = "wasm"))]
 use crate::vocabulary::{CreateVocabularyError, Vocabulary};
 #[cfg(any(feature = "python", feature = "wasm"))]
@@ -27,6 +31,24 @@ impl From<CreateEngineError> for JsValue {
         JsValue::from_str(error.to_string().as_str())
     }
 }
+#[cfg(feature = "wasm")]
+impl From<RestoreCheckpointError> for JsValue {
+    fn from(error: RestoreCheckpointError) -> Self {
+        JsValue::from_str(error.to_string().as_str())
+    }
+}
+#[cfg(feature = "wasm")]
+impl From<AcceptStrError> for JsValue {
+    fn from(error: AcceptStrError) -> Self {
+        JsValue::from_str(error.to_string().as_str())
+    }
+}
+#[cfg(feature = "python")]
+impl From<RestoreCheckpointError> for PyErr {
+    fn from(error: RestoreCheckpointError) -> Self {
+        PyErr::new::<PyValueError, _>(error.to_string())
+    }
+}
 #[cfg(feature = "python")]
 impl From<CreateVocabularyError> for PyErr {
     fn from(error: CreateVocabularyError) -> Self {
@@ -46,6 +68,18 @@ impl From<AcceptTokenError> for PyErr {
     }
 }
 #[cfg(feature = "python")]
+impl From<AcceptStrError> for PyErr {
+    fn from(error: AcceptStrError) -> Self {
+        PyErr::new::<PyValueError, _>(error.to_string())
+    }
+}
+#[cfg(feature = "python")]
+impl From<RollbackError> for PyErr {
+    fn from(error: RollbackError) -> Self {
+        PyErr::new::<PyValueError, _>(error.to_string())
+    }
+}
+#[cfg(feature = "python")]
 impl From<MaskLogitsError> for PyErr {
     fn from(error: MaskLogitsError) -> Self {
         PyErr::new::<PyValueError, _>(error.to_string())
@@ -163,7 +197,7 @@ impl Vocabulary {
     /// * `None` - If the token does not exist in the vocabulary.
     #[wasm_bindgen(js_name = getTokenId)]
     pub fn token_id_js(&self, token: &Token) -> Option<u32> {
-        self.token_to_id.get(token).copied()
+        self.token_id(token)
     }
     /// Retrieves the size of the vocabulary.
     #[wasm_bindgen(js_name = getVocabSize)]
@@ -204,6 +238,28 @@ impl Vocabulary {
     pub fn token_js(&self, token_id: u32) -> Option<Token> {
         self.id_to_token.get(&token_id).cloned()
     }
+
+    /// Adds a token to the vocabulary in place.
+    /// The engines created before adding the token do not recognize it.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The ID of the token, which may exceed the current vocabulary size.
+    /// * `token` - The token.
+    /// * `token_string` - The token in UTF-8 String representation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the token ID already exists or the token ID is too large.
+    #[wasm_bindgen(js_name = addToken)]
+    pub fn add_token_js(
+        &mut self,
+        token_id: u32,
+        token: Token,
+        token_string: String,
+    ) -> Result<(), CreateVocabularyErrorJs> {
+        Ok(self.add_token(token_id, token, token_string)?)
+    }
 }
 
 #[cfg(feature = "python")]
@@ -225,7 +281,26 @@ impl Vocabulary {
     /// * `None` - If the token does not exist in the vocabulary.
     #[pyo3(name = "get_token_id")]
     pub fn token_id_py(&self, token: &Token) -> Option<u32> {
-        self.token_to_id.get(token).copied()
+        self.token_id(token)
+    }
+    /// Adds a token to the vocabulary in place.
+    /// The engines created before adding the token do not recognize it.
+    ///
+    /// # Signature
+    ///
+    /// (self, token_id: int, token: Token, token_string: str) -> None
+    ///
+    /// # Errors
+    ///
+    /// Raises a `ValueError` when the token ID already exists or the token ID is too large.
+    #[pyo3(name = "add_token")]
+    pub fn add_token_py(
+        &mut self,
+        token_id: u32,
+        token: Token,
+        token_string: String,
+    ) -> Result<(), CreateVocabularyError> {
+        self.add_token(token_id, token, token_string)
     }
     /// Retrieves the size of the vocabulary.
     #[pyo3(name = "get_vocab_size")]
@@ -323,6 +398,77 @@ impl Engine {
     ) -> Result<Engine, CreateEngineError> {
         Self::with_config(kbnf_syntax_grammar_str, vocabulary, config)
     }
+    /// Create a new [`Engine`] from an KBNF grammar string and a [`Vocabulary`],
+    /// where `start_symbol` replaces the default [`Config::start_nonterminal`].
+    ///
+    /// # Arguments
+    ///
+    /// * `kbnf_syntax_grammar_str` - The KBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `start_symbol` - The nonterminal that the engine starts from.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the start symbol is not defined in the grammar,
+    /// the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    #[wasm_bindgen(js_name = withStartSymbol)]
+    pub fn with_start_symbol_js(
+        kbnf_syntax_grammar_str: &str,
+        vocabulary: Vocabulary,
+        start_symbol: &str,
+    ) -> Result<Engine, CreateEngineError> {
+        Self::with_start_symbol(kbnf_syntax_grammar_str, vocabulary, start_symbol)
+    }
+    /// Create a new [`Engine`] from a llama.cpp GBNF grammar string, a [`Vocabulary`], and a [`Config`].
+    ///
+    /// # Arguments
+    ///
+    /// * `gbnf_grammar_str` - The GBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `config` - The [`Config`] object.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the GBNF grammar is malformed, the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    #[wasm_bindgen(js_name = fromGbnf)]
+    pub fn from_gbnf_js(
+        gbnf_grammar_str: &str,
+        vocabulary: Vocabulary,
+        config: Config,
+    ) -> Result<Engine, CreateEngineError> {
+        Self::from_gbnf(gbnf_grammar_str, vocabulary, config)
+    }
+    /// Create a new [`Engine`] that matches a single regex.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regex.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `early_end` - Whether the regex ends as soon as it matches, like `#e"..."`.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the regex is invalid or matches only the empty string or nothing.
+    #[wasm_bindgen(js_name = fromRegex)]
+    pub fn from_regex_js(
+        pattern: &str,
+        vocabulary: Vocabulary,
+        early_end: bool,
+    ) -> Result<Engine, CreateEngineError> {
+        Self::from_regex(pattern, vocabulary, early_end)
+    }
     /// Tries to accept a new token with the given token ID.
     ///
     /// # Arguments
@@ -344,6 +490,30 @@ impl Engine {
     ) -> Result<AcceptTokenResult, AcceptTokenError> {
         EngineLike::try_accept_new_token(self, token_id)
     }
+    /// Tries to accept a new token given as its string, which is resolved to the token ID through the vocabulary.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AcceptTokenError::UnknownTokenStr`] when the string is not a known token.
+    /// See [`EngineLike::try_accept_token_str`] for more details.
+    #[wasm_bindgen(js_name = tryAcceptTokenStr)]
+    pub fn try_accept_token_str_js(
+        &mut self,
+        token_str: &str,
+    ) -> Result<AcceptTokenResult, AcceptTokenError> {
+        EngineLike::try_accept_token_str(self, token_str)
+    }
+
+    /// Tries to accept a new string, which is the same as accepting its UTF-8 bytes,
+    /// except that the rejection is reported in characters.
+    ///
+    /// # Errors
+    ///
+    /// Throws the message with the index of the first rejected character when the string is rejected.
+    #[wasm_bindgen(js_name = tryAcceptNewStr)]
+    pub fn try_accept_new_str_js(&mut self, s: &str) -> Result<AcceptTokenResult, AcceptStrError> {
+        EngineLike::try_accept_new_str(self, s)
+    }
 
     /// Computes the allowed token IDs based on current states.
     #[wasm_bindgen(js_name = computeAllowedTokenIds)]
@@ -351,6 +521,33 @@ impl Engine {
         EngineLike::compute_allowed_token_ids(self)
     }
 
+    /// Computes the allowed token IDs based on current states, but stops as soon as `limit` tokens are found.
+    ///
+    /// The allowed token IDs from the last computation are not updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of allowed token IDs to find.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u32>` - At most `limit` allowed token IDs in ascending order.
+    #[wasm_bindgen(js_name = allowedTokenIdsUntil)]
+    pub fn allowed_token_ids_until_js(&mut self, limit: usize) -> Vec<u32> {
+        EngineLike::allowed_token_ids_until(self, limit)
+    }
+
+    /// Finds any allowed token ID based on current states, stopping at the first one found.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(u32)` - An allowed token ID.
+    /// * `None` - If no token is allowed.
+    #[wasm_bindgen(js_name = anyAllowedToken)]
+    pub fn any_allowed_token_js(&mut self) -> Option<u32> {
+        EngineLike::any_allowed_token(self)
+    }
+
     /// Gets the allowed token IDs since last computation.
     /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
     ///
@@ -361,16 +558,171 @@ impl Engine {
             .ones()
             .collect()
     }
+    /// Gets the number of allowed token IDs since last computation.
+    #[wasm_bindgen(js_name = getNumberOfAllowedTokenIds)]
+    pub fn allowed_token_count_js(&self) -> usize {
+        EngineLike::allowed_token_count(self)
+    }
+    /// Gets the number of disallowed token IDs since last computation.
+    #[wasm_bindgen(js_name = getNumberOfDisallowedTokenIds)]
+    pub fn disallowed_token_count_js(&self) -> usize {
+        EngineLike::disallowed_token_count(self)
+    }
+    /// Gets the allowed token IDs since last computation as coalesced ranges.
+    ///
+    /// The ranges are flattened as `[start0, end0, start1, end1, ...]`,
+    /// where each `start` is inclusive and each `end` is exclusive.
+    #[wasm_bindgen(js_name = getAllowedTokenIdsAsRanges)]
+    pub fn allowed_token_ids_as_ranges_js(&self) -> Vec<u32> {
+        EngineLike::allowed_token_ids_as_ranges(self)
+            .into_iter()
+            .flat_map(|(start, end)| [start, end])
+            .collect()
+    }
+    /// Writes the additive mask to the given buffer.
+    /// Allowed token IDs are written as `0.0` and other entries are written as negative infinity.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteBufferError`] when the buffer is smaller than the vocabulary size.
+    #[wasm_bindgen(js_name = writeMaskToBuffer)]
+    pub fn write_mask_to_buffer_js(&self, buffer: &mut [f32]) -> Result<(), WriteBufferError> {
+        EngineLike::write_mask_to_buffer(self, buffer)
+    }
+    /// Gets the bytes allowed as the next byte at current states in ascending order.
+    ///
+    /// This is much cheaper than computing the allowed token IDs when only byte granularity is needed.
+    #[wasm_bindgen(js_name = allowedFirstBytes)]
+    pub fn allowed_first_bytes_js(&mut self) -> Vec<u8> {
+        EngineLike::allowed_first_bytes(self)
+            .ones()
+            .map(|x| x as u8)
+            .collect()
+    }
+    /// Accepts the bytes forced by the grammar until a branch point is reached or the engine is finished.
+    ///
+    /// The forced bytes do not necessarily align with the tokens in the vocabulary.
+    #[wasm_bindgen(js_name = fastForward)]
+    pub fn fast_forward_js(&mut self) -> Vec<u8> {
+        EngineLike::fast_forward(self)
+    }
+    /// Finds the shortest bytes that would let the engine finish from current states without modifying them.
+    ///
+    /// Returns `undefined` if no completion within `maxLen` bytes exists.
+    #[wasm_bindgen(js_name = shortestCompletion)]
+    pub fn shortest_completion_js(&self, max_len: usize) -> Option<Vec<u8>> {
+        EngineLike::shortest_completion(self, max_len)
+    }
+    /// Checks if the engine could finish at current states.
+    ///
+    /// A partial regex(`#p""`) that has not rejected the input is considered completable.
+    #[wasm_bindgen(js_name = canFinish)]
+    pub fn can_finish_js(&self) -> bool {
+        EngineLike::can_finish(self)
+    }
     /// Checks if the engine is finished.
     #[wasm_bindgen(js_name = isFinished)]
     pub fn is_finished_js(&self) -> bool {
         EngineLike::is_finished(self)
     }
+    /// Checks if the engine is dead, i.e. the current states have no possible continuation.
+    #[wasm_bindgen(js_name = isDead)]
+    pub fn is_dead_js(&self) -> bool {
+        EngineLike::is_dead(self)
+    }
     /// Resets the engine to its initial state. Notably, the cache is preserved.
     #[wasm_bindgen(js_name = reset)]
     pub fn reset_js(&mut self) {
         EngineLike::reset(self)
     }
+    /// Creates an engine at the initial state that shares the grammar, the vocabulary and the config with this engine.
+    /// The current states are not copied.
+    #[wasm_bindgen(js_name = cloneFresh)]
+    pub fn clone_fresh_js(&self) -> Engine {
+        EngineLike::clone_fresh(self)
+    }
+    /// Rewinds the engine to its initial state, while the accepted bytes and the cache are preserved.
+    #[wasm_bindgen(js_name = rewind)]
+    pub fn rewind_js(&mut self) {
+        EngineLike::rewind(self)
+    }
+    /// Creates a checkpoint of the current states, which can be restored later.
+    ///
+    /// The grammar and the vocabulary are shared, and the cache is not included,
+    /// so a checkpoint is much cheaper than cloning the engine.
+    #[wasm_bindgen(js_name = checkpoint)]
+    pub fn checkpoint_js(&self) -> EngineCheckpoint {
+        self.checkpoint()
+    }
+    /// Restores the states from the checkpoint. The cache of the engine is preserved.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the checkpoint is not created by the engine or its clones.
+    #[wasm_bindgen(js_name = restore)]
+    pub fn restore_js(
+        &mut self,
+        checkpoint: &EngineCheckpoint,
+    ) -> Result<(), RestoreCheckpointError> {
+        self.restore(checkpoint)
+    }
+    /// Gets a human-readable fingerprint of the current states,
+    /// which is identical across runs and platforms for the same grammar, vocabulary and sequence of accepted tokens.
+    #[wasm_bindgen(js_name = stateFingerprint)]
+    pub fn state_fingerprint_js(&self) -> String {
+        self.state_fingerprint()
+    }
+    /// Gets the index of the byte rejected last time in the token or the bytes,
+    /// which is only recorded when `detailed_errors` is set in the config.
+    #[wasm_bindgen(js_name = lastRejectedByteIndex)]
+    pub fn last_rejected_byte_index_js(&self) -> Option<usize> {
+        EngineLike::last_rejection(self).map(|x| x.byte_index)
+    }
+    /// Gets the bytes allowed in place of the byte rejected last time,
+    /// which is only recorded when `detailed_errors` is set in the config.
+    #[wasm_bindgen(js_name = lastRejectionAllowedFirstBytes)]
+    pub fn last_rejection_allowed_first_bytes_js(&self) -> Option<Vec<u8>> {
+        EngineLike::last_rejection(self)
+            .map(|x| x.allowed_first_bytes.ones().map(|x| x as u8).collect())
+    }
+    /// Gets the messages of the ambiguity warnings of the grammar,
+    /// which are empty unless `collect_ambiguity_warnings` is set in the config.
+    #[wasm_bindgen(js_name = ambiguityWarnings)]
+    pub fn ambiguity_warnings_js(&self) -> Vec<String> {
+        self.ambiguity_warnings()
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+    /// Rewinds the engine and accepts the bytes accepted since the last reset again.
+    #[wasm_bindgen(js_name = replay)]
+    pub fn replay_js(&mut self) -> Result<(), AcceptTokenError> {
+        EngineLike::replay(self)
+    }
+    /// Gets the bytes accepted since the last reset.
+    #[wasm_bindgen(js_name = acceptedBytes)]
+    pub fn accepted_bytes_js(&self) -> Vec<u8> {
+        EngineLike::accepted_bytes(self).to_vec()
+    }
+    /// Gets the number of entries in the cache of allowed token IDs.
+    #[wasm_bindgen(js_name = cacheLen)]
+    pub fn cache_len_js(&self) -> usize {
+        EngineLike::cache_len(self)
+    }
+    /// Clears the cache of allowed token IDs.
+    #[wasm_bindgen(js_name = clearCache)]
+    pub fn clear_cache_js(&mut self) {
+        EngineLike::clear_cache(self)
+    }
+    /// Rolls back the last `n` accepted tokens or bytes. The cache is preserved.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when `n` exceeds the number of accepted tokens since the last reset or rewind.
+    #[wasm_bindgen(js_name = rollback)]
+    pub fn rollback_js(&mut self, n: usize) -> Result<(), RollbackError> {
+        EngineLike::rollback(self, n)
+    }
     /// Gets the vocabulary of the engine.
     #[wasm_bindgen(js_name = getVocab)]
     pub fn vocab_js(&self) -> Vocabulary {
@@ -395,6 +747,24 @@ impl Engine {
         EngineLike::mask_logits(self, logits)
     }
 
+    /// Adds the logarithm of the priors of the weighted alternatives(`"A" %0.9`) to the logits.
+    ///
+    /// Only the weighted alternatives that start at current states are considered.
+    /// A token consistent with some of them is biased by the logarithm of their priors' sum,
+    /// while other tokens are left unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the input logits array is not of the expected length according to the vocabulary.
+    /// The logits array is not updated in this case.
+    #[wasm_bindgen(js_name = biasLogitsByGrammarPrior)]
+    pub fn bias_logits_by_grammar_prior_js(
+        &self,
+        logits: &mut [f32],
+    ) -> Result<(), MaskLogitsError> {
+        EngineLike::bias_logits_by_grammar_prior(self, logits)
+    }
+
     /// Try to accept the token ID and if succeeds, update the given logits array.
     ///
     /// # Arguments
@@ -419,6 +789,20 @@ impl Engine {
     ) -> Result<AcceptTokenResult, UpdateLogitsError> {
         EngineLike::update_logits(self, token_id, logits)
     }
+    /// Try to accept the token given as its string and if succeeds, update the given logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`UpdateLogitsError::UnknownTokenStr`] when the string is not a known token.
+    /// See [`EngineLike::update_logits_for_token_str`] for more details.
+    #[wasm_bindgen(js_name = updateLogitsForTokenStr)]
+    pub fn update_logits_for_token_str_js(
+        &mut self,
+        token_str: &str,
+        logits: &mut [f32],
+    ) -> Result<AcceptTokenResult, UpdateLogitsError> {
+        EngineLike::update_logits_for_token_str(self, token_str, logits)
+    }
 }
 
 #[cfg(feature = "python")]
@@ -455,6 +839,94 @@ impl Engine {
             None => Self::new(kbnf_syntax_grammar_str, vocabulary),
         }
     }
+    /// Create a new [`Engine`] from an KBNF grammar string and a [`Vocabulary`],
+    /// where `start_symbol` replaces the default [`Config::start_nonterminal`].
+    ///
+    /// # Signature
+    ///
+    /// (kbnf_syntax_grammar_str: str, vocabulary: Vocabulary, start_symbol: str) -> Engine
+    ///
+    /// # Arguments
+    ///
+    /// * `kbnf_syntax_grammar_str` - The KBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `start_symbol` - The nonterminal that the engine starts from.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the start symbol is not defined in the grammar,
+    /// the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    #[staticmethod]
+    #[pyo3(name = "with_start_symbol")]
+    pub fn with_start_symbol_py(
+        kbnf_syntax_grammar_str: &str,
+        vocabulary: Vocabulary,
+        start_symbol: &str,
+    ) -> Result<Engine, CreateEngineError> {
+        Self::with_start_symbol(kbnf_syntax_grammar_str, vocabulary, start_symbol)
+    }
+    /// Create a new [`Engine`] from a llama.cpp GBNF grammar string, a [`Vocabulary`], and an optional [`Config`].
+    ///
+    /// # Signature
+    ///
+    /// (gbnf_grammar_str: str, vocabulary: Vocabulary, config: Config) -> Engine
+    ///
+    /// # Arguments
+    ///
+    /// * `gbnf_grammar_str` - The GBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `config` - The [`Config`] object.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the GBNF grammar is malformed, the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    #[pyo3(signature = (gbnf_grammar_str, vocabulary, config=None))]
+    #[staticmethod]
+    #[pyo3(name = "from_gbnf")]
+    pub fn from_gbnf_py(
+        gbnf_grammar_str: &str,
+        vocabulary: Vocabulary,
+        config: Option<Config>,
+    ) -> Result<Engine, CreateEngineError> {
+        Self::from_gbnf(gbnf_grammar_str, vocabulary, config.unwrap_or_default())
+    }
+    /// Create a new [`Engine`] that matches a single regex.
+    ///
+    /// # Signature
+    ///
+    /// (pattern: str, vocabulary: Vocabulary, early_end: bool) -> Engine
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regex.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `early_end` - Whether the regex ends as soon as it matches, like `#e"..."`.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the regex is invalid or matches only the empty string or nothing.
+    #[pyo3(signature = (pattern, vocabulary, early_end=false))]
+    #[staticmethod]
+    #[pyo3(name = "from_regex")]
+    pub fn from_regex_py(
+        pattern: &str,
+        vocabulary: Vocabulary,
+        early_end: bool,
+    ) -> Result<Engine, CreateEngineError> {
+        Self::from_regex(pattern, vocabulary, early_end)
+    }
     /// Tries to accept a new token with the given token ID.
     ///
     /// # Signature
@@ -480,6 +952,31 @@ impl Engine {
     ) -> Result<AcceptTokenResult, AcceptTokenError> {
         EngineLike::try_accept_new_token(self, token_id)
     }
+    /// Tries to accept a new token given as its string, which is resolved to the token ID through the vocabulary.
+    ///
+    /// # Signature
+    ///
+    /// (self, token_str: str) -> AcceptTokenResult
+    ///
+    /// # Arguments
+    ///
+    /// * `token_str` - The string of the token to be accepted.
+    ///
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the token.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AcceptTokenError::UnknownTokenStr`] when the string is not a known token.
+    /// See [`EngineLike::try_accept_token_str`] for more details.
+    #[pyo3(name = "try_accept_token_str")]
+    pub fn try_accept_token_str_py(
+        &mut self,
+        token_str: &str,
+    ) -> Result<AcceptTokenResult, AcceptTokenError> {
+        EngineLike::try_accept_token_str(self, token_str)
+    }
     /// Tries to accept new bytes.
     ///
     /// # Signature
@@ -505,24 +1002,83 @@ impl Engine {
         EngineLike::try_accept_new_bytes(self, bytes)
     }
 
-    /// Computes the allowed token IDs based on current states.
+    /// Accepts new bytes that are expected to be valid, like a known prompt, faster than `try_accept_new_bytes`.
     ///
     /// # Signature
     ///
-    /// (self) -> None
-    #[pyo3(name = "compute_allowed_token_ids")]
-    pub fn compute_allowed_token_ids_py(&mut self, py: Python<'_>) {
-        py.allow_threads(|| EngineLike::compute_allowed_token_ids(self));
-    }
-
-    /// Gets the allowed token IDs since last computation.
-    /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
+    /// (self, bytes: bytes) -> AcceptTokenResult
     ///
-    /// In other words, [`EngineLike::try_accept_new_token`] DOES NOT compute the allowed token IDs and hence DOES NOT affect its result!
+    /// # Arguments
     ///
-    /// # Signature
+    /// * `bytes` - The bytes to be accepted.
     ///
-    /// (self) -> List[int]
+    /// # Returns
+    ///
+    /// * [`AcceptTokenResult`] - The result of accepting the bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`AcceptTokenError`] when the bytes are not accepted. The whole prefill is reverted in this case.
+    #[pyo3(name = "prefill")]
+    pub fn prefill_py(&mut self, bytes: &[u8]) -> Result<AcceptTokenResult, AcceptTokenError> {
+        EngineLike::prefill(self, bytes)
+    }
+
+    /// Tries to accept a new string, which is the same as accepting its UTF-8 bytes,
+    /// except that the rejection is reported in characters.
+    ///
+    /// # Signature
+    ///
+    /// (self, s: str) -> AcceptTokenResult
+    ///
+    /// # Errors
+    ///
+    /// Raises a `ValueError` with the index of the first rejected character when the string is rejected.
+    #[pyo3(name = "try_accept_new_str")]
+    pub fn try_accept_new_str_py(&mut self, s: &str) -> Result<AcceptTokenResult, AcceptStrError> {
+        EngineLike::try_accept_new_str(self, s)
+    }
+
+    /// Computes the allowed token IDs based on current states.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> None
+    #[pyo3(name = "compute_allowed_token_ids")]
+    pub fn compute_allowed_token_ids_py(&mut self, py: Python<'_>) {
+        py.allow_threads(|| EngineLike::compute_allowed_token_ids(self));
+    }
+
+    /// Computes the allowed token IDs based on current states, but stops as soon as `limit` tokens are found.
+    ///
+    /// The allowed token IDs from the last computation are not updated.
+    ///
+    /// # Signature
+    ///
+    /// (self, limit: int) -> List[int]
+    #[pyo3(name = "allowed_token_ids_until")]
+    pub fn allowed_token_ids_until_py(&mut self, py: Python<'_>, limit: usize) -> Vec<u32> {
+        py.allow_threads(|| EngineLike::allowed_token_ids_until(self, limit))
+    }
+
+    /// Finds any allowed token ID based on current states, stopping at the first one found.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Optional[int]
+    #[pyo3(name = "any_allowed_token")]
+    pub fn any_allowed_token_py(&mut self, py: Python<'_>) -> Option<u32> {
+        py.allow_threads(|| EngineLike::any_allowed_token(self))
+    }
+
+    /// Gets the allowed token IDs since last computation.
+    /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
+    ///
+    /// In other words, [`EngineLike::try_accept_new_token`] DOES NOT compute the allowed token IDs and hence DOES NOT affect its result!
+    ///
+    /// # Signature
+    ///
+    /// (self) -> List[int]
     #[pyo3(name = "get_allowed_token_ids_from_last_computation")]
     pub fn allowed_token_ids_from_last_computation_py(&self) -> Vec<usize> {
         EngineLike::allowed_token_ids_from_last_computation(self)
@@ -544,6 +1100,17 @@ impl Engine {
             .collect()
     }
 
+    /// Gets the allowed token IDs since last computation as sorted, disjoint `(start, end)` ranges,
+    /// where `start` is inclusive and `end` is exclusive.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> List[Tuple[int, int]]
+    #[pyo3(name = "get_allowed_token_ids_as_ranges")]
+    pub fn allowed_token_ids_as_ranges_py(&self) -> Vec<(u32, u32)> {
+        EngineLike::allowed_token_ids_as_ranges(self)
+    }
+
     /// Gets a hashable index of the allowed token IDs.
     ///
     /// # Signature
@@ -579,7 +1146,7 @@ impl Engine {
     /// (self) -> int
     #[pyo3(name = "get_number_of_disallowed_token_ids")]
     pub fn get_number_of_disallowed_token_ids_py(&self) -> usize {
-        EngineLike::allowed_token_ids_from_last_computation(self).count_zeroes(..)
+        EngineLike::disallowed_token_count(self)
     }
 
     /// Gets the number of allowed token IDs.
@@ -589,7 +1156,7 @@ impl Engine {
     /// (self) -> int
     #[pyo3(name = "get_number_of_allowed_token_ids")]
     pub fn get_number_of_allowed_token_ids_py(&self) -> usize {
-        EngineLike::allowed_token_ids_from_last_computation(self).count_ones(..)
+        EngineLike::allowed_token_count(self)
     }
     /// Writes the disallowed token IDs to the given buffer.
     ///
@@ -691,6 +1258,87 @@ impl Engine {
         EngineLike::write_allowed_token_ids_to_buffer(self, buffer)
     }
 
+    /// Writes the additive mask to the given buffer.
+    /// Allowed token IDs are written as `0.0` and other entries are written as negative infinity,
+    /// so the mask can be added to the logits directly, for example on GPU.
+    ///
+    /// # Signature
+    ///
+    /// (self, ptr: int, length: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `ptr` - The pointer to the float32 buffer.
+    /// * `length` - The length of the buffer.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteBufferError`] when the buffer is smaller than the vocabulary size.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to writable, aligned memory that contains float32 and the length is correct.
+    #[pyo3(name = "write_mask_to_buffer")]
+    pub unsafe fn write_mask_to_buffer_py(
+        &self,
+        ptr: usize,
+        length: usize,
+    ) -> Result<(), WriteBufferError> {
+        let buffer = std::slice::from_raw_parts_mut(ptr as *mut f32, length);
+        EngineLike::write_mask_to_buffer(self, buffer)
+    }
+
+    /// Gets the bytes allowed as the next byte at current states in ascending order.
+    ///
+    /// This is much cheaper than computing the allowed token IDs when only byte granularity is needed.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> bytes
+    #[pyo3(name = "allowed_first_bytes")]
+    pub fn allowed_first_bytes_py(&mut self) -> std::borrow::Cow<'static, [u8]> {
+        EngineLike::allowed_first_bytes(self)
+            .ones()
+            .map(|x| x as u8)
+            .collect::<Vec<_>>()
+            .into()
+    }
+    /// Accepts the bytes forced by the grammar until a branch point is reached or the engine is finished.
+    ///
+    /// The forced bytes do not necessarily align with the tokens in the vocabulary.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> bytes
+    #[pyo3(name = "fast_forward")]
+    pub fn fast_forward_py(&mut self) -> std::borrow::Cow<'static, [u8]> {
+        EngineLike::fast_forward(self).into()
+    }
+    /// Finds the shortest bytes that would let the engine finish from current states without modifying them.
+    ///
+    /// Returns `None` if no completion within `max_len` bytes exists.
+    ///
+    /// # Signature
+    ///
+    /// (self, max_len: int) -> Optional[bytes]
+    #[pyo3(name = "shortest_completion")]
+    pub fn shortest_completion_py(
+        &self,
+        max_len: usize,
+    ) -> Option<std::borrow::Cow<'static, [u8]>> {
+        EngineLike::shortest_completion(self, max_len).map(Into::into)
+    }
+    /// Checks if the engine could finish at current states.
+    ///
+    /// A partial regex(`#p""`) that has not rejected the input is considered completable.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> bool
+    #[pyo3(name = "can_finish")]
+    pub fn can_finish_py(&self) -> bool {
+        EngineLike::can_finish(self)
+    }
     /// Checks if the engine is finished.
     /// # Signature
     ///
@@ -699,6 +1347,15 @@ impl Engine {
     pub fn is_finished_py(&self) -> bool {
         EngineLike::is_finished(self)
     }
+    /// Checks if the engine is dead, i.e. the current states have no possible continuation.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> bool
+    #[pyo3(name = "is_dead")]
+    pub fn is_dead_py(&self) -> bool {
+        EngineLike::is_dead(self)
+    }
     /// Resets the engine to its initial state. Notably, the cache is preserved.
     ///
     /// # Signature
@@ -708,6 +1365,276 @@ impl Engine {
     pub fn reset_py(&mut self) {
         EngineLike::reset(self)
     }
+    /// Creates an engine at the initial state that shares the grammar, the vocabulary and the config with this engine.
+    /// The current states are not copied.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Engine
+    #[pyo3(name = "clone_fresh")]
+    pub fn clone_fresh_py(&self) -> Engine {
+        EngineLike::clone_fresh(self)
+    }
+    /// Rewinds the engine to its initial state, while the accepted bytes and the cache are preserved.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> None
+    #[pyo3(name = "rewind")]
+    pub fn rewind_py(&mut self) {
+        EngineLike::rewind(self)
+    }
+    /// Creates a checkpoint of the current states, which can be restored later.
+    ///
+    /// The grammar and the vocabulary are shared, and the cache is not included,
+    /// so a checkpoint is much cheaper than cloning the engine.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> EngineCheckpoint
+    #[pyo3(name = "checkpoint")]
+    pub fn checkpoint_py(&self) -> EngineCheckpoint {
+        self.checkpoint()
+    }
+    /// Restores the states from the checkpoint. The cache of the engine is preserved.
+    ///
+    /// # Signature
+    ///
+    /// (self, checkpoint: EngineCheckpoint) -> None
+    ///
+    /// # Errors
+    ///
+    /// Raises a ValueError when the checkpoint is not created by the engine or its clones.
+    #[pyo3(name = "restore")]
+    pub fn restore_py(
+        &mut self,
+        checkpoint: &EngineCheckpoint,
+    ) -> Result<(), RestoreCheckpointError> {
+        self.restore(checkpoint)
+    }
+    /// Gets the allowed token IDs since last computation paired with their token strings, which is useful for debugging grammars.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> list[tuple[int, str]]
+    #[pyo3(name = "allowed_token_strings")]
+    pub fn allowed_token_strings_py(&self) -> Vec<(u32, String)> {
+        self.allowed_token_strings()
+    }
+    /// Reconstructs one valid derivation of the bytes consumed by the current states from the start nonterminal.
+    ///
+    /// Returns `None` if the consumed bytes are not a complete match of the grammar.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Optional[ParseNode]
+    #[pyo3(name = "parse_tree")]
+    pub fn parse_tree_py(&self) -> Option<crate::engine_like::ParseNode> {
+        EngineLike::parse_tree(self)
+    }
+    /// Gets the bytes captured by the named groups of the regexes, like `year` in `#"(?P<year>[0-9]{4})"`,
+    /// in the derivation reconstructed by `parse_tree`.
+    ///
+    /// It is as slow as `parse_tree`, and it is empty if the consumed bytes are not a complete match of the grammar.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Dict[str, bytes]
+    #[pyo3(name = "captures")]
+    pub fn captures_py(
+        &self,
+    ) -> std::collections::HashMap<String, std::borrow::Cow<'static, [u8]>> {
+        EngineLike::captures(self)
+            .into_iter()
+            .map(|(name, bytes)| (name, bytes.into()))
+            .collect()
+    }
+    /// Gets the index of the byte rejected last time in the token or the bytes,
+    /// along with the bytes allowed in place of it.
+    ///
+    /// It is only recorded when `detailed_errors` is set in the config.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Optional[tuple[int, bytes]]
+    #[pyo3(name = "last_rejection")]
+    pub fn last_rejection_py(&self) -> Option<(usize, std::borrow::Cow<'static, [u8]>)> {
+        EngineLike::last_rejection(self).map(|x| {
+            (
+                x.byte_index,
+                x.allowed_first_bytes
+                    .ones()
+                    .map(|x| x as u8)
+                    .collect::<Vec<_>>()
+                    .into(),
+            )
+        })
+    }
+    /// Gets the work done by the last accept, i.e. the bytes scanned, the Earley items created,
+    /// the completions, the Earley sets removed by the compaction and whether the cache is hit.
+    ///
+    /// It is only counted when `collect_accept_metrics` is set in the config.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> AcceptMetrics
+    #[pyo3(name = "last_accept_metrics")]
+    pub fn last_accept_metrics_py(&self) -> crate::engine_like::AcceptMetrics {
+        EngineLike::last_accept_metrics(self)
+    }
+    /// Explains why the token is rejected at current states without modifying them.
+    ///
+    /// Returns the index of the rejected byte in the token, along with the nonterminal, the display form
+    /// and the expected bytes of each node that matched the bytes before it,
+    /// or None if the token is accepted, unknown, the EOS token or one of the ignored tokens.
+    ///
+    /// # Signature
+    ///
+    /// (self, token_id: int) -> Optional[tuple[int, list[tuple[str, str, bytes]]]]
+    #[pyo3(name = "explain_rejection")]
+    #[allow(clippy::type_complexity)]
+    pub fn explain_rejection_py(
+        &self,
+        token_id: u32,
+    ) -> Option<(
+        usize,
+        Vec<(String, String, std::borrow::Cow<'static, [u8]>)>,
+    )> {
+        EngineLike::explain_rejection(self, token_id).map(|x| {
+            (
+                x.byte_index,
+                x.expectations
+                    .into_iter()
+                    .map(|x| {
+                        (
+                            x.nonterminal,
+                            x.node,
+                            x.expected_bytes
+                                .ones()
+                                .map(|x| x as u8)
+                                .collect::<Vec<_>>()
+                                .into(),
+                        )
+                    })
+                    .collect(),
+            )
+        })
+    }
+    /// Gets a human-readable fingerprint of the current states,
+    /// which is identical across runs and platforms for the same grammar, vocabulary and sequence of accepted tokens.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> str
+    #[pyo3(name = "state_fingerprint")]
+    pub fn state_fingerprint_py(&self) -> String {
+        self.state_fingerprint()
+    }
+    /// Gets the messages of the ambiguity warnings of the grammar,
+    /// which are empty unless `collect_ambiguity_warnings` is set in the config.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> list[str]
+    #[pyo3(name = "ambiguity_warnings")]
+    pub fn ambiguity_warnings_py(&self) -> Vec<String> {
+        self.ambiguity_warnings()
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+    /// Sets the callback invoked with the name, the start byte offset and the end byte offset of each nonterminal
+    /// completed when accepting tokens or bytes, or removes the callback if it is None.
+    /// The callback is not carried into the clones of the engine.
+    /// The exceptions raised by the callback are reported as unraisable.
+    ///
+    /// # Signature
+    ///
+    /// (self, callback: Optional[Callable[[str, int, int], None]]) -> None
+    #[pyo3(name = "set_completion_hook")]
+    #[pyo3(signature = (callback))]
+    pub fn set_completion_hook_py(&mut self, callback: Option<pyo3::PyObject>) {
+        match callback {
+            Some(callback) => {
+                let grammar = self.grammar();
+                self.set_completion_hook(Box::new(move |nonterminal_id, start, end| {
+                    let name = grammar.nonterminal_str(nonterminal_id).unwrap_or_default();
+                    Python::with_gil(|py| {
+                        if let Err(e) = callback.call1(py, (name, start, end)) {
+                            e.write_unraisable(py, None);
+                        }
+                    })
+                }))
+            }
+            None => self.clear_completion_hook(),
+        }
+    }
+    /// Rewinds the engine and accepts the bytes accepted since the last reset again.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> None
+    #[pyo3(name = "replay")]
+    pub fn replay_py(&mut self) -> Result<(), AcceptTokenError> {
+        EngineLike::replay(self)
+    }
+    /// Gets the bytes accepted since the last reset, regardless of how they are tokenized.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> bytes
+    #[pyo3(name = "accepted_bytes")]
+    pub fn accepted_bytes_py(&self) -> std::borrow::Cow<'_, [u8]> {
+        EngineLike::accepted_bytes(self).into()
+    }
+    /// Gets the number of entries in the cache of allowed token IDs.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> int
+    #[pyo3(name = "cache_len")]
+    pub fn cache_len_py(&self) -> usize {
+        EngineLike::cache_len(self)
+    }
+    /// Clears the cache of allowed token IDs.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> None
+    #[pyo3(name = "clear_cache")]
+    pub fn clear_cache_py(&mut self) {
+        EngineLike::clear_cache(self)
+    }
+    /// Rolls back the last `n` accepted tokens or bytes. The cache is preserved.
+    ///
+    /// # Signature
+    ///
+    /// (self, n: int) -> None
+    ///
+    /// # Errors
+    ///
+    /// Raises a ValueError when `n` exceeds the number of accepted tokens since the last reset or rewind.
+    #[pyo3(name = "rollback")]
+    pub fn rollback_py(&mut self, n: usize) -> Result<(), RollbackError> {
+        EngineLike::rollback(self, n)
+    }
+    /// Rolls back the last accepted token or bytes and finds the allowed tokens extending the rolled-back bytes,
+    /// so the sampler can re-pick the last token.
+    ///
+    /// The allowed token IDs are computed after the rollback, and they are restricted to the candidates
+    /// when `token_healing` is set in the config.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> HealResult
+    ///
+    /// # Errors
+    ///
+    /// Raises a ValueError when no tokens are accepted since the last reset or rewind.
+    #[pyo3(name = "heal_last_token")]
+    pub fn heal_last_token_py(&mut self) -> Result<crate::engine_like::HealResult, RollbackError> {
+        EngineLike::heal_last_token(self)
+    }
     /// Gets the vocabulary of the engine.
     ///
     /// # Signature
@@ -750,6 +1677,89 @@ impl Engine {
         EngineLike::mask_logits(self, logits)
     }
 
+    /// Computes the allowed token IDs of each engine and masks the corresponding row of the logits,
+    /// which is much cheaper than masking the rows one by one in Python.
+    ///
+    /// The rows are located by the pointer of the first row and the stride between two rows,
+    /// so a 2D float32 array of shape `[len(engines), length]` can be masked in one call.
+    ///
+    /// # Signature
+    ///
+    /// (engines: List[InternalEngine], logits_ptr: int, length: int, stride: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `engines` - The engines, one for each row.
+    /// * `logits_ptr` - The pointer to the first row of the logits.
+    /// * `length` - The length of each row.
+    /// * `stride` - The number of float32 elements between the starts of two adjacent rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the stride is less than the length,
+    /// or a row is shorter than the vocabulary size of its engine.
+    /// Neither the engines nor the logits are updated in this case.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable, aligned memory that contains float32,
+    /// and the length and the stride are correct.
+    #[staticmethod]
+    #[pyo3(name = "mask_logits_batch")]
+    pub unsafe fn mask_logits_batch_py(
+        py: Python<'_>,
+        mut engines: Vec<pyo3::PyRefMut<'_, Engine>>,
+        logits_ptr: usize,
+        length: usize,
+        stride: usize,
+    ) -> Result<(), MaskLogitsError> {
+        if stride < length {
+            return Err(MaskLogitsError::InvalidLogitsLength);
+        }
+        let mut engines: Vec<&mut Engine> = engines.iter_mut().map(|x| &mut **x).collect();
+        // SAFETY: The rows do not overlap since the stride is not less than the length.
+        let rows = (0..engines.len())
+            .map(|i| {
+                std::slice::from_raw_parts_mut((logits_ptr as *mut f32).add(i * stride), length)
+            })
+            .collect();
+        py.allow_threads(|| crate::engine::mask_logits_rows(&mut engines, rows))
+    }
+
+    /// Adds the logarithm of the priors of the weighted alternatives(`"A" %0.9`) to the logits.
+    ///
+    /// Only the weighted alternatives that start at current states are considered.
+    /// A token consistent with some of them is biased by the logarithm of their priors' sum,
+    /// while other tokens are left unchanged.
+    /// Each weighted alternative costs one pass over the vocabulary unless the pass is cached when the cache is enabled.
+    ///
+    /// # Signature
+    ///
+    /// (self, logits_ptr: int, length: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `logits_ptr` - The pointer to the logits array.
+    /// * `length` - The length of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the input logits array is not of the expected length according to the vocabulary.
+    /// The logits array is not updated in this case.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable,aligned memory that contains float32 and the length is correct.
+    #[pyo3(name = "bias_logits_by_grammar_prior")]
+    pub unsafe fn bias_logits_by_grammar_prior_py(
+        &self,
+        logits_ptr: usize,
+        length: usize,
+    ) -> Result<(), MaskLogitsError> {
+        let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut f32, length);
+        EngineLike::bias_logits_by_grammar_prior(self, logits)
+    }
+
     /// Try to accept the token ID and if succeeds, update the given logits array.
     ///
     /// # Signature
@@ -785,6 +1795,36 @@ impl Engine {
         let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut f32, length);
         EngineLike::update_logits(self, token_id, logits)
     }
+    /// Try to accept the token given as its string and if succeeds, update the given logits array.
+    ///
+    /// # Signature
+    ///
+    /// (self, token_str: str, logits_ptr: int, length: int) -> AcceptTokenResult
+    ///
+    /// # Arguments
+    ///
+    /// * `token_str` - The string of the token.
+    /// * `logits_ptr` - The pointer to the logits array.
+    /// * `length` - The length of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`UpdateLogitsError::UnknownTokenStr`] when the string is not a known token.
+    /// See [`EngineLike::update_logits_for_token_str`] for more details.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable,aligned memory that contains float32 and the length is correct.
+    #[pyo3(name = "update_logits_for_token_str")]
+    pub unsafe fn update_logits_for_token_str_py(
+        &mut self,
+        token_str: &str,
+        logits_ptr: usize,
+        length: usize,
+    ) -> Result<AcceptTokenResult, UpdateLogitsError> {
+        let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut f32, length);
+        EngineLike::update_logits_for_token_str(self, token_str, logits)
+    }
 
     fn __repr__(&self) -> String {
         format!("Engine({:#?})", self)
@@ -810,6 +1850,21 @@ impl Config {
     pub fn new_js() -> Config {
         Config::default()
     }
+    /// Creates a configuration for large vocabularies. See [`Config::for_large_vocab`].
+    #[wasm_bindgen(js_name = forLargeVocab)]
+    pub fn for_large_vocab_js() -> Config {
+        Config::for_large_vocab()
+    }
+    /// Creates a configuration that keeps the memory usage small. See [`Config::low_memory`].
+    #[wasm_bindgen(js_name = lowMemory)]
+    pub fn low_memory_js() -> Config {
+        Config::low_memory()
+    }
+    /// Creates a configuration for the speed of computing the allowed token IDs. See [`Config::max_throughput`].
+    #[wasm_bindgen(js_name = maxThroughput)]
+    pub fn max_throughput_js() -> Config {
+        Config::max_throughput()
+    }
 }
 
 #[cfg(feature = "python")]
@@ -820,4 +1875,34 @@ impl Config {
     pub fn new_py() -> Config {
         Config::default()
     }
+    /// Creates a configuration for large vocabularies. See [`Config::for_large_vocab`].
+    ///
+    /// # Signature
+    ///
+    /// () -> Config
+    #[staticmethod]
+    #[pyo3(name = "for_large_vocab")]
+    pub fn for_large_vocab_py() -> Config {
+        Config::for_large_vocab()
+    }
+    /// Creates a configuration that keeps the memory usage small. See [`Config::low_memory`].
+    ///
+    /// # Signature
+    ///
+    /// () -> Config
+    #[staticmethod]
+    #[pyo3(name = "low_memory")]
+    pub fn low_memory_py() -> Config {
+        Config::low_memory()
+    }
+    /// Creates a configuration for the speed of computing the allowed token IDs. See [`Config::max_throughput`].
+    ///
+    /// # Signature
+    ///
+    /// () -> Config
+    #[staticmethod]
+    #[pyo3(name = "max_throughput")]
+    pub fn max_throughput_py() -> Config {
+        Config::max_throughput()
+    }
 }
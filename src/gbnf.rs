@@ -0,0 +1,294 @@
+//! The module that translates llama.cpp's GBNF grammars into KBNF grammars.
+use crate::utils::{kbnf_regex, kbnf_terminal};
+
+/// Translate a GBNF grammar into a KBNF grammar, where the `root` rule becomes the `start` nonterminal.
+///
+/// The supported syntax includes rules separated by newlines, `"literals"`, `[a-z]` and `[^a-z]` character classes,
+/// `.`, rule references, parentheses, alternations, `*`, `+`, `?`, `{m}`, `{m,}`, `{m,n}` and `#` comments.
+pub(crate) fn gbnf_to_kbnf(gbnf: &str) -> Result<String, String> {
+    let mut parser = GbnfParser {
+        input: gbnf,
+        position: 0,
+    };
+    let mut kbnf = String::new();
+    let mut has_root = false;
+    parser.skip_space(true);
+    while !parser.is_at_end() {
+        let name = parser.parse_name()?;
+        has_root |= name == "root";
+        parser.skip_space(false);
+        if !parser.eat("::=") {
+            return Err(parser.error("expecting ::="));
+        }
+        parser.skip_space(true);
+        let alternations = parser.parse_alternations(false)?;
+        if !parser.is_at_end() && !parser.eat("\r\n") && !parser.eat("\n") {
+            return Err(parser.error("expecting newline or end"));
+        }
+        parser.skip_space(true);
+        kbnf.push_str(&format!("{} ::= {alternations};\n", nonterminal(name)));
+    }
+    if !has_root {
+        return Err("the grammar does not define the root rule".to_string());
+    }
+    Ok(kbnf)
+}
+
+/// Map a GBNF rule name to a KBNF nonterminal.
+///
+/// GBNF rule names consist of ASCII alphanumerics and `-`, so replacing `-` with `_` is injective.
+/// The prefix `_` is reserved for names that start with a digit and the `start` rule.
+fn nonterminal(name: &str) -> String {
+    match name {
+        "root" => "start".to_string(),
+        "start" => "_start".to_string(),
+        name if name.starts_with(|c: char| c.is_ascii_digit()) => {
+            format!("_{}", name.replace('-', "_"))
+        }
+        name => name.replace('-', "_"),
+    }
+}
+
+/// Escape a character in a regex character class.
+fn class_char(c: char) -> String {
+    if c.is_ascii_alphanumeric() {
+        c.to_string()
+    } else {
+        format!("\\x{{{:X}}}", c as u32)
+    }
+}
+
+struct GbnfParser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> GbnfParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.position >= self.input.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            self.position += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        let line = self.input[..self.position].matches('\n').count() + 1;
+        let context: String = self.rest().chars().take(20).collect();
+        format!("{message} at line {line}: {context:?}")
+    }
+
+    /// Skip spaces, tabs and comments, and newlines if `newline_ok` is true.
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.position += 1,
+                '#' => match self.rest().find('\n') {
+                    Some(i) => self.position += i,
+                    None => self.position = self.input.len(),
+                },
+                '\r' | '\n' if newline_ok => self.position += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expecting name"));
+        }
+        self.position += len;
+        Ok(&rest[..len])
+    }
+
+    fn parse_alternations(&mut self, nested: bool) -> Result<String, String> {
+        let mut alternations = vec![self.parse_sequence(nested)?];
+        while self.eat("|") {
+            self.skip_space(true);
+            alternations.push(self.parse_sequence(nested)?);
+        }
+        Ok(alternations.join(" | "))
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<String, String> {
+        let mut sequence: Vec<String> = Vec::new();
+        loop {
+            let item = match self.peek() {
+                Some('"') => {
+                    self.position += 1;
+                    let mut literal = String::new();
+                    while !self.eat("\"") {
+                        if self.is_at_end() {
+                            return Err(self.error("unexpected end of input"));
+                        }
+                        literal.push(self.parse_char()?);
+                    }
+                    kbnf_terminal(&literal)
+                }
+                Some('[') => {
+                    self.position += 1;
+                    let mut class = String::from("[");
+                    if self.eat("^") {
+                        class.push('^');
+                    }
+                    while !self.eat("]") {
+                        if self.is_at_end() {
+                            return Err(self.error("unexpected end of input"));
+                        }
+                        class.push_str(&class_char(self.parse_char()?));
+                        if self.rest().starts_with('-') && !self.rest().starts_with("-]") {
+                            self.position += 1;
+                            if self.is_at_end() {
+                                return Err(self.error("unexpected end of input"));
+                            }
+                            class.push('-');
+                            class.push_str(&class_char(self.parse_char()?));
+                        }
+                    }
+                    class.push(']');
+                    if class == "[]" || class == "[^]" {
+                        return Err(self.error("empty character class"));
+                    }
+                    kbnf_regex(&class)
+                }
+                Some('(') => {
+                    self.position += 1;
+                    self.skip_space(true);
+                    let alternations = self.parse_alternations(true)?;
+                    if !self.eat(")") {
+                        return Err(self.error("expecting )"));
+                    }
+                    format!("({alternations})")
+                }
+                Some('.') => {
+                    self.position += 1;
+                    kbnf_regex("(?s:.)")
+                }
+                Some('<') => return Err(self.error("token references are not supported")),
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' => nonterminal(self.parse_name()?),
+                _ => break,
+            };
+            self.skip_space(nested);
+            let item = self.parse_repetition(item, nested)?;
+            sequence.extend(item);
+        }
+        if sequence.is_empty() {
+            // An empty sequence matches the empty string.
+            return Ok("\"\"".to_string());
+        }
+        Ok(sequence.join(" "))
+    }
+
+    /// Parse the postfix operators of an item and return the expanded sequence.
+    fn parse_repetition(&mut self, item: String, nested: bool) -> Result<Vec<String>, String> {
+        let sequence = match self.peek() {
+            Some(c @ ('*' | '+' | '?')) => {
+                self.position += 1;
+                vec![format!("{item}{c}")]
+            }
+            Some('{') => {
+                self.position += 1;
+                self.skip_space(nested);
+                let min = self.parse_integer()?;
+                self.skip_space(nested);
+                let max = if self.eat(",") {
+                    self.skip_space(nested);
+                    match self.peek() {
+                        Some('}') => None,
+                        _ => Some(self.parse_integer()?),
+                    }
+                } else {
+                    Some(min)
+                };
+                self.skip_space(nested);
+                if !self.eat("}") {
+                    return Err(self.error("expecting }"));
+                }
+                if max.is_some_and(|max| max < min) {
+                    return Err(self.error("the maximum repetition is less than the minimum"));
+                }
+                let mut sequence = vec![item.clone(); min];
+                match max {
+                    None => sequence.push(format!("{item}*")),
+                    Some(max) if max > min => {
+                        let mut optional = format!("{item}?");
+                        for _ in min + 1..max {
+                            optional = format!("({item} {optional})?");
+                        }
+                        sequence.push(optional);
+                    }
+                    Some(_) => {}
+                }
+                sequence
+            }
+            _ => return Ok(vec![item]),
+        };
+        self.skip_space(nested);
+        Ok(sequence)
+    }
+
+    fn parse_integer(&mut self) -> Result<usize, String> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let integer = rest[..len]
+            .parse()
+            .map_err(|_| self.error("expecting integer"))?;
+        self.position += len;
+        Ok(integer)
+    }
+
+    /// Parse a possibly escaped character in a literal or a character class.
+    fn parse_char(&mut self) -> Result<char, String> {
+        let Some(c) = self.peek() else {
+            return Err(self.error("unexpected end of input"));
+        };
+        self.position += c.len_utf8();
+        if c != '\\' {
+            return Ok(c);
+        }
+        let Some(escaped) = self.peek() else {
+            return Err(self.error("unexpected end of input"));
+        };
+        self.position += escaped.len_utf8();
+        let hex_len = match escaped {
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'n' => return Ok('\n'),
+            '\\' | '"' | '[' | ']' => return Ok(escaped),
+            _ => return Err(self.error("unknown escape")),
+        };
+        let hex = self
+            .rest()
+            .get(..hex_len)
+            .filter(|x| x.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("expecting hex digits"))?;
+        let c = u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error("invalid code point"))?;
+        self.position += hex_len;
+        Ok(c)
+    }
+}
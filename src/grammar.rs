@@ -3,14 +3,19 @@ use std::fmt::Debug;
 use std::hash::Hash;
 
 use crate::config::RegexConfig;
-use crate::utils::{self, dispatch_by_dfa_state_status, ByteSet};
+use crate::diagnostic::GrammarDiagnostic;
+use crate::preprocessor::{
+    self, ANY_CHAR_REGEX_MARKER, ATOMIC_REGEX_MARKER, BOUNDED_SUBSTRINGS_REGEX_MARKER,
+    JSON_REGEX_MARKER, LENGTH_PREFIXED_REGEX_MARKER, PARTIAL_REGEX_MARKER, PRIOR_REGEX_MARKER,
+};
+use crate::utils::{self, dispatch_by_dfa_state_status, ByteSet, GrammarAnalysis};
 use crate::Vocabulary;
 use ahash::AHashMap;
 use fixedbitset_stack::FixedBitSet;
 use general_sam::GeneralSamNodeID;
 use jaggedarray::jagged_array::JaggedArrayViewTrait;
 use jaggedarray::jagged_array::{JaggedArray, JaggedArrayView};
-use kbnf_regex_automata::dfa::Automaton;
+use kbnf_regex_automata::dfa::{dense, Automaton};
 use kbnf_regex_automata::util::primitives::StateID;
 use kbnf_syntax::node::{OperatorFlattenedNode, Rhs};
 use kbnf_syntax::simplified_grammar::SimplifiedGrammar;
@@ -23,8 +28,9 @@ use num::{
     traits::{ConstOne, ConstZero},
     Num,
 };
+use string_interner::backend::StringBackend;
 use string_interner::symbol::SymbolU32;
-use string_interner::Symbol;
+use string_interner::{StringInterner, Symbol};
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord)]
 #[repr(transparent)]
 /// The wrapper struct that represents the terminal id in the grammar.
@@ -49,7 +55,7 @@ where
     pub fn to_display_form(&self, grammar: &Grammar<T>) -> String {
         format!(
             "\"{}\"[{}]",
-            grammar.terminal_str(*self).unwrap(),
+            preprocessor::display_raw_bytes(grammar.terminal_str(*self).unwrap()),
             self.0.as_()
         )
     }
@@ -107,7 +113,7 @@ where
     pub fn to_display_form(&self, grammar: &Grammar<T>) -> String {
         format!(
             "#\"{}\"[{}]",
-            grammar.regex_str(*self).unwrap(),
+            preprocessor::display_raw_bytes(grammar.regex_str(*self).unwrap()),
             self.0.as_()
         )
     }
@@ -142,6 +148,413 @@ where
         )
     }
 }
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(transparent)]
+/// The wrapper struct that represents the length-prefixed node id in the grammar.
+pub struct LengthPrefixedID<T>(pub T)
+where
+    T: Num + AsPrimitive<usize> + ConstOne + ConstZero;
+impl<T> LengthPrefixedID<T>
+where
+    T: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<T>,
+{
+    /// Get the display form of the length-prefixed node id.
+    pub fn to_display_form(&self, grammar: &Grammar<T>) -> String {
+        let length_prefixed = grammar.length_prefixed(*self);
+        format!(
+            "#lenprefixed(#\"{}\", #\"{}\")[{}]",
+            length_prefixed.numeric_regex_str(),
+            length_prefixed.content_regex_str(),
+            self.0.as_()
+        )
+    }
+}
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(transparent)]
+/// The wrapper struct that represents the bounded substrings node id in the grammar.
+pub struct BoundedSubstringsID<T>(pub T)
+where
+    T: Num + AsPrimitive<usize> + ConstOne + ConstZero;
+impl<T> BoundedSubstringsID<T>
+where
+    T: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<T>,
+{
+    /// Get the display form of the bounded substrings node id.
+    pub fn to_display_form(&self, grammar: &Grammar<T>) -> String {
+        let bounded_substrings = grammar.bounded_substrings(*self);
+        format!(
+            "#substrs({},{})\"{}\"[{}]",
+            bounded_substrings.min(),
+            bounded_substrings.max(),
+            bounded_substrings.string(),
+            self.0.as_()
+        )
+    }
+}
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(transparent)]
+/// The wrapper struct that represents the byte set complement node id in the grammar.
+pub struct ByteSetID<T>(pub T)
+where
+    T: Num + AsPrimitive<usize> + ConstOne + ConstZero;
+impl<T> ByteSetID<T>
+where
+    T: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<T>,
+{
+    /// Get the display form of the byte set complement node id.
+    ///
+    /// The excluded ASCII characters are shown as a negated class.
+    pub fn to_display_form(&self, grammar: &Grammar<T>) -> String {
+        format!("#\"{}\"[{}]", self.negated_class(grammar), self.0.as_())
+    }
+
+    /// Get the negated class of the excluded ASCII characters, like `[^,\n]`.
+    fn negated_class(&self, grammar: &Grammar<T>) -> String {
+        let byte_set = grammar.byte_set_complement(*self);
+        let mut class = String::new();
+        let mut byte = 0u8;
+        while byte < 0x80 {
+            if byte_set.contains(byte as usize) {
+                byte += 1;
+                continue;
+            }
+            let mut end = byte;
+            while end < 0x7F && !byte_set.contains(end as usize + 1) {
+                end += 1;
+            }
+            class.push_str(&escape_class_byte(byte));
+            if end - byte >= 2 {
+                class.push('-');
+            }
+            if end > byte {
+                class.push_str(&escape_class_byte(end));
+            }
+            byte = end + 1;
+        }
+        format!("[^{}]", class)
+    }
+}
+
+fn escape_class_byte(byte: u8) -> String {
+    match byte {
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\r' => "\\r".to_string(),
+        b'\\' | b'[' | b']' | b'^' | b'-' => format!("\\{}", byte as char),
+        x if x.is_ascii_graphic() || x == b' ' => (x as char).to_string(),
+        x => format!("\\x{{{:X}}}", x),
+    }
+}
+
+/// Parse a regex that consists of a single negated class of ASCII characters, like `[^,\n]`,
+/// and return the bytes that the class excludes.
+///
+/// Returns `None` for any other regex, including classes with non-ASCII characters,
+/// Unicode classes like `\d` and set operations like `&&`. Those regexes are compiled into DFAs as usual.
+fn parse_negated_ascii_class(regex: &str) -> Option<ByteSet> {
+    let body = regex.strip_prefix("[^")?.strip_suffix(']')?;
+    if body.is_empty()
+        || body.starts_with(']')
+        || ["[", "&&", "--", "~~"].iter().any(|x| body.contains(x))
+    {
+        return None;
+    }
+    // None represents an unescaped `-`.
+    let mut atoms: Vec<Option<u8>> = Vec::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        atoms.push(match c {
+            '-' => None,
+            ']' => return None,
+            '\\' => Some(match chars.next()? {
+                'n' => b'\n',
+                't' => b'\t',
+                'r' => b'\r',
+                'f' => 0x0C,
+                'v' => 0x0B,
+                'a' => 0x07,
+                'x' => {
+                    let rest = chars.as_str();
+                    let (digits, len) = match rest.strip_prefix('{') {
+                        Some(rest) => {
+                            let end = rest.find('}')?;
+                            (&rest[..end], end + 2)
+                        }
+                        None => (rest.get(..2)?, 2),
+                    };
+                    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return None;
+                    }
+                    let value = u32::from_str_radix(digits, 16).ok()?;
+                    chars = rest[len..].chars();
+                    u8::try_from(value).ok().filter(u8::is_ascii)?
+                }
+                c if c.is_ascii_punctuation() && c != '<' && c != '>' => c as u8,
+                _ => return None,
+            }),
+            c if c.is_ascii() => Some(c as u8),
+            _ => return None,
+        });
+    }
+    let mut excluded = ByteSet::with_capacity(256);
+    let mut i = 0;
+    while i < atoms.len() {
+        match (atoms[i], atoms.get(i + 1), atoms.get(i + 2)) {
+            (Some(start), Some(None), Some(&Some(end))) => {
+                if start > end {
+                    return None;
+                }
+                excluded.insert_range(start as usize..end as usize + 1);
+                i += 3;
+            }
+            (Some(byte), _, _) => {
+                excluded.insert(byte as usize);
+                i += 1;
+            }
+            // A literal `-` is only unambiguous at the start or the end of the class.
+            (None, _, _) if i == 0 || i == atoms.len() - 1 => {
+                excluded.insert(b'-' as usize);
+                i += 1;
+            }
+            (None, _, _) => return None,
+        }
+    }
+    Some(excluded)
+}
+/// The length-prefixed node(`#lenprefixed(numeric, content)`) in the grammar.
+///
+/// The numeric regex matches the length field first,
+/// whose ASCII digits are read as a decimal number.
+/// The content regex then matches exactly that many bytes.
+#[derive(Debug, Clone)]
+pub struct LengthPrefixed {
+    numeric_regex: String,
+    content_regex: String,
+    numeric: dense::DFA<Vec<u32>>,
+    content: dense::DFA<Vec<u32>>,
+    numeric_start: StateID,
+    content_start: StateID,
+}
+
+impl LengthPrefixed {
+    fn new(
+        numeric_regex: String,
+        content_regex: String,
+        regex_config: &RegexConfig,
+    ) -> Result<Self, CreateGrammarError> {
+        let build = |regex: &str| {
+            let config = regex_config.dfa_config();
+            dense::Builder::new()
+                .configure(config.clone())
+                .syntax(regex_config.syntax_config())
+                .build(&format!("\\A(?:{regex})\\z"))
+                .map_err(|e| match utils::exceeded_size_limit(&e, &config) {
+                    Some(limit) => CreateGrammarError::RegexTooLargeToCompile {
+                        regex: regex.to_string(),
+                        limit,
+                    },
+                    None => CreateGrammarError::PreprocessingError(format!(
+                        "the regex {regex} in #lenprefixed cannot be compiled: {e}"
+                    )),
+                })
+        };
+        let numeric = build(&numeric_regex)?;
+        let content = build(&content_regex)?;
+        Self::from_dfas(numeric_regex, content_regex, numeric, content)
+    }
+    fn from_dfas(
+        numeric_regex: String,
+        content_regex: String,
+        numeric: dense::DFA<Vec<u32>>,
+        content: dense::DFA<Vec<u32>>,
+    ) -> Result<Self, CreateGrammarError> {
+        let start_config = kbnf_regex_automata::util::start::Config::new()
+            .anchored(kbnf_regex_automata::Anchored::Yes);
+        let numeric_start = numeric.start_state(&start_config)?;
+        let content_start = content.start_state(&start_config)?;
+        Ok(Self {
+            numeric_regex,
+            content_regex,
+            numeric,
+            content,
+            numeric_start,
+            content_start,
+        })
+    }
+    /// Get the numeric regex string.
+    pub fn numeric_regex_str(&self) -> &str {
+        &self.numeric_regex
+    }
+    /// Get the content regex string.
+    pub fn content_regex_str(&self) -> &str {
+        &self.content_regex
+    }
+    /// Get the DFA of the numeric regex.
+    pub fn numeric(&self) -> &dense::DFA<Vec<u32>> {
+        &self.numeric
+    }
+    /// Get the DFA of the content regex.
+    pub fn content(&self) -> &dense::DFA<Vec<u32>> {
+        &self.content
+    }
+    /// Get the anchored start state of the numeric regex.
+    pub fn numeric_start(&self) -> StateID {
+        self.numeric_start
+    }
+    /// Get the anchored start state of the content regex.
+    pub fn content_start(&self) -> StateID {
+        self.content_start
+    }
+}
+/// The bounded substrings node(`#substrs(min,max)"..."`) in the grammar.
+///
+/// It matches the substrings of the string whose lengths in bytes are between the minimum and the maximum.
+/// The minimum is at least 1, since the grammar makes the node optional when the empty substring is allowed.
+#[derive(Debug, Clone)]
+pub struct BoundedSubstrings {
+    string: String,
+    suffix_automaton: SuffixAutomaton,
+    first_bytes: Vec<ByteSet>,
+    min: usize,
+    max: usize,
+}
+
+impl BoundedSubstrings {
+    fn new(string: String, min: usize, max: usize) -> Self {
+        let suffix_automaton = SuffixAutomaton::from_bytes(&string);
+        let first_bytes = (0..suffix_automaton.num_of_nodes())
+            .map(|node_id| {
+                let mut set = ByteSet::with_capacity(256);
+                let state = suffix_automaton.get_state(node_id);
+                for byte in 0..=u8::MAX {
+                    let mut state = state.clone();
+                    state.feed([byte]);
+                    if !state.is_nil() {
+                        set.insert(byte as usize);
+                    }
+                }
+                set
+            })
+            .collect();
+        Self {
+            string,
+            suffix_automaton,
+            first_bytes,
+            min,
+            max,
+        }
+    }
+    /// Get the string whose substrings are matched.
+    pub fn string(&self) -> &str {
+        &self.string
+    }
+    /// Get the suffix automaton of the string.
+    pub fn suffix_automaton(&self) -> &SuffixAutomaton {
+        &self.suffix_automaton
+    }
+    /// Get the bytes that can follow the node of the suffix automaton.
+    pub fn first_bytes(&self, node_id: GeneralSamNodeID) -> &ByteSet {
+        &self.first_bytes[node_id]
+    }
+    /// Get the minimum length in bytes.
+    pub fn min(&self) -> usize {
+        self.min
+    }
+    /// Get the maximum length in bytes.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+/// The regex with named capture groups like `#"(?P<year>[0-9]{4})"` in the grammar.
+///
+/// The DFA of the regex cannot report the groups, so the regex is compiled once more into a regex with captures,
+/// which is only matched again on the bytes spanned by the regex by [`EngineLike::captures`](crate::engine_like::EngineLike::captures).
+#[derive(Debug, Clone)]
+pub struct CaptureRegex {
+    pattern: String,
+    byte_mode: bool,
+    regex: kbnf_regex_automata::meta::Regex,
+}
+
+impl CaptureRegex {
+    /// Returns the message of the error if the regex cannot be compiled.
+    fn new(pattern: String, byte_mode: bool) -> Result<Self, String> {
+        let regex = kbnf_regex_automata::meta::Regex::builder()
+            .syntax(
+                kbnf_regex_automata::util::syntax::Config::new()
+                    .unicode(!byte_mode)
+                    .utf8(false),
+            )
+            .build(&pattern)
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            pattern,
+            byte_mode,
+            regex,
+        })
+    }
+    /// Get the regex string with the raw bytes lowered, which is compiled into the regex with captures.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+    /// Check whether the regex is compiled in byte mode, i.e. with [`RegexConfig::dot_matches_bytes`].
+    pub fn byte_mode(&self) -> bool {
+        self.byte_mode
+    }
+    /// Get the names of the groups and the bytes they capture when the regex matches all the bytes.
+    ///
+    /// The groups that do not participate in the match are omitted.
+    pub fn named_groups<'a>(&self, bytes: &'a [u8]) -> Vec<(&str, &'a [u8])> {
+        let mut captures = self.regex.create_captures();
+        self.regex.captures(
+            kbnf_regex_automata::Input::new(bytes).anchored(kbnf_regex_automata::Anchored::Yes),
+            &mut captures,
+        );
+        self.user_group_names()
+            .filter_map(|(index, name)| Some((name, &bytes[captures.get_group(index)?.range()])))
+            .collect()
+    }
+    /// Get the indices and the names of the named groups written by the user,
+    /// skipping the internal groups like the marker of the partial regexes.
+    fn user_group_names(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.regex
+            .group_info()
+            .pattern_names(kbnf_regex_automata::PatternID::ZERO)
+            .enumerate()
+            .filter_map(|(index, name)| Some((index, name?)))
+            .filter(|(_, name)| !name.starts_with(preprocessor::GENERATED_NONTERMINAL_PREFIX))
+    }
+}
 /// The node of the grammar in HIR.
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub enum HIRNode<T>
@@ -160,6 +573,16 @@ where
     Substrings(SuffixAutomataID<T>),
     /// The regex complement node.
     RegexComplement(RegexID<T>),
+    /// The length-prefixed node.
+    LengthPrefixed(LengthPrefixedID<T>),
+    /// The byte set complement node, which matches one character outside a set of ASCII characters.
+    ByteSetComplement(ByteSetID<T>),
+    /// The any-character node(`#char`), which matches exactly one UTF-8 encoded character.
+    AnyChar,
+    /// The bounded substrings node.
+    BoundedSubstrings(BoundedSubstringsID<T>),
+    /// The JSON node(`#json`), which matches exactly one JSON value.
+    Json,
 }
 
 impl<TI> HIRNode<TI>
@@ -181,11 +604,19 @@ where
         match self {
             HIRNode::Terminal(x) => x.to_display_form(grammar),
             HIRNode::RegexString(x) => {
-                format!("#\"{}\"[{}]", grammar.regex_str(*x).unwrap(), x.0.as_())
+                format!(
+                    "#\"{}\"[{}]",
+                    preprocessor::display_raw_bytes(grammar.regex_str(*x).unwrap()),
+                    x.0.as_()
+                )
             }
             HIRNode::Nonterminal(x) => x.to_display_form(grammar),
             HIRNode::EarlyEndRegexString(x) => {
-                format!("#e\"{}\"[{}]", grammar.regex_str(*x).unwrap(), x.0.as_())
+                format!(
+                    "#e\"{}\"[{}]",
+                    preprocessor::display_raw_bytes(grammar.regex_str(*x).unwrap()),
+                    x.0.as_()
+                )
             }
             HIRNode::Substrings(x) => {
                 format!(
@@ -195,8 +626,17 @@ where
                 )
             }
             HIRNode::RegexComplement(x) => {
-                format!("#ex\"{}\"[{}]", grammar.regex_str(*x).unwrap(), x.0.as_())
+                format!(
+                    "#ex\"{}\"[{}]",
+                    preprocessor::display_raw_bytes(grammar.regex_str(*x).unwrap()),
+                    x.0.as_()
+                )
             }
+            HIRNode::LengthPrefixed(x) => x.to_display_form(grammar),
+            HIRNode::ByteSetComplement(x) => x.to_display_form(grammar),
+            HIRNode::AnyChar => "#char".to_string(),
+            HIRNode::BoundedSubstrings(x) => x.to_display_form(grammar),
+            HIRNode::Json => "#json".to_string(),
         }
     }
 }
@@ -216,8 +656,19 @@ where
     id_to_regex_first_bytes: AHashMap<(TI, StateID), ByteSet>,
     id_to_regex_complement_first_bytes: AHashMap<(TI, StateID), ByteSet>,
     id_to_terminals: JaggedArray<u8, Vec<usize>, 2>,
+    id_to_byte_set_complements: Vec<ByteSet>,
     id_to_suffix_automata: Vec<SuffixAutomaton>,
     id_to_suffix_automata_first_bytes: AHashMap<(usize, GeneralSamNodeID), ByteSet>,
+    partial_regexes: FixedBitSet,
+    id_to_capture_regexes: AHashMap<usize, CaptureRegex>,
+    nonterminal_priors: AHashMap<NonterminalID<TI>, f32>,
+    id_to_length_prefixed: Vec<LengthPrefixed>,
+    id_to_bounded_substrings: Vec<BoundedSubstrings>,
+    atomic_nonterminals: FixedBitSet,
+    nullable_nonterminals: FixedBitSet,
+    nonterminal_first_bytes: Vec<ByteSet>,
+    unreachable_nonterminals: Vec<String>,
+    nonproductive_nonterminals: Vec<String>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -226,12 +677,59 @@ pub enum CreateGrammarError {
     #[error("KBNF parsing error: {0}")]
     /// Error due to parsing the KBNF grammar.
     ParsingError(#[from] nom::Err<nom::error::VerboseError<String>>), // We have to clone the str to remove lifetime so pyo3 works later
+    #[error("GBNF parsing error: {0}")]
+    /// Error due to parsing the GBNF grammar.
+    GbnfParsingError(String),
+    #[error("KBNF preprocessing error: {0}")]
+    /// Error due to incorrect usages of the syntax extensions in the KBNF grammar.
+    PreprocessingError(String),
     #[error("KBNF semantics error: {0}")]
     /// Error due to semantic errors in the KBNF grammar.
     SemanticError(#[from] Box<kbnf_syntax::semantic_error::SemanticError>),
+    #[error("{}: {diagnostic}", .error.diagnosed_prefix())]
+    /// Error located in the grammar string, where `error` is [`CreateGrammarError::ParsingError`]
+    /// or [`CreateGrammarError::SemanticError`].
+    ///
+    /// The errors created from a grammar string are wrapped in this variant,
+    /// so match on `error` to handle the parsing and semantic errors.
+    DiagnosedError {
+        /// The parsing or semantic error.
+        #[source]
+        error: Box<CreateGrammarError>,
+        /// The location, the message and the nonterminal at fault of the error.
+        diagnostic: Box<GrammarDiagnostic>,
+    },
     #[error("The number of {0}, which is {1}, exceeds the maximum value {2}.")]
     /// Error due to the number of a certain type exceeding the maximum value specified in the generic parameter.
     IntConversionError(String, usize, usize),
+    #[error("The regex {regex} cannot be compiled within the size limit of {limit} bytes.")]
+    /// Error due to the DFA compiled from a regex exceeding the size limit in [`RegexConfig`].
+    RegexTooLargeToCompile {
+        /// The regex that is too large to compile.
+        regex: String,
+        /// The size limit in bytes that is exceeded.
+        limit: usize,
+    },
+    #[error("The fragment name {0} is used by multiple fragments.")]
+    /// Error due to multiple fragments with the same name in [`GrammarBuilder`].
+    DuplicateFragmentError(String),
+    #[error(
+        "The nonterminal {nonterminal} is defined in both fragment {first} and fragment {second}."
+    )]
+    /// Error due to a nonterminal defined in multiple fragments linked by [`GrammarBuilder`].
+    DuplicateNonterminalError {
+        /// The nonterminal defined in multiple fragments.
+        nonterminal: String,
+        /// The name of the fragment that defines the nonterminal first.
+        first: String,
+        /// The name of the fragment that defines the nonterminal again.
+        second: String,
+    },
+    #[error(
+        "The start nonterminal cannot match any string within the maximum recursion depth {0}."
+    )]
+    /// Error due to every match of the start nonterminal nesting deeper than [`Config::max_recursion_depth`](crate::config::Config::max_recursion_depth).
+    RecursionTooDeepError(usize),
     #[error("Regex initialization error: {0}")]
     /// Error when computing the start state for a DFA.
     DfaStartError(#[from] kbnf_regex_automata::dfa::StartError),
@@ -242,6 +740,183 @@ pub enum CreateGrammarError {
     /// Error due to inefficient cache usage in a lazy DFA.
     LazyDfaCacheError(#[from] kbnf_regex_automata::hybrid::CacheError),
 }
+impl CreateGrammarError {
+    /// Get the structured diagnostic of the error, which locates the error in the grammar string.
+    ///
+    /// Returns `None` for the errors that are not tied to the grammar string, e.g. the regex initialization errors.
+    /// For GBNF grammars, the location refers to the translated KBNF grammar.
+    pub fn diagnostic(&self) -> Option<&GrammarDiagnostic> {
+        match self {
+            CreateGrammarError::DiagnosedError { diagnostic, .. } => Some(diagnostic),
+            _ => None,
+        }
+    }
+
+    fn diagnosed_prefix(&self) -> &'static str {
+        match self {
+            CreateGrammarError::ParsingError(_) => "KBNF parsing error",
+            CreateGrammarError::SemanticError(_) => "KBNF semantics error",
+            _ => "KBNF error",
+        }
+    }
+}
+#[derive(Debug, thiserror::Error)]
+/// The error type for errors in [`Grammar`] deserialization.
+pub enum DeserializeGrammarError {
+    #[error("Bincode error: {0}")]
+    /// Error due to malformed bytes.
+    BincodeError(#[from] bincode::Error),
+    #[error(
+        "The serialization format version {0} is not supported; the supported version is {1}."
+    )]
+    /// Error due to bytes serialized by an incompatible version of the library.
+    UnsupportedFormatVersion(u32, u32),
+    #[error("The grammar is serialized with {0}-byte ids, but {1}-byte ids are expected.")]
+    /// Error due to the mismatched generic parameter(TI).
+    MismatchedIdWidth(usize, usize),
+    #[error("The grammar is serialized on a platform with a different endianness.")]
+    /// Error due to bytes serialized on a platform with a different endianness.
+    MismatchedEndianness,
+    #[error("Regex deserialization error: {0}")]
+    /// Error due to an invalid serialized DFA.
+    DfaDeserializeError(#[from] kbnf_regex_automata::util::wire::DeserializeError),
+    #[error("Invalid grammar data: {0}")]
+    /// Error due to inconsistent data in the serialized grammar.
+    InvalidData(String),
+    #[error("Grammar creation error: {0}")]
+    /// Error due to failures when rebuilding the grammar's automata.
+    CreateGrammarError(#[from] CreateGrammarError),
+}
+
+const SERIALIZATION_FORMAT_VERSION: u32 = 7;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+enum SerializedHIRNode {
+    Terminal(usize),
+    RegexString(usize),
+    Nonterminal(usize),
+    EarlyEndRegexString(usize),
+    Substrings(usize),
+    RegexComplement(usize),
+    LengthPrefixed(usize),
+    ByteSetComplement(usize),
+    AnyChar,
+    BoundedSubstrings(usize),
+    Json,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedLengthPrefixed {
+    numeric_regex: String,
+    content_regex: String,
+    numeric: Vec<u8>,
+    content: Vec<u8>,
+}
+
+/// The serialized form of [`Grammar`]. Ids are stored as [usize] and bitsets as their ones.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedGrammar {
+    format_version: u32,
+    id_width: usize,
+    little_endian: bool,
+    start_nonterminal_id: usize,
+    rules: Vec<Vec<Vec<SerializedHIRNode>>>,
+    nonterminals: Vec<String>,
+    terminals: Vec<String>,
+    regex_strings: Vec<String>,
+    sub_strings: Vec<String>,
+    id_to_regexes: Vec<Vec<u8>>,
+    regex_to_token_ids: Vec<(usize, u32, u8, usize, Vec<usize>)>,
+    id_to_regex_first_bytes: Vec<(usize, u32, Vec<usize>)>,
+    id_to_regex_complement_first_bytes: Vec<(usize, u32, Vec<usize>)>,
+    id_to_terminals: Vec<Vec<u8>>,
+    id_to_byte_set_complements: Vec<Vec<usize>>,
+    id_to_suffix_automata_first_bytes: Vec<(usize, usize, Vec<usize>)>,
+    partial_regexes: (usize, Vec<usize>),
+    id_to_capture_regexes: Vec<(usize, String, bool)>,
+    nonterminal_priors: Vec<(usize, f32)>,
+    id_to_length_prefixed: Vec<SerializedLengthPrefixed>,
+    id_to_bounded_substrings: Vec<(String, usize, usize)>,
+    atomic_nonterminals: (usize, Vec<usize>),
+    nullable_nonterminals: (usize, Vec<usize>),
+    unreachable_nonterminals: Vec<String>,
+    nonproductive_nonterminals: Vec<String>,
+}
+
+fn serialize_dfa(dfa: &dense::DFA<Vec<u32>>) -> Vec<u8> {
+    let (mut bytes, padding) = dfa.to_bytes_native_endian();
+    bytes.drain(..padding);
+    bytes
+}
+
+fn deserialize_dfa(bytes: &[u8]) -> Result<dense::DFA<Vec<u32>>, DeserializeGrammarError> {
+    // The DFA requires its bytes to be aligned to u32.
+    let mut buffer = vec![0u32; bytes.len().div_ceil(4)];
+    for (word, chunk) in buffer.iter_mut().zip(bytes.chunks(4)) {
+        let mut word_bytes = [0u8; 4];
+        word_bytes[..chunk.len()].copy_from_slice(chunk);
+        *word = u32::from_ne_bytes(word_bytes);
+    }
+    // SAFETY: The buffer holds at least bytes.len() initialized bytes, and any byte is a valid u8.
+    let aligned = unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), bytes.len()) };
+    let (dfa, _) = dense::DFA::from_bytes(aligned)?;
+    Ok(dfa.to_owned())
+}
+
+fn serialize_state_id(state_id: StateID) -> u32 {
+    state_id.as_u32()
+}
+
+fn deserialize_state_id(state_id: u32) -> Result<StateID, DeserializeGrammarError> {
+    StateID::new(state_id as usize)
+        .map_err(|e| DeserializeGrammarError::InvalidData(format!("invalid state id: {e}")))
+}
+
+fn serialize_bitset(bitset: &FixedBitSet) -> (usize, Vec<usize>) {
+    (bitset.len(), bitset.ones().collect())
+}
+
+fn deserialize_bitset(
+    (len, ones): (usize, Vec<usize>),
+) -> Result<FixedBitSet, DeserializeGrammarError> {
+    let mut bitset = FixedBitSet::with_capacity(len);
+    for i in ones {
+        if i >= len {
+            return Err(DeserializeGrammarError::InvalidData(format!(
+                "bit {i} exceeds the bitset length {len}"
+            )));
+        }
+        bitset.insert(i);
+    }
+    Ok(bitset)
+}
+
+fn deserialize_byte_set(ones: Vec<usize>) -> Result<ByteSet, DeserializeGrammarError> {
+    let mut set = ByteSet::with_capacity(256);
+    for i in ones {
+        if i >= 256 {
+            return Err(DeserializeGrammarError::InvalidData(format!(
+                "byte {i} exceeds the byte range"
+            )));
+        }
+        set.insert(i);
+    }
+    Ok(set)
+}
+
+fn serialize_interner(interner: &StringInterner<StringBackend<SymbolU32>>) -> Vec<String> {
+    interner.iter().map(|(_, x)| x.to_string()).collect()
+}
+
+fn deserialize_interner(strings: &[String]) -> StringInterner<StringBackend<SymbolU32>> {
+    let mut interner = StringInterner::new();
+    // The strings are interned in the order of their symbols, so the symbols are preserved.
+    for string in strings {
+        interner.get_or_intern(string);
+    }
+    interner
+}
+
 impl<TI> Debug for Grammar<TI>
 where
     TI: Num
@@ -357,6 +1032,177 @@ pub(crate) enum RegexType {
     Complement,
 }
 
+/// Wrap a regex into a complete KBNF grammar `start ::= #"...";`,
+/// or `start ::= #e"...";` when `early_end` is `true`.
+///
+/// The regex is escaped so that quotes, backslashes and newlines reach the regex engine unchanged.
+pub fn regex_to_kbnf(pattern: &str, early_end: bool) -> String {
+    let prefix = if early_end { "#e" } else { "#" };
+    format!(
+        "start ::= {prefix}\"{}\";",
+        utils::escape_kbnf_string(pattern)
+    )
+}
+
+/// The builder that links multiple named KBNF grammar fragments into one grammar string,
+/// so that common rules can be shared across grammars.
+///
+/// The fragments share one namespace, so a fragment can reference any nonterminal defined in another fragment.
+/// A nonterminal may be defined by multiple rules in one fragment as usual,
+/// but defining it in multiple fragments is an error rather than a silent merge.
+/// The linking is purely in memory; loading the fragments from files is left to the caller.
+///
+/// # Example
+///
+/// ```rust
+/// use kbnf::grammar::GrammarBuilder;
+/// let grammar = GrammarBuilder::new()
+///     .fragment("common", "digit ::= #\"[0-9]\";")
+///     .fragment("main", "start ::= digit digit;")
+///     .link()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct GrammarBuilder {
+    fragments: Vec<(String, String)>,
+}
+
+impl GrammarBuilder {
+    /// Create a new [`GrammarBuilder`] without any fragments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a fragment with the given name and KBNF grammar string.
+    ///
+    /// The name is only used in the error messages and must be unique among the fragments.
+    pub fn fragment(mut self, name: impl Into<String>, grammar: impl Into<String>) -> Self {
+        self.fragments.push((name.into(), grammar.into()));
+        self
+    }
+    /// Link the fragments into one KBNF grammar string in the order they are added,
+    /// which can be passed to [`Engine::new`](crate::engine::Engine::new) or [`Engine::with_config`](crate::engine::Engine::with_config).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CreateGrammarError::DuplicateFragmentError`] when multiple fragments share a name,
+    /// or [`CreateGrammarError::DuplicateNonterminalError`] when a nonterminal is defined in multiple fragments.
+    /// The undefined nonterminals are reported when the linked grammar is parsed, like in a single grammar string.
+    pub fn link(&self) -> Result<String, CreateGrammarError> {
+        let mut definitions: AHashMap<&str, &str> = AHashMap::default();
+        for (i, (name, grammar)) in self.fragments.iter().enumerate() {
+            if self.fragments[..i].iter().any(|(x, _)| x == name) {
+                return Err(CreateGrammarError::DuplicateFragmentError(name.clone()));
+            }
+            for nonterminal in preprocessor::defined_nonterminals(grammar) {
+                match definitions.get(nonterminal) {
+                    Some(&first) if first != name => {
+                        return Err(CreateGrammarError::DuplicateNonterminalError {
+                            nonterminal: nonterminal.to_string(),
+                            first: first.to_string(),
+                            second: name.clone(),
+                        })
+                    }
+                    _ => {
+                        definitions.insert(nonterminal, name);
+                    }
+                }
+            }
+        }
+        Ok(self
+            .fragments
+            .iter()
+            .map(|(_, grammar)| grammar.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+/// A warning about a likely source of ambiguity in the grammar, found by [`Grammar::analyze_ambiguity`].
+///
+/// An ambiguous grammar is still accepted by the engine, but each ambiguous parse is tracked separately,
+/// which may slow down the engine considerably.
+#[derive(Debug, displaydoc::Display, Clone, PartialEq, Eq, Hash)]
+pub enum AmbiguityWarning {
+    /// two productions of the nonterminal `{nonterminal}` start with overlapping prefixes.
+    OverlappingPrefixes {
+        /// The nonterminal whose productions overlap.
+        nonterminal: String,
+    },
+    /// the nonterminal `{nonterminal}` can reduce the same input via either `{first_nonterminal}` or `{second_nonterminal}`.
+    ReduceReduceConflict {
+        /// The nonterminal whose productions conflict.
+        nonterminal: String,
+        /// The first nonterminal that matches the same input.
+        first_nonterminal: String,
+        /// The second nonterminal that matches the same input.
+        second_nonterminal: String,
+    },
+    /// the nonterminal `{nonterminal}` has a production that both starts and ends with itself, so its associativity is ambiguous.
+    AmbiguousRecursion {
+        /// The recursive nonterminal.
+        nonterminal: String,
+    },
+}
+
+impl<TI> Grammar<TI>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq
+        + Debug
+        + Send
+        + Sync,
+    usize: num::traits::AsPrimitive<TI>,
+{
+    /// Generates a random string accepted by the grammar, which is useful as synthetic model output in tests.
+    ///
+    /// The string is built by a random walk that picks one of the allowed first bytes uniformly at each step.
+    /// Finishing becomes more likely as the string grows, and the shortest completion is appended
+    /// when the walk finishes, so recursive grammars do not generate unbounded strings.
+    /// The bytes after which the string cannot be finished within `max_len` are never picked.
+    /// The grammar is cloned into a fresh engine, so prefer reusing the samples over calling it in a hot loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - The source of random numbers, e.g. `|| rng.gen()` with the `rand` crate.
+    /// * `max_len` - The maximum length of the string in bytes. `usize::MAX` means no limit,
+    ///   where the walk almost never finishes early, so only the finite grammars end in reasonable time.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<u8>>` - The string, or `None` if no string within `max_len` bytes is found by the walk.
+    pub fn sample(&self, rng: &mut impl FnMut() -> u64, max_len: usize) -> Option<Vec<u8>> {
+        let config = crate::engine::EngineConfig {
+            cache_enabled: false,
+            // The bytes after which the string cannot be finished in time are rolled back,
+            // which is cheap only without compaction.
+            compaction_enabled: false,
+            compaction_interval: 1,
+            max_cache_entries: None,
+            max_earley_set_size: None,
+            max_total_items: None,
+            // The same random numbers always walk the same way.
+            deterministic: true,
+        };
+        // 32-bit integers fit any grammar that the smaller engines accept.
+        let mut engine = crate::engine_base::EngineBase::<TI, u32, u32, u32, u32>::new(
+            std::sync::Arc::new(Vocabulary::byte_level()),
+            std::sync::Arc::new(self.clone()),
+            config,
+        )
+        .ok()?;
+        engine.sample(rng, max_len)
+    }
+}
+
 impl<TI> Grammar<TI>
 where
     TI: Num
@@ -374,6 +1220,8 @@ where
 {
     /// Create a new grammar from a simplified KBNF grammar and configuration.
     ///
+    /// The regexes compiled into equivalent DFAs, like `#"[0-9]+"` and `#"[0-9][0-9]*"`, share one regex ID.
+    ///
     /// # Arguments
     ///
     /// * `grammar` - The simplified KBNF grammar.
@@ -395,9 +1243,42 @@ where
         let mut id_to_terminals = JaggedArray::<u8, Vec<usize>, 2>::new();
         for (id, terminal) in grammar.interned_strings.terminals.iter() {
             id_to_terminals.new_row::<0>();
-            id_to_terminals.extend_last_row_from_slice(terminal.as_bytes());
+            id_to_terminals.extend_last_row_from_slice(&preprocessor::lower_raw_bytes(terminal));
             assert!(id_to_terminals.len() - 1 == id.to_usize());
         }
+        let (id_to_length_prefixed, regex_to_length_prefixed) =
+            Self::construct_length_prefixed(&grammar.interned_strings, &regex_config)?;
+        let (id_to_bounded_substrings, regex_to_bounded_substrings) =
+            Self::construct_bounded_substrings(&grammar.interned_strings);
+        let (id_to_byte_set_complements, regex_to_byte_set_complement) =
+            if regex_config.dot_matches_bytes {
+                // The byte sets assume that the classes match UTF-8 encoded characters.
+                (Vec::new(), AHashMap::default())
+            } else {
+                Self::construct_byte_set_complements(&grammar.interned_strings)
+            };
+        let mut id_to_regexes = grammar.id_to_regex;
+        Self::construct_byte_regexes(&grammar.interned_strings, &mut id_to_regexes, &regex_config)?;
+        let id_to_capture_regexes =
+            Self::construct_capture_regexes(&grammar.interned_strings, &regex_config)?;
+        let regex_aliases = Self::construct_regex_aliases(
+            &grammar.interned_strings,
+            &mut id_to_regexes,
+            &id_to_capture_regexes,
+        );
+        let regex_id = |x: &SymbolU32| {
+            let id = x.to_usize();
+            regex_aliases.get(&id).copied().unwrap_or(id)
+        };
+        // The regexes are anchored by the parser.
+        let any_char_regex = grammar
+            .interned_strings
+            .regex_strings
+            .get(format!("\\A(?:{ANY_CHAR_REGEX_MARKER})\\z"));
+        let json_regex = grammar
+            .interned_strings
+            .regex_strings
+            .get(format!("\\A(?:{JSON_REGEX_MARKER})\\z"));
         let mut rules = JaggedArray::<HIRNode<TI>, Vec<usize>, 3>::with_capacity([
             grammar.expressions.len(),
             1,
@@ -421,17 +1302,62 @@ where
                                     )
                                 })?,
                             )),
-                            OperatorFlattenedNode::RegexString(x) => HIRNode::RegexString(RegexID(
-                                x.to_usize().try_into().map_err(|_| {
-                                    CreateGrammarError::IntConversionError(
-                                        "regex".to_string(),
-                                        x.to_usize(),
-                                        TI::max_value().as_(),
-                                    )
-                                })?,
-                            )),
-                            OperatorFlattenedNode::Nonterminal(x) => HIRNode::Nonterminal(
-                                NonterminalID(x.to_usize().try_into().map_err(|_| {
+                            OperatorFlattenedNode::RegexString(x) if Some(*x) == any_char_regex => {
+                                HIRNode::AnyChar
+                            }
+                            OperatorFlattenedNode::RegexString(x) if Some(*x) == json_regex => {
+                                HIRNode::Json
+                            }
+                            OperatorFlattenedNode::RegexString(x)
+                                if regex_to_bounded_substrings.contains_key(x) =>
+                            {
+                                let id = regex_to_bounded_substrings[x];
+                                HIRNode::BoundedSubstrings(BoundedSubstringsID(
+                                    id.try_into().map_err(|_| {
+                                        CreateGrammarError::IntConversionError(
+                                            "bounded substrings".to_string(),
+                                            id,
+                                            TI::max_value().as_(),
+                                        )
+                                    })?,
+                                ))
+                            }
+                            OperatorFlattenedNode::RegexString(x) => {
+                                match (
+                                    regex_to_length_prefixed.get(x),
+                                    regex_to_byte_set_complement.get(x),
+                                ) {
+                                    (Some(&id), _) => HIRNode::LengthPrefixed(LengthPrefixedID(
+                                        id.try_into().map_err(|_| {
+                                            CreateGrammarError::IntConversionError(
+                                                "length-prefixed".to_string(),
+                                                id,
+                                                TI::max_value().as_(),
+                                            )
+                                        })?,
+                                    )),
+                                    (None, Some(&id)) => HIRNode::ByteSetComplement(ByteSetID(
+                                        id.try_into().map_err(|_| {
+                                            CreateGrammarError::IntConversionError(
+                                                "byte set complement".to_string(),
+                                                id,
+                                                TI::max_value().as_(),
+                                            )
+                                        })?,
+                                    )),
+                                    (None, None) => HIRNode::RegexString(RegexID(
+                                        regex_id(x).try_into().map_err(|_| {
+                                            CreateGrammarError::IntConversionError(
+                                                "regex".to_string(),
+                                                regex_id(x),
+                                                TI::max_value().as_(),
+                                            )
+                                        })?,
+                                    )),
+                                }
+                            }
+                            OperatorFlattenedNode::Nonterminal(x) => HIRNode::Nonterminal(
+                                NonterminalID(x.to_usize().try_into().map_err(|_| {
                                     CreateGrammarError::IntConversionError(
                                         "nonterminal".to_string(),
                                         x.to_usize(),
@@ -441,20 +1367,20 @@ where
                             ),
                             OperatorFlattenedNode::EarlyEndRegexString(x) => {
                                 HIRNode::EarlyEndRegexString(RegexID(
-                                    x.to_usize().try_into().map_err(|_| {
+                                    regex_id(x).try_into().map_err(|_| {
                                         CreateGrammarError::IntConversionError(
                                             "regex".to_string(),
-                                            x.to_usize(),
+                                            regex_id(x),
                                             TI::max_value().as_(),
                                         )
                                     })?,
                                 ))
                             }
                             OperatorFlattenedNode::RegexComplement(x) => HIRNode::RegexComplement(
-                                RegexID(x.to_usize().try_into().map_err(|_| {
+                                RegexID(regex_id(x).try_into().map_err(|_| {
                                     CreateGrammarError::IntConversionError(
                                         "regex".to_string(),
-                                        x.to_usize(),
+                                        regex_id(x),
                                         TI::max_value().as_(),
                                     )
                                 })?),
@@ -473,8 +1399,17 @@ where
                 }
             }
         }
-        let id_to_regexes = grammar.id_to_regex;
+        let mut partial_regexes = FixedBitSet::with_capacity(id_to_regexes.len());
+        for (id, regex) in grammar.interned_strings.regex_strings.iter() {
+            if regex.contains(PARTIAL_REGEX_MARKER) {
+                partial_regexes.insert(id.to_usize());
+            }
+        }
         let id_to_suffix_automata = grammar.id_to_suffix_automaton;
+        let nonterminal_priors =
+            Self::construct_nonterminal_priors(&rules, &grammar.interned_strings);
+        let atomic_nonterminals =
+            Self::construct_atomic_nonterminals(&rules, &grammar.interned_strings);
         let (id_to_regex_first_bytes, id_to_regex_complement_first_bytes) =
             Self::construct_regex_first_bytes(&rules, &id_to_regexes);
         let id_to_suffix_automata_first_bytes =
@@ -484,7 +1419,8 @@ where
             regex_to_token_ids =
                 Self::construct_regex_to_token_ids(vocabulary, &rules, &id_to_regexes, limit);
         }
-        Ok(Self {
+        let nullable_nonterminals = FixedBitSet::with_capacity(rules.len());
+        let mut grammar = Self {
             start_nonterminal_id: NonterminalID(
                 grammar.start_symbol.to_usize().try_into().map_err(|_| {
                     CreateGrammarError::IntConversionError(
@@ -498,12 +1434,778 @@ where
             interned_strings: grammar.interned_strings,
             id_to_regexes,
             id_to_terminals,
+            id_to_byte_set_complements,
             id_to_regex_first_bytes,
             id_to_regex_complement_first_bytes,
             id_to_suffix_automata,
             id_to_suffix_automata_first_bytes,
             regex_to_token_ids,
-        })
+            partial_regexes,
+            id_to_capture_regexes,
+            nonterminal_priors,
+            id_to_length_prefixed,
+            id_to_bounded_substrings,
+            atomic_nonterminals,
+            nullable_nonterminals,
+            nonterminal_first_bytes: Vec::new(),
+            unreachable_nonterminals: Vec::new(),
+            nonproductive_nonterminals: Vec::new(),
+        };
+        grammar.nonterminal_first_bytes = grammar.construct_nonterminal_first_bytes();
+        Ok(grammar)
+    }
+
+    /// Attach the analysis of the rules removed by the simplification, which [`Grammar::new`] cannot see.
+    pub(crate) fn with_analysis(mut self, analysis: GrammarAnalysis) -> Self {
+        for nonterminal in &analysis.nullable_nonterminals {
+            if let Some(id) = self.interned_strings.nonterminals.get(nonterminal) {
+                if id.to_usize() < self.nullable_nonterminals.len() {
+                    self.nullable_nonterminals.insert(id.to_usize());
+                }
+            }
+        }
+        self.unreachable_nonterminals = analysis.unreachable_nonterminals;
+        self.nonproductive_nonterminals = analysis.nonproductive_nonterminals;
+        self
+    }
+
+    /// Create a new grammar from a llama.cpp GBNF grammar and configuration.
+    ///
+    /// The GBNF grammar is translated into KBNF, where the `root` rule becomes the `start` nonterminal,
+    /// `-` in rule names becomes `_`, and character classes become regexes.
+    ///
+    /// # Arguments
+    ///
+    /// * `gbnf_grammar_str` - The GBNF grammar string.
+    /// * `vocabulary` - The vocabulary.
+    /// * `regex_config` - The configuration of the regular expressions.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CreateGrammarError::GbnfParsingError`] if the GBNF grammar is malformed or uses unsupported features like token references,
+    /// and the other variants in the same cases as [`Grammar::new`].
+    pub fn from_gbnf(
+        gbnf_grammar_str: &str,
+        vocabulary: &Vocabulary,
+        regex_config: RegexConfig,
+    ) -> Result<Self, CreateGrammarError> {
+        let kbnf_grammar_str = crate::gbnf::gbnf_to_kbnf(gbnf_grammar_str)
+            .map_err(CreateGrammarError::GbnfParsingError)?;
+        let config = crate::Config {
+            regex_config,
+            ..Default::default()
+        };
+        let (grammar, analysis) = utils::construct_kbnf_syntax_grammar_and_analysis(
+            &kbnf_grammar_str,
+            config.internal_config(),
+        )?;
+        Ok(Self::new(grammar, vocabulary, regex_config)?.with_analysis(analysis))
+    }
+
+    /// Serialize the grammar into bytes, which can be deserialized by [`Grammar::from_bytes`] later.
+    ///
+    /// The compiled DFAs, the precomputed first bytes and the eager regex cache are included,
+    /// so deserialization skips the expensive compilation in [`Grammar::new`].
+    /// The width of the generic parameter(TI) and the endianness of the platform are embedded in the bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let id = |x: TI| -> usize { x.as_() };
+        let rules = (0..self.rules.len())
+            .map(|i| {
+                let view = self.rules.view::<1, 2>([i]);
+                (0..view.len())
+                    .map(|j| {
+                        view.view::<1, 1>([j])
+                            .as_slice()
+                            .iter()
+                            .map(|node| match *node {
+                                HIRNode::Terminal(x) => SerializedHIRNode::Terminal(id(x.0)),
+                                HIRNode::RegexString(x) => SerializedHIRNode::RegexString(id(x.0)),
+                                HIRNode::Nonterminal(x) => SerializedHIRNode::Nonterminal(id(x.0)),
+                                HIRNode::EarlyEndRegexString(x) => {
+                                    SerializedHIRNode::EarlyEndRegexString(id(x.0))
+                                }
+                                HIRNode::Substrings(x) => SerializedHIRNode::Substrings(id(x.0)),
+                                HIRNode::RegexComplement(x) => {
+                                    SerializedHIRNode::RegexComplement(id(x.0))
+                                }
+                                HIRNode::LengthPrefixed(x) => {
+                                    SerializedHIRNode::LengthPrefixed(id(x.0))
+                                }
+                                HIRNode::ByteSetComplement(x) => {
+                                    SerializedHIRNode::ByteSetComplement(id(x.0))
+                                }
+                                HIRNode::AnyChar => SerializedHIRNode::AnyChar,
+                                HIRNode::Json => SerializedHIRNode::Json,
+                                HIRNode::BoundedSubstrings(x) => {
+                                    SerializedHIRNode::BoundedSubstrings(id(x.0))
+                                }
+                            })
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut regex_to_token_ids: Vec<_> = self
+            .regex_to_token_ids
+            .iter()
+            .map(|(&(regex_id, state_id, regex_type), bitset)| {
+                let (len, ones) = serialize_bitset(bitset);
+                (
+                    id(regex_id.0),
+                    serialize_state_id(state_id),
+                    regex_type as u8,
+                    len,
+                    ones,
+                )
+            })
+            .collect();
+        regex_to_token_ids.sort_unstable_by_key(|x| (x.0, x.1, x.2));
+        let serialize_first_bytes = |map: &AHashMap<(TI, StateID), ByteSet>| {
+            let mut first_bytes: Vec<_> = map
+                .iter()
+                .map(|(&(regex_id, state_id), set)| {
+                    (
+                        id(regex_id),
+                        serialize_state_id(state_id),
+                        set.ones().collect(),
+                    )
+                })
+                .collect();
+            first_bytes.sort_unstable_by_key(|x| (x.0, x.1));
+            first_bytes
+        };
+        let mut id_to_suffix_automata_first_bytes: Vec<_> = self
+            .id_to_suffix_automata_first_bytes
+            .iter()
+            .map(|(&(i, node_id), set)| (i, node_id, set.ones().collect()))
+            .collect();
+        id_to_suffix_automata_first_bytes.sort_unstable_by_key(|x| (x.0, x.1));
+        let mut nonterminal_priors: Vec<_> = self
+            .nonterminal_priors
+            .iter()
+            .map(|(k, &v)| (id(k.0), v))
+            .collect();
+        nonterminal_priors.sort_unstable_by_key(|x| x.0);
+        let mut id_to_capture_regexes: Vec<_> = self
+            .id_to_capture_regexes
+            .iter()
+            .map(|(&regex_id, x)| (regex_id, x.pattern.clone(), x.byte_mode))
+            .collect();
+        id_to_capture_regexes.sort_unstable_by_key(|x| x.0);
+        let serialized = SerializedGrammar {
+            format_version: SERIALIZATION_FORMAT_VERSION,
+            id_width: std::mem::size_of::<TI>(),
+            little_endian: cfg!(target_endian = "little"),
+            start_nonterminal_id: id(self.start_nonterminal_id.0),
+            rules,
+            nonterminals: serialize_interner(&self.interned_strings.nonterminals),
+            terminals: serialize_interner(&self.interned_strings.terminals),
+            regex_strings: serialize_interner(&self.interned_strings.regex_strings),
+            sub_strings: serialize_interner(&self.interned_strings.sub_strings),
+            id_to_regexes: self
+                .id_to_regexes
+                .iter()
+                .map(|x| match x {
+                    FiniteStateAutomaton::Dfa(dfa) => serialize_dfa(dfa),
+                })
+                .collect(),
+            regex_to_token_ids,
+            id_to_regex_first_bytes: serialize_first_bytes(&self.id_to_regex_first_bytes),
+            id_to_regex_complement_first_bytes: serialize_first_bytes(
+                &self.id_to_regex_complement_first_bytes,
+            ),
+            id_to_terminals: (0..self.id_to_terminals.len())
+                .map(|x| self.id_to_terminals.view([x]).as_slice().to_vec())
+                .collect(),
+            id_to_byte_set_complements: self
+                .id_to_byte_set_complements
+                .iter()
+                .map(|x| x.ones().collect())
+                .collect(),
+            id_to_suffix_automata_first_bytes,
+            partial_regexes: serialize_bitset(&self.partial_regexes),
+            id_to_capture_regexes,
+            nonterminal_priors,
+            id_to_length_prefixed: self
+                .id_to_length_prefixed
+                .iter()
+                .map(|x| SerializedLengthPrefixed {
+                    numeric_regex: x.numeric_regex.clone(),
+                    content_regex: x.content_regex.clone(),
+                    numeric: serialize_dfa(&x.numeric),
+                    content: serialize_dfa(&x.content),
+                })
+                .collect(),
+            id_to_bounded_substrings: self
+                .id_to_bounded_substrings
+                .iter()
+                .map(|x| (x.string.clone(), x.min, x.max))
+                .collect(),
+            atomic_nonterminals: serialize_bitset(&self.atomic_nonterminals),
+            nullable_nonterminals: serialize_bitset(&self.nullable_nonterminals),
+            unreachable_nonterminals: self.unreachable_nonterminals.clone(),
+            nonproductive_nonterminals: self.nonproductive_nonterminals.clone(),
+        };
+        // SAFETY: Serializing the plain data types above into a Vec never fails.
+        bincode::serialize(&serialized).unwrap()
+    }
+
+    /// Deserialize a grammar from bytes created by [`Grammar::to_bytes`].
+    ///
+    /// The suffix automata are rebuilt from their strings, which takes linear time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bytes are malformed, or if they were serialized
+    /// with a different generic parameter(TI), endianness or format version.
+    /// More information about the error can be found in the [DeserializeGrammarError] enum docs.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeGrammarError> {
+        let serialized: SerializedGrammar = bincode::deserialize(bytes)?;
+        if serialized.format_version != SERIALIZATION_FORMAT_VERSION {
+            return Err(DeserializeGrammarError::UnsupportedFormatVersion(
+                serialized.format_version,
+                SERIALIZATION_FORMAT_VERSION,
+            ));
+        }
+        if serialized.id_width != std::mem::size_of::<TI>() {
+            return Err(DeserializeGrammarError::MismatchedIdWidth(
+                serialized.id_width,
+                std::mem::size_of::<TI>(),
+            ));
+        }
+        if serialized.little_endian != cfg!(target_endian = "little") {
+            return Err(DeserializeGrammarError::MismatchedEndianness);
+        }
+        let id = |x: usize| -> Result<TI, DeserializeGrammarError> {
+            x.try_into().map_err(|_| {
+                DeserializeGrammarError::InvalidData(format!(
+                    "id {x} exceeds the maximum value {}",
+                    TI::max_value().as_()
+                ))
+            })
+        };
+        let interned_strings = InternedStrings {
+            nonterminals: deserialize_interner(&serialized.nonterminals),
+            terminals: deserialize_interner(&serialized.terminals),
+            regex_strings: deserialize_interner(&serialized.regex_strings),
+            sub_strings: deserialize_interner(&serialized.sub_strings),
+        };
+        let mut rules = JaggedArray::<HIRNode<TI>, Vec<usize>, 3>::new();
+        for nonterminal in serialized.rules {
+            rules.new_row::<0>();
+            for dotted in nonterminal {
+                rules.new_row::<1>();
+                for node in dotted {
+                    rules.push_to_last_row(match node {
+                        SerializedHIRNode::Terminal(x) => HIRNode::Terminal(TerminalID(id(x)?)),
+                        SerializedHIRNode::RegexString(x) => HIRNode::RegexString(RegexID(id(x)?)),
+                        SerializedHIRNode::Nonterminal(x) => {
+                            HIRNode::Nonterminal(NonterminalID(id(x)?))
+                        }
+                        SerializedHIRNode::EarlyEndRegexString(x) => {
+                            HIRNode::EarlyEndRegexString(RegexID(id(x)?))
+                        }
+                        SerializedHIRNode::Substrings(x) => {
+                            HIRNode::Substrings(SuffixAutomataID(id(x)?))
+                        }
+                        SerializedHIRNode::RegexComplement(x) => {
+                            HIRNode::RegexComplement(RegexID(id(x)?))
+                        }
+                        SerializedHIRNode::LengthPrefixed(x) => {
+                            HIRNode::LengthPrefixed(LengthPrefixedID(id(x)?))
+                        }
+                        SerializedHIRNode::ByteSetComplement(x) => {
+                            HIRNode::ByteSetComplement(ByteSetID(id(x)?))
+                        }
+                        SerializedHIRNode::AnyChar => HIRNode::AnyChar,
+                        SerializedHIRNode::Json => HIRNode::Json,
+                        SerializedHIRNode::BoundedSubstrings(x) => {
+                            HIRNode::BoundedSubstrings(BoundedSubstringsID(id(x)?))
+                        }
+                    });
+                }
+            }
+        }
+        let id_to_regexes = serialized
+            .id_to_regexes
+            .iter()
+            .map(|x| deserialize_dfa(x).map(FiniteStateAutomaton::Dfa))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut regex_to_token_ids = AHashMap::default();
+        for (regex_id, state_id, regex_type, len, ones) in serialized.regex_to_token_ids {
+            let regex_type = match regex_type {
+                0 => RegexType::Normal,
+                1 => RegexType::Early,
+                2 => RegexType::Complement,
+                x => {
+                    return Err(DeserializeGrammarError::InvalidData(format!(
+                        "invalid regex type {x}"
+                    )))
+                }
+            };
+            regex_to_token_ids.insert(
+                (
+                    RegexID(id(regex_id)?),
+                    deserialize_state_id(state_id)?,
+                    regex_type,
+                ),
+                deserialize_bitset((len, ones))?,
+            );
+        }
+        let deserialize_first_bytes = |first_bytes: Vec<(usize, u32, Vec<usize>)>| {
+            let mut map = AHashMap::default();
+            for (regex_id, state_id, ones) in first_bytes {
+                map.insert(
+                    (id(regex_id)?, deserialize_state_id(state_id)?),
+                    deserialize_byte_set(ones)?,
+                );
+            }
+            Ok::<_, DeserializeGrammarError>(map)
+        };
+        let id_to_regex_first_bytes = deserialize_first_bytes(serialized.id_to_regex_first_bytes)?;
+        let id_to_regex_complement_first_bytes =
+            deserialize_first_bytes(serialized.id_to_regex_complement_first_bytes)?;
+        let mut id_to_terminals = JaggedArray::<u8, Vec<usize>, 2>::new();
+        for terminal in serialized.id_to_terminals {
+            id_to_terminals.new_row::<0>();
+            id_to_terminals.extend_last_row_from_slice(&terminal);
+        }
+        let id_to_byte_set_complements = serialized
+            .id_to_byte_set_complements
+            .into_iter()
+            .map(deserialize_byte_set)
+            .collect::<Result<Vec<_>, _>>()?;
+        // The suffix automata are deterministic, so the rebuilt node ids match the serialized first bytes.
+        let id_to_suffix_automata: Vec<SuffixAutomaton> = interned_strings
+            .sub_strings
+            .iter()
+            .map(|(_, x)| SuffixAutomaton::from_bytes(x))
+            .collect();
+        let mut id_to_suffix_automata_first_bytes = AHashMap::default();
+        for (i, node_id, ones) in serialized.id_to_suffix_automata_first_bytes {
+            id_to_suffix_automata_first_bytes.insert((i, node_id), deserialize_byte_set(ones)?);
+        }
+        let mut id_to_capture_regexes = AHashMap::default();
+        for (regex_id, pattern, byte_mode) in serialized.id_to_capture_regexes {
+            let capture_regex = CaptureRegex::new(pattern, byte_mode).map_err(|e| {
+                DeserializeGrammarError::InvalidData(format!("invalid capture regex: {e}"))
+            })?;
+            id_to_capture_regexes.insert(regex_id, capture_regex);
+        }
+        let mut nonterminal_priors = AHashMap::default();
+        for (nonterminal_id, prior) in serialized.nonterminal_priors {
+            nonterminal_priors.insert(NonterminalID(id(nonterminal_id)?), prior);
+        }
+        let id_to_length_prefixed = serialized
+            .id_to_length_prefixed
+            .into_iter()
+            .map(|x| {
+                Ok(LengthPrefixed::from_dfas(
+                    x.numeric_regex,
+                    x.content_regex,
+                    deserialize_dfa(&x.numeric)?,
+                    deserialize_dfa(&x.content)?,
+                )?)
+            })
+            .collect::<Result<Vec<_>, DeserializeGrammarError>>()?;
+        let id_to_bounded_substrings = serialized
+            .id_to_bounded_substrings
+            .into_iter()
+            .map(|(string, min, max)| BoundedSubstrings::new(string, min, max))
+            .collect();
+        let mut grammar = Self {
+            start_nonterminal_id: NonterminalID(id(serialized.start_nonterminal_id)?),
+            rules,
+            interned_strings,
+            id_to_regexes,
+            regex_to_token_ids,
+            id_to_regex_first_bytes,
+            id_to_regex_complement_first_bytes,
+            id_to_terminals,
+            id_to_byte_set_complements,
+            id_to_suffix_automata,
+            id_to_suffix_automata_first_bytes,
+            partial_regexes: deserialize_bitset(serialized.partial_regexes)?,
+            id_to_capture_regexes,
+            nonterminal_priors,
+            id_to_length_prefixed,
+            id_to_bounded_substrings,
+            atomic_nonterminals: deserialize_bitset(serialized.atomic_nonterminals)?,
+            nullable_nonterminals: deserialize_bitset(serialized.nullable_nonterminals)?,
+            nonterminal_first_bytes: Vec::new(),
+            unreachable_nonterminals: serialized.unreachable_nonterminals,
+            nonproductive_nonterminals: serialized.nonproductive_nonterminals,
+        };
+        grammar.validate()?;
+        // The first bytes of the nonterminals are derived from the rules, which are only valid after the validation.
+        grammar.nonterminal_first_bytes = grammar.construct_nonterminal_first_bytes();
+        Ok(grammar)
+    }
+
+    /// Checks that the ids in the grammar are in bounds, so the unchecked accesses in the engine are sound.
+    fn validate(&self) -> Result<(), DeserializeGrammarError> {
+        let check = |kind: &str, id: usize, len: usize| {
+            if id < len {
+                Ok(())
+            } else {
+                Err(DeserializeGrammarError::InvalidData(format!(
+                    "{kind} id {id} exceeds the number of {kind}s {len}"
+                )))
+            }
+        };
+        if self.rules.len() == 0 || self.rules.len() != self.interned_strings.nonterminals.len() {
+            return Err(DeserializeGrammarError::InvalidData(
+                "the number of rules does not match the number of nonterminals".to_string(),
+            ));
+        }
+        check(
+            "nonterminal",
+            self.start_nonterminal_id.0.as_(),
+            self.rules.len(),
+        )?;
+        if self.id_to_regexes.len() != self.interned_strings.regex_strings.len()
+            || self.id_to_terminals.len() != self.interned_strings.terminals.len()
+        {
+            return Err(DeserializeGrammarError::InvalidData(
+                "the number of automata does not match the number of strings".to_string(),
+            ));
+        }
+        for i in 0..self.rules.len() {
+            let view = self.rules.view::<1, 2>([i]);
+            for j in 0..view.len() {
+                for node in view.view::<1, 1>([j]).as_slice() {
+                    match *node {
+                        HIRNode::Terminal(x) => {
+                            check("terminal", x.0.as_(), self.id_to_terminals.len())?
+                        }
+                        HIRNode::RegexString(x)
+                        | HIRNode::EarlyEndRegexString(x)
+                        | HIRNode::RegexComplement(x) => {
+                            check("regex", x.0.as_(), self.id_to_regexes.len())?
+                        }
+                        HIRNode::Nonterminal(x) => {
+                            check("nonterminal", x.0.as_(), self.rules.len())?
+                        }
+                        HIRNode::Substrings(x) => check(
+                            "suffix automaton",
+                            x.0.as_(),
+                            self.id_to_suffix_automata.len(),
+                        )?,
+                        HIRNode::LengthPrefixed(x) => check(
+                            "length-prefixed",
+                            x.0.as_(),
+                            self.id_to_length_prefixed.len(),
+                        )?,
+                        HIRNode::ByteSetComplement(x) => check(
+                            "byte set complement",
+                            x.0.as_(),
+                            self.id_to_byte_set_complements.len(),
+                        )?,
+                        HIRNode::AnyChar | HIRNode::Json => {}
+                        HIRNode::BoundedSubstrings(x) => check(
+                            "bounded substrings",
+                            x.0.as_(),
+                            self.id_to_bounded_substrings.len(),
+                        )?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn construct_length_prefixed(
+        interned_strings: &InternedStrings,
+        regex_config: &RegexConfig,
+    ) -> Result<(Vec<LengthPrefixed>, AHashMap<SymbolU32, usize>), CreateGrammarError> {
+        let mut id_to_length_prefixed = Vec::new();
+        let mut regex_to_length_prefixed = AHashMap::default();
+        for (id, regex) in interned_strings.regex_strings.iter() {
+            let Some(index) = regex.find(LENGTH_PREFIXED_REGEX_MARKER) else {
+                continue;
+            };
+            let arguments = &regex[index + LENGTH_PREFIXED_REGEX_MARKER.len()..];
+            let end = arguments
+                .find(|c: char| !c.is_ascii_hexdigit() && c != ',')
+                .unwrap_or(arguments.len());
+            // The arguments are encoded by the preprocessor
+            let Some((numeric, content)) = arguments[..end].split_once(',') else {
+                continue;
+            };
+            let (Some(numeric), Some(content)) = (
+                preprocessor::decode_hex(numeric),
+                preprocessor::decode_hex(content),
+            ) else {
+                continue;
+            };
+            regex_to_length_prefixed.insert(id, id_to_length_prefixed.len());
+            id_to_length_prefixed.push(LengthPrefixed::new(numeric, content, regex_config)?);
+        }
+        Ok((id_to_length_prefixed, regex_to_length_prefixed))
+    }
+
+    fn construct_bounded_substrings(
+        interned_strings: &InternedStrings,
+    ) -> (Vec<BoundedSubstrings>, AHashMap<SymbolU32, usize>) {
+        let mut id_to_bounded_substrings = Vec::new();
+        let mut regex_to_bounded_substrings = AHashMap::default();
+        for (id, regex) in interned_strings.regex_strings.iter() {
+            let Some(index) = regex.find(BOUNDED_SUBSTRINGS_REGEX_MARKER) else {
+                continue;
+            };
+            let arguments = &regex[index + BOUNDED_SUBSTRINGS_REGEX_MARKER.len()..];
+            let end = arguments
+                .find(|c: char| !c.is_ascii_hexdigit() && c != ',')
+                .unwrap_or(arguments.len());
+            // The arguments are encoded by the preprocessor
+            let mut arguments = arguments[..end].split(',');
+            let (Some(Ok(min)), Some(Ok(max)), Some(Some(string)), None) = (
+                arguments.next().map(str::parse),
+                arguments.next().map(str::parse),
+                arguments.next().map(preprocessor::decode_hex),
+                arguments.next(),
+            ) else {
+                continue;
+            };
+            regex_to_bounded_substrings.insert(id, id_to_bounded_substrings.len());
+            id_to_bounded_substrings.push(BoundedSubstrings::new(string, min, max));
+        }
+        (id_to_bounded_substrings, regex_to_bounded_substrings)
+    }
+
+    /// Find the regexes whose DFAs are equivalent to the DFA of a regex with a smaller ID,
+    /// and map each of them to the smallest ID, so the equivalent regexes share one DFA, its first bytes
+    /// and its eager regex cache. The DFAs of the mapped regexes are replaced by a DFA that never matches to free the memory.
+    ///
+    /// The identical regexes are already merged by kbnf_syntax when the regex strings are interned.
+    /// The regexes with the markers of the syntax extensions are skipped, since the markers are recognized by their IDs.
+    fn construct_regex_aliases(
+        interned_strings: &InternedStrings,
+        id_to_regexes: &mut [FiniteStateAutomaton],
+        id_to_capture_regexes: &AHashMap<usize, CaptureRegex>,
+    ) -> AHashMap<usize, usize> {
+        // The DFAs are grouped by the bytes leading out of the dead state from the anchored start state,
+        // so only the DFAs in the same group are compared.
+        let mut groups: AHashMap<([u64; 4], bool), Vec<usize>> = AHashMap::default();
+        let mut aliases = AHashMap::default();
+        let mut regexes: Vec<_> = interned_strings
+            .regex_strings
+            .iter()
+            .filter(|(_, x)| !x.contains(preprocessor::GENERATED_NONTERMINAL_PREFIX))
+            .map(|(id, _)| id.to_usize())
+            // The equivalent DFAs may name different groups.
+            .filter(|id| !id_to_capture_regexes.contains_key(id))
+            .collect();
+        regexes.sort_unstable();
+        for id in regexes {
+            let FiniteStateAutomaton::Dfa(dfa) = &id_to_regexes[id];
+            let Ok(start) = dfa.start_state(
+                &kbnf_regex_automata::util::start::Config::new()
+                    .anchored(kbnf_regex_automata::Anchored::Yes),
+            ) else {
+                continue;
+            };
+            let mut first_bytes = [0u64; 4];
+            for byte in 0..=u8::MAX {
+                if !dfa.is_dead_state(dfa.next_state(start, byte)) {
+                    first_bytes[byte as usize / 64] |= 1 << (byte % 64);
+                }
+            }
+            let group = groups.entry((first_bytes, dfa.has_empty())).or_default();
+            let canonical = group.iter().copied().find(|&other| {
+                let FiniteStateAutomaton::Dfa(other) = &id_to_regexes[other];
+                utils::are_equivalent_dfas(dfa, other)
+            });
+            match canonical {
+                Some(canonical) => {
+                    aliases.insert(id, canonical);
+                }
+                None => group.push(id),
+            }
+        }
+        if let Ok(dfa) = dense::DFA::never_match() {
+            for &id in aliases.keys() {
+                id_to_regexes[id] = FiniteStateAutomaton::Dfa(dfa.clone());
+            }
+        }
+        aliases
+    }
+
+    /// Rewrite the characters standing for the raw bytes in the regex as byte escapes outside the Unicode mode.
+    fn lower_regex_raw_bytes(regex: &str) -> Result<String, CreateGrammarError> {
+        let mut lowered = String::with_capacity(regex.len());
+        let mut class_depth = 0usize;
+        let mut chars = regex.chars().peekable();
+        while let Some(c) = chars.next() {
+            if let Some(byte) = preprocessor::raw_byte(c) {
+                if class_depth > 0 {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "the raw byte \\x{byte:02X} in the regex {} cannot be used in a character class.",
+                        preprocessor::display_raw_bytes(regex)
+                    )));
+                }
+                lowered.push_str(&format!("(?-u:\\x{byte:02X})"));
+                continue;
+            }
+            lowered.push(c);
+            match c {
+                '\\' => lowered.extend(chars.next()),
+                '[' => {
+                    class_depth += 1;
+                    // `]` right after the opening bracket is a literal.
+                    lowered.extend(chars.next_if_eq(&'^'));
+                    lowered.extend(chars.next_if_eq(&']'));
+                }
+                ']' if class_depth > 0 => class_depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(lowered)
+    }
+
+    /// Compile the regexes with named capture groups once more into the regexes with captures.
+    fn construct_capture_regexes(
+        interned_strings: &InternedStrings,
+        regex_config: &RegexConfig,
+    ) -> Result<AHashMap<usize, CaptureRegex>, CreateGrammarError> {
+        let mut id_to_capture_regexes = AHashMap::default();
+        for (id, regex) in interned_strings.regex_strings.iter() {
+            // Most regexes have no named groups, which are not compiled again.
+            if !regex.contains("(?P<") && !regex.contains("(?<") {
+                continue;
+            }
+            let pattern = Self::lower_regex_raw_bytes(regex)?;
+            let capture_regex = CaptureRegex::new(pattern, regex_config.dot_matches_bytes)
+                .map_err(|e| {
+                    CreateGrammarError::PreprocessingError(format!(
+                        "the regex {} cannot be compiled with its capture groups: {e}",
+                        preprocessor::display_raw_bytes(regex)
+                    ))
+                })?;
+            if capture_regex.user_group_names().next().is_some() {
+                id_to_capture_regexes.insert(id.to_usize(), capture_regex);
+            }
+        }
+        Ok(id_to_capture_regexes)
+    }
+
+    /// Recompile the regexes that contain raw bytes written as `\xNN` escapes,
+    /// which kbnf_syntax compiles as the characters standing for the raw bytes.
+    /// All the regexes are recompiled in byte mode when [`RegexConfig::dot_matches_bytes`] is set.
+    fn construct_byte_regexes(
+        interned_strings: &InternedStrings,
+        id_to_regexes: &mut [FiniteStateAutomaton],
+        regex_config: &RegexConfig,
+    ) -> Result<(), CreateGrammarError> {
+        for (id, regex) in interned_strings.regex_strings.iter() {
+            let has_raw_bytes = regex.chars().any(|c| preprocessor::raw_byte(c).is_some());
+            if !has_raw_bytes && !regex_config.dot_matches_bytes {
+                continue;
+            }
+            let lowered = Self::lower_regex_raw_bytes(regex)?;
+            let config = regex_config.dfa_config();
+            let dfa = dense::Builder::new()
+                .configure(config.clone())
+                .syntax(regex_config.syntax_config().utf8(false))
+                .build(&lowered)
+                .map_err(|e| match utils::exceeded_size_limit(&e, &config) {
+                    Some(limit) => CreateGrammarError::RegexTooLargeToCompile {
+                        regex: utils::unanchored_regex(&preprocessor::display_raw_bytes(regex))
+                            .to_string(),
+                        limit,
+                    },
+                    None if has_raw_bytes => CreateGrammarError::PreprocessingError(format!(
+                        "the regex {} with raw bytes cannot be compiled: {e}",
+                        preprocessor::display_raw_bytes(regex)
+                    )),
+                    None => CreateGrammarError::PreprocessingError(format!(
+                        "the regex {} cannot be compiled in byte mode: {e}",
+                        preprocessor::display_raw_bytes(regex)
+                    )),
+                })?;
+            id_to_regexes[id.to_usize()] = FiniteStateAutomaton::Dfa(dfa);
+        }
+        Ok(())
+    }
+
+    /// Find the regexes that are simple negated classes of ASCII characters,
+    /// which are matched by byte set membership tests instead of DFAs.
+    ///
+    /// Each byte set contains the first bytes of the characters that the class matches.
+    fn construct_byte_set_complements(
+        interned_strings: &InternedStrings,
+    ) -> (Vec<ByteSet>, AHashMap<SymbolU32, usize>) {
+        let mut id_to_byte_set_complements = Vec::new();
+        let mut regex_to_byte_set_complement = AHashMap::default();
+        for (id, regex) in interned_strings.regex_strings.iter() {
+            // The regexes are anchored by the parser.
+            let Some(excluded) = regex
+                .strip_prefix("\\A(?:")
+                .and_then(|x| x.strip_suffix(")\\z"))
+                .and_then(parse_negated_ascii_class)
+            else {
+                continue;
+            };
+            let mut byte_set = ByteSet::with_capacity(256);
+            byte_set.insert_range(0..0x80);
+            byte_set.difference_with(&excluded);
+            byte_set.insert_range(utils::UTF8_LEADING_BYTES);
+            regex_to_byte_set_complement.insert(id, id_to_byte_set_complements.len());
+            id_to_byte_set_complements.push(byte_set);
+        }
+        (id_to_byte_set_complements, regex_to_byte_set_complement)
+    }
+
+    fn construct_nonterminal_priors(
+        rules: &JaggedArray<HIRNode<TI>, Vec<usize>, 3>,
+        interned_strings: &InternedStrings,
+    ) -> AHashMap<NonterminalID<TI>, f32> {
+        let mut nonterminal_priors = AHashMap::default();
+        for i in 0..rules.len() {
+            let view = rules.view::<2, 1>([i, 0]);
+            for node in view.as_slice() {
+                if let HIRNode::RegexString(regex_id) = node {
+                    let regex = interned_strings
+                        .regex_strings
+                        .resolve(SymbolU32::try_from_usize(regex_id.0.as_()).unwrap())
+                        .unwrap();
+                    if let Some(index) = regex.find(PRIOR_REGEX_MARKER) {
+                        let prior = &regex[index + PRIOR_REGEX_MARKER.len()..];
+                        let end = prior
+                            .find(|c: char| !c.is_ascii_digit() && c != '.')
+                            .unwrap_or(prior.len());
+                        // The prior is validated by the preprocessor
+                        if let Ok(prior) = prior[..end].parse::<f32>() {
+                            nonterminal_priors.insert(NonterminalID(i.as_()), prior);
+                        }
+                    }
+                }
+            }
+        }
+        nonterminal_priors
+    }
+
+    fn construct_atomic_nonterminals(
+        rules: &JaggedArray<HIRNode<TI>, Vec<usize>, 3>,
+        interned_strings: &InternedStrings,
+    ) -> FixedBitSet {
+        let mut atomic_nonterminals = FixedBitSet::with_capacity(rules.len());
+        for i in 0..rules.len() {
+            let view = rules.view::<2, 1>([i, 0]);
+            for node in view.as_slice() {
+                if let HIRNode::RegexString(regex_id) = node {
+                    let regex = interned_strings
+                        .regex_strings
+                        .resolve(SymbolU32::try_from_usize(regex_id.0.as_()).unwrap())
+                        .unwrap();
+                    if regex.contains(ATOMIC_REGEX_MARKER) {
+                        atomic_nonterminals.insert(i);
+                    }
+                }
+            }
+        }
+        atomic_nonterminals
     }
 
     fn construct_regex_to_token_ids(
@@ -591,6 +2293,7 @@ where
         regex_to_token_ids
     }
 
+    #[allow(clippy::type_complexity)]
     fn construct_regex_first_bytes(
         rules: &JaggedArray<HIRNode<TI>, Vec<usize>, 3>,
         id_to_regexes: &[FiniteStateAutomaton],
@@ -628,7 +2331,7 @@ where
                                 let mut set = ByteSet::with_capacity(256);
                                 let mut set_complement = ByteSet::with_capacity(256);
                                 let state_id = state.id();
-                                for byte in 0..u8::MAX {
+                                for byte in 0..=u8::MAX {
                                     let next_state = dfa.next_state(state_id, byte);
                                     let mut accepted = false;
                                     let mut acceptable = true;
@@ -666,6 +2369,104 @@ where
         (id_to_regex_first_bytes, id_to_regex_complement_first_bytes)
     }
 
+    /// Compute the first bytes of every nonterminal by a fixpoint iteration over the first nodes of the productions.
+    ///
+    /// The rules contain no empty productions and no nullable nodes after the simplification,
+    /// so only the first node of each production contributes to the first bytes.
+    fn construct_nonterminal_first_bytes(&self) -> Vec<ByteSet> {
+        let mut first_bytes = vec![ByteSet::with_capacity(256); self.rules.len()];
+        let mut first_nonterminals = vec![Vec::new(); self.rules.len()];
+        for (i, first_bytes) in first_bytes.iter_mut().enumerate() {
+            let view = self.rules.view::<1, 2>([i]);
+            if view.len() == 0 {
+                continue;
+            }
+            for node in view.view::<1, 1>([0]).as_slice() {
+                match *node {
+                    HIRNode::Nonterminal(x) => first_nonterminals[i].push(x.0.as_()),
+                    node => first_bytes.union_with(&self.first_bytes_of_node(node)),
+                }
+            }
+        }
+        loop {
+            let mut updated = false;
+            for (i, nonterminals) in first_nonterminals.iter().enumerate() {
+                for &nonterminal in nonterminals {
+                    if nonterminal != i && !first_bytes[nonterminal].is_subset(&first_bytes[i]) {
+                        let bytes = first_bytes[nonterminal].clone();
+                        first_bytes[i].union_with(&bytes);
+                        updated = true;
+                    }
+                }
+            }
+            if !updated {
+                break;
+            }
+        }
+        first_bytes
+    }
+
+    /// Get the bytes accepted by the node at its initial state.
+    fn first_bytes_of_node(&self, node: HIRNode<TI>) -> ByteSet {
+        let mut first_bytes = ByteSet::with_capacity(256);
+        let regex_first_bytes = |regex_id: RegexID<TI>, anchored| {
+            let FiniteStateAutomaton::Dfa(dfa) = self.regex(regex_id);
+            dfa.start_state(&kbnf_regex_automata::util::start::Config::new().anchored(anchored))
+                .ok()
+        };
+        match node {
+            HIRNode::Terminal(terminal_id) => {
+                first_bytes.insert(self.terminal(terminal_id)[0] as usize);
+            }
+            HIRNode::RegexString(regex_id) | HIRNode::EarlyEndRegexString(regex_id) => {
+                if let Some(bytes) = regex_first_bytes(regex_id, kbnf_regex_automata::Anchored::Yes)
+                    .and_then(|start| self.first_bytes_from_regex(regex_id, start))
+                {
+                    first_bytes.union_with(bytes);
+                }
+            }
+            HIRNode::RegexComplement(regex_id) => {
+                if let Some(bytes) = regex_first_bytes(regex_id, kbnf_regex_automata::Anchored::No)
+                    .and_then(|start| self.complement_first_bytes_from_regex(regex_id, start))
+                {
+                    first_bytes.union_with(bytes);
+                }
+            }
+            HIRNode::Substrings(id) => {
+                if let Some(bytes) = self
+                    .id_to_suffix_automata_first_bytes
+                    .get(&(id.0.as_(), general_sam::SAM_ROOT_NODE_ID))
+                {
+                    first_bytes.union_with(bytes);
+                }
+            }
+            HIRNode::LengthPrefixed(id) => {
+                let length_prefixed = self.length_prefixed(id);
+                let dfa = length_prefixed.numeric();
+                for byte in 0..=u8::MAX {
+                    let state = dfa.next_state(length_prefixed.numeric_start(), byte);
+                    if utils::check_dfa_state_status(state, dfa) != utils::FsaStateStatus::Reject {
+                        first_bytes.insert(byte as usize);
+                    }
+                }
+            }
+            HIRNode::BoundedSubstrings(id) => {
+                first_bytes.union_with(
+                    self.bounded_substrings(id)
+                        .first_bytes(general_sam::SAM_ROOT_NODE_ID),
+                );
+            }
+            HIRNode::ByteSetComplement(id) => first_bytes.union_with(self.byte_set_complement(id)),
+            HIRNode::AnyChar => {
+                first_bytes.insert_range(0..0x80);
+                first_bytes.insert_range(utils::UTF8_LEADING_BYTES);
+            }
+            HIRNode::Json => first_bytes.union_with(&crate::json::first_bytes(crate::json::START)),
+            HIRNode::Nonterminal(_) => {}
+        }
+        first_bytes
+    }
+
     fn construct_suffix_automata_first_bytes(
         id_to_suffix_automata: &[SuffixAutomaton],
     ) -> AHashMap<(usize, GeneralSamNodeID), ByteSet> {
@@ -674,7 +2475,7 @@ where
             for &node_id in suffix_automata.get_topo_and_suf_len_sorted_node_ids() {
                 let mut set = ByteSet::with_capacity(256);
                 let state = suffix_automata.get_state(node_id);
-                for byte in 0..u8::MAX {
+                for byte in 0..=u8::MAX {
                     let mut state = state.clone();
                     state.feed([byte]);
                     if !state.is_nil() {
@@ -686,6 +2487,95 @@ where
         }
         id_to_suffix_automata_first_bytes
     }
+    /// Render the grammar back to KBNF source, which can be parsed again into an equivalent grammar.
+    ///
+    /// The rules are rendered after the simplification, so the nonterminals generated by desugaring
+    /// like `__kbnf_repeat_1` appear in the source and the rule of the start nonterminal comes first.
+    /// Terminals, regexes and substrings are re-quoted and escaped, and the raw bytes are written as `\xNN` escapes.
+    /// The annotations like `@atomic` and the priors are kept as the never-matching regexes that carry them.
+    pub fn to_kbnf_source(&self) -> String {
+        let start = self.start_nonterminal_id.0.as_();
+        let mut source = String::new();
+        for nonterminal_id in
+            std::iter::once(start).chain((0..self.rules.len()).filter(|&x| x != start))
+        {
+            let view = self.rules.view::<1, 2>([nonterminal_id]);
+            if view.is_empty() || view.view::<1, 1>([0]).is_empty() {
+                continue;
+            }
+            let mut productions: Vec<Vec<String>> =
+                vec![Default::default(); view.view::<1, 1>([0]).len()];
+            for dot_position in 0..view.len() {
+                let view = view.view::<1, 1>([dot_position]);
+                for production_id in 0..view.len() {
+                    productions[production_id]
+                        .push(self.node_to_kbnf_source(view[[production_id]]));
+                }
+            }
+            let mut alternatives: Vec<_> = productions.iter().map(|x| x.join(" ")).collect();
+            if nonterminal_id == start && self.is_nullable(self.start_nonterminal_id) {
+                alternatives.push("\"\"".to_string());
+            }
+            source.push_str(&format!(
+                "{} ::= {};\n",
+                self.nonterminal_str(NonterminalID(nonterminal_id.as_()))
+                    .unwrap(),
+                alternatives.join(" | ")
+            ));
+        }
+        source
+    }
+
+    fn node_to_kbnf_source(&self, node: HIRNode<TI>) -> String {
+        // The raw bytes are not special to the escaping, so they are displayed after it.
+        let quote = |string: &str| {
+            format!(
+                "\"{}\"",
+                preprocessor::display_raw_bytes(&utils::escape_kbnf_string(string))
+            )
+        };
+        let regex = |regex_id: RegexID<TI>| {
+            quote(utils::unanchored_regex(self.regex_str(regex_id).unwrap()))
+        };
+        match node {
+            HIRNode::Terminal(x) => quote(self.terminal_str(x).unwrap()),
+            HIRNode::RegexString(x) => format!("#{}", regex(x)),
+            HIRNode::EarlyEndRegexString(x) => format!("#e{}", regex(x)),
+            HIRNode::RegexComplement(x) => {
+                // The complements are stored without the start anchor.
+                let regex = self.regex_str(x).unwrap();
+                let regex = regex
+                    .strip_prefix("(?:")
+                    .and_then(|x| x.strip_suffix(")\\z"))
+                    .unwrap_or(regex);
+                format!("#ex{}", quote(regex))
+            }
+            HIRNode::Substrings(x) => {
+                format!("#substrs{}", quote(self.suffix_automata_str(x).unwrap()))
+            }
+            HIRNode::Nonterminal(x) => self.nonterminal_str(x).unwrap().to_string(),
+            HIRNode::LengthPrefixed(x) => {
+                let length_prefixed = self.length_prefixed(x);
+                format!(
+                    "#lenprefixed(#{}, #{})",
+                    quote(length_prefixed.numeric_regex_str()),
+                    quote(length_prefixed.content_regex_str())
+                )
+            }
+            HIRNode::ByteSetComplement(x) => format!("#{}", quote(&x.negated_class(self))),
+            HIRNode::AnyChar => "#char".to_string(),
+            HIRNode::BoundedSubstrings(x) => {
+                let bounded_substrings = self.bounded_substrings(x);
+                format!(
+                    "#substrs({},{}){}",
+                    bounded_substrings.min(),
+                    bounded_substrings.max(),
+                    quote(bounded_substrings.string())
+                )
+            }
+            HIRNode::Json => "#json".to_string(),
+        }
+    }
     #[inline]
     /// Get the start nonterminal id.
     pub fn get_start_nonterminal_id(&self) -> NonterminalID<TI> {
@@ -762,6 +2652,16 @@ where
             .resolve(SymbolU32::try_from_usize(regex_id.0.as_()).unwrap())
     }
     #[inline]
+    /// Get the regex with named capture groups, which is compiled only if the regex names any group.
+    pub fn capture_regex(&self, regex_id: RegexID<TI>) -> Option<&CaptureRegex> {
+        self.id_to_capture_regexes.get(&regex_id.0.as_())
+    }
+    #[inline]
+    /// Check whether any regex in the grammar names a capture group.
+    pub fn has_capture_regexes(&self) -> bool {
+        !self.id_to_capture_regexes.is_empty()
+    }
+    #[inline]
     /// Get the suffix automata string from the grammar.
     pub fn suffix_automata_str(&self, suffix_automata_id: SuffixAutomataID<TI>) -> Option<&str> {
         self.interned_strings
@@ -774,6 +2674,84 @@ where
         &self.id_to_regexes[regex_id.0.as_()]
     }
     #[inline]
+    /// Check whether the regex is annotated as partial(`#p""`), in which case
+    /// the regex in any non-rejecting state is considered completable by [`EngineLike::can_finish`](crate::engine_like::EngineLike::can_finish).
+    pub fn is_partial_regex(&self, regex_id: RegexID<TI>) -> bool {
+        self.partial_regexes.contains(regex_id.0.as_())
+    }
+    #[inline]
+    /// Get the prior of the nonterminal, which wraps a weighted alternative like `"A" %0.9`.
+    ///
+    /// Returns [`None`] if the nonterminal does not wrap a weighted alternative.
+    pub fn nonterminal_prior(&self, nonterminal_id: NonterminalID<TI>) -> Option<f32> {
+        self.nonterminal_priors.get(&nonterminal_id).copied()
+    }
+    #[inline]
+    /// Check whether any alternative in the grammar is weighted.
+    pub fn has_priors(&self) -> bool {
+        !self.nonterminal_priors.is_empty()
+    }
+    #[inline]
+    /// Check whether the nonterminal is annotated as atomic(`@atomic name ::= ...;`),
+    /// in which case no token may straddle the start or the end of the nonterminal.
+    pub fn is_atomic_nonterminal(&self, nonterminal_id: NonterminalID<TI>) -> bool {
+        self.atomic_nonterminals.contains(nonterminal_id.0.as_())
+    }
+    #[inline]
+    /// Check whether any nonterminal in the grammar is annotated as atomic.
+    pub fn has_atomic_nonterminals(&self) -> bool {
+        !self.atomic_nonterminals.is_clear()
+    }
+    #[inline]
+    /// Check whether the nonterminal matches the empty string, like `start ::= "a"?;`.
+    ///
+    /// The simplification removes the empty string from the rules, so the analysis is done on the KBNF grammar string;
+    /// it is available in the same cases as [`Grammar::unreachable_nonterminals`] and is `false` otherwise.
+    pub fn is_nullable(&self, nonterminal_id: NonterminalID<TI>) -> bool {
+        self.nullable_nonterminals.contains(nonterminal_id.0.as_())
+    }
+    #[inline]
+    /// Get the bytes that can start a nonempty string matched by the nonterminal, i.e. its FIRST set in bytes.
+    ///
+    /// The first bytes are computed once when the grammar is created, following the nonterminals at the start of the productions.
+    /// They are not restricted to the bytes in the vocabulary.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the nonterminal id is out of bounds.
+    pub fn first_bytes_of_nonterminal(&self, nonterminal_id: NonterminalID<TI>) -> &ByteSet {
+        &self.nonterminal_first_bytes[nonterminal_id.0.as_()]
+    }
+    #[inline]
+    /// Get the length-prefixed node from the grammar.
+    pub fn length_prefixed(&self, length_prefixed_id: LengthPrefixedID<TI>) -> &LengthPrefixed {
+        &self.id_to_length_prefixed[length_prefixed_id.0.as_()]
+    }
+    #[inline]
+    /// Get the byte set of a byte set complement node from the grammar,
+    /// which contains the first bytes of the characters that the node matches.
+    pub fn byte_set_complement(&self, byte_set_id: ByteSetID<TI>) -> &ByteSet {
+        &self.id_to_byte_set_complements[byte_set_id.0.as_()]
+    }
+    #[inline]
+    /// Get the length-prefixed nodes from the grammar.
+    pub fn id_to_length_prefixed(&self) -> &[LengthPrefixed] {
+        &self.id_to_length_prefixed
+    }
+    #[inline]
+    /// Get the bounded substrings node from the grammar.
+    pub fn bounded_substrings(
+        &self,
+        bounded_substrings_id: BoundedSubstringsID<TI>,
+    ) -> &BoundedSubstrings {
+        &self.id_to_bounded_substrings[bounded_substrings_id.0.as_()]
+    }
+    #[inline]
+    /// Get the bounded substrings nodes from the grammar.
+    pub fn id_to_bounded_substrings(&self) -> &[BoundedSubstrings] {
+        &self.id_to_bounded_substrings
+    }
+    #[inline]
     /// Get the suffix automata from the grammar.
     pub fn suffix_automata(&self, suffix_automata_id: SuffixAutomataID<TI>) -> &SuffixAutomaton {
         &self.id_to_suffix_automata[suffix_automata_id.0.as_()]
@@ -836,6 +2814,119 @@ where
     pub fn nonterminals_size(&self) -> usize {
         self.interned_strings.nonterminals.len()
     }
+    /// Get the nonterminals that are defined but cannot be reached from the start nonterminal,
+    /// in the order of their definitions.
+    ///
+    /// The simplification removes these rules from the grammar, so the analysis is done on the KBNF grammar string;
+    /// it is only available when the grammar is created by [`Engine`](crate::engine::Engine) or [`Grammar::from_gbnf`],
+    /// and is empty for a grammar created by [`Grammar::new`].
+    /// The nonterminals created by desugaring the syntax extensions are not reported.
+    pub fn unreachable_nonterminals(&self) -> Vec<String> {
+        self.unreachable_nonterminals.clone()
+    }
+    /// Get the nonterminals that cannot derive any string, like a left recursion without a base case,
+    /// in the order of their definitions.
+    ///
+    /// The analysis is available in the same cases as [`Grammar::unreachable_nonterminals`].
+    pub fn nonproductive_nonterminals(&self) -> Vec<String> {
+        self.nonproductive_nonterminals.clone()
+    }
+    /// Analyze the grammar for likely sources of ambiguity.
+    ///
+    /// The analysis is done on the simplified grammar and only looks for common patterns:
+    /// productions of the same nonterminal with overlapping prefixes,
+    /// productions that differ only in two nonterminals sharing a production,
+    /// and productions that both start and end with their own nonterminal.
+    /// It is neither sound nor complete; a grammar without warnings may still be ambiguous
+    /// and a warning does not always imply ambiguity.
+    /// At most one warning of each kind is reported for each nonterminal.
+    pub fn analyze_ambiguity(&self) -> Vec<AmbiguityWarning> {
+        let productions: Vec<Vec<Vec<HIRNode<TI>>>> = (0..self.rules.len())
+            .map(|nonterminal_id| self.productions(nonterminal_id))
+            .collect();
+        let name = |nonterminal_id: NonterminalID<TI>| {
+            self.nonterminal_str(nonterminal_id)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let mut warnings = Vec::new();
+        for (nonterminal_id, productions_of_nonterminal) in productions.iter().enumerate() {
+            let nonterminal = NonterminalID(nonterminal_id.as_());
+            let pairs = || {
+                productions_of_nonterminal
+                    .iter()
+                    .enumerate()
+                    .flat_map(move |(i, x)| {
+                        productions_of_nonterminal[i + 1..]
+                            .iter()
+                            .map(move |y| (x, y))
+                    })
+            };
+            if pairs().any(|(x, y)| self.nodes_overlap(x[0], y[0])) {
+                warnings.push(AmbiguityWarning::OverlappingPrefixes {
+                    nonterminal: name(nonterminal),
+                });
+            }
+            let conflict = pairs().find_map(|(x, y)| {
+                if x.len() != y.len() {
+                    return None;
+                }
+                let mut differences = x.iter().zip(y.iter()).filter(|(a, b)| a != b);
+                match (differences.next(), differences.next()) {
+                    (Some((HIRNode::Nonterminal(a), HIRNode::Nonterminal(b))), None)
+                        if productions[a.0.as_()]
+                            .iter()
+                            .any(|x| productions[b.0.as_()].contains(x)) =>
+                    {
+                        Some((*a, *b))
+                    }
+                    _ => None,
+                }
+            });
+            if let Some((first, second)) = conflict {
+                warnings.push(AmbiguityWarning::ReduceReduceConflict {
+                    nonterminal: name(nonterminal),
+                    first_nonterminal: name(first),
+                    second_nonterminal: name(second),
+                });
+            }
+            if productions_of_nonterminal.iter().any(|x| {
+                x.len() >= 2
+                    && x[0] == HIRNode::Nonterminal(nonterminal)
+                    && x[x.len() - 1] == HIRNode::Nonterminal(nonterminal)
+            }) {
+                warnings.push(AmbiguityWarning::AmbiguousRecursion {
+                    nonterminal: name(nonterminal),
+                });
+            }
+        }
+        warnings
+    }
+    /// Rebuild the productions of the nonterminal from the dotted rules.
+    fn productions(&self, nonterminal_id: usize) -> Vec<Vec<HIRNode<TI>>> {
+        let view = self.rules.view::<1, 2>([nonterminal_id]);
+        if view.len() == 0 {
+            return Vec::new();
+        }
+        let mut productions = vec![Vec::new(); view.view::<1, 1>([0]).len()];
+        for dot_position in 0..view.len() {
+            let view = view.view::<1, 1>([dot_position]);
+            for production_id in 0..view.len() {
+                productions[production_id].push(view[[production_id]]);
+            }
+        }
+        productions
+    }
+    /// Check whether two leading nodes obviously match a common prefix.
+    fn nodes_overlap(&self, first: HIRNode<TI>, second: HIRNode<TI>) -> bool {
+        match (first, second) {
+            (HIRNode::Terminal(x), HIRNode::Terminal(y)) => {
+                let (x, y) = (self.terminal(x), self.terminal(y));
+                x.starts_with(y) || y.starts_with(x)
+            }
+            _ => first == second,
+        }
+    }
     #[inline]
     pub(crate) fn first_bytes_from_regex(
         &self,
@@ -862,11 +2953,25 @@ where
     pub(crate) unsafe fn dotted_productions(
         &self,
         nonterminal_id: NonterminalID<TI>,
-    ) -> JaggedArrayView<HIRNode<TI>, usize, 2> {
+    ) -> JaggedArrayView<'_, HIRNode<TI>, usize, 2> {
         unsafe { self.rules.view_unchecked::<1, 2>([nonterminal_id.0.as_()]) }
     }
     #[inline]
     pub(crate) fn rules(&self) -> &JaggedArray<HIRNode<TI>, Vec<usize>, 3> {
         &self.rules
     }
+    /// Get the maximum length of the productions and the maximum number of productions of a nonterminal,
+    /// which decide the generic parameters(TD and TP) of [`EngineBase`](crate::engine_base::EngineBase).
+    pub(crate) fn max_dotted_position_and_production_id(&self) -> (usize, usize) {
+        let mut max_dotted_position = 0;
+        let mut max_production_id = 0;
+        for i in 0..self.rules.len() {
+            let view = self.rules.view::<1, 2>([i]);
+            max_dotted_position = max_dotted_position.max(view.len());
+            if view.len() > 0 {
+                max_production_id = max_production_id.max(view.view::<1, 1>([0]).len());
+            }
+        }
+        (max_dotted_position, max_production_id)
+    }
 }
@@ -0,0 +1,211 @@
+//! The recognizer of JSON values(RFC 8259) behind the `#json` node, which the engine drives byte by byte.
+//!
+//! The whole state of the recognizer is packed into one integer, so it fits in the state id of an Earley item.
+//! The lowest [`MODE_BITS`] bits store the mode, i.e. what the recognizer expects next,
+//! and the bits above store the stack of the open arrays and objects.
+//! The stack starts from a single one bit and each open container pushes one bit, which is set for objects.
+use crate::utils::{self, ByteSet};
+
+/// The maximum nesting depth of arrays and objects.
+pub(crate) const MAX_DEPTH: u32 = 24;
+/// The number of bits storing the mode.
+const MODE_BITS: u32 = 6;
+/// The number of bits needed by the state of the recognizer.
+pub(crate) const STATE_BITS: u32 = MODE_BITS + MAX_DEPTH + 1;
+const MODE_MASK: usize = (1 << MODE_BITS) - 1;
+
+/// Expects a value.
+const VALUE: usize = 0;
+/// Expects a value or `]` right after `[`.
+const ARRAY_START: usize = 1;
+/// Expects a key or `}` right after `{`.
+const OBJECT_START: usize = 2;
+/// Expects a key after `,` in an object.
+const KEY: usize = 3;
+/// Expects `:` after a key.
+const COLON: usize = 4;
+/// Expects `,` or the end of the innermost container after a value.
+const AFTER_VALUE: usize = 5;
+/// Expects the integer part after `-`.
+const MINUS: usize = 6;
+/// A number whose integer part is `0`.
+const ZERO: usize = 7;
+/// A number in its integer part.
+const INT: usize = 8;
+/// Expects the fraction part after `.`.
+const DOT: usize = 9;
+/// A number in its fraction part.
+const FRAC: usize = 10;
+/// Expects the exponent or its sign after `e` or `E`.
+const EXP_MARK: usize = 11;
+/// Expects the exponent after its sign.
+const EXP_SIGN: usize = 12;
+/// A number in its exponent.
+const EXP: usize = 13;
+/// Expects `LITERAL_TAILS[mode - LITERAL]`, the rest of `true`, `false` or `null`.
+const LITERAL: usize = 14;
+/// The rest of the literals after their first bytes.
+const LITERAL_TAILS: &[u8] = b"ruealseull";
+/// The indices of the last bytes of the literals in [`LITERAL_TAILS`].
+const LITERAL_ENDS: [usize; 3] = [2, 6, 9];
+/// The modes in a string value, which are the string mode in [`STRING_MODES`] plus this base.
+const STRING: usize = LITERAL + LITERAL_TAILS.len();
+/// The modes in a key, which are the string mode in [`STRING_MODES`] plus this base.
+const KEY_STRING: usize = STRING + STRING_MODES;
+/// The string modes: 0 expects a character, 1 to 7 are the states of the UTF-8 decoder,
+/// [`ESCAPE`] is after `\` and the modes from [`HEX`] expect the four hex digits of `\u`.
+const STRING_MODES: usize = 13;
+const ESCAPE: usize = 8;
+const HEX: usize = 9;
+
+/// The state before any byte.
+pub(crate) const START: usize = (1 << MODE_BITS) | VALUE;
+
+#[inline]
+fn state(stack: usize, mode: usize) -> usize {
+    (stack << MODE_BITS) | mode
+}
+
+#[inline]
+fn push(stack: usize, is_object: bool) -> Option<usize> {
+    let stack = (stack << 1) | is_object as usize;
+    (stack >> MAX_DEPTH <= 1).then_some(stack)
+}
+
+#[inline]
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+/// Feed a byte to the recognizer.
+///
+/// Returns the next state, or `None` if the byte is rejected.
+pub(crate) fn feed(state_id: usize, byte: u8) -> Option<usize> {
+    let stack = state_id >> MODE_BITS;
+    let mode = state_id & MODE_MASK;
+    match mode {
+        VALUE | ARRAY_START => match byte {
+            b']' if mode == ARRAY_START => Some(state(stack >> 1, AFTER_VALUE)),
+            _ if is_whitespace(byte) => Some(state_id),
+            b'{' => Some(state(push(stack, true)?, OBJECT_START)),
+            b'[' => Some(state(push(stack, false)?, ARRAY_START)),
+            b'"' => Some(state(stack, STRING)),
+            b'-' => Some(state(stack, MINUS)),
+            b'0' => Some(state(stack, ZERO)),
+            b'1'..=b'9' => Some(state(stack, INT)),
+            b't' => Some(state(stack, LITERAL)),
+            b'f' => Some(state(stack, LITERAL + LITERAL_ENDS[0] + 1)),
+            b'n' => Some(state(stack, LITERAL + LITERAL_ENDS[1] + 1)),
+            _ => None,
+        },
+        OBJECT_START | KEY => match byte {
+            b'}' if mode == OBJECT_START => Some(state(stack >> 1, AFTER_VALUE)),
+            b'"' => Some(state(stack, KEY_STRING)),
+            _ if is_whitespace(byte) => Some(state_id),
+            _ => None,
+        },
+        COLON => match byte {
+            b':' => Some(state(stack, VALUE)),
+            _ if is_whitespace(byte) => Some(state_id),
+            _ => None,
+        },
+        // The top-level value is complete, which only accepts trailing whitespace.
+        AFTER_VALUE if stack == 1 => is_whitespace(byte).then_some(state_id),
+        AFTER_VALUE => {
+            let is_object = stack & 1 == 1;
+            match byte {
+                b',' => Some(state(stack, if is_object { KEY } else { VALUE })),
+                b'}' if is_object => Some(state(stack >> 1, AFTER_VALUE)),
+                b']' if !is_object => Some(state(stack >> 1, AFTER_VALUE)),
+                _ if is_whitespace(byte) => Some(state_id),
+                _ => None,
+            }
+        }
+        MINUS => match byte {
+            b'0' => Some(state(stack, ZERO)),
+            b'1'..=b'9' => Some(state(stack, INT)),
+            _ => None,
+        },
+        DOT => byte.is_ascii_digit().then_some(state(stack, FRAC)),
+        EXP_MARK => match byte {
+            b'+' | b'-' => Some(state(stack, EXP_SIGN)),
+            b'0'..=b'9' => Some(state(stack, EXP)),
+            _ => None,
+        },
+        EXP_SIGN => byte.is_ascii_digit().then_some(state(stack, EXP)),
+        ZERO | INT | FRAC | EXP => match byte {
+            b'0'..=b'9' if mode != ZERO => Some(state_id),
+            b'.' if mode == ZERO || mode == INT => Some(state(stack, DOT)),
+            b'e' | b'E' if mode != EXP => Some(state(stack, EXP_MARK)),
+            // The number ends here, so the byte is fed as the one after the value.
+            _ => feed(state(stack, AFTER_VALUE), byte),
+        },
+        LITERAL..STRING => {
+            let index = mode - LITERAL;
+            if byte != LITERAL_TAILS[index] {
+                None
+            } else if LITERAL_ENDS.contains(&index) {
+                Some(state(stack, AFTER_VALUE))
+            } else {
+                Some(state_id + 1)
+            }
+        }
+        _ => {
+            let (base, string_mode) = if mode >= KEY_STRING {
+                (KEY_STRING, mode - KEY_STRING)
+            } else {
+                (STRING, mode - STRING)
+            };
+            let next = match string_mode {
+                0 => match byte {
+                    b'"' => {
+                        let next = if base == KEY_STRING {
+                            COLON
+                        } else {
+                            AFTER_VALUE
+                        };
+                        return Some(state(stack, next));
+                    }
+                    b'\\' => ESCAPE,
+                    // Control characters must be escaped.
+                    0x00..=0x1F => return None,
+                    _ => utils::feed_any_utf8_char(0, byte)?,
+                },
+                ESCAPE => match byte {
+                    b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => 0,
+                    b'u' => HEX,
+                    _ => return None,
+                },
+                HEX..STRING_MODES => {
+                    if !byte.is_ascii_hexdigit() {
+                        return None;
+                    }
+                    if string_mode + 1 == STRING_MODES {
+                        0
+                    } else {
+                        string_mode + 1
+                    }
+                }
+                _ => utils::feed_any_utf8_char(string_mode, byte)?,
+            };
+            Some(state(stack, base + next))
+        }
+    }
+}
+
+/// Whether the bytes fed so far form a complete JSON value.
+pub(crate) fn is_complete(state_id: usize) -> bool {
+    state_id >> MODE_BITS == 1
+        && matches!(state_id & MODE_MASK, AFTER_VALUE | ZERO | INT | FRAC | EXP)
+}
+
+/// Get the bytes accepted by the recognizer in the state.
+pub(crate) fn first_bytes(state_id: usize) -> ByteSet {
+    let mut set = ByteSet::with_capacity(256);
+    for byte in 0..=u8::MAX {
+        if feed(state_id, byte).is_some() {
+            set.insert(byte as usize);
+        }
+    }
+    set
+}
@@ -78,7 +78,7 @@ assert_eq!(&format!("{:?}", logits), "[0.0, 0.0, 0.0, 0.0, 0.0, 0.0]");
 The primary type in this crate are [EngineLike] and [Engine]. [EngineLike] defines the behavior of an engine,
 while [Engine] is a concrete implementation of [EngineLike]. The most important method in [Engine] are as follows:
 - [Engine::new]: This method creates a new engine from a [KBNF grammar](#kbnf-grammar) string, a [Vocabulary] and default configuration.
-    [Engine::with_config] allows you to specify a custom configuration.
+  [Engine::with_config] allows you to specify a custom configuration.
 - [Engine::update_logits]: This method tries to accept a new token and then updates the logits accordingly.
 - [Engine::reset]: This method resets the engine to its initial state. Notably, the cache is preserved.
 
@@ -243,6 +243,29 @@ A terminal is a sequence of UTF-8 characters enclosed in double quotes or single
 All [Javascript escaped characters](https://developer.mozilla.org/en-US/docs/Web/JavaScript/Reference/Regular_expressions/Character_escape)
  are supported.
 
+A `\xNN` escape whose value is at least `0x80` stands for a raw byte rather than the character `U+00NN`,
+so a terminal containing such escapes is a byte literal, which may not be valid UTF-8 on its own.
+The same holds in regular expressions, except in character classes, where raw bytes are not supported.
+The characters `U+10FF80` to `U+10FFFF` are reserved to represent the raw bytes internally.
+Likewise, terminals and regular expressions may not contain `__kbnf_`, which is reserved for the markers of the syntax extensions internally.
+
+```ebnf
+start ::= "\xff\xfe";
+(*
+The engine will constrain the output to be exactly the two bytes 0xFF and 0xFE.
+*)
+```
+
+A terminal enclosed in `#i""` or `#i''` is case-insensitive. Only ASCII letters are folded,
+so the other characters, including non-ASCII letters like `é`, are matched literally.
+
+```ebnf
+start ::= #i"select" " *";
+(*
+The engine will constrain the output to be like "SELECT *", "select *" or "Select *".
+*)
+```
+
 ## Concatenation
 
 Two or more symbols in a sequence are concatenated.
@@ -281,6 +304,19 @@ that only contains "A" and "B".
 *)
 ```
 
+An alternative followed by `%` and a probability is weighted by the prior.
+The priors do not change the constraints, but [EngineLike::bias_logits_by_grammar_prior] adds their logarithms to the logits.
+Any positive weight is accepted as the prior, so a weight above 1 like `%2.0` raises the logits of its alternative
+while a weight below 1 lowers them.
+
+```ebnf
+start ::= "A" %0.9 | "B" %0.1;
+(*
+The engine will constrain the output to be either "A" or "B",
+while the tokens consistent with "A" are biased by ln(0.9) and the tokens consistent with "B" are biased by ln(0.1).
+*)
+```
+
 ## Grouping
 
 Symbols enclosed in parentheses are grouped.
@@ -325,8 +361,11 @@ The engine will constrain the output to be a sequence of balanced curly brackets
 *)
 ```
 
-**NOTE THAT KBNF does not allow the grammar to finish with an empty string.**
+**NOTE THAT KBNF does not allow the grammar to finish with an empty string by default.**
 Otherwise, the engine will finish immediately, which does not make sense.
+When [`Config::allow_empty_match`](crate::config::Config::allow_empty_match) is set,
+a nullable start nonterminal makes the engine [able to finish](crate::EngineLike::can_finish) before accepting any bytes
+without [finishing](crate::EngineLike::is_finished) it, so the caller decides whether to stop, e.g. with the EOS token.
 
 ## Repetition
 
@@ -358,9 +397,20 @@ start ::= ("A"|"B")+ "C";
 a nonempty sequence of "A"s and "B"s followed by exactly one "C".*)
 ```
 
+A symbol followed by `{m}`, `{m,n}` or `{m,}` is repeated exactly m times,
+between m and n times, or at least m times respectively. `{0,0}` skips the symbol entirely.
+The repetitions are expanded into nonterminals whose number grows logarithmically with the bounds,
+so large bounds do not blow up the grammar.
+
+```ebnf
+start ::= #"[0-9]"{3,5} ("," "A"{2})*;
+(*The engine will constrain the output to
+three to five digits followed by any number of ",AA".*)
+```
+
 ## Regular expression
 
-There are three types of regular expressions:
+There are five types of regular expressions:
 
 - A UTF-8 string enclosed in `#""` or `#''` is a regular expression. The escaped characters supported is the same as [Terminal](##terminal).
 
@@ -374,6 +424,9 @@ start ::= #".+" "A";
 *)
 ```
 
+After the regex matches, the engine offers both continuing the regex and the symbols after the regex,
+so the regex may still match a longer string.
+
 - A UTF-8 string enclosed in `#e""` or `#e''` is a regular expression. The escaped characters supported is the same as [Terminal](##terminal).
 
 ```ebnf
@@ -386,6 +439,10 @@ In other words, two consecutive A will not appear in the middle of the output.
 *)
 ```
 
+The regex ends at its first match, i.e. the shortest match, so the bytes after it must match the symbols after the regex.
+Since the engine finishes as soon as the start nonterminal matches, a regex at the end of the start nonterminal
+ends at its first match with either prefix.
+
 - A UTF-8 string enclosed in `#ex""` or `#ex''` is a complement of a regular expression. The escaped characters supported is the same as [Terminal](##terminal).
 
 ```ebnf
@@ -395,6 +452,29 @@ The engine will constrain the output to be anything that does not contain "a", "
 *)
 ```
 
+- A UTF-8 string enclosed in `#p""` or `#p''` is a partial regular expression. The escaped characters supported is the same as [Terminal](##terminal).
+  It constrains the output in the same way as `#""`, but [EngineLike::can_finish] considers it completable as long as the regex has not rejected the output.
+
+```ebnf
+start ::= #p"\\d{4}";
+(*
+The engine will constrain the output to be four digits,
+but the output is considered completable after a shorter prefix like "12".
+*)
+```
+
+- A UTF-8 string enclosed in `#u""` or `#u''` is an unanchored regular expression. The escaped characters supported is the same as [Terminal](##terminal).
+  Like an unanchored search, it skips any characters before an occurrence of the regex, so the match ends right after the occurrence.
+  It is equivalent to `#"(?s:.)*(?:...)"`.
+
+```ebnf
+start ::= #u"ab" "!";
+(*
+The engine will constrain the output to be like "ab!" or "xxab!",
+where "ab" may appear anywhere before the "!".
+*)
+```
+
 
 The Rust regex crate is used to support regular expressions,
 which means [the syntax supported](https://docs.rs/regex/latest/regex/index.html#syntax) might differ from other regex engines.
@@ -402,6 +482,13 @@ Notably, the regex crate does not support arbitrary lookarounds. In exchange, li
 **WARNING: the regular expression is compiled into a DFA which, by its nature, has worst case exponential time and space complexity.**
 If you are dealing with untrusted regular expressions,
 you should set a memory limit in [Config::regex_config] to prevent DoS attacks.
+A regex exceeding the limit results in [CreateGrammarError::RegexTooLargeToCompile](crate::grammar::CreateGrammarError::RegexTooLargeToCompile)
+instead of exhausting the memory.
+
+By default, `.` and negated classes like `[^a]` match one UTF-8 encoded character, so a token that is not valid UTF-8 on its own,
+like a token of the single byte `0xFF`, is never allowed by a regex.
+Set [RegexConfig::dot_matches_bytes](crate::config::RegexConfig::dot_matches_bytes) to let them match any single byte instead,
+at the cost of the Unicode features of the regexes.
 
 ## Substrings
 
@@ -416,6 +503,158 @@ since empty string is a substring of any string.
 *)
 ```
 
+The lengths of the substrings can be bounded by writing the minimum and the optional maximum length in bytes after `#substrs`,
+like the bounds of a [repetition](#repetition). The maximum defaults to the length of the string.
+
+```ebnf
+start ::= #substrs(2,3)"abcde" '\n';
+(*
+The engine will constrain the output to be a substring of "abcde" with 2 or 3 bytes ended with a newline,
+like "ab\n" or "cde\n", while "a\n" and "abcd\n" are rejected.
+`#substrs(3,)"abcde"` allows the substrings with at least 3 bytes, and `#substrs(2)"abcde"` those with exactly 2 bytes.
+*)
+```
+
+## Superstrings
+
+A UTF-8 string enclosed in `#contains""` is a superstrings symbol, the dual of the substrings symbol.
+A superstrings symbol constrains the output to contain the given string somewhere, so it can only finish after the string has appeared.
+
+```ebnf
+start ::= #contains"XYZ" '\n';
+(*
+The engine will constrain the output to be like "fooXYZbar\n" or "XYZ\n",
+while the output "foo\n" is not finished since "XYZ" has not appeared yet.
+*)
+```
+
+## Length-prefixed content
+
+`#lenprefixed(numeric, content)` constrains the output to be a length field followed by exactly that many bytes.
+Both arguments are regular expressions. The numeric regular expression matches the length field first,
+and the ASCII digits in the length field are read as a decimal number.
+The content regular expression then matches exactly that many bytes.
+
+```ebnf
+start ::= #lenprefixed(#"[0-9]+:", #"[a-z]*") ',';
+(*
+The engine will constrain the output to be like "3:abc," or "12:abcdefghijkl,",
+which cannot be expressed by a context-free grammar.
+*)
+```
+
+## Quoted strings
+
+`#qstring(quote, escape)` constrains the output to be a quoted string with escape sequences.
+Both arguments are string literals of exactly one character. Inside the string, the escape character
+makes the next character, including the quote character, part of the string, and the unescaped
+quote character closes the string.
+
+```ebnf
+start ::= #qstring('"', '\\') ';';
+(*
+The engine will constrain the output to be like "a\"b"; or "\\";,
+while the output "a\"; is not finished since the escaped quote does not close the string.
+*)
+```
+
+## Built-in regular expressions
+
+`#builtin(name)` is a regular expression for a common format, where `name` is one of:
+
+- `date`: an RFC 3339 full-date like `2024-02-29`. The days are not checked against the months.
+- `time`: an RFC 3339 partial-time like `23:59:60.5`.
+- `datetime`: an RFC 3339 date-time like `2024-02-29T23:59:60.5+08:00`.
+- `uuid`: a UUID in the canonical form like `123e4567-e89b-12d3-a456-426614174000`, in either case.
+- `ipv4`: a dotted-decimal IPv4 address without leading zeros like `192.168.0.1`.
+- `email`: an email address with an unquoted local part and a domain of at least two labels like `a.b@example.com`.
+
+```ebnf
+start ::= '{"id": "' #builtin(uuid) '", "at": "' #builtin(datetime) '"}';
+```
+
+## Any character
+
+`#char` matches exactly one UTF-8 encoded character. It is equivalent to `#"(?s:.)"`,
+but the engine decodes the character directly instead of stepping through a regex automaton.
+
+```ebnf
+start ::= #char{3} '\n';
+(*
+The engine will constrain the output to be like "abc\n" or "你好!\n".
+*)
+```
+
+## JSON
+
+`#json` matches exactly one JSON value as defined by RFC 8259, including the escapes in strings,
+the number formats and the balance of arrays and objects. Whitespace is allowed before, after and between
+the tokens of the value, following the `ws value ws` form of the RFC. The engine recognizes the value directly
+instead of expanding it into nonterminals, and the nesting depth of arrays and objects is limited to 24.
+
+```ebnf
+start ::= '<tool_call>' #json '</tool_call>';
+(*
+The engine will constrain the output to be like "<tool_call>{"name": "search", "args": [1, 2.5e3]}</tool_call>".
+*)
+```
+
+## Atomic nonterminals
+
+`@atomic` before the left hand side of a rule annotates the nonterminal as atomic.
+No token may straddle the start or the end of an atomic nonterminal, so every token either stays inside
+the nonterminal or lies entirely outside it. This is useful when a field should be tokenized on its own,
+for example to make the tokenization of the output similar to the tokenization of the training data.
+
+```ebnf
+start ::= field ',' field;
+@atomic field ::= #"[a-z]+";
+(*
+For the output "ab,cd", the engine will reject tokens like "b," or ",c".
+*)
+```
+
+The constraint only applies to tokens. [`EngineLike::try_accept_new_bytes`](crate::engine_like::EngineLike::try_accept_new_bytes)
+accepts bytes regardless of the token boundaries.
+
+## Preserved nonterminals
+
+The grammar is simplified before the engine is created, which inlines the nonterminals with only one symbol in their definitions.
+`@preserve` before the left hand side of a rule keeps the nonterminal from being inlined, so it appears in
+[`EngineLike::parse_tree`](crate::engine_like::EngineLike::parse_tree) and can be used to extract fields from the output.
+
+```ebnf
+start ::= key '=' value;
+@preserve key ::= #"[a-z]+";
+@preserve value ::= #"[0-9]+";
+(*
+For the output "abc=123", the parse tree contains `key` spanning 0..3 and `value` spanning 4..7.
+*)
+```
+
+The annotations can be combined, like `@atomic @preserve field ::= #"[a-z]+";`.
+Atomic nonterminals are never inlined anyway.
+
+## Parameterized rules
+
+A rule can take nonterminals as parameters, written in angle brackets after the left hand side.
+Each use of the rule with a distinct list of arguments, like `list<digit>`, is expanded into an ordinary rule
+where the parameters are replaced with the arguments. An argument can be another use of a parameterized rule.
+
+```ebnf
+start ::= list<digit> ';' list<pair<digit, letter>>;
+list<T> ::= T | T ',' list<T>;
+pair<A, B> ::= A '=' B;
+digit ::= #"[0-9]";
+letter ::= #"[a-z]";
+(*
+The engine will constrain the output to be like "1,2;3=a,4=b".
+*)
+```
+
+A parameterized rule can only refer to itself with the same arguments,
+since a rule like `nested<T> ::= T | nested<pair<T, T>>;` would expand forever and is rejected.
+
 # Performance
 
 ## Reducing ambuguity
@@ -427,13 +666,26 @@ for [ambiguity](https://en.wikipedia.org/wiki/Ambiguous_grammar). Unfortunately,
 There does exist some heuristics to detect ambiguity like
 [Shift-Reduce Conflict](https://www.gnu.org/software/bison/manual/html_node/Shift_002fReduce.html) and
 [Reduce-Reduce Conflict](https://www.gnu.org/software/bison/manual/html_node/Reduce_002fReduce.html#:~:text=A%20reduce/reduce%20conflict%20occurs,zero%20or%20more%20word%20groupings).
-They may be implemented in this crate in the future. Some locally disambiguation methods may be implemented in the future as well.
+Some of these heuristics are implemented in [Grammar::analyze_ambiguity](crate::grammar::Grammar::analyze_ambiguity),
+and [Config::collect_ambiguity_warnings] runs it when the engine is created,
+logging each warning and storing them in [Engine::ambiguity_warnings].
+Some locally disambiguation methods may be implemented in the future as well.
 
 ## Reuse an engine for multiple generations with cache enabled
 
 Caches are preserved between [Engine::reset] calls.
 Hence, if your grammar and vocabulary are fixed, you should reuse the engine for multiple generations,
 so when the engine hits the same state, it can directly fetch the allowed token IDs from the cache without recomputation.
+If multiple engines of the same grammar run at the same time, e.g. one engine per worker thread,
+create them with [Engine::with_shared_cache](crate::engine::Engine::with_shared_cache)
+so the allowed token IDs of the same state are computed once for all of them.
+
+## Compute allowed tokens in parallel with the `rayon` feature
+
+With the `rayon` feature enabled, [EngineLike::compute_allowed_token_ids] checks the tokens in parallel
+in the current [rayon](https://docs.rs/rayon) thread pool, where the tokens are sharded by their first bytes.
+This helps large vocabularies when the allowed tokens cannot be fetched from the caches,
+at the cost of copying the engine's states once per shard.
 
 ## Prefer regular expressions over context-free grammars
 
@@ -447,11 +699,17 @@ it still introduces a constant factor overhead.
 #![warn(missing_docs)]
 #![warn(rustdoc::broken_intra_doc_links)]
 pub mod config;
+pub mod diagnostic;
 pub mod engine;
 pub mod engine_base;
 pub mod engine_like;
 mod ffi_bindings;
+mod gbnf;
 pub mod grammar;
+mod json;
+mod preprocessor;
+pub mod schema;
+mod token_trie;
 pub mod utils;
 pub mod vocabulary;
 mod zero;
@@ -478,14 +736,20 @@ fn kbnf(m: &Bound<'_, PyModule>) -> PyResult<()> {
     pyo3_log::init();
     m.add_class::<Config>()?;
     m.add_class::<config::CompressionConfig>()?;
+    m.add_class::<config::EagerCacheConfig>()?;
     m.add_class::<config::Fsa>()?;
     m.add_class::<config::RegexConfig>()?;
     m.add_class::<engine::EngineConfig>()?;
     m.add_class::<Engine>()?;
+    m.add_class::<engine::EngineCheckpoint>()?;
     m.add_class::<AcceptTokenResult>()?;
     m.add_class::<engine_like::AcceptTokenError>()?;
+    m.add_class::<engine_like::RollbackError>()?;
     m.add_class::<engine_like::MaskLogitsError>()?;
     m.add_class::<engine_like::UpdateLogitsError>()?;
+    m.add_class::<engine_like::ParseNode>()?;
+    m.add_class::<engine_like::AcceptMetrics>()?;
+    m.add_class::<engine_like::HealResult>()?;
     m.add_class::<Vocabulary>()?;
     m.add_class::<Token>()?;
     Ok(())
@@ -0,0 +1,1518 @@
+//! The preprocessor that desugars the syntax extensions of KBNF into the syntax understood by [`kbnf_syntax`].
+//!
+//! The preprocessor works on the grammar string directly. It only recognizes comments, string literals,
+//! the structure of alternations and the extensions themselves, and leaves everything else untouched,
+//! so the parsing errors are still reported by [`kbnf_syntax`].
+use std::borrow::Cow;
+
+use ahash::AHashMap;
+
+use crate::grammar::CreateGrammarError;
+
+/// The prefix of the marker inserted at the beginning of a partial regex(`#p""`).
+///
+/// The marker is an empty named capture group, so it does not change the language accepted by the regex.
+/// Each marker gets a unique name because the regexes may be merged together during simplification.
+pub(crate) const PARTIAL_REGEX_MARKER: &str = "(?P<__kbnf_partial_";
+/// The marker of the regex that carries the prior of a weighted alternative(`"A" %0.9`).
+///
+/// The empty class makes the regex never match anything, while the regex keeps the wrapper nonterminal
+/// of the alternative from being inlined during simplification. The prior follows the marker directly.
+pub(crate) const PRIOR_REGEX_MARKER: &str = "[a&&b]__kbnf_prior=";
+const PRIOR_NONTERMINAL_PREFIX: &str = "__kbnf_prior_";
+/// The common prefix of the nonterminals created by desugaring the syntax extensions.
+pub(crate) const GENERATED_NONTERMINAL_PREFIX: &str = "__kbnf_";
+/// The prefix of the nonterminals created for a bounded repetition(`"a"{2,4}`).
+const REPETITION_NONTERMINAL_PREFIX: &str = "__kbnf_repeat_";
+/// The prefix of the nonterminals created for an instance of a parameterized rule(`list<digit>`).
+const GENERIC_NONTERMINAL_PREFIX: &str = "__kbnf_generic_";
+/// The marker of the regex that stands for a length-prefixed node(`#lenprefixed(numeric, content)`).
+///
+/// The empty class makes the regex itself never match anything, since [`Grammar`](crate::grammar::Grammar)
+/// replaces it with the length-prefixed node. The hex-encoded numeric regex and content regex,
+/// separated by a comma, follow the marker directly.
+pub(crate) const LENGTH_PREFIXED_REGEX_MARKER: &str = "[a&&b]__kbnf_lenprefixed=";
+/// The marker of the regex that stands for a bounded substrings node(`#substrs(min,max)"..."`).
+///
+/// The empty class makes the regex itself never match anything, since [`Grammar`](crate::grammar::Grammar)
+/// replaces it with the bounded substrings node. The minimum length, the maximum length and the hex-encoded string,
+/// separated by commas, follow the marker directly.
+pub(crate) const BOUNDED_SUBSTRINGS_REGEX_MARKER: &str = "[a&&b]__kbnf_substrs=";
+/// The marker of the regex that annotates a nonterminal as atomic(`@atomic name ::= ...;`).
+///
+/// The empty class makes the regex never match anything, so the alternative appended to the rule
+/// does not change the language of the nonterminal.
+pub(crate) const ATOMIC_REGEX_MARKER: &str = "[a&&b]__kbnf_atomic";
+/// The marker of the regex that keeps a nonterminal from being inlined(`@preserve name ::= ...;`).
+///
+/// The grammar simplification inlines the nonterminals with only one symbol in their definitions,
+/// which the alternative appended to the rule prevents without changing the language of the nonterminal.
+const PRESERVE_REGEX_MARKER: &str = "[a&&b]__kbnf_preserve";
+/// The marker of the regex that stands for a node matching any single character(`#char`).
+///
+/// The empty class makes the regex itself never match anything, since [`Grammar`](crate::grammar::Grammar)
+/// replaces it with the any-character node.
+pub(crate) const ANY_CHAR_REGEX_MARKER: &str = "[a&&b]__kbnf_anychar";
+/// The marker of the regex that stands for a node matching one JSON value(`#json`).
+///
+/// The empty class makes the regex itself never match anything, since [`Grammar`](crate::grammar::Grammar)
+/// replaces it with the JSON node.
+pub(crate) const JSON_REGEX_MARKER: &str = "[a&&b]__kbnf_json";
+/// The first character of the range that stands for the raw bytes written as `\xNN` escapes in terminals and regexes.
+///
+/// kbnf_syntax only stores UTF-8 strings, so the escapes of the bytes that are not ASCII are rewritten into
+/// the characters `U+10FF80..=U+10FFFF` at the end of the Supplementary Private Use Area-B,
+/// which [`Grammar`](crate::grammar::Grammar) lowers back into the raw bytes.
+/// The literals that contain these characters themselves are rejected, see [`find_raw_byte_char`].
+const RAW_BYTE_CHAR_BASE: u32 = 0x10FF00;
+/// The prefixes of the string literals where `\xNN` escapes stand for raw bytes.
+const RAW_BYTE_PREFIXES: &[&str] = &["", "#", "#e", "#ex", "#p", "#u"];
+/// The annotations written before the left hand side of a rule, e.g. `@atomic`.
+const ANNOTATIONS: &[&str] = &["atomic", "preserve"];
+/// The extensions written like function calls, e.g. `#lenprefixed(#"[0-9]+:", #".*")`.
+const CALL_EXTENSIONS: &[&str] = &["builtin", "lenprefixed", "qstring"];
+/// The names and the regexes of the built-in macros(`#builtin(uuid)`).
+///
+/// The regexes avoid backslashes, so they can be written into regex literals without escaping.
+const BUILTIN_REGEXES: &[(&str, &str)] = &[
+    // RFC 3339 full-date, e.g. 2024-02-29. The days are not checked against the months.
+    (
+        "date",
+        "[0-9]{4}-(?:0[1-9]|1[0-2])-(?:0[1-9]|[12][0-9]|3[01])",
+    ),
+    // RFC 3339 partial-time, e.g. 23:59:60.5. The leap second is allowed.
+    (
+        "time",
+        "(?:[01][0-9]|2[0-3]):[0-5][0-9]:(?:[0-5][0-9]|60)(?:[.][0-9]+)?",
+    ),
+    // RFC 3339 date-time, e.g. 2024-02-29T23:59:60.5+08:00.
+    (
+        "datetime",
+        "[0-9]{4}-(?:0[1-9]|1[0-2])-(?:0[1-9]|[12][0-9]|3[01])[Tt](?:[01][0-9]|2[0-3]):[0-5][0-9]:(?:[0-5][0-9]|60)(?:[.][0-9]+)?(?:[Zz]|[+-](?:[01][0-9]|2[0-3]):[0-5][0-9])",
+    ),
+    // RFC 4122 UUID in the canonical 8-4-4-4-12 form of either case.
+    (
+        "uuid",
+        "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+    ),
+    // The dotted-decimal IPv4 address without leading zeros, e.g. 192.168.0.1.
+    (
+        "ipv4",
+        "(?:(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])[.]){3}(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])",
+    ),
+    // The email address with an unquoted local part and a domain of at least two labels,
+    // which is the common subset of RFC 5322 accepted by most validators.
+    (
+        "email",
+        "[A-Za-z0-9!#$%&'*+/=?^_`{|}~-]+(?:[.][A-Za-z0-9!#$%&'*+/=?^_`{|}~-]+)*@[A-Za-z0-9](?:[A-Za-z0-9-]*[A-Za-z0-9])?(?:[.][A-Za-z0-9](?:[A-Za-z0-9-]*[A-Za-z0-9])?)+",
+    ),
+];
+/// The extensions written as a bare keyword, e.g. `#char`.
+const KEYWORD_EXTENSIONS: &[&str] = &["char", "json"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lexeme<'a> {
+    /// A comment like `(* comment *)`.
+    Comment(&'a str),
+    /// A string literal with an optional prefix like `"a"`, `#'a'` or `#substrs"a"`.
+    /// The body is kept as is, including the escape sequences.
+    Literal {
+        prefix: Cow<'a, str>,
+        quote: char,
+        body: Cow<'a, str>,
+    },
+    /// `::=` or `=`.
+    Definition(&'a str),
+    /// One of `(`, `[` and `{`.
+    GroupStart(char),
+    /// One of `)`, `]` and `}`.
+    GroupEnd(char),
+    /// `|`.
+    Alternation,
+    /// `;`.
+    RuleEnd,
+    /// The prior of an alternative like `%0.9`, without the `%`.
+    Prior(&'a str),
+    /// An extension written like a function call, e.g. `#lenprefixed(#"[0-9]+:", #".*")`.
+    /// The arguments are kept as is, without the parentheses.
+    Call { name: &'a str, arguments: &'a str },
+    /// An extension written as a bare keyword like `#char`, without the `#`.
+    Keyword(&'a str),
+    /// An annotation of the following rule like `@atomic`, without the `@`.
+    Annotation(&'a str),
+    /// The bounds of a bounded repetition like `{2,4}`, without the curly brackets.
+    Repetition(&'a str),
+    /// A symbol created by desugaring, e.g. `(#"...")?`.
+    Symbol(String),
+    /// Anything else.
+    Verbatim(&'a str),
+}
+
+impl Lexeme<'_> {
+    fn write_to(&self, buffer: &mut String) {
+        match self {
+            Lexeme::Comment(x) | Lexeme::Verbatim(x) | Lexeme::Definition(x) => buffer.push_str(x),
+            Lexeme::Symbol(x) => buffer.push_str(x),
+            Lexeme::Literal {
+                prefix,
+                quote,
+                body,
+            } => {
+                buffer.push_str(prefix);
+                buffer.push(*quote);
+                buffer.push_str(body);
+                buffer.push(*quote);
+            }
+            Lexeme::GroupStart(x) | Lexeme::GroupEnd(x) => buffer.push(*x),
+            Lexeme::Alternation => buffer.push('|'),
+            Lexeme::RuleEnd => buffer.push(';'),
+            Lexeme::Prior(x) => {
+                buffer.push('%');
+                buffer.push_str(x);
+            }
+            Lexeme::Annotation(x) => {
+                buffer.push('@');
+                buffer.push_str(x);
+            }
+            Lexeme::Repetition(x) => {
+                buffer.push('{');
+                buffer.push_str(x);
+                buffer.push('}');
+            }
+            Lexeme::Call { name, arguments } => {
+                buffer.push('#');
+                buffer.push_str(name);
+                buffer.push('(');
+                buffer.push_str(arguments);
+                buffer.push(')');
+            }
+            Lexeme::Keyword(x) => {
+                buffer.push('#');
+                buffer.push_str(x);
+            }
+        }
+    }
+}
+
+fn is_identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// Finds the end(exclusive) of the string literal whose body starts at `start`.
+fn find_literal_end(bytes: &[u8], start: usize, quote: u8) -> Option<usize> {
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            x if x == quote => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Finds the `}` of the bounded repetition like `{2,4}` whose `{` is at `start`.
+///
+/// Since a symbol in a repetition group never starts with a digit, `{` followed by digits is always a bounded repetition.
+fn find_repetition_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    let mut found_digit = false;
+    let mut found_comma = false;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'0'..=b'9' => found_digit = true,
+            b',' if found_digit && !found_comma => found_comma = true,
+            b'}' if found_digit => return Some(i),
+            x if x.is_ascii_whitespace() => {}
+            _ => return None,
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Finds the start of the last symbol in the verbatim text, if the text ends with a nonterminal
+/// followed by optional whitespaces and postfix operators.
+fn find_last_nonterminal_start(verbatim: &str) -> Option<usize> {
+    let bytes = verbatim.as_bytes();
+    let mut end = bytes.len();
+    while end > 0 && (bytes[end - 1].is_ascii_whitespace() || b"?*+".contains(&bytes[end - 1])) {
+        end -= 1;
+    }
+    let mut start = end;
+    while start > 0 && is_identifier_byte(bytes[start - 1]) {
+        start -= 1;
+    }
+    (start < end && !bytes[start].is_ascii_digit()).then_some(start)
+}
+
+/// Finds the matching `)`(exclusive) of the `(` right before `start`,
+/// skipping the parentheses inside string literals and comments.
+fn find_call_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut depth = 1;
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'(' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = bytes[i + 2..].windows(2).position(|x| x == b"*)")?;
+                i += 2 + end + 2;
+                continue;
+            }
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            quote @ (b'\'' | b'"') => i = find_literal_end(bytes, i + 1, quote)?,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Splits the arguments of a call at the top-level commas.
+fn split_arguments(arguments: &str) -> Vec<&str> {
+    let bytes = arguments.as_bytes();
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut argument_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth -= 1,
+            b',' if depth == 0 => {
+                result.push(&arguments[argument_start..i]);
+                argument_start = i + 1;
+            }
+            quote @ (b'\'' | b'"') => {
+                i = find_literal_end(bytes, i + 1, quote).unwrap_or(bytes.len());
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    result.push(&arguments[argument_start..]);
+    result
+}
+
+fn encode_hex(input: &str) -> String {
+    input.bytes().map(|x| format!("{x:02x}")).collect()
+}
+
+/// Decodes the hex string created by the preprocessor.
+pub(crate) fn decode_hex(input: &str) -> Option<String> {
+    if !input.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Rewrites the `\xNN` escapes of the bytes that are not ASCII into the characters standing for raw bytes,
+/// or returns `None` if the body has no such escapes.
+fn escape_raw_bytes(body: &str) -> Option<String> {
+    let mut result = String::with_capacity(body.len());
+    let mut changed = false;
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let byte = body
+            .get(i + 1..i + 4)
+            .and_then(|x| x.strip_prefix('x'))
+            .and_then(|x| u8::from_str_radix(x, 16).ok())
+            .filter(|x| !x.is_ascii());
+        match byte {
+            Some(byte) => {
+                result.push_str(&format!(
+                    "\\u{{{:x}}}",
+                    RAW_BYTE_CHAR_BASE + u32::from(byte)
+                ));
+                chars.nth(2);
+                changed = true;
+            }
+            None => {
+                result.push(c);
+                if let Some((_, c)) = chars.next() {
+                    result.push(c);
+                }
+            }
+        }
+    }
+    changed.then_some(result)
+}
+
+/// Finds the character standing for a raw byte in the body of a literal, written either as is or as a `\u{...}` escape.
+///
+/// Such characters are reserved for the `\xNN` escapes, since they would be lowered into the raw bytes otherwise.
+fn find_raw_byte_char(body: &str) -> Option<char> {
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if raw_byte(c).is_some() {
+            return Some(c);
+        }
+        if c != '\\' {
+            continue;
+        }
+        let escaped = body
+            .get(i + 1..)
+            .and_then(|x| x.strip_prefix("u{"))
+            .and_then(|x| x.split_once('}'))
+            .and_then(|(x, _)| u32::from_str_radix(x, 16).ok())
+            .and_then(char::from_u32)
+            .filter(|&x| raw_byte(x).is_some());
+        if escaped.is_some() {
+            return escaped;
+        }
+        chars.next();
+    }
+    None
+}
+
+/// Gets the raw byte that the character stands for, see [`RAW_BYTE_CHAR_BASE`].
+pub(crate) fn raw_byte(c: char) -> Option<u8> {
+    (c as u32)
+        .checked_sub(RAW_BYTE_CHAR_BASE)
+        .and_then(|x| u8::try_from(x).ok())
+        .filter(|x| !x.is_ascii())
+}
+
+/// Lowers the characters standing for raw bytes in the string into the raw bytes.
+pub(crate) fn lower_raw_bytes(input: &str) -> Cow<'_, [u8]> {
+    if !input.chars().any(|c| raw_byte(c).is_some()) {
+        return Cow::Borrowed(input.as_bytes());
+    }
+    let mut bytes = Vec::with_capacity(input.len());
+    for c in input.chars() {
+        match raw_byte(c) {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Cow::Owned(bytes)
+}
+
+/// Shows the characters standing for raw bytes in the string as `\xNN` escapes.
+pub(crate) fn display_raw_bytes(input: &str) -> Cow<'_, str> {
+    if !input.chars().any(|c| raw_byte(c).is_some()) {
+        return Cow::Borrowed(input);
+    }
+    Cow::Owned(
+        input
+            .chars()
+            .map(|c| match raw_byte(c) {
+                Some(byte) => format!("\\x{byte:02X}"),
+                None => c.to_string(),
+            })
+            .collect(),
+    )
+}
+
+fn lex(input: &str) -> Vec<Lexeme<'_>> {
+    let bytes = input.as_bytes();
+    let mut lexemes = Vec::new();
+    let mut verbatim_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let lexeme_start = i;
+        let lexeme = if bytes[i..].starts_with(b"(*") {
+            let end = input[i + 2..]
+                .find("*)")
+                .map(|x| i + 2 + x + 2)
+                .unwrap_or(bytes.len());
+            i = end;
+            Some(Lexeme::Comment(&input[lexeme_start..end]))
+        } else if bytes[i..].starts_with(b"::=") {
+            i += 3;
+            Some(Lexeme::Definition(&input[lexeme_start..i]))
+        } else if bytes[i] == b'#' || bytes[i] == b'\'' || bytes[i] == b'"' {
+            let mut j = i;
+            if bytes[j] == b'#' {
+                j += 1;
+                while j < bytes.len() && is_identifier_byte(bytes[j]) {
+                    j += 1;
+                }
+            }
+            match bytes.get(j) {
+                Some(&quote) if quote == b'\'' || quote == b'"' => {
+                    match find_literal_end(bytes, j + 1, quote) {
+                        Some(end) => {
+                            i = end + 1;
+                            Some(Lexeme::Literal {
+                                prefix: Cow::Borrowed(&input[lexeme_start..j]),
+                                quote: quote as char,
+                                body: Cow::Borrowed(&input[j + 1..end]),
+                            })
+                        }
+                        // Leave the unterminated literal to kbnf_syntax so it reports the error.
+                        None => {
+                            i = bytes.len();
+                            None
+                        }
+                    }
+                }
+                // The bounds of `#substrs(min,max)"..."` are kept in the prefix of the literal.
+                Some(b'(') if &input[i + 1..j] == "substrs" => {
+                    let literal = find_call_end(bytes, j + 1).and_then(|end| {
+                        let quote = *bytes.get(end + 1)?;
+                        if quote != b'\'' && quote != b'"' {
+                            return None;
+                        }
+                        Some((end + 1, find_literal_end(bytes, end + 2, quote)?))
+                    });
+                    match literal {
+                        Some((start, end)) => {
+                            i = end + 1;
+                            Some(Lexeme::Literal {
+                                prefix: Cow::Borrowed(&input[lexeme_start..start]),
+                                quote: bytes[start] as char,
+                                body: Cow::Borrowed(&input[start + 1..end]),
+                            })
+                        }
+                        None => {
+                            i = j;
+                            None
+                        }
+                    }
+                }
+                _ if CALL_EXTENSIONS.contains(&&input[i + 1..j]) => {
+                    let name = &input[i + 1..j];
+                    let mut k = j;
+                    while k < bytes.len() && bytes[k].is_ascii_whitespace() {
+                        k += 1;
+                    }
+                    match bytes.get(k) {
+                        Some(b'(') => match find_call_end(bytes, k + 1) {
+                            Some(end) => {
+                                i = end + 1;
+                                Some(Lexeme::Call {
+                                    name,
+                                    arguments: &input[k + 1..end],
+                                })
+                            }
+                            // Leave the unterminated call to kbnf_syntax so it reports the error.
+                            None => {
+                                i = bytes.len();
+                                None
+                            }
+                        },
+                        _ => {
+                            i = j;
+                            None
+                        }
+                    }
+                }
+                _ if KEYWORD_EXTENSIONS.contains(&&input[i + 1..j]) => {
+                    i = j;
+                    Some(Lexeme::Keyword(&input[lexeme_start + 1..j]))
+                }
+                _ => {
+                    i = j.max(i + 1);
+                    None
+                }
+            }
+        } else if bytes[i] == b'%' {
+            let mut j = i + 1;
+            while j < bytes.len() && (bytes[j].is_ascii_digit() || bytes[j] == b'.') {
+                j += 1;
+            }
+            i = j;
+            Some(Lexeme::Prior(&input[lexeme_start + 1..j]))
+        } else if bytes[i] == b'@' {
+            let mut j = i + 1;
+            while j < bytes.len() && is_identifier_byte(bytes[j]) {
+                j += 1;
+            }
+            i = j;
+            Some(Lexeme::Annotation(&input[lexeme_start + 1..j]))
+        } else if let Some(end) = (bytes[i] == b'{')
+            .then(|| find_repetition_end(bytes, i))
+            .flatten()
+        {
+            i = end + 1;
+            Some(Lexeme::Repetition(&input[lexeme_start + 1..end]))
+        } else {
+            i += 1;
+            match bytes[lexeme_start] {
+                b'=' => Some(Lexeme::Definition(&input[lexeme_start..i])),
+                x @ (b'(' | b'[' | b'{') => Some(Lexeme::GroupStart(x as char)),
+                x @ (b')' | b']' | b'}') => Some(Lexeme::GroupEnd(x as char)),
+                b'|' => Some(Lexeme::Alternation),
+                b';' => Some(Lexeme::RuleEnd),
+                _ => None,
+            }
+        };
+        if let Some(lexeme) = lexeme {
+            if verbatim_start < lexeme_start {
+                lexemes.push(Lexeme::Verbatim(&input[verbatim_start..lexeme_start]));
+            }
+            lexemes.push(lexeme);
+            verbatim_start = i;
+        }
+    }
+    if verbatim_start < bytes.len() {
+        lexemes.push(Lexeme::Verbatim(&input[verbatim_start..]));
+    }
+    lexemes
+}
+
+/// Finds the byte offset of the first occurrence of the nonterminal in the grammar string,
+/// skipping the string literals and comments.
+pub(crate) fn find_nonterminal(input: &str, name: &str) -> Option<usize> {
+    lex(input).into_iter().find_map(|lexeme| {
+        let Lexeme::Verbatim(verbatim) = lexeme else {
+            return None;
+        };
+        let bytes = verbatim.as_bytes();
+        let offset = verbatim.as_ptr() as usize - input.as_ptr() as usize;
+        verbatim.match_indices(name).find_map(|(i, _)| {
+            let end = i + name.len();
+            let is_boundary = (i == 0 || !is_identifier_byte(bytes[i - 1]))
+                && (end == bytes.len() || !is_identifier_byte(bytes[end]));
+            is_boundary.then_some(offset + i)
+        })
+    })
+}
+
+/// Finds the nonterminals defined in the KBNF grammar string in order of their definitions.
+///
+/// A nonterminal defined by multiple rules appears multiple times.
+pub(crate) fn defined_nonterminals(input: &str) -> Vec<&str> {
+    let mut nonterminals = Vec::new();
+    let mut last_verbatim = None;
+    for lexeme in lex(input) {
+        match lexeme {
+            Lexeme::Verbatim(x) => last_verbatim = Some(x),
+            Lexeme::Definition(_) => {
+                if let Some(verbatim) = last_verbatim.take() {
+                    if let Some(start) = find_last_nonterminal_start(verbatim) {
+                        nonterminals.push(verbatim[start..].trim_end());
+                    }
+                }
+            }
+            Lexeme::Comment(_) | Lexeme::Annotation(_) => {}
+            _ => last_verbatim = None,
+        }
+    }
+    nonterminals
+}
+
+/// An alternative in a group whose content is already desugared.
+#[derive(Debug)]
+struct Alternative {
+    content: String,
+    prior: Option<f64>,
+    /// Whether the alternative only contains whitespaces and comments so far.
+    is_empty: bool,
+    /// The start of the last symbol in the content, which a bounded repetition applies to.
+    last_symbol_start: Option<usize>,
+}
+
+impl Alternative {
+    fn new() -> Self {
+        Self {
+            content: String::new(),
+            prior: None,
+            is_empty: true,
+            last_symbol_start: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Group {
+    start: Option<char>,
+    alternatives: Vec<Alternative>,
+}
+
+impl Group {
+    fn new(start: Option<char>) -> Self {
+        Self {
+            start,
+            alternatives: vec![Alternative::new()],
+        }
+    }
+
+    fn last_alternative(&mut self) -> &mut Alternative {
+        // SAFETY: a group always has at least one alternative
+        unsafe { self.alternatives.last_mut().unwrap_unchecked() }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Desugarer {
+    partial_regex_count: usize,
+    prior_count: usize,
+    repetition_count: usize,
+    /// The rules created during desugaring, which are appended to the end of the grammar.
+    extra_rules: String,
+}
+
+/// Parses an argument of a call that must be a regex like `#"[0-9]+"`, returning the unescaped regex.
+fn parse_regex_argument(name: &str, argument: &str) -> Result<String, CreateGrammarError> {
+    let error = || {
+        CreateGrammarError::PreprocessingError(format!(
+            "the argument {} of #{name} is not a regular expression like #\"[0-9]+\".",
+            argument.trim()
+        ))
+    };
+    match lex(argument.trim()).as_slice() {
+        [Lexeme::Literal { prefix, body, .. }] if prefix == "#" => {
+            unescaper::unescape(body).map_err(|_| error())
+        }
+        _ => Err(error()),
+    }
+}
+
+/// Parses an argument of a call that must be a string literal of exactly one character like `'"'`.
+fn parse_char_argument(name: &str, argument: &str) -> Result<char, CreateGrammarError> {
+    let error = || {
+        CreateGrammarError::PreprocessingError(format!(
+            "the argument {} of #{name} is not a string literal of exactly one character like '\"'.",
+            argument.trim()
+        ))
+    };
+    match lex(argument.trim()).as_slice() {
+        [Lexeme::Literal { prefix, body, .. }] if prefix.is_empty() => {
+            let body = unescaper::unescape(body).map_err(|_| error())?;
+            let mut chars = body.chars();
+            match (chars.next(), chars.next()) {
+                (Some(x), None) => Ok(x),
+                _ => Err(error()),
+            }
+        }
+        _ => Err(error()),
+    }
+}
+
+impl Desugarer {
+    fn desugar_call<'a>(
+        &mut self,
+        name: &str,
+        arguments: &str,
+    ) -> Result<Lexeme<'a>, CreateGrammarError> {
+        match name {
+            "builtin" => {
+                let argument = arguments.trim();
+                let Some((_, regex)) = BUILTIN_REGEXES.iter().find(|(x, _)| *x == argument) else {
+                    let names: Vec<_> = BUILTIN_REGEXES.iter().map(|(x, _)| *x).collect();
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "#builtin expects one of {}, but ({arguments}) is given.",
+                        names.join(", ")
+                    )));
+                };
+                Ok(Lexeme::Literal {
+                    prefix: Cow::Borrowed("#"),
+                    quote: '"',
+                    body: Cow::Borrowed(regex),
+                })
+            }
+            "lenprefixed" => {
+                let [numeric, content] = split_arguments(arguments)[..] else {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "#lenprefixed expects a numeric regex and a content regex, but ({arguments}) is given."
+                    )));
+                };
+                let numeric = parse_regex_argument(name, numeric)?;
+                let content = parse_regex_argument(name, content)?;
+                Ok(Lexeme::Literal {
+                    prefix: Cow::Borrowed("#"),
+                    quote: '"',
+                    body: Cow::Owned(format!(
+                        "{LENGTH_PREFIXED_REGEX_MARKER}{},{}",
+                        encode_hex(&numeric),
+                        encode_hex(&content)
+                    )),
+                })
+            }
+            "qstring" => {
+                let [quote, escape] = split_arguments(arguments)[..] else {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "#qstring expects a quote character and an escape character, but ({arguments}) is given."
+                    )));
+                };
+                let quote = parse_char_argument(name, quote)?;
+                let escape = parse_char_argument(name, escape)?;
+                if quote == escape {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "the quote character and the escape character of #qstring are both {quote:?}."
+                    )));
+                }
+                // The characters are written as hex escapes, so they never need to be escaped in the regex.
+                // The backslashes are doubled since kbnf_syntax unescapes the regex literal.
+                let quote = format!("\\\\x{{{:x}}}", quote as u32);
+                let escape = format!("\\\\x{{{:x}}}", escape as u32);
+                Ok(Lexeme::Literal {
+                    prefix: Cow::Borrowed("#"),
+                    quote: '"',
+                    body: Cow::Owned(format!(
+                        "{quote}(?:[^{quote}{escape}]|{escape}(?s:.))*{quote}"
+                    )),
+                })
+            }
+            _ => unreachable!("The names of calls are restricted by CALL_EXTENSIONS."),
+        }
+    }
+
+    fn desugar_lexeme<'a>(&mut self, lexeme: Lexeme<'a>) -> Result<Lexeme<'a>, CreateGrammarError> {
+        if let Lexeme::Literal { body, .. } = &lexeme {
+            if let Some(c) = find_raw_byte_char(body) {
+                return Err(CreateGrammarError::PreprocessingError(format!(
+                    "the character U+{:X} in \"{body}\" is reserved for the raw bytes written as \\xNN escapes.",
+                    c as u32
+                )));
+            }
+            // The markers inserted by desugaring are recognized by their bodies, which the literals must not forge.
+            if body.contains(GENERATED_NONTERMINAL_PREFIX) {
+                return Err(CreateGrammarError::PreprocessingError(format!(
+                    "{GENERATED_NONTERMINAL_PREFIX} in \"{body}\" is reserved for the markers of the syntax extensions."
+                )));
+            }
+        }
+        let lexeme = match lexeme {
+            Lexeme::Literal {
+                prefix,
+                quote,
+                body,
+            } if RAW_BYTE_PREFIXES.contains(&prefix.as_ref()) => Lexeme::Literal {
+                body: escape_raw_bytes(&body).map_or(body, Cow::Owned),
+                prefix,
+                quote,
+            },
+            lexeme => lexeme,
+        };
+        Ok(match lexeme {
+            Lexeme::Call { name, arguments } => self.desugar_call(name, arguments)?,
+            Lexeme::Keyword("char") => Lexeme::Literal {
+                prefix: Cow::Borrowed("#"),
+                quote: '"',
+                body: Cow::Borrowed(ANY_CHAR_REGEX_MARKER),
+            },
+            Lexeme::Keyword("json") => Lexeme::Literal {
+                prefix: Cow::Borrowed("#"),
+                quote: '"',
+                body: Cow::Borrowed(JSON_REGEX_MARKER),
+            },
+            Lexeme::Literal {
+                prefix,
+                quote,
+                body,
+            } if prefix == "#p" => {
+                self.partial_regex_count += 1;
+                Lexeme::Literal {
+                    prefix: Cow::Borrowed("#"),
+                    quote,
+                    body: Cow::Owned(format!(
+                        "{PARTIAL_REGEX_MARKER}{}>)(?:{body})",
+                        self.partial_regex_count
+                    )),
+                }
+            }
+            Lexeme::Literal {
+                prefix,
+                quote,
+                body,
+            } if prefix == "#u" => Lexeme::Literal {
+                // Any prefix is skipped like an unanchored search, so the match ends right after an occurrence of the regex.
+                prefix: Cow::Borrowed("#"),
+                quote,
+                body: Cow::Owned(format!("(?s:.)*(?:{body})")),
+            },
+            Lexeme::Literal {
+                prefix,
+                quote,
+                body,
+            } if prefix == "#i" => {
+                let string = unescaper::unescape(&body).map_err(|_| {
+                    CreateGrammarError::PreprocessingError(format!(
+                        "the case-insensitive terminal #i{quote}{body}{quote} contains invalid escape sequences."
+                    ))
+                })?;
+                // Only ASCII letters are folded. The other characters are written as hex escapes,
+                // so they never need to be escaped in the regex and match literally.
+                // The backslashes are doubled since kbnf_syntax unescapes the regex literal.
+                let mut regex = String::with_capacity(string.len() * 4);
+                for c in string.chars() {
+                    if c.is_ascii_alphabetic() {
+                        regex.push_str(&format!(
+                            "[{}{}]",
+                            c.to_ascii_lowercase(),
+                            c.to_ascii_uppercase()
+                        ));
+                    } else {
+                        regex.push_str(&format!("\\\\x{{{:x}}}", c as u32));
+                    }
+                }
+                Lexeme::Literal {
+                    prefix: Cow::Borrowed("#"),
+                    quote: '"',
+                    body: Cow::Owned(regex),
+                }
+            }
+            Lexeme::Literal {
+                prefix,
+                quote,
+                body,
+            } if prefix.starts_with("#substrs(") => {
+                let bounds = &prefix["#substrs(".len()..prefix.len() - 1];
+                let string = unescaper::unescape(&body).map_err(|_| {
+                    CreateGrammarError::PreprocessingError(format!(
+                        "the substrings symbol {prefix}{quote}{body}{quote} contains invalid escape sequences."
+                    ))
+                })?;
+                let (min, max) = parse_substrings_bounds(bounds, string.len())?;
+                let regex = format!(
+                    "{BOUNDED_SUBSTRINGS_REGEX_MARKER}{},{max},{}",
+                    min.max(1),
+                    encode_hex(&string)
+                );
+                match (min, max) {
+                    (_, 0) => Lexeme::Literal {
+                        prefix: Cow::Borrowed(""),
+                        quote: '\'',
+                        body: Cow::Borrowed(""),
+                    },
+                    // kbnf_syntax drops the regexes matching only the empty string,
+                    // so the node is made optional in the grammar instead.
+                    (0, _) => Lexeme::Symbol(format!(" (#\"{regex}\")? ")),
+                    _ => Lexeme::Literal {
+                        prefix: Cow::Borrowed("#"),
+                        quote: '"',
+                        body: Cow::Owned(regex),
+                    },
+                }
+            }
+            Lexeme::Literal {
+                prefix,
+                quote,
+                body,
+            } if prefix == "#contains" => {
+                let string = unescaper::unescape(&body).map_err(|_| {
+                    CreateGrammarError::PreprocessingError(format!(
+                        "the superstrings symbol #contains{quote}{body}{quote} contains invalid escape sequences."
+                    ))
+                })?;
+                // The characters are written as hex escapes, so they never need to be escaped in the regex.
+                // The backslashes are doubled since kbnf_syntax unescapes the regex literal.
+                let mut regex = String::with_capacity(string.len() * 8 + 16);
+                regex.push_str("(?s:.)*");
+                for c in string.chars() {
+                    regex.push_str(&format!("\\\\x{{{:x}}}", c as u32));
+                }
+                regex.push_str("(?s:.)*");
+                Lexeme::Literal {
+                    prefix: Cow::Borrowed("#"),
+                    quote: '"',
+                    body: Cow::Owned(regex),
+                }
+            }
+            lexeme => lexeme,
+        })
+    }
+
+    fn desugar_alternative(
+        &mut self,
+        alternative: Alternative,
+        buffer: &mut String,
+    ) -> Result<(), CreateGrammarError> {
+        match alternative.prior {
+            Some(prior) => {
+                if alternative.is_empty {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "the prior {prior} is not attached to any alternative."
+                    )));
+                }
+                // The prior is carried by a wrapper nonterminal so the engine can find the alternative later.
+                self.prior_count += 1;
+                let nonterminal = format!("{PRIOR_NONTERMINAL_PREFIX}{}", self.prior_count);
+                self.extra_rules.push_str(&format!(
+                    "\n{nonterminal} ::= {} | #'{PRIOR_REGEX_MARKER}{prior}';",
+                    alternative.content
+                ));
+                buffer.push(' ');
+                buffer.push_str(&nonterminal);
+                buffer.push(' ');
+            }
+            None => buffer.push_str(&alternative.content),
+        }
+        Ok(())
+    }
+
+    fn desugar_group(
+        &mut self,
+        group: Group,
+        end: Option<char>,
+        buffer: &mut String,
+    ) -> Result<(), CreateGrammarError> {
+        if let Some(start) = group.start {
+            buffer.push(start);
+        }
+        for (i, alternative) in group.alternatives.into_iter().enumerate() {
+            if i != 0 {
+                buffer.push('|');
+            }
+            self.desugar_alternative(alternative, buffer)?;
+        }
+        if let Some(end) = end {
+            buffer.push(end);
+        }
+        Ok(())
+    }
+}
+
+impl Desugarer {
+    /// Desugars the bounded repetition of `symbol` into the nonterminals that match the exact number of repetitions.
+    ///
+    /// The nonterminals are created by halving the bounds, so the number of them grows logarithmically with the bounds,
+    /// and every number of repetitions is matched by exactly one alternative to avoid ambiguity.
+    fn desugar_repetition(
+        &mut self,
+        symbol: &str,
+        bounds: &str,
+    ) -> Result<String, CreateGrammarError> {
+        let error = || {
+            CreateGrammarError::PreprocessingError(format!(
+                "the bounds {{{bounds}}} of the repetition are invalid."
+            ))
+        };
+        let parse = |x: &str| x.trim().parse::<usize>().map_err(|_| error());
+        let (min, max) = match bounds.split_once(',') {
+            Some((min, max)) if max.trim().is_empty() => (parse(min)?, None),
+            Some((min, max)) => (parse(min)?, Some(parse(max)?)),
+            None => (parse(bounds)?, Some(parse(bounds)?)),
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(error());
+        }
+        if max == Some(0) {
+            // Skips the symbol entirely.
+            return Ok(" '' ".to_string());
+        }
+        self.repetition_count += 1;
+        let nonterminal = format!("{REPETITION_NONTERMINAL_PREFIX}{}", self.repetition_count);
+        self.extra_rules
+            .push_str(&format!("\n{nonterminal} ::= {symbol};"));
+        let mut exactly = AHashMap::new();
+        let mut at_most = AHashMap::new();
+        let mut result = String::from(" ");
+        if min > 0 {
+            result.push_str(&self.define_exact_repetition(&nonterminal, min, &mut exactly));
+            result.push(' ');
+        }
+        match max {
+            None => result.push_str(&format!("{nonterminal}*")),
+            Some(max) if max > min => {
+                let optional = self.define_bounded_repetition(
+                    &nonterminal,
+                    max - min,
+                    &mut exactly,
+                    &mut at_most,
+                );
+                result.push_str(&format!("{optional}?"));
+            }
+            Some(_) => {}
+        }
+        result.push(' ');
+        Ok(result)
+    }
+
+    /// Defines the nonterminal that matches exactly `count` repetitions of `nonterminal`.
+    fn define_exact_repetition(
+        &mut self,
+        nonterminal: &str,
+        count: usize,
+        defined: &mut AHashMap<usize, String>,
+    ) -> String {
+        if count == 1 {
+            return nonterminal.to_string();
+        }
+        if let Some(name) = defined.get(&count) {
+            return name.clone();
+        }
+        let half = self.define_exact_repetition(nonterminal, count / 2, defined);
+        let name = format!("{nonterminal}_exactly_{count}");
+        let rest = if count % 2 == 1 { nonterminal } else { "" };
+        self.extra_rules
+            .push_str(&format!("\n{name} ::= {half} {half} {rest};"));
+        defined.insert(count, name.clone());
+        name
+    }
+
+    /// Defines the nonterminal that matches one to `count` repetitions of `nonterminal`.
+    fn define_bounded_repetition(
+        &mut self,
+        nonterminal: &str,
+        count: usize,
+        exactly: &mut AHashMap<usize, String>,
+        defined: &mut AHashMap<usize, String>,
+    ) -> String {
+        if count == 1 {
+            return nonterminal.to_string();
+        }
+        if let Some(name) = defined.get(&count) {
+            return name.clone();
+        }
+        // 1..=count is split into 1..=half and half+1..=count.
+        let half = count / 2;
+        let lower = self.define_bounded_repetition(nonterminal, half, exactly, defined);
+        let prefix = self.define_exact_repetition(nonterminal, half, exactly);
+        let upper = self.define_bounded_repetition(nonterminal, count - half, exactly, defined);
+        let name = format!("{nonterminal}_at_most_{count}");
+        self.extra_rules
+            .push_str(&format!("\n{name} ::= {lower} | {prefix} {upper};"));
+        defined.insert(count, name.clone());
+        name
+    }
+}
+
+/// Parses the bounds of `#substrs(min,max)"..."` in bytes, where the maximum is clamped to the length of the string.
+fn parse_substrings_bounds(
+    bounds: &str,
+    length: usize,
+) -> Result<(usize, usize), CreateGrammarError> {
+    let error = || {
+        CreateGrammarError::PreprocessingError(format!(
+            "the bounds ({bounds}) of the substrings symbol are invalid."
+        ))
+    };
+    let parse = |x: &str| x.trim().parse::<usize>().map_err(|_| error());
+    let (min, max) = match bounds.split_once(',') {
+        Some((min, max)) if max.trim().is_empty() => (parse(min)?, length),
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => (parse(bounds)?, parse(bounds)?),
+    };
+    if max < min {
+        return Err(error());
+    }
+    if min > length {
+        return Err(CreateGrammarError::PreprocessingError(format!(
+            "the minimum length {min} of the substrings symbol exceeds the length {length} of the string."
+        )));
+    }
+    Ok((min, max.min(length)))
+}
+
+fn parse_prior(prior: &str) -> Result<f64, CreateGrammarError> {
+    match prior.parse::<f64>() {
+        Ok(x) if x > 0.0 && x.is_finite() => Ok(x),
+        _ => Err(CreateGrammarError::PreprocessingError(format!(
+            "the prior %{prior} is not a positive number."
+        ))),
+    }
+}
+
+/// A rule with parameters like `list<T> ::= T ("," T)*;`.
+#[derive(Debug, Clone)]
+struct Template<'a> {
+    parameters: Vec<&'a str>,
+    /// The text before the left hand side, e.g. the annotations.
+    prefix: String,
+    /// The right hand side without the `;`.
+    body: Vec<Lexeme<'a>>,
+}
+
+fn is_identifier(input: &str) -> bool {
+    !input.is_empty()
+        && input.bytes().all(is_identifier_byte)
+        && !input.as_bytes()[0].is_ascii_digit()
+}
+
+/// Parses the left hand side of a parameterized rule at the end of the verbatim text,
+/// returning the start of the left hand side, the name and the parameters.
+fn parse_template_head(verbatim: &str) -> Option<(usize, &str, &str)> {
+    let head = verbatim.trim_end().strip_suffix('>')?;
+    let open = head.rfind('<')?;
+    let name_end = head[..open].trim_end().len();
+    let name_start = find_last_nonterminal_start(&head[..name_end])?;
+    Some((name_start, &head[name_start..name_end], &head[open + 1..]))
+}
+
+/// Splits the arguments of an instantiation at the commas outside nested instantiations.
+fn split_type_arguments(arguments: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut depth = 0usize;
+    let mut argument_start = 0;
+    for (i, byte) in arguments.bytes().enumerate() {
+        match byte {
+            b'<' => depth += 1,
+            b'>' => depth = depth.saturating_sub(1),
+            b',' if depth == 0 => {
+                result.push(&arguments[argument_start..i]);
+                argument_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(&arguments[argument_start..]);
+    result
+}
+
+/// Replaces the parameters in the verbatim text with the arguments.
+fn substitute_parameters(verbatim: &str, parameters: &[&str], arguments: &[String]) -> String {
+    let bytes = verbatim.as_bytes();
+    let mut result = String::with_capacity(verbatim.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if !is_identifier_byte(bytes[i]) {
+            let end = bytes[i..]
+                .iter()
+                .position(|&x| is_identifier_byte(x))
+                .map_or(bytes.len(), |x| i + x);
+            result.push_str(&verbatim[i..end]);
+            i = end;
+            continue;
+        }
+        let end = bytes[i..]
+            .iter()
+            .position(|&x| !is_identifier_byte(x))
+            .map_or(bytes.len(), |x| i + x);
+        let word = &verbatim[i..end];
+        match parameters.iter().position(|x| *x == word) {
+            Some(index) => result.push_str(&arguments[index]),
+            None => result.push_str(word),
+        }
+        i = end;
+    }
+    result
+}
+
+/// Expands the instances of the parameterized rules into ordinary nonterminals.
+#[derive(Debug, Default)]
+struct TemplateExpander<'a> {
+    templates: AHashMap<&'a str, Vec<Template<'a>>>,
+    /// The nonterminals created for the instances, keyed by the names and the arguments.
+    instances: AHashMap<(&'a str, Vec<String>), String>,
+    /// The parameterized rules being instantiated.
+    stack: Vec<&'a str>,
+    /// The rules created for the instances, which are appended to the end of the grammar.
+    extra_rules: String,
+}
+
+impl<'a> TemplateExpander<'a> {
+    /// Replaces the instantiations like `list<digit>` in the verbatim text with the nonterminals of the instances.
+    ///
+    /// `next` is the lexeme after the verbatim text, which ends the arguments of an unclosed instantiation.
+    fn expand_instantiations(
+        &mut self,
+        verbatim: &str,
+        next: Option<&Lexeme<'_>>,
+    ) -> Result<String, CreateGrammarError> {
+        let mut result = String::with_capacity(verbatim.len());
+        let mut rest = verbatim;
+        while let Some(open) = rest.find('<') {
+            let name_end = rest[..open].trim_end().len();
+            let name_start = rest[..name_end]
+                .bytes()
+                .rposition(|x| !is_identifier_byte(x))
+                .map_or(0, |x| x + 1);
+            let name = &rest[name_start..name_end];
+            if !is_identifier(name) {
+                return Err(CreateGrammarError::PreprocessingError(format!(
+                    "the < in {} is not placed after a parameterized rule.",
+                    verbatim.trim()
+                )));
+            }
+            let mut depth = 0usize;
+            let close = rest[open..].bytes().position(|x| {
+                match x {
+                    b'<' => depth += 1,
+                    b'>' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            });
+            let Some(close) = close.map(|x| open + x) else {
+                if let Some(literal @ Lexeme::Literal { .. }) = next {
+                    let mut argument = String::new();
+                    literal.write_to(&mut argument);
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "the argument {argument} of {name} is not a nonterminal."
+                    )));
+                }
+                return Err(CreateGrammarError::PreprocessingError(format!(
+                    "the arguments of {name} in {} are not closed by >.",
+                    verbatim.trim()
+                )));
+            };
+            let arguments = split_type_arguments(&rest[open + 1..close])
+                .into_iter()
+                .map(|argument| {
+                    let expanded = self.expand_instantiations(argument, None)?;
+                    let expanded = expanded.trim();
+                    if !is_identifier(expanded) {
+                        return Err(CreateGrammarError::PreprocessingError(format!(
+                            "the argument {} of {name} is not a nonterminal.",
+                            argument.trim()
+                        )));
+                    }
+                    Ok(expanded.to_string())
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            result.push_str(&rest[..name_start]);
+            result.push_str(&self.instantiate(name, arguments)?);
+            rest = &rest[close + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /// Gets the nonterminal of the instance of the parameterized rule, which is defined on the first use.
+    fn instantiate(
+        &mut self,
+        name: &str,
+        arguments: Vec<String>,
+    ) -> Result<String, CreateGrammarError> {
+        let Some((&name, templates)) = self.templates.get_key_value(name) else {
+            return Err(CreateGrammarError::PreprocessingError(format!(
+                "the nonterminal {name} is given arguments but is not a parameterized rule."
+            )));
+        };
+        let templates = templates.clone();
+        let key = (name, arguments);
+        if let Some(nonterminal) = self.instances.get(&key) {
+            return Ok(nonterminal.clone());
+        }
+        let arguments = key.1.clone();
+        if self.stack.contains(&name) {
+            return Err(CreateGrammarError::PreprocessingError(format!(
+                "the parameterized rule {name} is instantiated within itself with different arguments <{}>, which never ends.",
+                arguments.join(", ")
+            )));
+        }
+        let nonterminal = format!(
+            "{GENERIC_NONTERMINAL_PREFIX}{name}_{}",
+            self.instances.len() + 1
+        );
+        // The instance is registered before its body is expanded, so it can refer to itself.
+        self.instances.insert(key, nonterminal.clone());
+        self.stack.push(name);
+        for template in templates {
+            if template.parameters.len() != arguments.len() {
+                return Err(CreateGrammarError::PreprocessingError(format!(
+                    "the parameterized rule {name} expects {} arguments, but {} are given.",
+                    template.parameters.len(),
+                    arguments.len()
+                )));
+            }
+            let mut rule = format!("\n{}{nonterminal} ::=", template.prefix.trim_start());
+            for (i, lexeme) in template.body.iter().enumerate() {
+                match lexeme {
+                    Lexeme::Verbatim(x) => {
+                        let substituted =
+                            substitute_parameters(x, &template.parameters, &arguments);
+                        rule.push_str(
+                            &self.expand_instantiations(&substituted, template.body.get(i + 1))?,
+                        );
+                    }
+                    lexeme => lexeme.write_to(&mut rule),
+                }
+            }
+            rule.push(';');
+            self.extra_rules.push_str(&rule);
+        }
+        self.stack.pop();
+        Ok(nonterminal)
+    }
+}
+
+/// Expands the parameterized rules like `list<T> ::= T ("," T)*;` into an ordinary rule for each distinct
+/// list of arguments they are used with, like `list<digit>`, and removes the parameterized rules themselves.
+fn expand_parameterized_rules(input: &str) -> Result<Cow<'_, str>, CreateGrammarError> {
+    if !input.contains('<') {
+        return Ok(Cow::Borrowed(input));
+    }
+    let lexemes = lex(input);
+    let mut expander = TemplateExpander::default();
+    let mut rules = Vec::new();
+    for rule in lexemes.split_inclusive(|x| *x == Lexeme::RuleEnd) {
+        let definition = rule.iter().position(|x| matches!(x, Lexeme::Definition(_)));
+        let head = definition
+            .filter(|&x| x > 0)
+            .and_then(|x| match &rule[x - 1] {
+                Lexeme::Verbatim(verbatim) => {
+                    parse_template_head(verbatim).map(|head| (x, verbatim, head))
+                }
+                _ => None,
+            });
+        let Some((definition, verbatim, (start, name, parameters))) = head else {
+            rules.push(rule);
+            continue;
+        };
+        let parameters: Vec<_> = parameters.split(',').map(str::trim).collect();
+        for (i, parameter) in parameters.iter().enumerate() {
+            if !is_identifier(parameter) || parameters[..i].contains(parameter) {
+                return Err(CreateGrammarError::PreprocessingError(format!(
+                    "the parameters <{}> of {name} are not distinct nonterminals.",
+                    parameters.join(", ")
+                )));
+            }
+        }
+        let mut prefix = String::new();
+        for lexeme in &rule[..definition - 1] {
+            lexeme.write_to(&mut prefix);
+        }
+        prefix.push_str(&verbatim[..start]);
+        let end = rule.len() - usize::from(rule.last() == Some(&Lexeme::RuleEnd));
+        expander.templates.entry(name).or_default().push(Template {
+            parameters,
+            prefix,
+            body: rule[definition + 1..end].to_vec(),
+        });
+    }
+    let mut buffer = String::with_capacity(input.len());
+    let mut lexemes = rules.into_iter().flatten().peekable();
+    while let Some(lexeme) = lexemes.next() {
+        match lexeme {
+            Lexeme::Verbatim(x) => {
+                buffer.push_str(&expander.expand_instantiations(x, lexemes.peek().copied())?)
+            }
+            lexeme => lexeme.write_to(&mut buffer),
+        }
+    }
+    buffer.push_str(&expander.extra_rules);
+    Ok(Cow::Owned(buffer))
+}
+
+/// Desugars the syntax extensions in the KBNF grammar string.
+///
+/// # Errors
+///
+/// Returns [`CreateGrammarError::PreprocessingError`] when an extension is used incorrectly.
+pub(crate) fn preprocess(input: &str) -> Result<String, CreateGrammarError> {
+    let input = &*expand_parameterized_rules(input)?;
+    let mut buffer = String::with_capacity(input.len());
+    let mut desugarer = Desugarer::default();
+    // The groups in the right hand side of the current rule. It is empty outside right hand sides.
+    let mut groups: Vec<Group> = Vec::new();
+    // The annotations of the current or the next rule, e.g. `atomic` for `@atomic`.
+    let mut annotations: Vec<&str> = Vec::new();
+    for lexeme in lex(input) {
+        let lexeme = desugarer.desugar_lexeme(lexeme)?;
+        let depth = groups.len();
+        let Some(group) = groups.last_mut() else {
+            match lexeme {
+                Lexeme::Annotation(annotation) => {
+                    if !ANNOTATIONS.contains(&annotation) {
+                        return Err(CreateGrammarError::PreprocessingError(format!(
+                            "the annotation @{annotation} is unknown."
+                        )));
+                    }
+                    if annotations.contains(&annotation) {
+                        return Err(CreateGrammarError::PreprocessingError(format!(
+                            "the annotation @{annotation} is attached to the same rule multiple times."
+                        )));
+                    }
+                    annotations.push(annotation);
+                }
+                Lexeme::RuleEnd if !annotations.is_empty() => {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "the annotation @{} is not attached to any rule.",
+                        annotations[0]
+                    )));
+                }
+                lexeme => {
+                    lexeme.write_to(&mut buffer);
+                    if let Lexeme::Definition(_) = lexeme {
+                        groups.push(Group::new(None));
+                    }
+                }
+            }
+            continue;
+        };
+        match lexeme {
+            Lexeme::GroupStart(x) => {
+                group.last_alternative().is_empty = false;
+                groups.push(Group::new(Some(x)));
+            }
+            Lexeme::GroupEnd(x) if depth > 1 => {
+                // SAFETY: depth > 1
+                let group = unsafe { groups.pop().unwrap_unchecked() };
+                let parent = unsafe { groups.last_mut().unwrap_unchecked() }.last_alternative();
+                parent.last_symbol_start = Some(parent.content.len());
+                desugarer.desugar_group(group, Some(x), &mut parent.content)?;
+            }
+            Lexeme::Repetition(bounds) => {
+                let alternative = group.last_alternative();
+                let Some(start) = alternative.last_symbol_start.take() else {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "the repetition {{{bounds}}} is not attached to any symbol."
+                    )));
+                };
+                let symbol = alternative.content.split_off(start);
+                let repetition = desugarer.desugar_repetition(&symbol, bounds)?;
+                alternative.content.push_str(&repetition);
+                alternative.last_symbol_start = Some(start);
+            }
+            Lexeme::Alternation => group.alternatives.push(Alternative::new()),
+            Lexeme::RuleEnd => {
+                // Unbalanced groups are left to kbnf_syntax so it reports the error.
+                for group in std::mem::take(&mut groups) {
+                    desugarer.desugar_group(group, None, &mut buffer)?;
+                }
+                for annotation in std::mem::take(&mut annotations) {
+                    if annotation == "atomic" {
+                        // The marker is found by Grammar later to annotate the nonterminal.
+                        buffer.push_str(&format!("| #'{ATOMIC_REGEX_MARKER}'"));
+                    } else {
+                        buffer.push_str(&format!("| #'{PRESERVE_REGEX_MARKER}'"));
+                    }
+                }
+                lexeme.write_to(&mut buffer);
+            }
+            Lexeme::Annotation(annotation) => {
+                return Err(CreateGrammarError::PreprocessingError(format!(
+                    "the annotation @{annotation} is not placed before the left hand side of a rule."
+                )));
+            }
+            Lexeme::Prior(prior) => {
+                let alternative = group.last_alternative();
+                if alternative.prior.is_some() {
+                    return Err(CreateGrammarError::PreprocessingError(format!(
+                        "multiple priors are attached to the same alternative as %{prior}."
+                    )));
+                }
+                alternative.prior = Some(parse_prior(prior)?);
+            }
+            lexeme => {
+                let alternative = group.last_alternative();
+                if !matches!(lexeme, Lexeme::Comment(_))
+                    && !matches!(lexeme, Lexeme::Verbatim(x) if x.trim().is_empty())
+                {
+                    alternative.is_empty = false;
+                }
+                match lexeme {
+                    Lexeme::Literal { .. } | Lexeme::Symbol(_) => {
+                        alternative.last_symbol_start = Some(alternative.content.len())
+                    }
+                    Lexeme::Verbatim(x) => {
+                        if let Some(start) = find_last_nonterminal_start(x) {
+                            alternative.last_symbol_start = Some(alternative.content.len() + start);
+                        }
+                    }
+                    _ => {}
+                }
+                lexeme.write_to(&mut alternative.content);
+            }
+        }
+    }
+    if !annotations.is_empty() && groups.is_empty() {
+        return Err(CreateGrammarError::PreprocessingError(format!(
+            "the annotation @{} is not attached to any rule.",
+            annotations[0]
+        )));
+    }
+    for group in std::mem::take(&mut groups) {
+        desugarer.desugar_group(group, None, &mut buffer)?;
+    }
+    buffer.push_str(&desugarer.extra_rules);
+    Ok(buffer)
+}
@@ -0,0 +1,311 @@
+//! The schema module that compiles JSON schemas into KBNF grammars.
+use serde_json::Value;
+
+use crate::utils::{kbnf_regex as regex, kbnf_terminal as terminal};
+
+const WHITESPACE: &str = "json_ws";
+const STRING: &str = "json_string";
+const VALUE: &str = "json_value";
+const OBJECT: &str = "json_object";
+const INTEGER_REGEX: &str = r"-?(?:0|[1-9][0-9]*)";
+const NUMBER_REGEX: &str = r"-?(?:0|[1-9][0-9]*)(?:\.[0-9]+)?(?:[eE][+-]?[0-9]+)?";
+const STRING_REGEX: &str = r#""(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})*""#;
+
+#[derive(Debug, thiserror::Error)]
+/// The error type for errors in JSON schema compilation.
+pub enum SchemaError {
+    #[error("Invalid JSON schema: {0}")]
+    /// Error due to a malformed JSON schema or a schema that matches nothing.
+    InvalidSchema(String),
+    #[error("The type {0} is not supported.")]
+    /// Error due to an unsupported `type`.
+    UnsupportedType(String),
+    #[error("The keyword {0} is not supported.")]
+    /// Error due to an unsupported keyword like `$ref`.
+    UnsupportedKeyword(String),
+}
+
+/// Compile a JSON schema into a KBNF grammar string whose start nonterminal is `start`.
+///
+/// The supported subset includes:
+/// - `object` with `properties` and `required`. The properties are emitted in the schema's order,
+///   and additional properties are not allowed.
+/// - `array` with `items`.
+/// - `string` with an optional `pattern`, which is embedded as a regex matching the content between the quotes.
+/// - `number`, `integer`, `boolean` and `null`.
+/// - `enum`, `const`, `anyOf`, `oneOf` and a list of types.
+///
+/// A schema without `type`, like `{}` or `true`, matches any JSON value.
+///
+/// # Errors
+///
+/// Returns a [`SchemaError`] when the schema is malformed or uses unsupported features.
+pub fn json_schema_to_kbnf(schema: &Value) -> Result<String, SchemaError> {
+    let mut compiler = SchemaCompiler::default();
+    let start = compiler.compile(schema)?;
+    let mut grammar = format!("start ::= {start};\n");
+    for rule in compiler.rules.iter() {
+        grammar.push_str(rule);
+        grammar.push('\n');
+    }
+    if compiler.uses_whitespace {
+        grammar.push_str(&format!(
+            "{WHITESPACE} ::= #\"[ \\\\t\\\\n\\\\r]{{0,20}}\";\n"
+        ));
+    }
+    if compiler.uses_string {
+        grammar.push_str(&format!("{STRING} ::= {};\n", regex(STRING_REGEX)));
+    }
+    Ok(grammar)
+}
+
+#[derive(Default)]
+struct SchemaCompiler {
+    rules: Vec<String>,
+    uses_whitespace: bool,
+    uses_string: bool,
+    uses_value: bool,
+}
+
+impl SchemaCompiler {
+    /// Define a new nonterminal and return its name.
+    fn define(&mut self, rhs: String) -> String {
+        let name = format!("json_{}", self.rules.len());
+        self.rules.push(format!("{name} ::= {rhs};"));
+        name
+    }
+
+    fn whitespace(&mut self) -> &'static str {
+        self.uses_whitespace = true;
+        WHITESPACE
+    }
+
+    fn string(&mut self) -> &'static str {
+        self.uses_string = true;
+        STRING
+    }
+
+    /// Define the nonterminals of arbitrary JSON values once.
+    fn value(&mut self) -> &'static str {
+        if !self.uses_value {
+            self.uses_value = true;
+            let ws = self.whitespace();
+            let string = self.string();
+            let member = format!("{string} {ws} \":\" {ws} {VALUE}");
+            self.rules.push(format!(
+                "{VALUE} ::= {OBJECT} | json_array | {string} | {} | \"true\" | \"false\" | \"null\";",
+                regex(NUMBER_REGEX)
+            ));
+            self.rules.push(format!(
+                "{OBJECT} ::= \"{{\" {ws} ({member} ({ws} \",\" {ws} {member})* {ws})? \"}}\";"
+            ));
+            self.rules.push(format!(
+                "json_array ::= \"[\" {ws} ({VALUE} ({ws} \",\" {ws} {VALUE})* {ws})? \"]\";"
+            ));
+        }
+        VALUE
+    }
+
+    /// Compile a schema into a KBNF expression.
+    fn compile(&mut self, schema: &Value) -> Result<String, SchemaError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.value().to_string()),
+            Value::Bool(false) => {
+                return Err(SchemaError::InvalidSchema(
+                    "the false schema matches nothing".to_string(),
+                ))
+            }
+            Value::Object(schema) => schema,
+            _ => {
+                return Err(SchemaError::InvalidSchema(format!(
+                    "a schema must be an object or a boolean, but {schema} is found"
+                )))
+            }
+        };
+        for keyword in ["$ref", "allOf", "not", "if"] {
+            if schema.contains_key(keyword) {
+                return Err(SchemaError::UnsupportedKeyword(keyword.to_string()));
+            }
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(terminal(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = match values {
+                Value::Array(values) if !values.is_empty() => values,
+                _ => {
+                    return Err(SchemaError::InvalidSchema(
+                        "enum must be a non-empty array".to_string(),
+                    ))
+                }
+            };
+            let alternatives: Vec<_> = values.iter().map(|x| terminal(&x.to_string())).collect();
+            return Ok(format!("({})", alternatives.join(" | ")));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = match schemas {
+                    Value::Array(schemas) if !schemas.is_empty() => schemas,
+                    _ => {
+                        return Err(SchemaError::InvalidSchema(format!(
+                            "{keyword} must be a non-empty array"
+                        )))
+                    }
+                };
+                let alternatives = schemas
+                    .iter()
+                    .map(|x| self.compile(x))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(format!("({})", alternatives.join(" | ")));
+            }
+        }
+        match schema.get("type") {
+            None if schema.contains_key("properties") => self.compile_type("object", schema),
+            None => Ok(self.value().to_string()),
+            Some(Value::String(ty)) => self.compile_type(ty, schema),
+            Some(Value::Array(types)) if !types.is_empty() => {
+                let mut alternatives = Vec::with_capacity(types.len());
+                for ty in types {
+                    let Value::String(ty) = ty else {
+                        return Err(SchemaError::InvalidSchema(format!(
+                            "a type must be a string, but {ty} is found"
+                        )));
+                    };
+                    alternatives.push(self.compile_type(ty, schema)?);
+                }
+                Ok(format!("({})", alternatives.join(" | ")))
+            }
+            Some(ty) => Err(SchemaError::InvalidSchema(format!(
+                "type must be a string or a non-empty array, but {ty} is found"
+            ))),
+        }
+    }
+
+    fn compile_type(
+        &mut self,
+        ty: &str,
+        schema: &serde_json::Map<String, Value>,
+    ) -> Result<String, SchemaError> {
+        match ty {
+            "object" => self.compile_object(schema),
+            "array" => self.compile_array(schema),
+            "string" => self.compile_string(schema),
+            "number" => Ok(regex(NUMBER_REGEX)),
+            "integer" => Ok(regex(INTEGER_REGEX)),
+            "boolean" => Ok("(\"true\" | \"false\")".to_string()),
+            "null" => Ok("\"null\"".to_string()),
+            ty => Err(SchemaError::UnsupportedType(ty.to_string())),
+        }
+    }
+
+    fn compile_string(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+    ) -> Result<String, SchemaError> {
+        match schema.get("pattern") {
+            None => Ok(self.string().to_string()),
+            Some(Value::String(pattern)) => {
+                // The regex is always anchored in KBNF.
+                let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+                let pattern = match pattern.strip_suffix('$') {
+                    Some(x) if !x.ends_with('\\') => x,
+                    _ => pattern,
+                };
+                Ok(format!("\"\\\"\" {} \"\\\"\"", regex(pattern)))
+            }
+            Some(pattern) => Err(SchemaError::InvalidSchema(format!(
+                "pattern must be a string, but {pattern} is found"
+            ))),
+        }
+    }
+
+    fn compile_array(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+    ) -> Result<String, SchemaError> {
+        let item = match schema.get("items") {
+            Some(items) => {
+                let item = self.compile(items)?;
+                self.define(item)
+            }
+            None => self.value().to_string(),
+        };
+        let ws = self.whitespace();
+        Ok(self.define(format!(
+            "\"[\" {ws} ({item} ({ws} \",\" {ws} {item})* {ws})? \"]\""
+        )))
+    }
+
+    fn compile_object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+    ) -> Result<String, SchemaError> {
+        let properties = match schema.get("properties") {
+            None => {
+                self.value();
+                return Ok(OBJECT.to_string());
+            }
+            Some(Value::Object(properties)) => properties,
+            Some(properties) => {
+                return Err(SchemaError::InvalidSchema(format!(
+                    "properties must be an object, but {properties} is found"
+                )))
+            }
+        };
+        let required: Vec<&str> = match schema.get("required") {
+            None => Vec::new(),
+            Some(Value::Array(required)) => required
+                .iter()
+                .map(|x| {
+                    x.as_str().ok_or_else(|| {
+                        SchemaError::InvalidSchema(format!(
+                            "a required property must be a string, but {x} is found"
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            Some(required) => {
+                return Err(SchemaError::InvalidSchema(format!(
+                    "required must be an array, but {required} is found"
+                )))
+            }
+        };
+        if let Some(key) = required.iter().find(|x| !properties.contains_key(**x)) {
+            return Err(SchemaError::InvalidSchema(format!(
+                "the required property {key} is not defined in properties"
+            )));
+        }
+        let ws = self.whitespace();
+        let mut members = Vec::with_capacity(properties.len());
+        for (key, value) in properties {
+            let value = self.compile(value)?;
+            let key = terminal(&Value::String(key.clone()).to_string());
+            members.push(format!("{key} {ws} \":\" {ws} {value}"));
+        }
+        // first: the properties from i, where at least one of them is present and none before i is present.
+        // rest: the properties from i, where a property before i is present.
+        let mut first: Option<String> = None;
+        let mut rest: Option<String> = None;
+        for (i, key) in properties.keys().enumerate().rev() {
+            let member = &members[i];
+            let continuation = rest.as_ref().map(|x| format!(" {x}")).unwrap_or_default();
+            let present = format!("{member}{continuation}");
+            let present_after_comma = format!("{ws} \",\" {ws} {member}{continuation}");
+            let is_required = required.contains(&key.as_str());
+            first = Some(self.define(match (&first, is_required) {
+                (Some(first), false) => format!("{present} | {first}"),
+                _ => present,
+            }));
+            rest = Some(self.define(match (&rest, is_required) {
+                (_, true) => present_after_comma,
+                (Some(rest), false) => format!("{present_after_comma} | {rest}"),
+                (None, false) => format!("({present_after_comma})?"),
+            }));
+        }
+        Ok(match first {
+            None => format!("\"{{\" {ws} \"}}\""),
+            Some(first) if required.is_empty() => format!("\"{{\" {ws} ({first} {ws})? \"}}\""),
+            Some(first) => format!("\"{{\" {ws} {first} {ws} \"}}\""),
+        })
+    }
+}
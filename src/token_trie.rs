@@ -0,0 +1,196 @@
+//! The byte trie of the tokens in a [`Vocabulary`](crate::vocabulary::Vocabulary).
+//!
+//! The nodes are stored in depth-first preorder, so the subtree of a node is the contiguous range of nodes
+//! from the node to [`TokenTrie::subtree_end`], and the children of a node are sorted by their bytes.
+//! The token IDs are stored in the same order, so the token IDs in a subtree are contiguous as well.
+
+/// A node of [`TokenTrie`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Node {
+    /// The byte on the edge from the parent, which is unused for the root.
+    byte: u8,
+    /// The index of the first node after the subtree of this node.
+    subtree_end: u32,
+    /// The index of the first token ID of this node in [`TokenTrie::token_ids`].
+    token_ids_start: u32,
+}
+
+/// The byte trie of the tokens, where each node represents the prefix spelled by the bytes from the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TokenTrie {
+    nodes: Vec<Node>,
+    token_ids: Vec<u32>,
+}
+
+impl TokenTrie {
+    /// The root, which represents the empty prefix.
+    pub(crate) const ROOT: usize = 0;
+
+    /// Creates the trie of the tokens. The empty tokens are ignored.
+    pub(crate) fn new<'a>(tokens: impl Iterator<Item = (u32, &'a [u8])>) -> Self {
+        let mut tokens: Vec<_> = tokens.filter(|(_, token)| !token.is_empty()).collect();
+        tokens.sort_unstable_by(|(x_id, x), (y_id, y)| x.cmp(y).then(x_id.cmp(y_id)));
+        let mut nodes = vec![Node {
+            byte: 0,
+            subtree_end: 0,
+            token_ids_start: 0,
+        }];
+        let mut token_ids = Vec::with_capacity(tokens.len());
+        // The nodes on the path from the root to the previous token, excluding the root.
+        let mut path: Vec<usize> = Vec::new();
+        let mut previous: &[u8] = &[];
+        for (token_id, token) in tokens {
+            let common_prefix_len = previous
+                .iter()
+                .zip(token)
+                .take_while(|(x, y)| x == y)
+                .count();
+            for node in path.drain(common_prefix_len..) {
+                nodes[node].subtree_end = nodes.len() as u32;
+            }
+            for &byte in &token[common_prefix_len..] {
+                path.push(nodes.len());
+                nodes.push(Node {
+                    byte,
+                    subtree_end: 0,
+                    token_ids_start: token_ids.len() as u32,
+                });
+            }
+            token_ids.push(token_id);
+            previous = token;
+        }
+        for node in path.into_iter().chain([Self::ROOT]) {
+            nodes[node].subtree_end = nodes.len() as u32;
+        }
+        Self { nodes, token_ids }
+    }
+
+    /// Inserts a token into the trie. The empty token is ignored.
+    pub(crate) fn insert(&mut self, token_id: u32, token: &[u8]) {
+        if token.is_empty() {
+            return;
+        }
+        let mut path = vec![Self::ROOT];
+        let mut node = Self::ROOT;
+        let mut matched = 0;
+        for &byte in token {
+            match self.child(node, byte) {
+                Some(child) => {
+                    node = child;
+                    path.push(node);
+                    matched += 1;
+                }
+                None => break,
+            }
+        }
+        if matched == token.len() {
+            let position = self.own_token_ids_end(node);
+            self.token_ids.insert(position, token_id);
+            for node in &mut self.nodes[node + 1..] {
+                node.token_ids_start += 1;
+            }
+            return;
+        }
+        // The new nodes are inserted before the first child whose byte is greater than the next byte.
+        let byte = token[matched];
+        let position = self
+            .children(node)
+            .find(|&child| self.nodes[child].byte > byte)
+            .unwrap_or(self.subtree_end(node));
+        let token_ids_position = self.token_ids_start(position);
+        let added = (token.len() - matched) as u32;
+        for node in path {
+            self.nodes[node].subtree_end += added;
+        }
+        for node in &mut self.nodes[position..] {
+            node.subtree_end += added;
+            node.token_ids_start += 1;
+        }
+        let subtree_end = position as u32 + added;
+        self.nodes.splice(
+            position..position,
+            token[matched..].iter().map(|&byte| Node {
+                byte,
+                subtree_end,
+                token_ids_start: token_ids_position as u32,
+            }),
+        );
+        self.token_ids.insert(token_ids_position, token_id);
+    }
+
+    /// Gets the byte on the edge from the parent of the node.
+    #[inline]
+    pub(crate) fn byte(&self, node: usize) -> u8 {
+        self.nodes[node].byte
+    }
+
+    /// Gets the index of the first node after the subtree of the node,
+    /// which is the next sibling of the node if it exists.
+    #[inline]
+    pub(crate) fn subtree_end(&self, node: usize) -> usize {
+        self.nodes[node].subtree_end as usize
+    }
+
+    /// Checks whether the node has any children, i.e. whether some tokens are longer than the prefix of the node.
+    #[inline]
+    pub(crate) fn has_children(&self, node: usize) -> bool {
+        self.subtree_end(node) > node + 1
+    }
+
+    /// Gets the children of the node in the order of their bytes.
+    pub(crate) fn children(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let end = self.subtree_end(node);
+        let mut next = node + 1;
+        std::iter::from_fn(move || {
+            let child = next;
+            (child < end).then(|| {
+                next = self.subtree_end(child);
+                child
+            })
+        })
+    }
+
+    /// Gets the child of the node with the given byte.
+    pub(crate) fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        self.children(node)
+            .take_while(|&child| self.nodes[child].byte <= byte)
+            .find(|&child| self.nodes[child].byte == byte)
+    }
+
+    /// Gets the number of nodes, including the root.
+    #[inline]
+    pub(crate) fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Gets the node whose prefix is exactly the bytes.
+    pub(crate) fn find(&self, bytes: &[u8]) -> Option<usize> {
+        bytes
+            .iter()
+            .try_fold(Self::ROOT, |node, &byte| self.child(node, byte))
+    }
+
+    /// Gets the IDs of the tokens that are exactly the prefix of the node.
+    #[inline]
+    pub(crate) fn token_ids(&self, node: usize) -> &[u32] {
+        &self.token_ids[self.token_ids_start(node)..self.own_token_ids_end(node)]
+    }
+
+    /// Gets the IDs of the tokens in the subtree of the node, i.e. the tokens starting with the prefix of the node.
+    #[inline]
+    pub(crate) fn subtree_token_ids(&self, node: usize) -> &[u32] {
+        &self.token_ids[self.token_ids_start(node)..self.token_ids_start(self.subtree_end(node))]
+    }
+
+    #[inline]
+    fn token_ids_start(&self, node: usize) -> usize {
+        self.nodes
+            .get(node)
+            .map_or(self.token_ids.len(), |x| x.token_ids_start as usize)
+    }
+
+    #[inline]
+    fn own_token_ids_end(&self, node: usize) -> usize {
+        self.token_ids_start(node + 1)
+    }
+}
@@ -1,16 +1,25 @@
 //! Utility functions for the library.
 use ahash::{AHashMap, AHashSet};
 use fixedbitset_stack::on_stack::{get_nblock, FixedBitSet};
-use kbnf_regex_automata::dfa::Automaton;
+use kbnf_regex_automata::dfa::{dense, Automaton};
 use kbnf_regex_automata::util::primitives::StateID;
-use kbnf_syntax::regex::FiniteStateAutomaton;
+use kbnf_syntax::node::{NodeWithID, OperatorFlattenedNode, RegexExtKind, Rhs, SymbolKind};
+use kbnf_syntax::regex::{FiniteStateAutomaton, FiniteStateAutomatonConfig};
+use kbnf_syntax::semantic_error::SemanticError;
 use kbnf_syntax::simplified_grammar::SimplifiedGrammar;
+use kbnf_syntax::validated_grammar::ValidatedGrammar;
+use kbnf_syntax::InternedStrings;
 use nom::error::VerboseError;
+use string_interner::symbol::SymbolU32;
+use string_interner::Symbol;
 
 use crate::config::InternalConfig;
+use crate::diagnostic::GrammarDiagnostic;
 use crate::grammar::CreateGrammarError;
+use crate::preprocessor;
 
-pub(crate) type ByteSet = FixedBitSet<{ get_nblock(u8::MAX as usize) }>;
+/// The set of bytes, where each bit represents whether the byte is in the set.
+pub type ByteSet = FixedBitSet<{ get_nblock(u8::MAX as usize) }>;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
 pub(crate) enum FsaStateStatus {
     Accept,
@@ -22,7 +31,30 @@ pub fn construct_kbnf_syntax_grammar(
     input: &str,
     config: InternalConfig,
 ) -> Result<SimplifiedGrammar, CreateGrammarError> {
-    let grammar = kbnf_syntax::get_grammar(input).map_err(|e| match e {
+    construct_kbnf_syntax_grammar_and_analysis(input, config).map(|(grammar, _)| grammar)
+}
+/// The facts about a KBNF grammar that are lost in the simplification.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GrammarAnalysis {
+    /// Whether the start nonterminal matches the empty string.
+    pub start_nullable: bool,
+    /// The nonterminals that match the empty string.
+    pub nullable_nonterminals: Vec<String>,
+    /// The user-defined nonterminals that cannot be reached from the start nonterminal.
+    pub unreachable_nonterminals: Vec<String>,
+    /// The user-defined nonterminals that cannot derive any string.
+    pub nonproductive_nonterminals: Vec<String>,
+}
+/// Construct a simplified grammar from an KBNF grammar string, along with its [`GrammarAnalysis`].
+///
+/// The analysis is computed before the simplification, which removes the empty string, the unused rules
+/// and the rules that cannot match from the grammar.
+pub(crate) fn construct_kbnf_syntax_grammar_and_analysis(
+    input: &str,
+    config: InternalConfig,
+) -> Result<(SimplifiedGrammar, GrammarAnalysis), CreateGrammarError> {
+    let preprocessed = preprocessor::preprocess(input)?;
+    let grammar = kbnf_syntax::get_grammar(&preprocessed).map_err(|e| match e {
         nom::Err::Error(e) => nom::Err::Error(VerboseError {
             errors: e
                 .errors
@@ -38,14 +70,794 @@ pub fn construct_kbnf_syntax_grammar(
                 .collect::<Vec<_>>(),
         }),
         nom::Err::Incomplete(e) => nom::Err::Incomplete(e),
+    });
+    let grammar = grammar.map_err(|error| CreateGrammarError::DiagnosedError {
+        diagnostic: Box::new(GrammarDiagnostic::from_parsing_error(
+            input,
+            &preprocessed,
+            &error,
+        )),
+        error: Box::new(CreateGrammarError::ParsingError(error)),
     })?;
-    let grammar = grammar.validate_grammar(&config.start_nonterminal, config.regex_config)?;
-    let grammar = grammar.simplify_grammar(
+    let mut grammar = grammar;
+    if config.normalize_newlines {
+        normalize_newlines(&mut grammar);
+    }
+    if let Some(whitespace) = &config.implicit_whitespace {
+        insert_implicit_whitespace(&mut grammar, whitespace);
+    }
+    let regex_config = config.regex_config.clone();
+    let grammar = grammar
+        .validate_grammar(&config.start_nonterminal, config.regex_config)
+        .map_err(|error| {
+            if let SemanticError::DfaRegexBuildError(e) = error.as_ref() {
+                if let Some(error) =
+                    find_regex_too_large_to_compile(&preprocessed, e, &regex_config)
+                {
+                    return error;
+                }
+            }
+            CreateGrammarError::DiagnosedError {
+                diagnostic: Box::new(GrammarDiagnostic::from_semantic_error(input, &error)),
+                error: Box::new(CreateGrammarError::SemanticError(error)),
+            }
+        })?;
+    let nullable_nonterminals = find_nullable_nonterminals(&grammar);
+    let analysis = GrammarAnalysis {
+        start_nullable: nullable_nonterminals.contains(&grammar.start_symbol),
+        nullable_nonterminals: nullable_nonterminals
+            .iter()
+            .filter_map(|&x| grammar.interned_strings.nonterminals.resolve(x))
+            .map(|x| x.to_string())
+            .collect(),
+        unreachable_nonterminals: find_unreachable_nonterminals(&grammar),
+        nonproductive_nonterminals: find_nonproductive_nonterminals(&grammar),
+    };
+    let mut grammar = grammar.simplify_grammar(
         config.compression_config,
         &kbnf_regex_automata::util::start::Config::new()
             .anchored(kbnf_regex_automata::Anchored::Yes),
     );
-    Ok(grammar)
+    if let Some(max_depth) = config.max_recursion_depth {
+        if !grammar.is_empty() {
+            bound_recursion_depth(&mut grammar, max_depth)?;
+        }
+    }
+    Ok((grammar, analysis))
+}
+/// The prefix of the copies of the recursive nonterminals created by [`bound_recursion_depth`].
+const DEPTH_NONTERMINAL_PREFIX: &str = "__kbnf_depth_";
+/// Which references within a strongly connected component of nonterminals nest one level deeper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Nesting {
+    /// The references that are neither the first nor the last of their alternations.
+    Middle,
+    /// The references that are not the last of their alternations,
+    /// used when the recursion nests through multiple nonterminals without any middle references.
+    NotLast,
+}
+/// Find the strongly connected components of the nonterminals with Kosaraju's algorithm.
+///
+/// Returns the index of the component of each nonterminal.
+fn find_strongly_connected_components(expressions: &[Rhs]) -> Vec<usize> {
+    let successors: Vec<Vec<usize>> = expressions
+        .iter()
+        .map(|rhs| {
+            rhs.alternations
+                .iter()
+                .flat_map(|x| x.concatenations.iter())
+                .filter_map(|node| match node {
+                    OperatorFlattenedNode::Nonterminal(x) => Some(x.to_usize()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+    let mut predecessors = vec![Vec::new(); expressions.len()];
+    for (i, successors) in successors.iter().enumerate() {
+        for &j in successors {
+            predecessors[j].push(i);
+        }
+    }
+    // The nonterminals in the order of finishing the depth-first search.
+    let mut order = Vec::with_capacity(expressions.len());
+    let mut visited = vec![false; expressions.len()];
+    for root in 0..expressions.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some((node, next)) = stack.last_mut() {
+            if let Some(&successor) = successors[*node].get(*next) {
+                *next += 1;
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            } else {
+                order.push(*node);
+                stack.pop();
+            }
+        }
+    }
+    let mut components = vec![usize::MAX; expressions.len()];
+    let mut component_count = 0;
+    for &root in order.iter().rev() {
+        if components[root] != usize::MAX {
+            continue;
+        }
+        components[root] = component_count;
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            for &predecessor in &predecessors[node] {
+                if components[predecessor] == usize::MAX {
+                    components[predecessor] = component_count;
+                    stack.push(predecessor);
+                }
+            }
+        }
+        component_count += 1;
+    }
+    components
+}
+/// Get the nodes of the alternation without the references to the nonterminals in the component,
+/// where the adjacent terminals are merged like the simplification of the grammar does.
+///
+/// An alternation like `'{' '}'` is the nesting alternation `'{' start '}'` with the nested part left out
+/// when the two give the same nodes.
+fn nesting_skeleton(
+    alternation: &kbnf_syntax::node::Alternation,
+    is_in_component: impl Fn(usize) -> bool,
+    interned_strings: &InternedStrings,
+) -> Vec<Result<String, OperatorFlattenedNode>> {
+    let mut nodes: Vec<Result<String, OperatorFlattenedNode>> = Vec::new();
+    for node in &alternation.concatenations {
+        match node {
+            OperatorFlattenedNode::Nonterminal(x) if is_in_component(x.to_usize()) => {}
+            OperatorFlattenedNode::Terminal(x) => {
+                let terminal = interned_strings.terminals.resolve(*x).unwrap();
+                match nodes.last_mut() {
+                    Some(Ok(last)) => last.push_str(terminal),
+                    _ => nodes.push(Ok(terminal.to_string())),
+                }
+            }
+            node => nodes.push(Err(node.clone())),
+        }
+    }
+    nodes
+}
+/// Bound the nesting depth of the middle recursion by expanding the recursive nonterminals
+/// into one copy per number of the nesting references from the start nonterminal.
+///
+/// The original nonterminals are the copies at depth 1.
+/// An alternation nests, i.e. matches one level of the recursion, when it holds a reference that nests
+/// or is such an alternation with the nested part left out, so the copies at `max_depth + 1` can only match
+/// the alternations that do not nest, like `'x'` in `value ::= obj | 'x'; obj ::= '[' value ']';`.
+/// The alternations that nest beyond `max_depth` or reference the copies that cannot match are removed.
+fn bound_recursion_depth(
+    grammar: &mut SimplifiedGrammar,
+    max_depth: usize,
+) -> Result<(), CreateGrammarError> {
+    let max_depth = max_depth.max(1);
+    let expressions = &grammar.expressions;
+    let components = find_strongly_connected_components(expressions);
+    let component_count = components.iter().max().map_or(0, |x| x + 1);
+    let mut has_middle = vec![false; component_count];
+    let mut has_not_first = vec![false; component_count];
+    let mut has_not_last = vec![false; component_count];
+    for (lhs, rhs) in expressions.iter().enumerate() {
+        for alternation in &rhs.alternations {
+            let len = alternation.concatenations.len();
+            for (i, node) in alternation.concatenations.iter().enumerate() {
+                match node {
+                    OperatorFlattenedNode::Nonterminal(x)
+                        if components[x.to_usize()] == components[lhs] =>
+                    {
+                        let component = components[lhs];
+                        has_middle[component] |= i > 0 && i + 1 < len;
+                        has_not_first[component] |= i > 0;
+                        has_not_last[component] |= i + 1 < len;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    let nesting: Vec<_> = (0..component_count)
+        .map(|component| {
+            if has_middle[component] {
+                Some(Nesting::Middle)
+            } else if has_not_first[component] && has_not_last[component] {
+                Some(Nesting::NotLast)
+            } else {
+                None
+            }
+        })
+        .collect();
+    if nesting.iter().all(Option::is_none) {
+        return Ok(());
+    }
+    // Whether each alternation nests, where the last references of `Nesting::NotLast` do not count,
+    // since the alternation referencing them opens the level that the other one closes.
+    let nests: Vec<Vec<bool>> = expressions
+        .iter()
+        .enumerate()
+        .map(|(lhs, rhs)| {
+            let component = components[lhs];
+            let Some(kind) = nesting[component] else {
+                return vec![false; rhs.alternations.len()];
+            };
+            let holds_nesting_reference: Vec<_> = rhs
+                .alternations
+                .iter()
+                .map(|alternation| {
+                    let len = alternation.concatenations.len();
+                    alternation
+                        .concatenations
+                        .iter()
+                        .enumerate()
+                        .any(|(j, node)| match node {
+                            OperatorFlattenedNode::Nonterminal(x)
+                                if components[x.to_usize()] == component =>
+                            {
+                                match kind {
+                                    Nesting::Middle => j > 0 && j + 1 < len,
+                                    Nesting::NotLast => j > 0,
+                                }
+                            }
+                            _ => false,
+                        })
+                })
+                .collect();
+            let skeleton = |alternation| {
+                nesting_skeleton(
+                    alternation,
+                    |x| components[x] == component,
+                    &grammar.interned_strings,
+                )
+            };
+            let nesting_skeletons: Vec<_> = rhs
+                .alternations
+                .iter()
+                .zip(&holds_nesting_reference)
+                .filter(|(_, &x)| x)
+                .map(|(alternation, _)| skeleton(alternation))
+                .collect();
+            rhs.alternations
+                .iter()
+                .zip(&holds_nesting_reference)
+                .map(|(alternation, &x)| x || nesting_skeletons.contains(&skeleton(alternation)))
+                .collect()
+        })
+        .collect();
+    // The (nonterminal, depth) of each copy, where the original nonterminals come first.
+    let mut copies: Vec<_> = (0..expressions.len()).map(|x| (x, 1)).collect();
+    let mut copy_ids = AHashMap::default();
+    for (nonterminal, &component) in components.iter().enumerate() {
+        if nesting[component].is_some() {
+            for depth in 2..=max_depth + 1 {
+                copy_ids.insert((nonterminal, depth), copies.len());
+                copies.push((nonterminal, depth));
+            }
+        }
+    }
+    // The alternations of each copy, with the index of the original alternation
+    // and the copies referenced by the alternation in order.
+    let mut productions: Vec<Vec<(usize, Vec<usize>)>> = copies
+        .iter()
+        .map(|&(lhs, depth)| {
+            let component = components[lhs];
+            expressions[lhs]
+                .alternations
+                .iter()
+                .enumerate()
+                .filter_map(|(i, alternation)| {
+                    if depth > max_depth && nests[lhs][i] {
+                        return None;
+                    }
+                    let len = alternation.concatenations.len();
+                    let mut references = Vec::new();
+                    for (j, node) in alternation.concatenations.iter().enumerate() {
+                        let OperatorFlattenedNode::Nonterminal(x) = node else {
+                            continue;
+                        };
+                        let x = x.to_usize();
+                        let x_depth = if components[x] != component {
+                            1
+                        } else {
+                            depth
+                                + match nesting[component] {
+                                    Some(Nesting::Middle) => (j > 0 && j + 1 < len) as usize,
+                                    Some(Nesting::NotLast) => (j + 1 < len) as usize,
+                                    None => 0,
+                                }
+                        };
+                        if x_depth > max_depth + 1 {
+                            return None;
+                        }
+                        references.push(if x_depth == 1 {
+                            x
+                        } else {
+                            copy_ids[&(x, x_depth)]
+                        });
+                    }
+                    Some((i, references))
+                })
+                .collect()
+        })
+        .collect();
+    let mut productive = vec![false; copies.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (copy, alternations) in productions.iter().enumerate() {
+            if !productive[copy]
+                && alternations
+                    .iter()
+                    .any(|(_, references)| references.iter().all(|&x| productive[x]))
+            {
+                productive[copy] = true;
+                changed = true;
+            }
+        }
+    }
+    let start = grammar.start_symbol.to_usize();
+    if !productive[start] {
+        return Err(CreateGrammarError::RecursionTooDeepError(max_depth));
+    }
+    for alternations in &mut productions {
+        alternations.retain(|(_, references)| references.iter().all(|&x| productive[x]));
+    }
+    let mut reachable = vec![false; copies.len()];
+    reachable[start] = true;
+    let mut stack = vec![start];
+    while let Some(copy) = stack.pop() {
+        for &x in productions[copy]
+            .iter()
+            .flat_map(|(_, references)| references)
+        {
+            if !reachable[x] {
+                reachable[x] = true;
+                stack.push(x);
+            }
+        }
+    }
+    let mut symbols: Vec<_> = (0..expressions.len())
+        .map(|x| Some(SymbolU32::try_from_usize(x).unwrap()))
+        .collect();
+    for (copy, &(nonterminal, depth)) in copies.iter().enumerate().skip(expressions.len()) {
+        symbols.push(reachable[copy].then(|| {
+            let name = grammar
+                .interned_strings
+                .nonterminals
+                .resolve(SymbolU32::try_from_usize(nonterminal).unwrap())
+                .unwrap();
+            let name = format!("{DEPTH_NONTERMINAL_PREFIX}{depth}_{name}");
+            grammar.interned_strings.nonterminals.get_or_intern(name)
+        }));
+    }
+    let rhs_of = |copy: usize| Rhs {
+        alternations: productions[copy]
+            .iter()
+            .map(|(i, references)| {
+                let mut alternation = grammar.expressions[copies[copy].0].alternations[*i].clone();
+                let mut references = references.iter();
+                for node in &mut alternation.concatenations {
+                    if let OperatorFlattenedNode::Nonterminal(x) = node {
+                        *x = symbols[*references.next().unwrap()].unwrap();
+                    }
+                }
+                alternation
+            })
+            .collect(),
+    };
+    // The nonterminals that cannot match stay unchanged, which are no longer referenced.
+    let rhs: Vec<_> = (0..copies.len())
+        .filter(|&copy| symbols[copy].is_some() && productive[copy])
+        .map(|copy| (copy, rhs_of(copy)))
+        .collect();
+    for (copy, rhs) in rhs {
+        if copy < grammar.expressions.len() {
+            grammar.expressions[copy] = rhs;
+        } else {
+            debug_assert_eq!(symbols[copy].unwrap().to_usize(), grammar.expressions.len());
+            grammar.expressions.push(rhs);
+        }
+    }
+    Ok(())
+}
+/// The regex matching a newline in any common line ending, which replaces the newlines in terminals and regexes.
+const NEWLINE_REGEX: &str = "(?:\\r\\n?|\\n)";
+/// Make the newlines in the terminals and the regexes also match `\r\n` and a lone `\r`.
+///
+/// The terminals are split at their newlines with the newline regex in between,
+/// and the newlines outside the character classes of the regexes are replaced by the newline regex.
+fn normalize_newlines(grammar: &mut kbnf_syntax::grammar::Grammar) {
+    fn normalize(
+        node: &mut NodeWithID,
+        interned_strings: &mut InternedStrings,
+        newline: SymbolU32,
+    ) {
+        match node {
+            NodeWithID::Terminal(terminal) => {
+                let string = interned_strings.terminals.resolve(*terminal).unwrap();
+                if !string.contains('\n') {
+                    return;
+                }
+                let pieces: Vec<_> = string.split('\n').map(str::to_string).collect();
+                let mut nodes = Vec::with_capacity(pieces.len() * 2);
+                for (i, piece) in pieces.into_iter().enumerate() {
+                    if i > 0 {
+                        nodes.push(NodeWithID::RegexString(newline));
+                    }
+                    if !piece.is_empty() {
+                        nodes.push(NodeWithID::Terminal(
+                            interned_strings.terminals.get_or_intern(piece),
+                        ));
+                    }
+                }
+                *node = NodeWithID::Multiple(nodes);
+            }
+            NodeWithID::RegexString(regex) | NodeWithID::EarlyEndRegexString(regex) => {
+                let string = interned_strings.regex_strings.resolve(*regex).unwrap();
+                if let Some(normalized) = normalize_regex_newlines(string) {
+                    *regex = interned_strings.regex_strings.get_or_intern(normalized);
+                }
+            }
+            NodeWithID::Multiple(nodes) => {
+                for node in nodes {
+                    normalize(node, interned_strings, newline);
+                }
+            }
+            NodeWithID::RegexExt(node, _) | NodeWithID::Group(node) => {
+                normalize(node, interned_strings, newline)
+            }
+            NodeWithID::Symbol(lhs, _, rhs) => {
+                normalize(lhs, interned_strings, newline);
+                normalize(rhs, interned_strings, newline);
+            }
+            _ => {}
+        }
+    }
+    // The regexes are anchored like the ones from the parser.
+    let newline = grammar
+        .interned_strings
+        .regex_strings
+        .get_or_intern(format!("\\A{NEWLINE_REGEX}\\z"));
+    for expression in grammar.expressions.iter_mut() {
+        normalize(&mut expression.rhs, &mut grammar.interned_strings, newline);
+    }
+}
+/// Replace the newlines outside the character classes of the regex, either a raw newline or `\n`, with the newline regex.
+///
+/// Returns `None` if the regex has no such newlines.
+fn normalize_regex_newlines(regex: &str) -> Option<String> {
+    let mut normalized = String::with_capacity(regex.len());
+    let mut class_depth = 0usize;
+    let mut changed = false;
+    let mut chars = regex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') if class_depth == 0 => {
+                    normalized.push_str(NEWLINE_REGEX);
+                    changed = true;
+                }
+                next => {
+                    normalized.push(c);
+                    normalized.extend(next);
+                }
+            },
+            '\n' if class_depth == 0 => {
+                normalized.push_str(NEWLINE_REGEX);
+                changed = true;
+            }
+            '[' => {
+                class_depth += 1;
+                normalized.push(c);
+                // A `]` right after the opening bracket, possibly negated, is a literal.
+                if chars.peek() == Some(&'^') {
+                    normalized.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    normalized.push(chars.next().unwrap());
+                }
+            }
+            ']' if class_depth > 0 => {
+                class_depth -= 1;
+                normalized.push(c);
+            }
+            _ => normalized.push(c),
+        }
+    }
+    changed.then_some(normalized)
+}
+/// Insert the regex matching any number of the whitespace between every two symbols of the concatenations,
+/// where `x*` and `x+` are rewritten to `(x (ws x)*)?` and `x (ws x)*` to skip the whitespace between the repetitions.
+fn insert_implicit_whitespace(grammar: &mut kbnf_syntax::grammar::Grammar, whitespace: &str) {
+    fn insert(node: &mut NodeWithID, whitespace: SymbolU32) {
+        let ws = || NodeWithID::RegexString(whitespace);
+        match node {
+            NodeWithID::Multiple(nodes) => {
+                let mut interleaved = Vec::with_capacity(nodes.len() * 2);
+                for (i, mut node) in std::mem::take(nodes).into_iter().enumerate() {
+                    insert(&mut node, whitespace);
+                    if i > 0 {
+                        interleaved.push(ws());
+                    }
+                    interleaved.push(node);
+                }
+                *nodes = interleaved;
+            }
+            NodeWithID::RegexExt(inner, kind) => {
+                insert(inner, whitespace);
+                if matches!(kind, RegexExtKind::Repeat0 | RegexExtKind::Repeat1) {
+                    let inner = std::mem::replace(inner.as_mut(), NodeWithID::Unknown);
+                    let tail = NodeWithID::Multiple(vec![ws(), inner.clone()]);
+                    let repeated = NodeWithID::Multiple(vec![
+                        inner,
+                        NodeWithID::RegexExt(
+                            Box::new(NodeWithID::Group(Box::new(tail))),
+                            RegexExtKind::Repeat0,
+                        ),
+                    ]);
+                    *node = match kind {
+                        RegexExtKind::Repeat0 => NodeWithID::RegexExt(
+                            Box::new(NodeWithID::Group(Box::new(repeated))),
+                            RegexExtKind::Optional,
+                        ),
+                        _ => repeated,
+                    };
+                }
+            }
+            NodeWithID::Symbol(lhs, kind, rhs) => {
+                insert(lhs, whitespace);
+                insert(rhs, whitespace);
+                if matches!(kind, SymbolKind::Concatenation) {
+                    let rhs_node = std::mem::replace(rhs.as_mut(), NodeWithID::Unknown);
+                    **rhs = NodeWithID::Multiple(vec![ws(), rhs_node]);
+                }
+            }
+            NodeWithID::Group(inner) => insert(inner, whitespace),
+            _ => {}
+        }
+    }
+    // The regexes are anchored like the ones from the parser.
+    let whitespace = grammar
+        .interned_strings
+        .regex_strings
+        .get_or_intern(format!("\\A(?:(?:{whitespace})*)\\z"));
+    for expression in grammar.expressions.iter_mut() {
+        insert(&mut expression.rhs, whitespace);
+    }
+}
+/// Find the nonterminals of the validated grammar that match the empty string.
+///
+/// The nullable nonterminals are found by a fixpoint iteration, where the nodes are treated as kbnf_syntax does:
+/// early-end regexes and regex complements are never nullable, while substrings always are.
+fn find_nullable_nonterminals(grammar: &ValidatedGrammar) -> AHashSet<SymbolU32> {
+    fn is_nullable(
+        node: &NodeWithID,
+        grammar: &ValidatedGrammar,
+        nullable_nonterminals: &AHashSet<SymbolU32>,
+    ) -> bool {
+        match node {
+            NodeWithID::Terminal(terminal) => grammar
+                .interned_strings
+                .terminals
+                .resolve(*terminal)
+                .is_some_and(|x| x.is_empty()),
+            NodeWithID::RegexString(regex) => grammar
+                .id_to_regex
+                .get(regex)
+                .is_some_and(|x| x.has_empty()),
+            NodeWithID::Nonterminal(nonterminal) => nullable_nonterminals.contains(nonterminal),
+            NodeWithID::Multiple(nodes) => nodes
+                .iter()
+                .all(|x| is_nullable(x, grammar, nullable_nonterminals)),
+            NodeWithID::RegexExt(node, kind) => match kind {
+                RegexExtKind::Repeat0 | RegexExtKind::Optional => true,
+                RegexExtKind::Repeat1 => is_nullable(node, grammar, nullable_nonterminals),
+            },
+            NodeWithID::Symbol(lhs, kind, rhs) => {
+                let lhs = is_nullable(lhs, grammar, nullable_nonterminals);
+                let rhs = is_nullable(rhs, grammar, nullable_nonterminals);
+                match kind {
+                    SymbolKind::Concatenation => lhs && rhs,
+                    SymbolKind::Alternation => lhs || rhs,
+                }
+            }
+            NodeWithID::Group(node) => is_nullable(node, grammar, nullable_nonterminals),
+            NodeWithID::Substrings(_) => true,
+            NodeWithID::EarlyEndRegexString(_)
+            | NodeWithID::RegexComplement(_)
+            | NodeWithID::Unknown => false,
+        }
+    }
+    let mut nullable_nonterminals = AHashSet::default();
+    loop {
+        let mut updated = false;
+        for expression in &grammar.expressions {
+            if !nullable_nonterminals.contains(&expression.lhs)
+                && is_nullable(&expression.rhs, grammar, &nullable_nonterminals)
+            {
+                nullable_nonterminals.insert(expression.lhs);
+                updated = true;
+            }
+        }
+        if !updated {
+            break;
+        }
+    }
+    nullable_nonterminals
+}
+/// Find the nonterminals that are not referenced by any rule reachable from the start nonterminal.
+fn find_unreachable_nonterminals(grammar: &ValidatedGrammar) -> Vec<String> {
+    fn visit(node: &NodeWithID, referenced: &mut Vec<SymbolU32>) {
+        match node {
+            NodeWithID::Nonterminal(nonterminal) => referenced.push(*nonterminal),
+            NodeWithID::Multiple(nodes) => {
+                for node in nodes {
+                    visit(node, referenced);
+                }
+            }
+            NodeWithID::RegexExt(node, _) | NodeWithID::Group(node) => visit(node, referenced),
+            NodeWithID::Symbol(lhs, _, rhs) => {
+                visit(lhs, referenced);
+                visit(rhs, referenced);
+            }
+            _ => {}
+        }
+    }
+    let mut reachable = AHashSet::default();
+    let mut stack = vec![grammar.start_symbol];
+    while let Some(nonterminal) = stack.pop() {
+        if !reachable.insert(nonterminal) {
+            continue;
+        }
+        for expression in grammar.expressions.iter().filter(|x| x.lhs == nonterminal) {
+            visit(&expression.rhs, &mut stack);
+        }
+    }
+    user_defined_nonterminals(grammar, |x| !reachable.contains(&x))
+}
+/// Find the nonterminals that cannot derive any string, like a left recursion without a base case.
+///
+/// The productive nonterminals are found by a fixpoint iteration, where every terminal and regex is considered productive.
+fn find_nonproductive_nonterminals(grammar: &ValidatedGrammar) -> Vec<String> {
+    fn is_productive(node: &NodeWithID, productive_nonterminals: &AHashSet<SymbolU32>) -> bool {
+        match node {
+            NodeWithID::Nonterminal(nonterminal) => productive_nonterminals.contains(nonterminal),
+            NodeWithID::Multiple(nodes) => nodes
+                .iter()
+                .all(|x| is_productive(x, productive_nonterminals)),
+            NodeWithID::RegexExt(node, kind) => match kind {
+                RegexExtKind::Repeat0 | RegexExtKind::Optional => true,
+                RegexExtKind::Repeat1 => is_productive(node, productive_nonterminals),
+            },
+            NodeWithID::Symbol(lhs, kind, rhs) => {
+                let lhs = is_productive(lhs, productive_nonterminals);
+                let rhs = is_productive(rhs, productive_nonterminals);
+                match kind {
+                    SymbolKind::Concatenation => lhs && rhs,
+                    SymbolKind::Alternation => lhs || rhs,
+                }
+            }
+            NodeWithID::Group(node) => is_productive(node, productive_nonterminals),
+            NodeWithID::Terminal(_)
+            | NodeWithID::RegexString(_)
+            | NodeWithID::EarlyEndRegexString(_)
+            | NodeWithID::Substrings(_)
+            | NodeWithID::RegexComplement(_) => true,
+            NodeWithID::Unknown => false,
+        }
+    }
+    let mut productive_nonterminals = AHashSet::default();
+    loop {
+        let mut updated = false;
+        for expression in &grammar.expressions {
+            if !productive_nonterminals.contains(&expression.lhs)
+                && is_productive(&expression.rhs, &productive_nonterminals)
+            {
+                productive_nonterminals.insert(expression.lhs);
+                updated = true;
+            }
+        }
+        if !updated {
+            break;
+        }
+    }
+    user_defined_nonterminals(grammar, |x| !productive_nonterminals.contains(&x))
+}
+/// Collect the names of the defined nonterminals satisfying the predicate in the order of their definitions,
+/// excluding the nonterminals generated by the syntax extensions.
+fn user_defined_nonterminals(
+    grammar: &ValidatedGrammar,
+    mut predicate: impl FnMut(SymbolU32) -> bool,
+) -> Vec<String> {
+    let mut visited = AHashSet::default();
+    grammar
+        .expressions
+        .iter()
+        .map(|x| x.lhs)
+        .filter(|&x| visited.insert(x) && predicate(x))
+        .filter_map(|x| grammar.interned_strings.nonterminals.resolve(x))
+        .filter(|x| !x.starts_with(preprocessor::GENERATED_NONTERMINAL_PREFIX))
+        .map(str::to_string)
+        .collect()
+}
+/// Find the regex whose DFA exceeds the size limit, since the error from kbnf_syntax does not tell which regex it is.
+fn find_regex_too_large_to_compile(
+    preprocessed: &str,
+    error: &dense::BuildError,
+    config: &FiniteStateAutomatonConfig,
+) -> Option<CreateGrammarError> {
+    let FiniteStateAutomatonConfig::Dfa(config) = config;
+    let limit = exceeded_size_limit(error, config)?;
+    // The grammar is parsed again, since the validation consumes the parsed grammar.
+    let grammar = kbnf_syntax::get_grammar(preprocessed).ok()?;
+    let regex = grammar
+        .interned_strings
+        .regex_strings
+        .iter()
+        .map(|(_, x)| x)
+        .find(|x| {
+            dense::Builder::new()
+                .configure(config.clone())
+                .build(x)
+                .is_err()
+        })?;
+    Some(CreateGrammarError::RegexTooLargeToCompile {
+        regex: unanchored_regex(&preprocessor::display_raw_bytes(regex)).to_string(),
+        limit,
+    })
+}
+/// Get the size limit in the config that the DFA construction exceeds,
+/// or `None` if the error is not caused by a size limit.
+pub(crate) fn exceeded_size_limit(
+    error: &dense::BuildError,
+    config: &dense::Config,
+) -> Option<usize> {
+    // The kind of the error is private, so it is recognized by its message.
+    let message = error.to_string();
+    if message.starts_with("DFA exceeded size limit") {
+        config.get_dfa_size_limit()
+    } else if message.starts_with("determinization exceeded size limit") {
+        config.get_determinize_size_limit()
+    } else {
+        None
+    }
+}
+/// Removes the anchors that the parser adds to the regexes.
+pub(crate) fn unanchored_regex(regex: &str) -> &str {
+    regex
+        .strip_prefix("\\A(?:")
+        .and_then(|x| x.strip_suffix(")\\z"))
+        .unwrap_or(regex)
+}
+/// Escapes a string into a KBNF terminal like `"abc"`.
+pub(crate) fn kbnf_terminal(string: &str) -> String {
+    format!("\"{}\"", escape_kbnf_string(string))
+}
+/// Escapes a regex into a KBNF regex like `#"[a-z]+"`.
+pub(crate) fn kbnf_regex(regex: &str) -> String {
+    format!("#\"{}\"", escape_kbnf_string(regex))
+}
+/// Escapes a string so it can be placed between the double quotes of a KBNF string literal.
+pub(crate) fn escape_kbnf_string(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
 }
 /// Helper function to find the maximum state ID from an KBNF grammar.
 /// This is useful for determining [EngineBase](crate::engine_base::EngineBase) and [Grammar](crate::grammar::Grammar)'s generic parameter(TS).
@@ -53,7 +865,7 @@ pub fn find_max_state_id_from_kbnf_syntax_grammar(grammar: &SimplifiedGrammar) -
     let mut max_state_id = 0;
     let terminals = &grammar.interned_strings.terminals;
     for (_, i) in terminals {
-        max_state_id = max_state_id.max(i.bytes().len());
+        max_state_id = max_state_id.max(i.len());
     }
     let regexes = &grammar.id_to_regex;
     for i in regexes {
@@ -65,6 +877,15 @@ pub fn find_max_state_id_from_kbnf_syntax_grammar(grammar: &SimplifiedGrammar) -
     for i in suffix_automata {
         max_state_id = max_state_id.max(i.num_of_nodes());
     }
+    if grammar
+        .interned_strings
+        .regex_strings
+        .iter()
+        .any(|(_, x)| x.contains(preprocessor::LENGTH_PREFIXED_REGEX_MARKER))
+    {
+        // The length-prefixed nodes store the length in the state id, which requires a larger StateID(TS) in practice.
+        max_state_id = max_state_id.max(u16::MAX as usize + 1);
+    }
     max_state_id
 }
 /// Helper function to find the maximum dotted position from an KBNF grammar.
@@ -126,6 +947,48 @@ pub(crate) fn get_display_form_from_bitset(bitset: &fixedbitset_stack::FixedBitS
     bitset.ones().collect()
 }
 
+/// Groups the consecutive ones of the bitset into half-open ranges in ascending order.
+pub(crate) fn get_ranges_from_bitset(bitset: &fixedbitset_stack::FixedBitSet) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for index in bitset.ones() {
+        let index = index as u32;
+        match ranges.last_mut() {
+            Some((_, end)) if *end == index => *end += 1,
+            _ => ranges.push((index, index + 1)),
+        }
+    }
+    ranges
+}
+
+/// The arbitrary but fixed seeds of the hashers in deterministic mode.
+const DETERMINISTIC_HASHER_SEEDS: [u64; 4] = [
+    0x2b7e_1516_28ae_d2a6,
+    0xabf7_1588_09cf_4f3c,
+    0x7624_8c3d_07c3_b8b4,
+    0x2f4d_88f1_2eef_d8e0,
+];
+
+/// Creates the hasher of a hash map or set, which uses fixed seeds when `deterministic` is true,
+/// so the iteration order of the map or set is the same across runs.
+pub(crate) fn hasher(deterministic: bool) -> ahash::RandomState {
+    if deterministic {
+        let [k0, k1, k2, k3] = DETERMINISTIC_HASHER_SEEDS;
+        ahash::RandomState::with_seeds(k0, k1, k2, k3)
+    } else {
+        ahash::RandomState::new()
+    }
+}
+
+/// Creates an empty hash set with the hasher from [`hasher`].
+pub(crate) fn new_hash_set<T>(deterministic: bool) -> AHashSet<T> {
+    AHashSet::with_hasher(hasher(deterministic))
+}
+
+/// Creates an empty hash map with the hasher from [`hasher`].
+pub(crate) fn new_hash_map<K, V>(deterministic: bool) -> AHashMap<K, V> {
+    AHashMap::with_hasher(hasher(deterministic))
+}
+
 pub(crate) fn get_deterministic_display_form_from_hash_set<T, U: Ord>(
     set: &AHashSet<T>,
     process: impl FnMut(&T) -> U,
@@ -135,11 +998,11 @@ pub(crate) fn get_deterministic_display_form_from_hash_set<T, U: Ord>(
     a
 }
 
-pub(crate) fn get_deterministic_display_form_from_hash_map<K, V, U: Ord + Clone, Y>(
-    map: &AHashMap<K, V>,
-    process: impl FnMut((&K, &V)) -> (U, Y),
+pub(crate) fn get_deterministic_display_form_from_hash_map<'a, K: 'a, V: 'a, U: Ord + Clone, Y>(
+    map: impl IntoIterator<Item = (&'a K, &'a V)>,
+    process: impl FnMut((&'a K, &'a V)) -> (U, Y),
 ) -> Vec<(U, Y)> {
-    let mut a: Vec<_> = map.iter().map(process).collect();
+    let mut a: Vec<_> = map.into_iter().map(process).collect();
     a.sort_by_cached_key(|(k, _)| k.clone());
     a
 }
@@ -150,3 +1013,162 @@ pub(crate) fn fill_debug_form_of_id_to_x<'a, T: std::fmt::Debug>(
 ) -> AHashMap<String, T> {
     id_to_x.enumerate().map(|(i, x)| (get_str(i), x)).collect()
 }
+
+/// The leading bytes of the multi-byte characters in well-formed UTF-8.
+pub(crate) const UTF8_LEADING_BYTES: std::ops::Range<usize> = 0xC2..0xF5;
+
+/// The continuation bytes accepted in each state of the UTF-8 decoder and the next state.
+///
+/// The state 0 expects the first byte of a character and is not listed here.
+/// The indices are the states minus one, and a next state of 0 means the character is complete.
+const UTF8_CONTINUATIONS: [(u8, u8, usize); 7] = [
+    (0x80, 0xBF, 0),
+    (0x80, 0xBF, 1),
+    (0x80, 0xBF, 2),
+    (0xA0, 0xBF, 1),
+    (0x80, 0x9F, 1),
+    (0x90, 0xBF, 2),
+    (0x80, 0x8F, 2),
+];
+
+/// Feed a byte to the UTF-8 decoder of one character whose first byte must be in `first_bytes`.
+///
+/// Returns the next state, where 0 means the character is complete, or `None` if the byte is rejected.
+pub(crate) fn feed_utf8_char(first_bytes: &ByteSet, state: usize, byte: u8) -> Option<usize> {
+    if state == 0 && !first_bytes.contains(byte as usize) {
+        return None;
+    }
+    feed_any_utf8_char(state, byte)
+}
+
+/// Feed a byte to the UTF-8 decoder of any one character.
+///
+/// Returns the next state, where 0 means the character is complete, or `None` if the byte is rejected.
+pub(crate) fn feed_any_utf8_char(state: usize, byte: u8) -> Option<usize> {
+    if state != 0 {
+        let (low, high, next) = UTF8_CONTINUATIONS[state - 1];
+        return (low..=high).contains(&byte).then_some(next);
+    }
+    Some(match byte {
+        0x00..=0x7F => 0,
+        0xC2..=0xDF => 1,
+        0xE0 => 4,
+        0xED => 5,
+        0xE1..=0xEF => 2,
+        0xF0 => 6,
+        0xF4 => 7,
+        0xF1..=0xF3 => 3,
+        // Continuation bytes and the bytes never used in UTF-8.
+        _ => return None,
+    })
+}
+
+/// Whether the two DFAs accept the same language from both the anchored and the unanchored start states.
+///
+/// The pairs of states reachable by the same bytes are visited from the start states,
+/// and the DFAs are equivalent if every pair agrees on whether the states match, are dead or quit.
+pub(crate) fn are_equivalent_dfas(a: &dense::DFA<Vec<u32>>, b: &dense::DFA<Vec<u32>>) -> bool {
+    let mut stack = Vec::new();
+    for anchored in [
+        kbnf_regex_automata::Anchored::Yes,
+        kbnf_regex_automata::Anchored::No,
+    ] {
+        let config = kbnf_regex_automata::util::start::Config::new().anchored(anchored);
+        match (a.start_state(&config), b.start_state(&config)) {
+            (Ok(x), Ok(y)) => stack.push((x, y)),
+            (Err(_), Err(_)) => {}
+            _ => return false,
+        }
+    }
+    let mut visited = AHashSet::default();
+    while let Some((x, y)) = stack.pop() {
+        if !visited.insert((x, y)) {
+            continue;
+        }
+        if a.is_match_state(x) != b.is_match_state(y)
+            || a.is_dead_state(x) != b.is_dead_state(y)
+            || a.is_quit_state(x) != b.is_quit_state(y)
+            || a.is_match_state(a.next_eoi_state(x)) != b.is_match_state(b.next_eoi_state(y))
+        {
+            return false;
+        }
+        for byte in 0..=u8::MAX {
+            stack.push((a.next_state(x, byte), b.next_state(y, byte)));
+        }
+    }
+    true
+}
+
+/// Get the bytes accepted by the UTF-8 decoder in a state other than 0.
+pub(crate) fn utf8_continuation_bytes(state: usize) -> ByteSet {
+    let (low, high, _) = UTF8_CONTINUATIONS[state - 1];
+    let mut set = ByteSet::with_capacity(256);
+    set.insert_range(low as usize..high as usize + 1);
+    set
+}
+
+/// A cache that evicts the least recently used entry when the number of entries exceeds the capacity.
+///
+/// Finding the entry to evict scans all the entries,
+/// which is negligible compared to the vocabulary scan needed to compute a new entry of the engine's cache.
+#[derive(Debug, Clone)]
+pub(crate) struct LruCache<K, V> {
+    map: AHashMap<K, (V, u64)>,
+    capacity: Option<usize>,
+    clock: u64,
+}
+
+impl<K: Eq + std::hash::Hash, V> LruCache<K, V> {
+    /// Creates an empty cache that holds at most `capacity` entries, or unlimited entries if `capacity` is `None`.
+    pub(crate) fn new(capacity: Option<usize>) -> Self {
+        Self {
+            map: AHashMap::default(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Gets the value of the key and marks the entry as the most recently used one.
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.map.get_mut(key).map(|(value, last_used)| {
+            *last_used = clock;
+            &*value
+        })
+    }
+
+    /// Inserts the entry as the most recently used one and evicts the least recently used entries if needed.
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.clock += 1;
+        self.map.insert(key, (value, self.clock));
+        if let Some(capacity) = self.capacity {
+            while self.map.len() > capacity {
+                let least_recently_used = self
+                    .map
+                    .values()
+                    .map(|(_, last_used)| *last_used)
+                    .min()
+                    .unwrap();
+                self.map
+                    .retain(|_, (_, last_used)| *last_used != least_recently_used);
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map.iter().map(|(key, (value, _))| (key, value))
+    }
+}
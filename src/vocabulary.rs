@@ -1,94 +1,108 @@
 //! This module contains the `Vocabulary` struct, which represents a language model's vocabulary.
 use ahash::AHashMap;
-use jaggedarray::jagged_array::JaggedArray;
-use jaggedarray::jagged_array::JaggedArrayViewTrait;
-use nonmax::NonMaxU8;
-use num::ToPrimitive;
 #[cfg(feature = "python")]
 use pyo3::prelude::*;
 use serde::Deserialize;
-use std::array;
-use std::collections::hash_map::Entry;
 use std::fmt::Debug;
-use tinyvec::ArrayVec;
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
+use crate::token_trie::TokenTrie;
 use crate::utils;
 use crate::utils::ByteSet;
 
-const TOKEN_SEPARATOR: u8 = 0xFF;
-const BYTES_NUM: usize = 257; // 256 + 1 because jagged array's implementation requires one additional index.
-
 /// A wrapper struct that represents a token in bytes in a language model's vocabulary.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 #[repr(transparent)]
 #[cfg_attr(feature = "wasm", wasm_bindgen(getter_with_clone))]
 #[cfg_attr(feature = "python", pyclass)]
 pub struct Token(pub Box<[u8]>);
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub(crate) struct FirstBytes([u32; BYTES_NUM]);
-impl tinyvec::Array for FirstBytes {
-    type Item = u32;
-    const CAPACITY: usize = BYTES_NUM;
-
-    fn as_slice(&self) -> &[Self::Item] {
-        &self.0
-    }
-
-    fn as_slice_mut(&mut self) -> &mut [Self::Item] {
-        &mut self.0
-    }
-
-    fn default() -> Self {
-        Self([0; BYTES_NUM])
-    }
-}
 /// The struct represents a language model's vocabulary.
 #[derive(Clone)]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[cfg_attr(feature = "python", pyclass)]
 pub struct Vocabulary {
-    pub(crate) token_to_id: AHashMap<Token, u32>,
     pub(crate) id_to_token: AHashMap<u32, Token>,
     pub(crate) id_to_token_string: AHashMap<u32, String>,
-    /// This field represents a map from the first byte of a token to the token id and token that DO NOT contain byte 0xFF.
-    /// memory representation: \[Unicode unused byte\]\[token_id(3 bytes little endian)\]\[token(remaining bytes)\]
-    // TODO: check whether a variable length token_id encoding is better
-    first_byte_to_normal_tokens: JaggedArray<u8, ArrayVec<FirstBytes>, 2>,
-    /// This field represents a map from the token id to the token that contains the Unicode unused byte in `first_byte_to_normal_tokens``.
-    /// The number of such tokens is expected to be small so we probably do not need a jagged array(which does have some overhead).
-    tokens_containing_separators: Vec<(u32, Token)>,
+    /// The byte trie of the non-empty tokens, through which the engine feeds the bytes shared by several tokens only once.
+    /// It also maps the tokens back to their IDs, so the bytes of each token are only stored in `id_to_token`.
+    token_trie: TokenTrie,
+    /// The bytes present in at least one token, which are the only bytes the model can produce.
+    pub(crate) covered_bytes: ByteSet,
 }
 
 impl Debug for Vocabulary {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Vocabulary")
-            .field("token_to_id", &self.token_to_id)
             .field("id_to_token", &self.id_to_token)
             .field("id_to_token_string", &self.id_to_token_string)
-            .field("first_byte_to_normal_tokens", {
-                let mut hash_map = AHashMap::new();
-                for byte in 0..u8::MAX as usize + 1 {
-                    let mut iter = self.normal_tokens_from_first_byte(byte as u8);
-                    while let Some(item) = iter.next() {
-                        if let TokenIterItem::TokenByte(byte) = item {
-                            hash_map
-                                .entry(iter.current_token_id())
-                                .or_insert_with(Vec::new)
-                                .push(byte.get());
-                        }
-                    }
-                }
-                &Box::new(hash_map)
-            })
+            .field("token_trie", &self.token_trie)
             .field(
-                "tokens_containing_separators",
-                &self.tokens_containing_separators,
+                "covered_bytes",
+                &utils::get_display_form_from_bitset_on_stack(&self.covered_bytes),
             )
             .finish()
     }
 }
+/// How a Hugging Face tokenizer encodes the bytes of its tokens as the strings in its vocabulary.
+///
+/// The hints usually can be read from the `pre_tokenizer` and `decoder` fields in `tokenizer.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct HfTokenEncoding {
+    /// Whether the bytes are encoded by the GPT-2 byte-to-unicode table,
+    /// which is specified by the `ByteLevel` pre-tokenizer or decoder. For example, `Ġ` represents a space.
+    pub byte_level: bool,
+    /// Whether the `<0xXX>` tokens represent single raw bytes,
+    /// which is specified by the `ByteFallback` decoder or `byte_fallback` in the BPE model.
+    pub byte_fallback: bool,
+    /// The character that replaces spaces, like `▁` specified by the `Metaspace` pre-tokenizer or decoder.
+    pub metaspace: Option<char>,
+}
+
+/// How [`Vocabulary::from_utf16_tokens`] handles an unpaired surrogate in a token,
+/// which has no UTF-8 representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum UnpairedSurrogate {
+    /// Replaces the unpaired surrogate with the replacement character `U+FFFD`.
+    #[default]
+    Replace,
+    /// Returns [`CreateVocabularyError::UnpairedSurrogate`].
+    Error,
+}
+
+/// Maps the printable characters in the GPT-2 byte-to-unicode table back to the bytes.
+fn gpt2_char_to_byte(c: char) -> Option<u8> {
+    let c = c as u32;
+    match c {
+        // The printable bytes are mapped to themselves
+        0x21..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF => Some(c as u8),
+        // The other bytes are mapped to 256, 257, ... in ascending order
+        0x100..=0x120 => Some((c - 0x100) as u8),
+        0x121..=0x142 => Some((c - 0x121 + 0x7F) as u8),
+        0x143 => Some(0xAD),
+        _ => None,
+    }
+}
+
+/// Decodes the `<0xXX>` byte-fallback token.
+fn decode_byte_fallback(token: &str) -> Option<u8> {
+    let hex = token.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+/// The report of a [Vocabulary] created by [`Vocabulary::new_with_report`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct VocabularyReport {
+    /// The bytes in `0..=247` that are not present in any token in ascending order.
+    ///
+    /// Every UTF-8 string is made of these bytes, so a missing byte likely indicates that the vocabulary loading code is wrong
+    /// or the tokenizer is not UTF-8 compatible. The grammar branches requiring a missing byte never allow any token.
+    pub uncovered_bytes: Vec<u8>,
+}
+
 #[derive(Debug, thiserror::Error)]
 /// The error type for [Vocabulary] creation.
 pub enum CreateVocabularyError {
@@ -98,50 +112,80 @@ pub enum CreateVocabularyError {
     /// The token's length exceeds the maximum supported length.
     #[error("The token's length is {0}, while the maximum supported is {1}.")]
     TokenTooLong(usize, usize),
+    /// The token ID already exists in the vocabulary.
+    #[error("The token ID {0} already exists in the vocabulary.")]
+    TokenIDAlreadyExists(u32),
+    /// The UTF-16 code units of the token contain an unpaired surrogate.
+    #[error("The token ID {0} contains the unpaired surrogate {1:#06X}.")]
+    UnpairedSurrogate(u32, u16),
 }
 
 impl Vocabulary {
     /// Creates a new instance of [Vocabulary].
     ///
+    /// The token IDs need not be contiguous, which is common for the tokenizers with reserved or unused IDs.
+    /// The missing IDs below [`Vocabulary::vocab_size`] are never allowed, so they are masked in a logits array
+    /// covering the whole ID space like any other disallowed token.
+    ///
     /// # Arguments
     ///
     /// * `id_to_token` - A map from token IDs to tokens.
     /// * `id_to_token_string` - A map from token IDs to tokens in UTF-8 String representation.
-    ///     This parameter is necessary because a token's UTF-8 representation may not be equivalent to the UTF-8 string decoded from its bytes,
-    ///     vice versa. For example, a token may contain `0xFF` byte.
+    ///   This parameter is necessary because a token's UTF-8 representation may not be equivalent to the UTF-8 string decoded from its bytes,
+    ///   vice versa. For example, a token may contain `0xFF` byte.
     pub fn new(
         id_to_token: AHashMap<u32, Token>,
         id_to_token_string: AHashMap<u32, String>,
     ) -> Result<Vocabulary, CreateVocabularyError> {
+        Self::new_with_report(id_to_token, id_to_token_string).map(|(vocabulary, _)| vocabulary)
+    }
+
+    /// Creates a new instance of [Vocabulary] like [`Vocabulary::new`],
+    /// along with a [`VocabularyReport`] so the caller can decide whether to proceed with a vocabulary missing some bytes.
+    ///
+    /// The missing bytes are still logged once as a warning.
+    /// A grammar requiring a missing byte does not panic; the byte is never allowed
+    /// and the grammar branches requiring it yield no tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `id_to_token` - A map from token IDs to tokens.
+    /// * `id_to_token_string` - A map from token IDs to tokens in UTF-8 String representation.
+    pub fn new_with_report(
+        id_to_token: AHashMap<u32, Token>,
+        id_to_token_string: AHashMap<u32, String>,
+    ) -> Result<(Vocabulary, VocabularyReport), CreateVocabularyError> {
         if id_to_token.len() >= 0x1000000 {
             return Err(CreateVocabularyError::VocabularyTooLarge(
                 id_to_token.len(),
                 0x1000000,
             ));
         }
-        
-        let mut token_to_id = AHashMap::with_capacity(id_to_token.len());
-        let mut conflicting_token_ids: Vec<(u32, u32)> = Vec::new();
         for (&token_id, token) in id_to_token.iter() {
-            match token_to_id.entry(token.clone()) {
-                Entry::Occupied(entry) => {
-                    conflicting_token_ids.push((token_id, *entry.get()));
-                }
-                Entry::Vacant(entry) => {
-                    entry.insert(token_id);
-                }
+            if token.0.is_empty() {
+                log::warn!(
+                    "Token ID {} corresponds to an empty token. 
+                    The token will be ignored. ",
+                    token_id
+                );
             }
         }
-        if !conflicting_token_ids.is_empty() {
-            let conflicting_pairs: Vec<String> = conflicting_token_ids
+        let token_trie = TokenTrie::new(
+            id_to_token
                 .iter()
-                .map(|(new_id, existing_id)| {
-                    format!(
-                        "({}, {})",
-                        existing_id, new_id
-                    )
-                })
-                .collect();
+                .map(|(&token_id, token)| (token_id, &*token.0)),
+        );
+        // The token IDs of a node are sorted, so the smallest one is matched to the token.
+        let conflicting_pairs: Vec<String> = (0..token_trie.node_count())
+            .flat_map(|node| {
+                let token_ids = token_trie.token_ids(node);
+                token_ids
+                    .iter()
+                    .skip(1)
+                    .map(move |token_id| format!("({}, {})", token_ids[0], token_id))
+            })
+            .collect();
+        if !conflicting_pairs.is_empty() {
             log::warn!(
                 "Multiple token ids correspond to the same token. Matching \
                 tokens to token ids is only used for debugging purposes. The second \
@@ -150,79 +194,190 @@ impl Vocabulary {
                 conflicting_pairs.join(", ")
             );
         }
+        let covered_bytes = Self::find_covered_bytes(id_to_token.values());
+        let report = Self::check_vocabulary_utf8_support(&covered_bytes);
+        Ok((
+            Self {
+                id_to_token,
+                id_to_token_string,
+                token_trie,
+                covered_bytes,
+            },
+            report,
+        ))
+    }
 
-        let mut first_byte_to_token = JaggedArray::with_capacity([256, 256]);
-        let mut temp: [Vec<(u32, &Token)>; 256] = array::from_fn(|_| (vec![]));
-        for (&token_id, token) in id_to_token.iter() {
-            if token.0.is_empty() {
-                log::warn!(
-                    "Token ID {} corresponds to an empty token. 
-                    The token will be ignored. ",
-                    token_id
-                );
-                continue;
-            }
-            let first_byte = token.0[0];
-            temp[first_byte as usize].push((token_id, token));
+    /// Creates a vocabulary without any token that still covers every byte,
+    /// for the engines that are driven by bytes instead of tokens.
+    pub(crate) fn byte_level() -> Vocabulary {
+        let mut covered_bytes = ByteSet::with_capacity(256);
+        covered_bytes.insert_range(..);
+        Self {
+            id_to_token: AHashMap::default(),
+            id_to_token_string: AHashMap::default(),
+            token_trie: TokenTrie::new(std::iter::empty()),
+            covered_bytes,
         }
-        let mut tokens_containing_separators = Vec::new();
-        for tokens in temp.iter() {
-            first_byte_to_token.new_row::<0>();
-            for &(token_id, token) in tokens.iter() {
-                let mut buffer = vec![TOKEN_SEPARATOR];
-                if token.0.contains(&TOKEN_SEPARATOR) {
-                    tokens_containing_separators.push((token_id, token.clone()));
-                    continue;
+    }
+
+    /// Creates a new instance of [Vocabulary] from a Hugging Face tokenizer's vocabulary,
+    /// which is usually obtained by `get_vocab()` or the `model.vocab` field in `tokenizer.json`.
+    ///
+    /// The token strings are decoded back to the bytes they represent according to `encoding`,
+    /// while the characters that are not encoded are kept as their UTF-8 bytes.
+    /// The token strings are kept as they are if the decoded bytes are not valid UTF-8.
+    ///
+    /// # Arguments
+    ///
+    /// * `vocab` - A map from token strings to token IDs.
+    /// * `encoding` - How the tokenizer encodes the bytes of its tokens.
+    pub fn from_hf_tokenizer_bytes<S: AsRef<str>>(
+        vocab: impl IntoIterator<Item = (S, u32)>,
+        encoding: HfTokenEncoding,
+    ) -> Result<Vocabulary, CreateVocabularyError> {
+        let mut id_to_token = AHashMap::default();
+        let mut id_to_token_string = AHashMap::default();
+        for (token_string, token_id) in vocab {
+            let token_string = token_string.as_ref();
+            let mut bytes = Vec::with_capacity(token_string.len());
+            match decode_byte_fallback(token_string) {
+                Some(byte) if encoding.byte_fallback => bytes.push(byte),
+                _ => {
+                    for c in token_string.chars() {
+                        if encoding.metaspace == Some(c) {
+                            bytes.push(b' ');
+                            continue;
+                        }
+                        match gpt2_char_to_byte(c) {
+                            Some(byte) if encoding.byte_level => bytes.push(byte),
+                            _ => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                        }
+                    }
                 }
-                buffer.extend(token_id.to_le_bytes().into_iter().take(3));
-                let token_len =
-                    token
-                        .0
-                        .len()
-                        .to_u8()
-                        .ok_or(CreateVocabularyError::TokenTooLong(
-                            token.0.len(),
-                            u8::MAX as usize,
-                        ))?
-                        - 1;
-                buffer.push(token_len);
-                buffer.extend(token.0.iter().skip(1));
-                first_byte_to_token.extend_last_row(buffer.into_iter());
             }
+            let decoded_string =
+                String::from_utf8(bytes.clone()).unwrap_or_else(|_| token_string.to_string());
+            id_to_token.insert(token_id, Token(bytes.into_boxed_slice()));
+            id_to_token_string.insert(token_id, decoded_string);
         }
-        Self::check_vocabulary_utf8_support(&token_to_id);
-        Ok(Self {
-            token_to_id,
-            id_to_token,
-            id_to_token_string,
-            first_byte_to_normal_tokens: first_byte_to_token,
-            tokens_containing_separators,
-        })
+        Self::new(id_to_token, id_to_token_string)
     }
 
-    fn check_vocabulary_utf8_support(token_to_id: &AHashMap<Token, u32>) {
+    /// Creates a new instance of [Vocabulary] from the tokens in UTF-16 code units,
+    /// which are transcoded to the UTF-8 bytes the engine operates on.
+    ///
+    /// A surrogate pair is transcoded to the four bytes of the astral-plane character it represents.
+    /// An unpaired surrogate cannot be represented in UTF-8, so it is handled according to `unpaired_surrogate`.
+    /// Note that a character split across two tokens leaves an unpaired surrogate in each of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `vocab` - The token IDs and the tokens in UTF-16 code units.
+    /// * `unpaired_surrogate` - How to handle an unpaired surrogate.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CreateVocabularyError::UnpairedSurrogate`] when a token contains an unpaired surrogate
+    /// and `unpaired_surrogate` is [`UnpairedSurrogate::Error`], and the other variants in the same cases as [`Vocabulary::new`].
+    pub fn from_utf16_tokens<T: AsRef<[u16]>>(
+        vocab: impl IntoIterator<Item = (u32, T)>,
+        unpaired_surrogate: UnpairedSurrogate,
+    ) -> Result<Vocabulary, CreateVocabularyError> {
+        let mut id_to_token = AHashMap::default();
+        let mut id_to_token_string = AHashMap::default();
+        for (token_id, code_units) in vocab {
+            let token_string = char::decode_utf16(code_units.as_ref().iter().copied())
+                .map(|c| match (c, unpaired_surrogate) {
+                    (Ok(c), _) => Ok(c),
+                    (Err(_), UnpairedSurrogate::Replace) => Ok(char::REPLACEMENT_CHARACTER),
+                    (Err(e), UnpairedSurrogate::Error) => Err(
+                        CreateVocabularyError::UnpairedSurrogate(token_id, e.unpaired_surrogate()),
+                    ),
+                })
+                .collect::<Result<String, _>>()?;
+            id_to_token.insert(
+                token_id,
+                Token(token_string.as_bytes().to_vec().into_boxed_slice()),
+            );
+            id_to_token_string.insert(token_id, token_string);
+        }
+        Self::new(id_to_token, id_to_token_string)
+    }
+
+    /// Adds a token to the vocabulary in place, which is much cheaper than creating a new [Vocabulary].
+    ///
+    /// The [`Engine`](crate::engine::Engine)s share the vocabulary they are created with behind an `Arc`,
+    /// so the engines created before adding the token do not recognize it.
+    /// Add the tokens before sharing the vocabulary, or add them to a copy of a shared vocabulary
+    /// by [`Arc::make_mut`](std::sync::Arc::make_mut) and create new engines with the copy.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The ID of the token, which may exceed the current vocabulary size.
+    /// * `token` - The token.
+    /// * `token_string` - The token in UTF-8 String representation.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`CreateVocabularyError`] when the token ID already exists or the token ID is too large.
+    /// The vocabulary is not updated in this case.
+    pub fn add_token(
+        &mut self,
+        token_id: u32,
+        token: Token,
+        token_string: String,
+    ) -> Result<(), CreateVocabularyError> {
+        if self.id_to_token.contains_key(&token_id) {
+            return Err(CreateVocabularyError::TokenIDAlreadyExists(token_id));
+        }
+        if token_id >= 0x1000000 {
+            return Err(CreateVocabularyError::VocabularyTooLarge(
+                token_id as usize + 1,
+                0x1000000,
+            ));
+        }
+        if let Some(existing_token_id) = self.token_id(&token) {
+            log::warn!(
+                "Token ID {} corresponds to the same token as token ID {}. \
+                The former will be ignored when matching tokens to ids.",
+                token_id,
+                existing_token_id
+            );
+        }
+        if token.0.is_empty() {
+            log::warn!(
+                "Token ID {} corresponds to an empty token. The token will be ignored. ",
+                token_id
+            );
+        } else {
+            // The ID is inserted after the existing IDs of the same token, so it is never matched to the token.
+            self.token_trie.insert(token_id, &token.0);
+        }
+        for &byte in token.0.iter() {
+            self.covered_bytes.insert(byte as usize);
+        }
+        self.id_to_token.insert(token_id, token);
+        self.id_to_token_string.insert(token_id, token_string);
+        Ok(())
+    }
+
+    fn find_covered_bytes<'a>(tokens: impl Iterator<Item = &'a Token>) -> ByteSet {
+        let mut covered_bytes = ByteSet::with_capacity(256);
+        for token in tokens {
+            for &byte in token.0.iter() {
+                covered_bytes.insert(byte as usize);
+            }
+        }
+        covered_bytes
+    }
+
+    fn check_vocabulary_utf8_support(covered_bytes: &ByteSet) -> VocabularyReport {
         let mut not_existing_bytes = ByteSet::with_capacity(256);
-        fn check_non_existing_byte_in_range(
-            token_to_id: &AHashMap<Token, u32>,
-            not_existing_bytes: &mut ByteSet,
-            start: u8,
-            end: u8,
-        ) {
-            for byte in start..=end {
-                // iterate over all tokens and check the presence of the byte
-                let mut found = false;
-                for token in token_to_id.keys() {
-                    if token.0.contains(&byte) {
-                        found = true;
-                        break;
-                    }
-                }
-                if !found {
-                    not_existing_bytes.insert(byte as usize);
-                }
+        for byte in 0..=247 {
+            if !covered_bytes.contains(byte) {
+                not_existing_bytes.insert(byte);
             }
         }
-        check_non_existing_byte_in_range(token_to_id, &mut not_existing_bytes, 0, 247);
         if !not_existing_bytes.is_clear() {
             log::warn!(
                 "\
@@ -234,6 +389,9 @@ processing the vocab like the tokenizer.",
                 utils::get_display_form_from_bitset_on_stack(&not_existing_bytes)
             );
         }
+        VocabularyReport {
+            uncovered_bytes: not_existing_bytes.ones().map(|x| x as u8).collect(),
+        }
     }
 
     /// Retrieves the token associated with the given token ID.
@@ -264,56 +422,62 @@ processing the vocab like the tokenizer.",
         self.id_to_token_string.get(&token_id).map(|x| x.as_str())
     }
 
-    /// Retrieves an iterator over the normal tokens that have the given first byte.
+    /// Retrieves the byte trie of the non-empty tokens.
+    pub(crate) fn token_trie(&self) -> &TokenTrie {
+        &self.token_trie
+    }
+}
+impl Vocabulary {
+    /// Retrieves the token ID associated with the given token.
     ///
     /// # Arguments
     ///
-    /// * `first_byte` - The first byte of the tokens to retrieve.
+    /// * `token` - The token to retrieve the ID for.
     ///
     /// # Returns
     ///
-    /// An iterator over the normal tokens with the given first byte.
-    pub(crate) fn normal_tokens_from_first_byte(&self, first_byte: u8) -> TokensIter {
-        let slice = self
-            .first_byte_to_normal_tokens
-            .view::<1, 1>([first_byte as usize])
-            .as_slice();
-        TokensIter {
-            current_token_id: usize::MAX,
-            current_token_remaining_length: usize::MAX,
-            current: slice.as_ptr(),
-            // SAFETY: the existence of this slice guarantees that this add is safe
-            end: unsafe { slice.as_ptr().add(slice.len()) },
-            placeholder: std::marker::PhantomData,
+    /// * `Some(u32)` - The token ID if it exists.
+    /// * `None` - If the token does not exist in the vocabulary.
+    pub fn token_id(&self, token: &Token) -> Option<u32> {
+        if token.0.is_empty() {
+            // The empty tokens are not in the trie.
+            return self
+                .id_to_token
+                .iter()
+                .filter(|(_, x)| x.0.is_empty())
+                .map(|(&token_id, _)| token_id)
+                .min();
         }
+        let node = self.token_trie.find(&token.0)?;
+        self.token_trie.token_ids(node).first().copied()
     }
-
-    /// Retrieves an iterator over the tokens that contain separators.
-    ///
-    /// # Returns
+    /// Retrieves the token ID associated with the given token string.
     ///
-    /// An iterator over the tokens that contain separators.
-    pub(crate) fn tokens_containing_separators(&self) -> impl Iterator<Item = (u32, &Token)> {
-        self.tokens_containing_separators
-            .iter()
-            .map(|(x, y)| (*x, y))
-    }
-}
-impl Vocabulary {
-    /// Retrieves the token ID associated with the given token.
+    /// The string is first looked up as the bytes of a token, which is what a streaming tokenizer decodes to.
+    /// Otherwise it is compared to the token strings, which may differ from the bytes,
+    /// e.g. `Ġhello` of a byte-level BPE tokenizer for ` hello`. The latter scans the whole vocabulary.
     ///
     /// # Arguments
     ///
-    /// * `token` - The token to retrieve the ID for.
+    /// * `token_str` - The token string to retrieve the ID for.
     ///
     /// # Returns
     ///
-    /// * `Some(u32)` - The token ID if it exists.
-    /// * `None` - If the token does not exist in the vocabulary.
-    pub fn token_id(&self, token: &Token) -> Option<u32> {
-        self.token_to_id.get(token).copied()
+    /// * `Some(u32)` - The token ID if it exists. The smallest token ID is returned when several token strings match.
+    /// * `None` - If neither a token nor a token string matches.
+    pub fn token_id_from_str(&self, token_str: &str) -> Option<u32> {
+        if let Some(token_id) = self.token_id(&Token(token_str.as_bytes().into())) {
+            return Some(token_id);
+        }
+        self.id_to_token_string
+            .iter()
+            .filter(|(_, x)| x.as_str() == token_str)
+            .map(|(&token_id, _)| token_id)
+            .min()
     }
-    /// Retrieves the size of the vocabulary.
+    /// Retrieves the size of the vocabulary, which is the largest token ID plus one.
+    ///
+    /// It is larger than the number of tokens when the token IDs are not contiguous.
     pub fn vocab_size(&self) -> usize {
         self.id_to_token
             .keys()
@@ -323,68 +487,3 @@ impl Vocabulary {
             .unwrap_or(0) as usize
     }
 }
-
-#[derive(Debug, Clone)]
-pub(crate) struct TokensIter<'a> {
-    current_token_id: usize,
-    current_token_remaining_length: usize,
-    current: *const u8,
-    end: *const u8,
-    placeholder: std::marker::PhantomData<&'a u8>,
-}
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub(crate) enum TokenIterItem {
-    TokenByte(NonMaxU8),
-    NewToken,
-}
-
-impl Iterator for TokensIter<'_> {
-    type Item = TokenIterItem; // We excludes 0xFF from the token before
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current == self.end {
-            return None;
-        }
-        // SAFETY: We have checked that self.current != self.end
-        let x = unsafe { self.next_unchecked() };
-        if x == TOKEN_SEPARATOR {
-            // SAFETY: TOKEN_SEPARATOR must be followed by 3 bytes of token id and 1 byte of token length
-            let buffer = unsafe {
-                [
-                    self.next_unchecked(),
-                    self.next_unchecked(),
-                    self.next_unchecked(),
-                    0x00,
-                ]
-            };
-            self.current_token_remaining_length = unsafe { self.next_unchecked() } as usize;
-            self.current_token_id = u32::from_le_bytes(buffer) as usize;
-            Some(TokenIterItem::NewToken)
-        } else {
-            self.current_token_remaining_length -= 1;
-            // SAFETY: We excludes 0xFF from the token before
-            Some(TokenIterItem::TokenByte(unsafe {
-                NonMaxU8::new_unchecked(x)
-            }))
-        }
-    }
-}
-
-impl TokensIter<'_> {
-    /// SAFETY: The caller must ensure that self.current != self.end
-    unsafe fn next_unchecked(&mut self) -> u8 {
-        let value = self.current.read();
-        self.current = self.current.add(1);
-        value
-    }
-
-    #[inline]
-    pub fn current_token_id(&self) -> usize {
-        self.current_token_id
-    }
-    #[inline]
-    pub fn next_token(&mut self) {
-        // SAFETY: current_token_remaining_length<=u8::MAX
-        self.current = unsafe { self.current.add(self.current_token_remaining_length) };
-    }
-}
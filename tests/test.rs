@@ -1,8 +1,6 @@
 #[cfg(test)]
-
 mod tests {
     use std::{
-        cell::RefCell,
         fs::File,
         io::BufReader,
         path::Path,
@@ -13,7 +11,9 @@ mod tests {
     use insta::assert_snapshot;
     use kbnf::{
         engine::EngineConfig,
-        engine_like::{AcceptTokenResult, EngineLike},
+        engine_like::{
+            AcceptStrError, AcceptTokenError, AcceptTokenResult, EngineLike, WriteBufferError,
+        },
         vocabulary::{Token, Vocabulary},
     };
     #[derive(Debug, thiserror::Error)]
@@ -71,7 +71,7 @@ mod tests {
     fn single_terminal() {
         let input = "start::='Hello, World!\n';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
         assert!(
             engine.try_accept_new_token(get_token_id_from_str(&vocab, "b").unwrap())
@@ -106,7 +106,7 @@ mod tests {
     fn single_regex() {
         let input = "start::=#'Hello, World!\n';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
         assert!(
             engine.try_accept_new_token(get_token_id_from_str(&vocab, "b").unwrap())
@@ -144,10 +144,15 @@ mod tests {
             engine_config: EngineConfig {
                 cache_enabled: true,
                 compaction_enabled: false,
+                compaction_interval: 1,
+                max_cache_entries: None,
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
             },
             ..Default::default()
         };
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
         assert!(
             engine.try_accept_new_token(get_token_id_from_str(&vocab, "b").unwrap())
@@ -181,7 +186,7 @@ mod tests {
     fn minimal_case() {
         let input = "start::='aaa';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
         assert!(
             engine.try_accept_new_token(get_token_id_from_str(&vocab, "b").unwrap())
@@ -267,9 +272,9 @@ mod tests {
     fn escaped_literal() {
         let input = "start::=#'(\\n\\n)+';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
-        for i in 0..10 {
+        for _i in 0..10 {
             engine.compute_allowed_token_ids();
             assert!(
                 !engine.allowed_token_ids_from_last_computation().is_empty(),
@@ -297,7 +302,7 @@ mod tests {
     fn left_recursion() {
         let input = "start::='bb'|start'bb';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
         let result = engine
             .try_accept_new_token(
@@ -314,16 +319,21 @@ mod tests {
     fn right_recursion() {
         let input = "start::=C'\n';C::='c'|#'c' C;";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let config = kbnf::config::Config {
             engine_config: EngineConfig {
                 cache_enabled: true,
                 compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: None,
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
             },
             ..Default::default()
         };
         let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
-        for i in 0..10 {
+        for _i in 0..10 {
             let result = engine
                 .try_accept_new_token(
                     vocab
@@ -350,16 +360,21 @@ mod tests {
     fn escaped_character() {
         let input = "start::=C'\n';C::='\\u0020'| #'\\u0020' C;";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let config = kbnf::config::Config {
             engine_config: EngineConfig {
                 cache_enabled: true,
                 compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: None,
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
             },
             ..Default::default()
         };
         let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
-        for i in 0..10 {
+        for _i in 0..10 {
             let result = engine
                 .try_accept_new_token(
                     vocab
@@ -385,11 +400,16 @@ mod tests {
     fn indirect_right_recursion() {
         let input = "start::=A'\n';A::='x'|'x' B;B::='y'|'y' A;";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let config = kbnf::config::Config {
             engine_config: EngineConfig {
                 cache_enabled: true,
                 compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: None,
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
             },
             ..Default::default()
         };
@@ -421,7 +441,7 @@ mod tests {
     fn middle_recursion() {
         let input = "start::=('{'start'}')?;";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
         for _ in 0..10 {
             let result = engine
@@ -459,10 +479,10 @@ mod tests {
     fn always_match_regex() {
         let input = "start::=#\".+\"'\n';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
-        for j in 0..1 {
-            for i in 0..5 {
+        for _j in 0..1 {
+            for _i in 0..5 {
                 let result = engine
                     .try_accept_new_token(
                         vocab
@@ -489,7 +509,7 @@ mod tests {
     fn substrings() {
         let input = "start::=#substrs'abcbc''\n';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
         engine.compute_allowed_token_ids();
         assert_snapshot!(format!("{:#?}", engine));
@@ -521,7 +541,7 @@ mod tests {
             )
             .unwrap();
         assert_eq!(result, AcceptTokenResult::Ongoing);
-        let result = engine.try_accept_new_bytes(b"c").unwrap();
+        engine.try_accept_new_bytes(b"c").unwrap();
         let result = engine.try_accept_new_token(
             vocab
                 .token_id(&Token("c".as_bytes().to_vec().into_boxed_slice()))
@@ -535,10 +555,10 @@ mod tests {
     fn early_regex() {
         let input = "start::=#e'(.|\n)+\n\n''a';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
-        for j in 0..1 {
-            for i in 0..5 {
+        for _j in 0..1 {
+            for _i in 0..5 {
                 let result = engine
                     .try_accept_new_token(
                         vocab
@@ -617,7 +637,7 @@ __schema_json_1_next ::=
     | __schema_json_1_next_0;
 "#;
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
-        let logits = vec![0.0; vocab.vocab_size()];
+        let _logits = vec![0.0; vocab.vocab_size()];
         let mut engine = kbnf::engine::Engine::new(grammar_str, vocab.clone()).unwrap();
         engine
             .try_accept_new_bytes("```json\n{\"value\": 2, \"next\":".as_bytes())
@@ -684,4 +704,4705 @@ __schema_json_1_next ::=
             "Should reject sequence containing invalid byte 'a'"
         );
     }
+
+    #[test]
+    fn fast_forward() {
+        let input = "start::='Hello' ('A'|'B') '!\n';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(engine.fast_forward(), b"Hello");
+        assert!(!engine.is_finished());
+        assert_eq!(
+            engine.fast_forward(),
+            b"",
+            "Branch point should stop fast-forwarding"
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"B"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(engine.fast_forward(), b"!\n");
+        assert!(engine.is_finished());
+        assert_eq!(engine.fast_forward(), b"");
+    }
+
+    #[test]
+    fn partial_regex() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=#p'[0-9]{4}';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"12").unwrap();
+        assert!(!engine.is_finished());
+        assert!(engine.can_finish());
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a"),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"34"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.can_finish());
+        let input = "start::=#'[0-9]{4}';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"12").unwrap();
+        assert!(!engine.can_finish());
+        let input = "start::='(' year ')' | year; year::=#p'[0-9]{4}';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"12").unwrap();
+        assert!(engine.can_finish());
+        engine.reset();
+        engine.try_accept_new_bytes(b"(12").unwrap();
+        assert!(!engine.can_finish());
+    }
+
+    #[test]
+    fn write_mask_to_buffer() {
+        let input = "start::='Hello' ('A'|'B');";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let mut mask = vec![1.0; vocab.vocab_size()];
+        assert_eq!(
+            engine.write_mask_to_buffer(&mut mask[..vocab.vocab_size() - 1]),
+            Err(WriteBufferError::BufferTooSmall)
+        );
+        engine.write_mask_to_buffer(&mut mask).unwrap();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "Hello").unwrap() as usize));
+        for (token_id, value) in mask.iter().enumerate() {
+            if allowed.contains(token_id) {
+                assert_eq!(*value, 0.0);
+            } else {
+                assert_eq!(*value, f32::NEG_INFINITY);
+            }
+        }
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        engine.mask_logits(&mut logits).unwrap();
+        for (logit, value) in logits.iter().zip(mask.iter()) {
+            assert_eq!(*logit, 1.0 + *value);
+        }
+    }
+
+    #[test]
+    fn allowed_token_ids_as_ranges() {
+        let input = "start::=#'.+';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let ranges = engine.allowed_token_ids_as_ranges();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(ranges.len() < allowed.count_ones(..));
+        let mut expected = Vec::new();
+        for (start, end) in ranges.iter().copied() {
+            assert!(start < end);
+            expected.extend((start..end).map(|x| x as usize));
+        }
+        assert!(ranges.windows(2).all(|x| x[0].1 < x[1].0));
+        assert_eq!(expected, allowed.ones().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn grammar_prior() {
+        let input = "start::=('A' %0.9 | 'B' %0.1) 'C';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let a = get_token_id_from_str(&vocab, "A").unwrap() as usize;
+        let b = get_token_id_from_str(&vocab, "B").unwrap() as usize;
+        let c = get_token_id_from_str(&vocab, "C").unwrap() as usize;
+        let mut logits = vec![0.0; vocab.vocab_size()];
+        engine.bias_logits_by_grammar_prior(&mut logits).unwrap();
+        assert_eq!(logits[a], 0.9f32.ln());
+        assert_eq!(logits[b], 0.1f32.ln());
+        assert_eq!(logits[c], 0.0);
+        engine.try_accept_new_bytes(b"A").unwrap();
+        let mut logits = vec![0.0; vocab.vocab_size()];
+        engine.bias_logits_by_grammar_prior(&mut logits).unwrap();
+        assert!(logits.iter().all(|x| *x == 0.0));
+        assert_eq!(
+            engine.try_accept_new_bytes(b"C"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(kbnf::engine::Engine::new("start::='A' %0 | 'B';", vocab.clone()).is_err());
+        assert!(kbnf::engine::Engine::new("start::='A' | %0.5;", vocab.clone()).is_err());
+    }
+
+    #[test]
+    fn length_prefixed() {
+        let input = "start::=#lenprefixed(#'[0-9]+:', #'[a-z]*') ',';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"3:abc,"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        engine.try_accept_new_bytes(b"3:ab").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b","),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "c").unwrap() as usize));
+        assert!(!allowed.contains(get_token_id_from_str(&vocab, "cd").unwrap() as usize));
+        assert!(!allowed.contains(get_token_id_from_str(&vocab, ",").unwrap() as usize));
+        engine.try_accept_new_bytes(b"c").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"d"),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"12:abcdefghijkl,"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"0:,"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(
+            kbnf::engine::Engine::new("start::=#lenprefixed('3:', #'[a-z]*');", vocab.clone())
+                .is_err()
+        );
+        assert!(
+            kbnf::engine::Engine::new("start::=#lenprefixed(#'[0-9]+:');", vocab.clone()).is_err()
+        );
+    }
+
+    #[test]
+    fn transaction() {
+        let input = "start::=#'[0-9]+' ',' ('A'|'B');";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // The cache is disabled since the cache entries added in the transaction are kept.
+        let config = kbnf::config::Config {
+            engine_config: EngineConfig {
+                cache_enabled: false,
+                compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: None,
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
+            },
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        engine.try_accept_new_bytes(b"1").unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation().clone();
+        let before = format!("{:?}", engine);
+        {
+            let mut transaction = engine.transaction();
+            transaction.try_accept_new_bytes(b"23,").unwrap();
+            transaction.compute_allowed_token_ids();
+            assert_eq!(
+                transaction.try_accept_new_bytes(b"C"),
+                Err(AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                transaction.try_accept_new_bytes(b"A"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            assert!(transaction.engine().is_finished());
+        }
+        assert!(!engine.is_finished());
+        assert_eq!(engine.allowed_token_ids_from_last_computation(), &allowed);
+        assert_eq!(format!("{:?}", engine), before);
+        let mut transaction = engine.transaction();
+        transaction.try_accept_new_bytes(b"4").unwrap();
+        transaction.commit();
+        engine.try_accept_new_bytes(b"56,").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"B"),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn atomic_nonterminal() {
+        let input = "start::='ab' field ' ' field; @atomic field::=#'[a-z]+';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "ab").unwrap() as usize));
+        assert!(!allowed.contains(get_token_id_from_str(&vocab, "abc").unwrap() as usize));
+        engine
+            .try_accept_new_token(get_token_id_from_str(&vocab, "ab").unwrap())
+            .unwrap();
+        engine
+            .try_accept_new_token(get_token_id_from_str(&vocab, "cd").unwrap())
+            .unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "de").unwrap() as usize));
+        assert!(allowed.contains(get_token_id_from_str(&vocab, " ").unwrap() as usize));
+        assert!(!allowed.contains(get_token_id_from_str(&vocab, " cd").unwrap() as usize));
+        assert_eq!(
+            engine
+                .clone()
+                .try_accept_new_token(get_token_id_from_str(&vocab, " cd").unwrap()),
+            Err(AcceptTokenError::Rejected)
+        );
+        // Bytes are not tokens, so they may straddle the boundaries.
+        assert_eq!(
+            engine.try_accept_new_bytes(b" cd"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let input = "start::=field 'de'; @atomic field::='ab'|'abc';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine
+            .try_accept_new_token(get_token_id_from_str(&vocab, "ab").unwrap())
+            .unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "c").unwrap() as usize));
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "de").unwrap() as usize));
+        assert!(!allowed.contains(get_token_id_from_str(&vocab, "cd").unwrap() as usize));
+        let mut engine =
+            kbnf::engine::Engine::new("start::=field 'de'; field::='ab'|'abc';", vocab.clone())
+                .unwrap();
+        engine
+            .try_accept_new_token(get_token_id_from_str(&vocab, "ab").unwrap())
+            .unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "cd").unwrap() as usize));
+        assert!(kbnf::engine::Engine::new("@unknown start::='a';", vocab.clone()).is_err());
+        assert!(kbnf::engine::Engine::new("start::=@atomic 'a';", vocab.clone()).is_err());
+        assert!(kbnf::engine::Engine::new("start::='a'; @atomic", vocab.clone()).is_err());
+    }
+
+    #[test]
+    fn rewind_and_replay() {
+        let input = "start::=#'[0-9]+' ',' ('A'|'B');";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"12,").unwrap();
+        engine.rewind();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"A"),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.replay().unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"A"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // The replayed engine finishes again.
+        engine.replay().unwrap();
+        assert!(engine.is_finished());
+        engine.rewind();
+        assert!(!engine.is_finished());
+        engine.try_accept_new_bytes(b"3").unwrap();
+        // "12,A3" is rejected, so the engine stays rewound.
+        assert_eq!(engine.replay(), Err(AcceptTokenError::Rejected));
+        engine.reset();
+        engine.replay().unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"A"),
+            Err(AcceptTokenError::Rejected)
+        );
+    }
+
+    #[test]
+    fn quoted_string() {
+        let input = r#"start::=#qstring('"', '\\') ';';"#;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(br#""a\"b";"#),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(br#""\\";"#),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        // The escaped quote does not close the string.
+        assert_eq!(
+            engine.try_accept_new_bytes(br#""a\";"#),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert!(!engine.can_finish());
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a"),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.try_accept_new_bytes(br#""ab""#).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"c"),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert!(kbnf::engine::Engine::new("start::=#qstring('\"');", vocab.clone()).is_err());
+        assert!(kbnf::engine::Engine::new("start::=#qstring('\"', '\"');", vocab.clone()).is_err());
+        assert!(
+            kbnf::engine::Engine::new("start::=#qstring('ab', '\\\\');", vocab.clone()).is_err()
+        );
+    }
+
+    #[test]
+    fn checkpoint_and_restore() {
+        let input = "start::=#'[0-9]+' ',' ('A'|'B');";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"1").unwrap();
+        let checkpoint = engine.checkpoint();
+        let before = format!("{:?}", engine);
+        assert_eq!(
+            engine.try_accept_new_bytes(b"23,A"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.restore(&checkpoint).unwrap();
+        assert!(!engine.is_finished());
+        assert_eq!(format!("{:?}", engine), before);
+        assert_eq!(
+            engine.try_accept_new_bytes(b",B"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // A checkpoint can be restored multiple times, including by the clones of the engine.
+        let mut cloned = engine.clone();
+        cloned.restore(&checkpoint).unwrap();
+        engine.restore(&checkpoint).unwrap();
+        assert_eq!(format!("{:?}", cloned), format!("{:?}", engine));
+        assert_eq!(
+            cloned.try_accept_new_bytes(b"4,A"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let mut other = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            other.restore(&checkpoint),
+            Err(kbnf::engine_base::RestoreCheckpointError::MismatchedEngine)
+        );
+    }
+
+    #[test]
+    fn rollback() {
+        let input = "start::=#'[0-9]+' ',' ('A'|'B');";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        for compaction_enabled in [false, true] {
+            let config = kbnf::config::Config {
+                engine_config: EngineConfig {
+                    cache_enabled: true,
+                    compaction_enabled,
+                    compaction_interval: 1,
+                    max_cache_entries: None,
+                    max_earley_set_size: None,
+                    max_total_items: None,
+                    deterministic: false,
+                },
+                ..Default::default()
+            };
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            assert_eq!(
+                engine.rollback(1),
+                Err(kbnf::engine_like::RollbackError::NotEnoughAcceptedTokens)
+            );
+            engine
+                .try_accept_new_token(get_token_id_from_str(&vocab, "1").unwrap())
+                .unwrap();
+            engine
+                .try_accept_new_token(get_token_id_from_str(&vocab, "2").unwrap())
+                .unwrap();
+            engine.try_accept_new_bytes(b",").unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"A"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            assert_eq!(
+                engine.rollback(5),
+                Err(kbnf::engine_like::RollbackError::NotEnoughAcceptedTokens)
+            );
+            assert!(engine.is_finished());
+            engine.rollback(1).unwrap();
+            assert!(!engine.is_finished());
+            assert_eq!(
+                engine.try_accept_new_bytes(b"B"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            engine.rollback(2).unwrap();
+            // "12" is kept, so more digits are still allowed.
+            assert_eq!(
+                engine.try_accept_new_bytes(b"3,A"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            engine.rollback(0).unwrap();
+            assert!(engine.is_finished());
+            engine.rollback(3).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b","),
+                Err(AcceptTokenError::Rejected)
+            );
+            engine.try_accept_new_bytes(b"4,").unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"A"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            engine.reset();
+            assert_eq!(
+                engine.rollback(1),
+                Err(kbnf::engine_like::RollbackError::NotEnoughAcceptedTokens)
+            );
+        }
+    }
+
+    #[test]
+    fn grammar_serialization() {
+        let input = "start::=#'[0-9]+' ',' (field %0.5 | #substrs'abcbc' | #substrs(2,3)'xyz' | #lenprefixed(#'[0-9]:', #'[a-z]*') | #ex'[a-z]*z' | #'[^;]' | #char) ';'; @atomic field::='hello'|'world';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config::default();
+        let regex_config = config.regex_config;
+        let internal_config = config.internal_config();
+        let syntax_grammar =
+            kbnf::utils::construct_kbnf_syntax_grammar(input, internal_config.clone()).unwrap();
+        let grammar: kbnf::grammar::Grammar<u8> =
+            kbnf::grammar::Grammar::new(syntax_grammar, &vocab, regex_config).unwrap();
+        let bytes = grammar.to_bytes();
+        let deserialized = kbnf::grammar::Grammar::<u8>::from_bytes(&bytes).unwrap();
+        assert_eq!(bytes, deserialized.to_bytes());
+        assert!(matches!(
+            kbnf::grammar::Grammar::<u16>::from_bytes(&bytes),
+            Err(kbnf::grammar::DeserializeGrammarError::MismatchedIdWidth(
+                1, 2
+            ))
+        ));
+        assert!(kbnf::grammar::Grammar::<u8>::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+        let vocab = std::sync::Arc::new(vocab);
+        let new_engine = |grammar| {
+            kbnf::engine_base::EngineBase::<u8, u8, u16, u16, u32>::new(
+                vocab.clone(),
+                std::sync::Arc::new(grammar),
+                internal_config.engine_config,
+            )
+            .unwrap()
+        };
+        let mut engine = new_engine(grammar);
+        let mut deserialized_engine = new_engine(deserialized);
+        for bytes in [&b"12"[..], b",", b"b", b"cb", b";"] {
+            engine.compute_allowed_token_ids();
+            deserialized_engine.compute_allowed_token_ids();
+            assert_eq!(
+                engine.allowed_token_ids_from_last_computation(),
+                deserialized_engine.allowed_token_ids_from_last_computation()
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(bytes),
+                deserialized_engine.try_accept_new_bytes(bytes)
+            );
+        }
+        assert!(deserialized_engine.is_finished());
+    }
+
+    #[test]
+    fn allowed_first_bytes() {
+        let input = "start::=\"你好\" #e\"(.|\\n)*\\n\\n\";";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let first_byte = "你".as_bytes()[0] as usize;
+        assert_eq!(
+            engine.allowed_first_bytes().ones().collect::<Vec<_>>(),
+            vec![first_byte]
+        );
+        engine.try_accept_new_bytes("你".as_bytes()).unwrap();
+        assert_eq!(
+            engine.allowed_first_bytes().ones().collect::<Vec<_>>(),
+            vec!["好".as_bytes()[0] as usize]
+        );
+        engine.try_accept_new_bytes("好".as_bytes()).unwrap();
+        assert!(engine.allowed_first_bytes().contains(b'\n' as usize));
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a\n\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.allowed_first_bytes().is_clear());
+    }
+
+    #[test]
+    fn eos_token_id() {
+        let input = "start::='a' 'b';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // The EOS token is excluded even though its bytes are accepted by the grammar
+        let eos_token_id = get_token_id_from_str(&vocab, "b").unwrap();
+        let config = kbnf::config::Config {
+            eos_token_id: Some(eos_token_id),
+            ..Default::default()
+        };
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        engine.try_accept_new_bytes(b"a").unwrap();
+        engine.compute_allowed_token_ids();
+        assert!(engine.allowed_token_ids_from_last_computation().is_clear());
+        assert_eq!(
+            engine.try_accept_new_token(eos_token_id),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"b"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            vec![eos_token_id as usize]
+        );
+        assert_eq!(
+            engine.try_accept_new_token(eos_token_id),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let config = kbnf::config::Config {
+            eos_token_id: Some(vocab.vocab_size() as u32),
+            ..Default::default()
+        };
+        assert!(kbnf::engine::Engine::with_config(input, vocab.clone(), config).is_err());
+    }
+
+    #[test]
+    fn hf_tokenizer_vocabulary() {
+        let token_bytes = |vocab: &Vocabulary, id| vocab.token(id).unwrap().0.to_vec();
+        let vocab = [
+            ("Ġhello", 0),
+            ("<0x0A>", 1),
+            ("Ċ", 2),
+            ("ĀĠ~Ń", 3),
+            ("<|endoftext|>", 4),
+        ];
+        let vocab = Vocabulary::from_hf_tokenizer_bytes(
+            vocab,
+            kbnf::vocabulary::HfTokenEncoding {
+                byte_level: true,
+                byte_fallback: true,
+                metaspace: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(token_bytes(&vocab, 0), b" hello");
+        assert_eq!(token_bytes(&vocab, 1), b"\n");
+        assert_eq!(token_bytes(&vocab, 2), b"\n");
+        assert_eq!(token_bytes(&vocab, 3), b"\x00 ~\xAD");
+        assert_eq!(token_bytes(&vocab, 4), b"<|endoftext|>");
+        assert_eq!(vocab.token_string(0), Some(" hello"));
+        let vocab = [("▁world", 0), ("<0xFF>", 1), ("Ġ", 2)];
+        let vocab = Vocabulary::from_hf_tokenizer_bytes(
+            vocab,
+            kbnf::vocabulary::HfTokenEncoding {
+                byte_level: false,
+                byte_fallback: true,
+                metaspace: Some('▁'),
+            },
+        )
+        .unwrap();
+        assert_eq!(token_bytes(&vocab, 0), b" world");
+        assert_eq!(token_bytes(&vocab, 1), b"\xFF");
+        assert_eq!(vocab.token_string(1), Some("<0xFF>"));
+        assert_eq!(token_bytes(&vocab, 2), "Ġ".as_bytes());
+    }
+
+    #[test]
+    fn json_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "nickname": {"type": "string"},
+                "role": {"enum": ["admin", "user"]},
+                "tags": {"type": "array", "items": {"type": "string", "pattern": "^[a-z]+$"}},
+                "active": {"type": "boolean"},
+                "parent": {"type": "null"}
+            },
+            "required": ["name", "age", "role", "tags", "active", "parent"]
+        });
+        let grammar = kbnf::schema::json_schema_to_kbnf(&schema).unwrap();
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(&grammar, vocab.clone()).unwrap();
+        let json = br#"{"name": "Alice \"A\"", "age": -12, "role": "user", "tags": ["a", "bc"], "active": true, "parent": null}"#;
+        assert_eq!(
+            engine.try_accept_new_bytes(json),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        let json = br#"{"name":"Bob","age":0,"nickname":"B","role":"admin","tags":[],"active":false,"parent":null}"#;
+        assert_eq!(
+            engine.try_accept_new_bytes(json),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        // The required properties are emitted in order.
+        assert_eq!(
+            engine.try_accept_new_bytes(br#"{"age""#),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(br#"{"name": "Bob", "age": 01"#),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(br#"{"name": "Bob", "age": 1, "role": "guest""#),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(
+                br#"{"name": "Bob", "age": 1, "role": "user", "tags": ["A"]"#
+            ),
+            Err(AcceptTokenError::Rejected)
+        );
+        let schema = serde_json::json!({
+            "type": "array",
+            "items": {"anyOf": [{"type": "number"}, {"const": {"a": 1}}, {}]}
+        });
+        let grammar = kbnf::schema::json_schema_to_kbnf(&schema).unwrap();
+        let mut engine = kbnf::engine::Engine::new(&grammar, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(br#"[1.5e3, {"a":1}, {"b": [null, "c"]}]"#),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"a": {"type": "integer"}, "b": {"type": "integer"}}
+        });
+        let grammar = kbnf::schema::json_schema_to_kbnf(&schema).unwrap();
+        let mut engine = kbnf::engine::Engine::new(&grammar, vocab.clone()).unwrap();
+        for json in [
+            &br#"{}"#[..],
+            br#"{"b": 2}"#,
+            br#"{"a": 1, "b": 2}"#,
+            br#"{"a": 1}"#,
+        ] {
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(json),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(br#"{"b": 2, "a""#),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert!(matches!(
+            kbnf::schema::json_schema_to_kbnf(&serde_json::json!({"$ref": "#/a"})),
+            Err(kbnf::schema::SchemaError::UnsupportedKeyword(_))
+        ));
+        assert!(matches!(
+            kbnf::schema::json_schema_to_kbnf(&serde_json::json!({"type": "tuple"})),
+            Err(kbnf::schema::SchemaError::UnsupportedType(_))
+        ));
+        assert!(matches!(
+            kbnf::schema::json_schema_to_kbnf(&serde_json::json!({
+                "type": "object",
+                "properties": {},
+                "required": ["a"]
+            })),
+            Err(kbnf::schema::SchemaError::InvalidSchema(_))
+        ));
+    }
+
+    #[test]
+    fn gbnf() {
+        // The JSON grammars from llama.cpp.
+        const JSON_GBNF: &str = r#"root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4}) # escapes
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+# Optional space: by convention, applied in this grammar after literal chars when allowed
+ws ::= | " " | "\n" [ \t]{0,20}
+"#;
+        const JSON_ARR_GBNF: &str = r#"# This is the same as json.gbnf but we restrict whitespaces at the end of the root array
+# Useful for generating JSON arrays
+
+root   ::= arr
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+arr  ::=
+  "[\n" ws (
+            value
+    (",\n" ws value)*
+  )? "]"
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4}) # escapes
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+# Optional space: by convention, applied in this grammar after literal chars when allowed
+ws ::= ([ \t\n] ws)?
+"#;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let accepts = |engine: &mut kbnf::Engine, input: &str| {
+            engine.reset();
+            engine.try_accept_new_bytes(input.as_bytes()).is_ok() && engine.can_finish()
+        };
+        // serde_json is the reference, and the inputs follow the whitespace conventions of the grammars.
+        let mut engine =
+            kbnf::Engine::from_gbnf(JSON_GBNF, vocab.clone(), kbnf::Config::default()).unwrap();
+        for input in [
+            r#"{}"#,
+            r#"{"a": 1, "b": [true, false, null], "c": {"d": "e\"\u00e9\n"}}"#,
+            r#"{"你好": -0.5e+3, "list": [ ], "x":"\/"}"#,
+            r#"{"a":
+  [1,2.25]}"#,
+            r#"{"a": 01}"#,
+            r#"{"a": 1,}"#,
+            r#"{"a": "\x"}"#,
+            r#"{"a": "b
+"}"#,
+            r#"[1, 2]"#,
+            r#"{"a" 1}"#,
+            r#"{"a": tru}"#,
+        ] {
+            assert_eq!(
+                accepts(&mut engine, input),
+                serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(input).is_ok(),
+                "{input}"
+            );
+        }
+        let mut engine =
+            kbnf::Engine::from_gbnf(JSON_ARR_GBNF, vocab.clone(), kbnf::Config::default()).unwrap();
+        // The root array must separate the values with newlines.
+        for (input, expected) in [
+            ("[\n]", true),
+            ("[\n{\"a\": [1, {\"b\": null}]},\n\"c\",\n 3.5]", true),
+            ("[\n1,\n2,\n]", false),
+            ("[\n1, 2]", false),
+            ("[1]", false),
+        ] {
+            assert_eq!(accepts(&mut engine, input), expected, "{input}");
+            assert!(!expected || serde_json::from_str::<Vec<serde_json::Value>>(input).is_ok());
+        }
+        let gbnf = "root ::= (start-x | 2x){2,3} [^a-c\\]]? .\nstart-x ::= | \"\\x41\" | \"\\u00e9\"+\nstart ::= \"s\"\n2x ::= [b-] start";
+        let grammar =
+            kbnf::Grammar::<u8>::from_gbnf(gbnf, &vocab, kbnf::Config::default().regex_config)
+                .unwrap();
+        assert_eq!(
+            grammar.nonterminal_str(grammar.get_start_nonterminal_id()),
+            Some("start")
+        );
+        let mut engine =
+            kbnf::Engine::from_gbnf(gbnf, vocab.clone(), kbnf::Config::default()).unwrap();
+        for (input, expected) in [
+            ("AA.", true),
+            ("éé-sAd", true),
+            ("bsbsbsx", true),
+            ("AAAAAx", false),
+            ("AAa.", false),
+            ("AA]x", false),
+            ("A", true),
+            ("", false),
+        ] {
+            assert_eq!(accepts(&mut engine, input), expected, "{input}");
+        }
+        for gbnf in [
+            "start ::= \"a\"",
+            "root ::= \"a\" )",
+            "root ::= [a-z",
+            "root ::= \"\\q\"",
+            "root ::= <[1]>",
+            "root ::= \"a\"{3,2}",
+            "root ::= undefined",
+        ] {
+            assert!(
+                kbnf::Engine::from_gbnf(gbnf, vocab.clone(), kbnf::Config::default()).is_err(),
+                "{gbnf}"
+            );
+        }
+    }
+
+    #[test]
+    fn case_insensitive_terminal() {
+        let input = r#"start::=#i"select" " *";"#;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for input in [&b"SELECT *"[..], b"select *", b"SeLeCt *"] {
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(input),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"selectt"),
+            Err(AcceptTokenError::Rejected)
+        );
+        // Only ASCII letters are folded, and the escape sequences are supported.
+        let input = r#"start::=#i'é\x41.*' ';';"#;
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes("éa.*;".as_bytes()),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes("ÉA".as_bytes()),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes("éAb".as_bytes()),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert!(kbnf::engine::Engine::new(r#"start::=#i"\u{zz}";"#, vocab.clone()).is_err());
+    }
+
+    #[test]
+    fn bounded_repetition() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let accepts = |engine: &mut kbnf::Engine, input: &str| {
+            engine.reset();
+            engine.try_accept_new_bytes(input.as_bytes()) == Ok(AcceptTokenResult::Finished)
+        };
+        let mut engine = kbnf::engine::Engine::new("start::='a'{3} ';';", vocab.clone()).unwrap();
+        assert!(accepts(&mut engine, "aaa;"));
+        assert!(!accepts(&mut engine, "aa;"));
+        assert!(!accepts(&mut engine, "aaaa;"));
+        let mut engine =
+            kbnf::engine::Engine::new("start::=('a'|b){2,4} ';'; b::='b';", vocab.clone()).unwrap();
+        for (input, expected) in [
+            ("a;", false),
+            ("ab;", true),
+            ("bab;", true),
+            ("abba;", true),
+            ("ababa;", false),
+        ] {
+            assert_eq!(accepts(&mut engine, input), expected, "{input}");
+        }
+        let mut engine =
+            kbnf::engine::Engine::new("start::=b {2,} ';'; b::='b';", vocab.clone()).unwrap();
+        assert!(!accepts(&mut engine, "b;"));
+        assert!(accepts(&mut engine, "bb;"));
+        assert!(accepts(&mut engine, "bbbbbbb;"));
+        let mut engine =
+            kbnf::engine::Engine::new("start::='x' 'a'{0,0} ';';", vocab.clone()).unwrap();
+        assert!(accepts(&mut engine, "x;"));
+        assert!(!accepts(&mut engine, "xa;"));
+        // Large bounds are expanded into a few nonterminals.
+        let mut engine =
+            kbnf::engine::Engine::new("start::=#'[0-9]'{100,1000} ';';", vocab.clone()).unwrap();
+        for (count, expected) in [
+            (99, false),
+            (100, true),
+            (777, true),
+            (1000, true),
+            (1001, false),
+        ] {
+            assert_eq!(
+                accepts(&mut engine, &format!("{};", "7".repeat(count))),
+                expected,
+                "{count}"
+            );
+        }
+        for grammar in [
+            "start::='a'{3,2};",
+            "start::={2};",
+            "start::='a'{99999999999999999999999};",
+        ] {
+            assert!(
+                kbnf::engine::Engine::new(grammar, vocab.clone()).is_err(),
+                "{grammar}"
+            );
+        }
+    }
+
+    #[test]
+    fn can_finish() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(r#"start::="a" "b"?;"#, vocab.clone()).unwrap();
+        assert!(!engine.can_finish());
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.can_finish());
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.can_finish());
+        // The check does not modify the states.
+        let mut engine =
+            kbnf::engine::Engine::new(r#"start::="a" b? "c"; b::="b";"#, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert!(!engine.can_finish());
+        assert!(!engine.can_finish());
+        assert!(!engine.is_finished());
+        assert_eq!(
+            engine.try_accept_new_bytes(b"b"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert!(!engine.can_finish());
+        assert_eq!(
+            engine.try_accept_new_bytes(b"c"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.can_finish());
+    }
+
+    #[test]
+    fn eager_cache() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let grammar =
+            r#"start::=item (", " item)* "."; item::="apple" | "banana" | "cherry" | "apple pie";"#;
+        let mut expected = kbnf::engine::Engine::new(grammar, vocab.clone()).unwrap();
+        for max_states in [Some(0), Some(1), Some(8), Some(100000)] {
+            let mut config = kbnf::config::Config::default();
+            config.eager_cache_config.max_states = max_states;
+            let mut engine =
+                kbnf::engine::Engine::with_config(grammar, vocab.clone(), config).unwrap();
+            expected.reset();
+            assert!(!engine.can_finish());
+            for token in ["apple", " pie", ",", " banana", "."] {
+                engine.compute_allowed_token_ids();
+                expected.compute_allowed_token_ids();
+                assert_eq!(
+                    engine.allowed_token_ids_from_last_computation(),
+                    expected.allowed_token_ids_from_last_computation(),
+                    "{max_states:?} {token}"
+                );
+                let token_id = get_token_id_from_str(&vocab, token).unwrap();
+                assert_eq!(
+                    engine.try_accept_new_token(token_id),
+                    expected.try_accept_new_token(token_id),
+                    "{max_states:?} {token}"
+                );
+            }
+            assert!(engine.is_finished());
+        }
+    }
+
+    #[test]
+    fn lru_cache() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let grammar = r#"start::="a" "1" | "b" "2" | "c" "3";"#;
+        let config = kbnf::config::Config {
+            engine_config: EngineConfig {
+                cache_enabled: true,
+                compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: Some(2),
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
+            },
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(grammar, vocab.clone(), config).unwrap();
+        let cached_token_strings = |engine: &kbnf::engine::Engine| {
+            let debug = format!("{engine:?}");
+            let cache =
+                &debug[debug.find("cache:").unwrap()..debug.find("to_be_completed_items").unwrap()];
+            ["1", "2", "3"]
+                .into_iter()
+                .filter(|x| cache.contains(&format!("\"{x}[")))
+                .collect::<Vec<_>>()
+        };
+        let visit = |engine: &mut kbnf::engine::Engine, token: &str| {
+            engine.reset();
+            engine.compute_allowed_token_ids();
+            engine
+                .try_accept_new_token(get_token_id_from_str(&vocab, token).unwrap())
+                .unwrap();
+            engine.compute_allowed_token_ids();
+        };
+        visit(&mut engine, "a");
+        assert_eq!(engine.cache_len(), 2);
+        assert_eq!(cached_token_strings(&engine), ["1"]);
+        // The initial state is used again, so the state after "a" is the least recently used one.
+        visit(&mut engine, "b");
+        assert_eq!(engine.cache_len(), 2);
+        assert_eq!(cached_token_strings(&engine), ["2"]);
+        visit(&mut engine, "c");
+        assert_eq!(engine.cache_len(), 2);
+        assert_eq!(cached_token_strings(&engine), ["3"]);
+        // The evicted states are computed again correctly.
+        visit(&mut engine, "a");
+        assert!(engine
+            .allowed_token_ids_from_last_computation()
+            .contains(get_token_id_from_str(&vocab, "1").unwrap() as usize));
+        engine.clear_cache();
+        assert_eq!(engine.cache_len(), 0);
+        let mut engine = kbnf::engine::Engine::new(grammar, vocab.clone()).unwrap();
+        for token in ["a", "b", "c"] {
+            visit(&mut engine, token);
+        }
+        assert_eq!(engine.cache_len(), 4);
+    }
+
+    #[test]
+    fn mask_logits_across_sparsity() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let vocab_size = vocab.vocab_size();
+        for input in [
+            "start::='a';",
+            "start::=#'[a-z]+';",
+            "start::=#'[^0-9]+';",
+            "start::=#'(?s:.)+';",
+        ] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            engine.compute_allowed_token_ids();
+            let allowed = engine.allowed_token_ids_from_last_computation().clone();
+            let mut logits: Vec<f32> = (0..vocab_size + 100).map(|x| x as f32).collect();
+            assert_eq!(
+                engine.mask_logits(&mut logits[..vocab_size - 1]),
+                Err(kbnf::engine_like::MaskLogitsError::InvalidLogitsLength)
+            );
+            engine.mask_logits(&mut logits).unwrap();
+            // The logits beyond the vocabulary are masked only when most tokens are disallowed.
+            let padding = if allowed.count_zeroes(..) > logits.len() / 2 {
+                f32::NEG_INFINITY
+            } else {
+                1.0
+            };
+            for (token_id, logit) in logits.iter().copied().enumerate() {
+                let expected = if token_id >= vocab_size {
+                    token_id as f32 * padding
+                } else if allowed.contains(token_id) {
+                    token_id as f32
+                } else {
+                    f32::NEG_INFINITY
+                };
+                assert_eq!(logit, expected, "{input} {token_id}");
+            }
+        }
+    }
+
+    #[test]
+    fn mask_after_finish() {
+        let input = "start::='a' 'b';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let eos_token_id = 0;
+        let a = get_token_id_from_str(&vocab, "a").unwrap();
+        let b = get_token_id_from_str(&vocab, "b").unwrap();
+        let mut config = kbnf::config::Config {
+            eos_token_id: Some(eos_token_id),
+            ..Default::default()
+        };
+        // The logits are not updated after the engine is finished by default.
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        engine.update_logits(a, &mut logits).unwrap();
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        assert_eq!(
+            engine.update_logits(b, &mut logits),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(logits.iter().all(|x| *x == 1.0));
+        // Only the EOS token is left when the flag is set.
+        config.mask_after_finish = true;
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        engine.update_logits(a, &mut logits).unwrap();
+        assert_eq!(logits[a as usize], f32::NEG_INFINITY);
+        assert_eq!(logits[b as usize], 1.0);
+        for _ in 0..2 {
+            let mut logits = vec![1.0; vocab.vocab_size()];
+            let token_id = if engine.is_finished() {
+                eos_token_id
+            } else {
+                b
+            };
+            assert_eq!(
+                engine.update_logits(token_id, &mut logits),
+                Ok(AcceptTokenResult::Finished)
+            );
+            for (token_id, logit) in logits.iter().copied().enumerate() {
+                if token_id == eos_token_id as usize {
+                    assert_eq!(logit, 1.0);
+                } else {
+                    assert_eq!(logit, f32::NEG_INFINITY);
+                }
+            }
+            engine.mask_logits(&mut logits).unwrap();
+            assert_eq!(logits[eos_token_id as usize], 1.0);
+        }
+        // The flag has no effect without the EOS token.
+        config.eos_token_id = None;
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        engine.update_logits(a, &mut logits).unwrap();
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        assert_eq!(
+            engine.update_logits(b, &mut logits),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(logits.iter().all(|x| *x == 1.0));
+    }
+
+    #[test]
+    fn accepted_bytes() {
+        let input = "start::=#'(?s:.)+' '\\n\\n';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(engine.accepted_bytes(), b"");
+        for token in ["你", "好"] {
+            engine
+                .try_accept_new_token(get_token_id_from_str(&vocab, token).unwrap())
+                .unwrap();
+        }
+        engine.try_accept_new_bytes(b"!").unwrap();
+        assert_eq!(engine.accepted_bytes(), "你好!".as_bytes());
+        engine.rollback(1).unwrap();
+        assert_eq!(engine.accepted_bytes(), "你好".as_bytes());
+        assert_eq!(
+            engine.try_accept_new_token(get_token_id_from_str(&vocab, "\n\n").unwrap()),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(engine.accepted_bytes(), "你好\n\n".as_bytes());
+        engine.reset();
+        assert_eq!(engine.accepted_bytes(), b"");
+    }
+
+    #[test]
+    fn untracked_accepted_bytes() {
+        let input = "start::=(#'[a-z]+' '\n\n')?;";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            track_accepted_bytes: false,
+            allow_empty_match: true,
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        assert!(engine.can_finish());
+        engine.try_accept_new_bytes(b"abc").unwrap();
+        assert_eq!(engine.accepted_bytes(), b"");
+        assert_eq!(
+            engine.rollback(1),
+            Err(kbnf::engine_like::RollbackError::AcceptedBytesNotTracked)
+        );
+        assert_eq!(
+            engine.heal_last_token().map(|_| ()),
+            Err(kbnf::engine_like::RollbackError::AcceptedBytesNotTracked)
+        );
+        assert_eq!(
+            engine.replay(),
+            Err(AcceptTokenError::AcceptedBytesNotTracked)
+        );
+        // The failed rollbacks and replay leave the states untouched.
+        assert!(!engine.can_finish());
+        assert_eq!(
+            engine.try_accept_new_token(get_token_id_from_str(&vocab, "\n\n").unwrap()),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(engine.accepted_bytes(), b"");
+        assert!(engine.parse_tree().is_none());
+    }
+
+    #[test]
+    fn byte_set_complement() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // The regex with a non-capturing group is compiled into a DFA, which is the reference.
+        let native = "start::=field (',' field)* '\\n'; field::=#'[^,\\n]'+;";
+        let dfa = "start::=field (',' field)* '\\n'; field::=#'(?:[^,\\n])'+;";
+        let mut native = kbnf::engine::Engine::new(native, vocab.clone()).unwrap();
+        let mut dfa = kbnf::engine::Engine::new(dfa, vocab.clone()).unwrap();
+        assert!(format!("{:?}", native).contains(r#"#\"[^\\n,]\"[0]"#));
+        assert!(!format!("{:?}", dfa).contains(r#"#\"[^\\n,]\"[0]"#));
+        let accepts = |engine: &mut kbnf::Engine, input: &[u8]| {
+            engine.reset();
+            engine.try_accept_new_bytes(input).is_ok() && engine.can_finish()
+        };
+        for (input, expected) in [
+            (b"a,b\n".as_slice(), true),
+            ("你好,🎉 x\t\n".as_bytes(), true),
+            (b"a,,b\n", false),
+            (b",\n", false),
+            (b"a\nb\n", false),
+            (b"\n", false),
+            (b"\xC0\x80\n", false),
+            (b"\xED\xA0\x80\n", false),
+            (b"\xF4\x90\x80\x80\n", false),
+            (b"\xE4\xBD\n", false),
+            (b"\xF4\x8F\xBF\xBF\n", true),
+        ] {
+            assert_eq!(accepts(&mut native, input), expected, "{input:?}");
+            assert_eq!(accepts(&mut dfa, input), expected, "{input:?}");
+        }
+        for input in ["", "a", "你", "a,b"] {
+            for engine in [&mut native, &mut dfa] {
+                engine.reset();
+                engine.try_accept_new_bytes(input.as_bytes()).unwrap();
+                engine.compute_allowed_token_ids();
+            }
+            assert_eq!(
+                native.allowed_token_ids_from_last_computation(),
+                dfa.allowed_token_ids_from_last_computation(),
+                "{input}"
+            );
+            assert_eq!(
+                native.allowed_first_bytes().clone(),
+                dfa.allowed_first_bytes().clone(),
+                "{input}"
+            );
+        }
+        // The allowed tokens cannot contain commas or newlines inside a field.
+        native.reset();
+        native.try_accept_new_bytes(b"a").unwrap();
+        native.compute_allowed_token_ids();
+        let comma = get_token_id_from_str(&vocab, ",").unwrap();
+        let newline = get_token_id_from_str(&vocab, "\n").unwrap();
+        let allowed = native.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(comma as usize));
+        assert!(allowed.contains(newline as usize));
+        assert!(!allowed.contains(get_token_id_from_str(&vocab, ",,").unwrap() as usize));
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "你").unwrap() as usize));
+        native.try_accept_new_token(comma).unwrap();
+        native.compute_allowed_token_ids();
+        let allowed = native.allowed_token_ids_from_last_computation();
+        assert!(!allowed.contains(comma as usize));
+        assert!(!allowed.contains(newline as usize));
+        // Classes beyond simple ASCII characters fall back to DFAs.
+        for regex in [r"[^a-z\\d]", "[^é]", "[^a&&b]", "[^[:alpha:]]", "[^a]+"] {
+            let grammar = format!("start::=#'{regex}';");
+            let engine = kbnf::engine::Engine::new(&grammar, vocab.clone()).unwrap();
+            assert!(!format!("{:?}", engine).contains("#\\\"[^"), "{regex}");
+        }
+    }
+
+    #[test]
+    fn grammar_diagnostic() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let diagnostic = |input: &str| {
+            kbnf::engine::Engine::new(input, vocab.clone())
+                .unwrap_err()
+                .diagnostic()
+                .cloned()
+                .unwrap()
+        };
+        let d = diagnostic("start ::= undefined_rule;");
+        assert_eq!(d.nonterminal.as_deref(), Some("undefined_rule"));
+        assert!(d.message.contains("undefined_rule"));
+        let location = d.location.unwrap();
+        assert_eq!(
+            (location.offset, location.line, location.column),
+            (10, 1, 11)
+        );
+        assert_eq!(location.snippet, "start ::= undefined_rule;");
+        // The literals and comments are skipped when locating the nonterminal.
+        let d = diagnostic("start ::= 'x' | other;\n(* x *) other ::= \"x\" x;");
+        assert_eq!(d.nonterminal.as_deref(), Some("x"));
+        let location = d.location.unwrap();
+        assert_eq!((location.line, location.column), (2, 23));
+        // The parsing errors are located after desugaring the syntax extensions.
+        let d = diagnostic("start ::= 'a'{2,3} #\"[0-9]\";\nrest ::= 'b' | ;");
+        assert_eq!(d.nonterminal, None);
+        let location = d.location.clone().unwrap();
+        assert_eq!((location.line, location.column), (2, 14));
+        assert_eq!(location.snippet, "rest ::= 'b' | ;");
+        assert_eq!(
+            d.to_string(),
+            format!(
+                "{}\n  --> line 2, column 14\n2 | rest ::= 'b' | ;\n  |              ^",
+                d.message
+            )
+        );
+        let d = diagnostic("start ::= \"\";");
+        assert_eq!(d.nonterminal.as_deref(), Some("start"));
+        assert_eq!(d.location.unwrap().offset, 0);
+        assert!(kbnf::engine::Engine::new("start ::= 'a';", vocab.clone()).is_ok());
+        // The parsing and semantic errors keep their variants inside the diagnosed error.
+        let error = |input: &str| {
+            let kbnf::engine::CreateEngineError::GrammarError(error) =
+                kbnf::engine::Engine::new(input, vocab.clone()).unwrap_err()
+            else {
+                panic!("expected a grammar error");
+            };
+            let kbnf::grammar::CreateGrammarError::DiagnosedError { error, .. } = error else {
+                panic!("expected a diagnosed error");
+            };
+            *error
+        };
+        assert!(matches!(
+            error("start ::= 'a' | ;"),
+            kbnf::grammar::CreateGrammarError::ParsingError(_)
+        ));
+        assert!(matches!(
+            error("start ::= undefined_rule;"),
+            kbnf::grammar::CreateGrammarError::SemanticError(_)
+        ));
+        let message = format!(
+            "{}",
+            kbnf::engine::Engine::new("start ::= undefined_rule;", vocab.clone()).unwrap_err()
+        );
+        assert!(message.contains("KBNF semantics error: "));
+    }
+
+    #[test]
+    fn mask_logits_batch() {
+        let input = "start::=#'[a-z]+' '\\n\\n' | '你' #'[0-9]*';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let vocab_size = vocab.vocab_size();
+        let mut engines: Vec<_> = (0..3)
+            .map(|_| kbnf::engine::Engine::new(input, vocab.clone()).unwrap())
+            .collect();
+        engines[1].try_accept_new_bytes(b"ab").unwrap();
+        engines[2].try_accept_new_bytes("你1".as_bytes()).unwrap();
+        let logits: Vec<f32> = (0..3 * vocab_size).map(|x| x as f32).collect();
+        let mut expected = logits.clone();
+        for (engine, row) in engines.iter_mut().zip(expected.chunks_mut(vocab_size)) {
+            let mut engine = engine.clone();
+            engine.compute_allowed_token_ids();
+            engine.mask_logits(row).unwrap();
+        }
+        let mut batch = logits.clone();
+        let mut refs: Vec<_> = engines.iter_mut().collect();
+        kbnf::engine::mask_logits_batch(&mut refs, &mut batch).unwrap();
+        assert!(batch
+            .iter()
+            .zip(expected.iter())
+            .all(|(x, y)| x.to_bits() == y.to_bits()));
+        // The rows differ since the engines are at different states.
+        assert_ne!(batch[..vocab_size], batch[vocab_size..2 * vocab_size]);
+        // The allowed token IDs are computed for each engine.
+        let newline = get_token_id_from_str(&vocab, "\n\n").unwrap() as usize;
+        assert!(refs[1]
+            .allowed_token_ids_from_last_computation()
+            .contains(newline));
+        assert!(!refs[2]
+            .allowed_token_ids_from_last_computation()
+            .contains(newline));
+        // Nothing is updated when the logits cannot be split into rows.
+        let mut short = logits[..3 * vocab_size - 3].to_vec();
+        assert_eq!(
+            kbnf::engine::mask_logits_batch(&mut refs, &mut short),
+            Err(kbnf::engine_like::MaskLogitsError::InvalidLogitsLength)
+        );
+        assert_eq!(short, logits[..3 * vocab_size - 3]);
+        assert!(kbnf::engine::mask_logits_batch(&mut [], &mut []).is_ok());
+    }
+
+    #[test]
+    fn with_start_symbol() {
+        let input = "start::=json_start|sql_start;json_start::='{' #'[0-9]+' '}';sql_start::='SELECT ' #'[a-z]+' ';';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let brace = get_token_id_from_str(&vocab, "{").unwrap() as usize;
+        let select = get_token_id_from_str(&vocab, "SELECT").unwrap() as usize;
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(brace) && allowed.contains(select));
+        let mut engine =
+            kbnf::engine::Engine::with_start_symbol(input, vocab.clone(), "sql_start").unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(!allowed.contains(brace) && allowed.contains(select));
+        assert_eq!(
+            engine.try_accept_new_bytes(b"SELECT abc;").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        let mut engine =
+            kbnf::engine::Engine::with_start_symbol(input, vocab.clone(), "json_start").unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(brace) && !allowed.contains(select));
+        assert!(engine.try_accept_new_bytes(b"SELECT").is_err());
+        let error = kbnf::engine::Engine::with_start_symbol(input, vocab, "xml_start").unwrap_err();
+        assert_eq!(
+            error.diagnostic().unwrap().nonterminal.as_deref(),
+            Some("xml_start")
+        );
+    }
+
+    #[test]
+    fn streaming_acceptor() {
+        use kbnf::engine_like::{StreamResult, StreamingAcceptor};
+        let input = "start::='a你好' #'[0-9你]+' '\\n';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let mut acceptor = StreamingAcceptor::new(&mut engine);
+        assert_eq!(acceptor.feed(b"a"), StreamResult::Accepted);
+        let [first, second, third] = "你".as_bytes().try_into().unwrap();
+        assert_eq!(acceptor.feed(&[first]), StreamResult::NeedMore);
+        assert_eq!(acceptor.feed(&[second]), StreamResult::NeedMore);
+        assert_eq!(acceptor.engine().accepted_bytes(), b"a");
+        assert_eq!(acceptor.feed(&[third]), StreamResult::Accepted);
+        assert_eq!(acceptor.engine().accepted_bytes(), "a你".as_bytes());
+        // A byte that can never complete an acceptable character is rejected immediately.
+        assert_eq!(acceptor.feed(&"你".as_bytes()[..1]), StreamResult::Rejected);
+        assert!(acceptor.pending_bytes().is_empty());
+        // The complete part is committed while the incomplete suffix is held back.
+        let bytes = "好1你".as_bytes();
+        assert_eq!(acceptor.feed(&bytes[..5]), StreamResult::NeedMore);
+        assert_eq!(acceptor.engine().accepted_bytes(), "a你好1".as_bytes());
+        assert_eq!(acceptor.pending_bytes(), &bytes[4..5]);
+        // The held back bytes are kept when the new bytes are rejected.
+        assert_eq!(acceptor.feed(b"2"), StreamResult::Rejected);
+        assert_eq!(acceptor.pending_bytes(), &bytes[4..5]);
+        // Flushing commits the held back bytes as they are.
+        assert_eq!(acceptor.flush(), StreamResult::Accepted);
+        assert_eq!(
+            acceptor.engine().accepted_bytes(),
+            &"a你好1你".as_bytes()[..9]
+        );
+        assert_eq!(acceptor.feed(&bytes[5..]), StreamResult::Accepted);
+        let mut engine = kbnf::engine::Engine::new(input, vocab).unwrap();
+        let mut acceptor = StreamingAcceptor::new(&mut engine);
+        assert_eq!(acceptor.feed("a你好1".as_bytes()), StreamResult::Accepted);
+        assert_eq!(acceptor.feed(b"\n"), StreamResult::Accepted);
+        assert!(acceptor.engine().is_finished());
+        assert_eq!(acceptor.feed(b"1"), StreamResult::Rejected);
+        assert_eq!(acceptor.flush(), StreamResult::Accepted);
+    }
+
+    #[test]
+    fn rejected_bytes_with_compaction() {
+        let input = "start::='a你好' #'[0-9你]+' '\\n';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes("a你好1".as_bytes()).unwrap();
+        let expected = engine.clone();
+        assert_eq!(
+            engine.try_accept_new_bytes(&"你".as_bytes()[..2]),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        engine.reset();
+        engine.try_accept_new_bytes("a你好1".as_bytes()).unwrap();
+        // The states are not updated even if the Earley sets could be compacted before the last byte is rejected.
+        assert_eq!(
+            engine.try_accept_new_bytes(&[0xE4, 0xBD, b'2']),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(format!("{:?}", engine), format!("{:?}", expected));
+        assert_eq!(
+            engine.try_accept_new_bytes("你\n".as_bytes()),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn allowed_token_ids_until() {
+        let input = "start::=#'[^\\n]+' '\\n';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let token_id = engine.any_allowed_token().unwrap();
+        let partial = engine.allowed_token_ids_until(5);
+        assert_eq!(partial.len(), 5);
+        assert!(partial.windows(2).all(|x| x[0] < x[1]));
+        // The allowed token IDs from the last computation are not updated.
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .count_ones(..),
+            0
+        );
+        // The partial results are not cached, so the full computation still finds every allowed token.
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation().clone();
+        assert!(allowed.count_ones(..) > vocab.vocab_size() / 2);
+        assert!(allowed.contains(token_id as usize));
+        assert!(partial.iter().all(|&x| allowed.contains(x as usize)));
+        // The cached full results are used once available.
+        assert_eq!(
+            engine.allowed_token_ids_until(usize::MAX),
+            allowed.ones().map(|x| x as u32).collect::<Vec<_>>()
+        );
+        // A computation that finds all the allowed tokens matches the full computation.
+        let input = "start::='ab'|'cd';";
+        let mut config = kbnf::config::Config::default();
+        config.engine_config.cache_enabled = false;
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        let limited = engine.allowed_token_ids_until(1000);
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            limited,
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .map(|x| x as u32)
+                .collect::<Vec<_>>()
+        );
+        assert!(engine.allowed_token_ids_until(0).is_empty());
+        engine.try_accept_new_bytes(b"cd").unwrap();
+        assert_eq!(engine.any_allowed_token(), None);
+        let config = kbnf::config::Config {
+            eos_token_id: Some(0),
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab, config).unwrap();
+        assert!(!engine.allowed_token_ids_until(1000).contains(&0));
+        engine.try_accept_new_bytes(b"ab").unwrap();
+        assert_eq!(engine.any_allowed_token(), Some(0));
+    }
+
+    #[test]
+    fn vocabulary_add_token() {
+        let mut vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let token_id = vocab.vocab_size() as u32 + 10;
+        let token = Token("<tool_call>".as_bytes().into());
+        assert_eq!(vocab.token_id(&token), None);
+        vocab
+            .add_token(token_id, token.clone(), "<tool_call>".to_string())
+            .unwrap();
+        assert_eq!(vocab.token_id(&token), Some(token_id));
+        assert_eq!(vocab.token_string(token_id), Some("<tool_call>"));
+        assert_eq!(vocab.vocab_size(), token_id as usize + 1);
+        let raw_token_id = token_id + 1;
+        vocab
+            .add_token(
+                raw_token_id,
+                Token(Box::new([0xFF, b'<'])),
+                "\u{FFFD}<".to_string(),
+            )
+            .unwrap();
+        assert!(matches!(
+            vocab.add_token(token_id, Token("x".as_bytes().into()), "x".to_string()),
+            Err(kbnf::vocabulary::CreateVocabularyError::TokenIDAlreadyExists(id)) if id == token_id
+        ));
+        // The tokens whose first bytes come after the added token's are still recognized.
+        let input = "start::=#'[a-z]+';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let mut original_engine = kbnf::engine::Engine::new(
+            input,
+            read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap(),
+        )
+        .unwrap();
+        engine.compute_allowed_token_ids();
+        original_engine.compute_allowed_token_ids();
+        assert!(engine
+            .allowed_token_ids_from_last_computation()
+            .ones()
+            .eq(original_engine
+                .allowed_token_ids_from_last_computation()
+                .ones()));
+        let input = "start::=('<tool_call>' | '<') #'[a-z]+' '\\n';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        assert!(engine
+            .allowed_token_ids_from_last_computation()
+            .contains(token_id as usize));
+        assert_eq!(
+            engine.try_accept_new_token(token_id).unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert_eq!(engine.accepted_bytes(), b"<tool_call>");
+        // The other tokens starting with the same byte are still recognized.
+        let existing = get_token_id_from_str(&vocab, "<").unwrap();
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_token(existing).unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_token(raw_token_id),
+            Err(AcceptTokenError::Rejected)
+        );
+    }
+
+    #[test]
+    fn ambiguity_warnings() {
+        use kbnf::grammar::AmbiguityWarning;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            collect_ambiguity_warnings: true,
+            ..Default::default()
+        };
+        let warnings = |input: &str| {
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone())
+                .unwrap()
+                .ambiguity_warnings()
+                .to_vec()
+        };
+        let result = warnings("start ::= 'a' 'a' | 'a' start;");
+        assert_eq!(
+            result,
+            vec![AmbiguityWarning::OverlappingPrefixes {
+                nonterminal: "start".to_string()
+            }]
+        );
+        assert_eq!(
+            result[0].to_string(),
+            "two productions of the nonterminal `start` start with overlapping prefixes."
+        );
+        assert!(
+            warnings("start ::= E '\\n'; E ::= E '+' E | #'[0-9]+';").contains(
+                &AmbiguityWarning::AmbiguousRecursion {
+                    nonterminal: "E".to_string()
+                }
+            )
+        );
+        let result = warnings("start ::= 'q' A 'e' | 'q' B 'e'; A ::= 'x' | 'y'; B ::= 'x' | 'z';");
+        assert!(result.iter().any(|x| matches!(
+            x,
+            AmbiguityWarning::ReduceReduceConflict {
+                nonterminal,
+                first_nonterminal,
+                second_nonterminal,
+            } if nonterminal == "start"
+                && [first_nonterminal.as_str(), second_nonterminal.as_str()].contains(&"A")
+                && [first_nonterminal.as_str(), second_nonterminal.as_str()].contains(&"B")
+        )));
+        assert!(warnings("start ::= 'a' start | 'b';").is_empty());
+        // The warnings are not collected by default.
+        let engine =
+            kbnf::engine::Engine::new("start ::= 'a' 'a' | 'a' start;", vocab.clone()).unwrap();
+        assert!(engine.ambiguity_warnings().is_empty());
+    }
+
+    #[test]
+    fn any_char() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // The regex matching any character is compiled into a DFA, which is the reference.
+        let native = "start::=#char{2} '\\n';";
+        let dfa = "start::=#'(?s:.)'{2} '\\n';";
+        let mut native = kbnf::engine::Engine::new(native, vocab.clone()).unwrap();
+        let mut dfa = kbnf::engine::Engine::new(dfa, vocab.clone()).unwrap();
+        assert!(format!("{:?}", native).contains("#char"));
+        let accepts = |engine: &mut kbnf::Engine, input: &[u8]| {
+            engine.reset();
+            engine.try_accept_new_bytes(input).is_ok() && engine.can_finish()
+        };
+        for (input, expected) in [
+            (b"ab\n".as_slice(), true),
+            ("你好\n".as_bytes(), true),
+            ("\n🎉\n".as_bytes(), true),
+            (b"a\n", false),
+            (b"abc\n", false),
+            (b"\xFFa\n", false),
+            (b"\x80a\n", false),
+            (b"\xC0\x80a\n", false),
+            (b"\xED\xA0\x80a\n", false),
+            (b"\xE4\xBDa\n", false),
+            (b"\xF4\x8F\xBF\xBFa\n", true),
+        ] {
+            assert_eq!(accepts(&mut native, input), expected, "{input:?}");
+            assert_eq!(accepts(&mut dfa, input), expected, "{input:?}");
+        }
+        // The last input stops in the middle of a character.
+        for input in [b"".as_slice(), b"a", "你".as_bytes(), b"\xE4"] {
+            for engine in [&mut native, &mut dfa] {
+                engine.reset();
+                engine.try_accept_new_bytes(input).unwrap();
+                engine.compute_allowed_token_ids();
+            }
+            assert_eq!(
+                native.allowed_token_ids_from_last_computation(),
+                dfa.allowed_token_ids_from_last_computation(),
+                "{input:?}"
+            );
+            assert!(
+                native
+                    .allowed_first_bytes()
+                    .ones()
+                    .eq(dfa.allowed_first_bytes().ones()),
+                "{input:?}"
+            );
+        }
+        native.reset();
+        assert!(native.allowed_first_bytes().contains(0xE4));
+        assert!(!native.allowed_first_bytes().contains(0xFF));
+        assert!(!native.allowed_first_bytes().contains(0x80));
+        assert_eq!(
+            native.try_accept_new_bytes(b"\xFF"),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            native
+                .try_accept_new_token(get_token_id_from_str(&vocab, "你").unwrap())
+                .unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+    }
+
+    #[test]
+    fn raw_byte_escapes() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine =
+            kbnf::engine::Engine::new("start ::= \"\\xff\\xfe\";", vocab.clone()).unwrap();
+        assert!(format!("{:?}", engine).contains(r#"\"\\xFF\\xFE\"[0]"#));
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\xff\xfe").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        // The escapes are raw bytes rather than the characters U+00FF and U+00FE.
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes("\u{ff}".as_bytes()),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.compute_allowed_token_ids();
+        assert!(engine.allowed_first_bytes().ones().eq([0xFF]));
+        if let Some(token_id) = vocab.token_id(&Token(Box::new([0xFF]))) {
+            assert!(engine
+                .allowed_token_ids_from_last_computation()
+                .contains(token_id as usize));
+        }
+        // The bytes of a valid UTF-8 sequence still match the character.
+        let mut engine =
+            kbnf::engine::Engine::new("start ::= '\\xe4\\xbd\\xa0';", vocab.clone()).unwrap();
+        assert_eq!(
+            engine
+                .try_accept_new_token(get_token_id_from_str(&vocab, "你").unwrap())
+                .unwrap(),
+            AcceptTokenResult::Finished
+        );
+        let accepts = |grammar: &str, input: &[u8]| {
+            let mut engine = kbnf::engine::Engine::new(grammar, vocab.clone()).unwrap();
+            engine.try_accept_new_bytes(input).is_ok() && engine.can_finish()
+        };
+        assert!(accepts("start ::= #\"\\xff+\" 'a';", b"\xff\xffa"));
+        assert!(!accepts("start ::= #\"\\xff+\" 'a';", "\u{ff}a".as_bytes()));
+        assert!(accepts("start ::= #\"(?:\\xfe|b)\\xff*\";", b"b\xff"));
+        // The terminals compressed into a regex are still raw bytes.
+        let compressed = "start ::= ('\\xfa'|'\\xfb'|'\\xfc'|'\\xfd'|'\\xfe'|'\\xff') 'a';";
+        assert!(accepts(compressed, b"\xffa"));
+        assert!(!accepts(compressed, "\u{ff}a".as_bytes()));
+        // ASCII escapes and escaped backslashes are unchanged.
+        assert!(accepts("start ::= '\\x41\\\\xff';", b"A\\xff"));
+        assert!(kbnf::engine::Engine::new("start ::= #'[\\xff]';", vocab.clone()).is_err());
+        // The characters standing for the raw bytes cannot be written in the literals.
+        for grammar in [
+            "start ::= '\u{10FF80}';",
+            "start ::= 'a\\u{10ffff}';",
+            "start ::= #\"[\u{10FFC0}]+\";",
+        ] {
+            assert!(matches!(
+                kbnf::engine::Engine::new(grammar, vocab.clone()),
+                Err(kbnf::engine::CreateEngineError::GrammarError(
+                    kbnf::grammar::CreateGrammarError::PreprocessingError(_)
+                ))
+            ));
+        }
+        assert!(accepts("start ::= '\\u{10ff7f}';", "\u{10ff7f}".as_bytes()));
+    }
+
+    #[test]
+    fn reserved_markers() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // The literals cannot forge the markers inserted by desugaring the syntax extensions.
+        for grammar in [
+            "start ::= #\"[a&&b]__kbnf_json\";",
+            "start ::= #\"[a&&b]__kbnf_prior=0.5\" | 'a';",
+            "start ::= 'x__kbnf_atomic';",
+            "start ::= #e\"__kbnf_anychar\";",
+        ] {
+            assert!(matches!(
+                kbnf::engine::Engine::new(grammar, vocab.clone()),
+                Err(kbnf::engine::CreateEngineError::GrammarError(
+                    kbnf::grammar::CreateGrammarError::PreprocessingError(_)
+                ))
+            ));
+        }
+        assert!(kbnf::engine::Engine::new("start ::= '__kbnf' | #\"_kbnf_\";", vocab).is_ok());
+    }
+
+    #[test]
+    fn regex_too_large_to_compile() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start ::= 'x' #\"(a|b)*a(a|b){20}\";";
+        let mut config = kbnf::config::Config::default();
+        config.regex_config.dfa_size_limit = Some(10_000);
+        let result = kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone());
+        assert!(matches!(
+            result,
+            Err(kbnf::engine::CreateEngineError::GrammarError(
+                kbnf::grammar::CreateGrammarError::RegexTooLargeToCompile { ref regex, limit: 10_000 }
+            )) if regex == "(a|b)*a(a|b){20}"
+        ));
+        // The regexes compiled by the grammar itself are limited as well.
+        let length_prefixed = kbnf::engine::Engine::with_config(
+            "start ::= #lenprefixed(#\"[0-9]+:\", #\"(a|b)*a(a|b){20}\");",
+            vocab.clone(),
+            config.clone(),
+        );
+        assert!(matches!(
+            length_prefixed,
+            Err(kbnf::engine::CreateEngineError::GrammarError(
+                kbnf::grammar::CreateGrammarError::RegexTooLargeToCompile { limit: 10_000, .. }
+            ))
+        ));
+        config.regex_config.dfa_size_limit = None;
+        config.regex_config.max_memory_usage = Some(10_000);
+        assert!(matches!(
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()),
+            Err(kbnf::engine::CreateEngineError::GrammarError(
+                kbnf::grammar::CreateGrammarError::RegexTooLargeToCompile { limit: 10_000, .. }
+            ))
+        ));
+        // Small regexes still fit in the limit.
+        assert!(
+            kbnf::engine::Engine::with_config("start ::= #\"[a-z]+\";", vocab.clone(), config)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn allowed_token_count() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let engine = kbnf::engine::Engine::new("start ::= '你' | '好';", vocab.clone()).unwrap();
+        let mut engine: Box<dyn EngineLike> = engine.into_boxed_engine();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert_eq!(engine.allowed_token_count(), allowed.count_ones(..));
+        assert_eq!(engine.disallowed_token_count(), allowed.count_zeroes(..));
+        assert_eq!(
+            engine.allowed_token_count() + engine.disallowed_token_count(),
+            allowed.len()
+        );
+        assert!(engine.allowed_token_count() >= 2);
+        engine
+            .try_accept_new_token(get_token_id_from_str(&vocab, "你").unwrap())
+            .unwrap();
+        engine.compute_allowed_token_ids();
+        assert_eq!(engine.allowed_token_count(), 0);
+        assert_eq!(
+            engine.disallowed_token_count(),
+            engine.allowed_token_ids_from_last_computation().len()
+        );
+    }
+
+    #[test]
+    fn regex_to_kbnf() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let pattern = "\"a\\\\b\"\n[\\n\"]";
+        assert_eq!(
+            kbnf::grammar::regex_to_kbnf(pattern, false),
+            r#"start ::= #"\"a\\\\b\"\n[\\n\"]";"#
+        );
+        let mut engine = kbnf::engine::Engine::from_regex(pattern, vocab.clone(), false).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\"a\\b\"\n").unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\"").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        let mut engine = kbnf::engine::Engine::from_regex(pattern, vocab.clone(), false).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\"a\\b\"\n\n").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        // The early-end regex finishes as soon as it matches.
+        assert_eq!(
+            kbnf::grammar::regex_to_kbnf("'[a-z]+'", true),
+            r#"start ::= #e"'[a-z]+'";"#
+        );
+        let mut engine = kbnf::engine::Engine::from_regex("'[a-z]+'", vocab.clone(), true).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"'ab'").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        assert!(kbnf::engine::Engine::from_regex("(", vocab, false).is_err());
+    }
+
+    #[test]
+    fn shortest_completion() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine =
+            kbnf::engine::Engine::new("start ::= \"ab\" \"cd\";", vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"ab").unwrap();
+        assert_eq!(engine.shortest_completion(16), Some(b"cd".to_vec()));
+        // The engine itself is not modified.
+        assert_eq!(engine.accepted_bytes(), b"ab");
+        assert_eq!(engine.shortest_completion(1), None);
+        engine.try_accept_new_bytes(b"cd").unwrap();
+        assert_eq!(engine.shortest_completion(0), Some(Vec::new()));
+        let engine = kbnf::engine::Engine::new(
+            "start ::= #'[a-z]{8}' | 'hello' 'world' | 'z' start;",
+            vocab.clone(),
+        )
+        .unwrap();
+        assert_eq!(engine.shortest_completion(64), Some(b"aaaaaaaa".to_vec()));
+        let engine = kbnf::engine::Engine::new("start ::= 'a' start | 'a' 'b';", vocab).unwrap();
+        assert_eq!(engine.shortest_completion(64), Some(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn superstrings() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start ::= #contains\"XYZ\" \"\\n\";";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"foo\n").unwrap();
+        assert!(!engine.can_finish());
+        engine.try_accept_new_bytes(b"XY").unwrap();
+        assert!(!engine.can_finish());
+        engine.try_accept_new_bytes(b"Zbar\n").unwrap();
+        assert!(engine.can_finish());
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"fooXYZbar\n").unwrap();
+        assert!(engine.can_finish());
+        // The string is matched literally, even when it contains regex metacharacters.
+        let mut engine =
+            kbnf::engine::Engine::new("start ::= #contains\"a.\\\"\" ';';", vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"ab;").unwrap();
+        assert!(!engine.can_finish());
+        engine.try_accept_new_bytes(b"a.\";").unwrap();
+        assert!(engine.can_finish());
+        assert_eq!(engine.shortest_completion(8), Some(Vec::new()));
+        let engine = kbnf::engine::Engine::new("start ::= #contains\"你好\";", vocab).unwrap();
+        assert_eq!(
+            engine.shortest_completion(8),
+            Some("你好".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn shared_cache() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start ::= #'[0-9]+' ',' ('你好' | 'hello');";
+        let shared_cache = kbnf::engine::SharedCache::new();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let engine = kbnf::engine::Engine::with_shared_cache(
+                    input,
+                    vocab.clone(),
+                    kbnf::config::Config::default(),
+                    &shared_cache,
+                )
+                .unwrap();
+                std::thread::spawn(move || {
+                    let mut engine = engine;
+                    engine.try_accept_new_bytes(b"12,").unwrap();
+                    engine.compute_allowed_token_ids();
+                    (
+                        engine.allowed_token_ids_from_last_computation().clone(),
+                        engine.cache_len(),
+                    )
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|x| x.join().unwrap()).collect();
+        // Both engines hit the same states, whose allowed token IDs are computed once and stored in the shared cache.
+        assert_eq!(shared_cache.len(), 1);
+        assert_eq!(results[0], results[1]);
+        assert!(results.iter().all(|(_, cache_len)| *cache_len == 0));
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"12,").unwrap();
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine.allowed_token_ids_from_last_computation(),
+            &results[0].0
+        );
+        // A clone shares the cache with the original engine.
+        let mut engine = kbnf::engine::Engine::with_shared_cache(
+            input,
+            vocab.clone(),
+            kbnf::config::Config::default(),
+            &shared_cache,
+        )
+        .unwrap();
+        let mut clone = engine.clone();
+        engine.compute_allowed_token_ids();
+        clone.compute_allowed_token_ids();
+        assert_eq!(shared_cache.len(), 2);
+        shared_cache.clear();
+        assert!(shared_cache.is_empty());
+        // The engines of a grammar that needs different integer types cannot share the cache.
+        let config = kbnf::config::Config {
+            expected_output_length: u16::MAX as usize,
+            ..Default::default()
+        };
+        assert!(matches!(
+            kbnf::engine::Engine::with_shared_cache(input, vocab, config, &shared_cache),
+            Err(kbnf::engine::CreateEngineError::MismatchedSharedCacheError)
+        ));
+    }
+
+    #[test]
+    fn bounded_shared_cache() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let grammar = r#"start::="a" "1" | "b" "2" | "c" "3";"#;
+        let config = kbnf::config::Config {
+            engine_config: EngineConfig {
+                cache_enabled: true,
+                compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: Some(2),
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
+            },
+            ..Default::default()
+        };
+        let shared_cache = kbnf::engine::SharedCache::new();
+        let mut engine =
+            kbnf::engine::Engine::with_shared_cache(grammar, vocab.clone(), config, &shared_cache)
+                .unwrap();
+        for token in ["a", "b", "c"] {
+            engine.reset();
+            engine.compute_allowed_token_ids();
+            engine
+                .try_accept_new_token(get_token_id_from_str(&vocab, token).unwrap())
+                .unwrap();
+            engine.compute_allowed_token_ids();
+            assert_eq!(shared_cache.len(), 2);
+        }
+        // The evicted states are computed again with the same result.
+        let mut uncached = kbnf::engine::Engine::new(grammar, vocab.clone()).unwrap();
+        engine.reset();
+        engine.compute_allowed_token_ids();
+        uncached.compute_allowed_token_ids();
+        assert_eq!(
+            engine.allowed_token_ids_from_last_computation(),
+            uncached.allowed_token_ids_from_last_computation()
+        );
+    }
+
+    #[test]
+    fn ignored_token_ids() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start ::= '<' 'a' | 'b';";
+        let bos = vocab.vocab_size() as u32 + 1;
+        let lt = get_token_id_from_str(&vocab, "<").unwrap();
+        let b = get_token_id_from_str(&vocab, "b").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        assert!(engine
+            .allowed_token_ids_from_last_computation()
+            .contains(lt as usize));
+        let config = kbnf::config::Config {
+            ignored_token_ids: vec![bos, lt],
+            eos_token_id: Some(lt),
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        // The ignored tokens are no-ops, even if they are not in the vocabulary.
+        assert_eq!(
+            engine.try_accept_new_token(bos).unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert_eq!(
+            engine.try_accept_new_token(lt).unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert!(engine.accepted_bytes().is_empty());
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation().clone();
+        assert!(!allowed.contains(lt as usize));
+        assert!(allowed.contains(b as usize));
+        assert_eq!(
+            engine.allowed_token_ids_until(usize::MAX),
+            allowed.ones().map(|x| x as u32).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            engine.try_accept_new_token(b).unwrap(),
+            AcceptTokenResult::Finished
+        );
+        // The ignored EOS token is never allowed, and accepting an ignored token keeps the engine finished.
+        engine.compute_allowed_token_ids();
+        assert_eq!(engine.allowed_token_count(), 0);
+        assert!(engine.allowed_token_ids_until(usize::MAX).is_empty());
+        assert_eq!(
+            engine.try_accept_new_token(bos).unwrap(),
+            AcceptTokenResult::Finished
+        );
+        assert_eq!(engine.accepted_bytes(), b"b");
+    }
+
+    #[test]
+    fn allowed_token_strings() {
+        let token_strings: AHashMap<u32, String> = [
+            (1, "a".to_string()),
+            (2, "hello".to_string()),
+            (4, "\n".to_string()),
+            (5, "\n\n".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let tokens = token_strings
+            .iter()
+            .map(|(k, v)| (*k, Token(v.as_bytes().to_vec().into_boxed_slice())))
+            .collect::<AHashMap<u32, _>>();
+        let vocab = Vocabulary::new(tokens, token_strings).unwrap();
+        let mut engine =
+            kbnf::engine::Engine::new("start ::= #e\"(.|\\n)*\\n\\n\";", vocab).unwrap();
+        assert!(engine.allowed_token_strings().is_empty());
+        engine.compute_allowed_token_ids();
+        let strings = engine.allowed_token_strings();
+        assert!(strings.contains(&(1, "a".to_string())));
+        assert!(strings.contains(&(2, "hello".to_string())));
+        assert_eq!(
+            strings.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![1, 2, 4, 5]
+        );
+    }
+
+    #[test]
+    fn parse_tree() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(
+            "start ::= key \"=\" value; @preserve key ::= #\"[a-z]+\"; @preserve value ::= #\"[0-9]+\";",
+            vocab.clone(),
+        )
+        .unwrap();
+        assert_eq!(engine.parse_tree(), None);
+        engine.try_accept_new_bytes(b"abc=123").unwrap();
+        let tree = engine.parse_tree().unwrap();
+        assert_eq!(
+            (tree.nonterminal.as_str(), tree.start, tree.end),
+            ("start", 0, 7)
+        );
+        let key = tree.find("key").unwrap();
+        let value = tree.find("value").unwrap();
+        assert_eq!((key.start, key.end), (0, 3));
+        assert_eq!((value.start, value.end), (4, 7));
+        assert_eq!(tree.children, vec![key.clone(), value.clone()]);
+        // An incomplete match has no derivation.
+        engine.reset();
+        engine.try_accept_new_bytes(b"abc=").unwrap();
+        assert_eq!(engine.parse_tree(), None);
+        // Left recursion is supported, and the bytes preserved by rewinding are not part of the derivation.
+        let mut engine = kbnf::engine::Engine::new(
+            "start ::= list \";\"; list ::= list \",\" item | item; @preserve item ::= #\"[0-9]+\";",
+            vocab,
+        )
+        .unwrap();
+        engine.try_accept_new_bytes(b"1;").unwrap();
+        engine.rewind();
+        engine.try_accept_new_bytes(b"12,3,45;").unwrap();
+        let tree = engine.parse_tree().unwrap();
+        assert_eq!(tree.end, 8);
+        assert_eq!(
+            tree.find_all("item")
+                .iter()
+                .map(|x| (x.start, x.end))
+                .collect::<Vec<_>>(),
+            vec![(0, 2), (3, 4), (5, 7)]
+        );
+    }
+
+    #[test]
+    fn try_accept_new_str() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new("start ::= \"你好\";", vocab).unwrap();
+        assert_eq!(
+            engine.try_accept_new_str("你好x"),
+            Err(AcceptStrError::Rejected { char_index: 2 })
+        );
+        assert!(engine.accepted_bytes().is_empty());
+        assert_eq!(
+            engine.try_accept_new_str("你").unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert_eq!(
+            engine.try_accept_new_str("x"),
+            Err(AcceptStrError::Rejected { char_index: 0 })
+        );
+        assert_eq!(
+            engine.try_accept_new_str("好").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        assert_eq!(
+            engine.try_accept_new_str("好"),
+            Err(AcceptStrError::Finished)
+        );
+    }
+
+    #[test]
+    fn grammar_builder() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let builder = kbnf::grammar::GrammarBuilder::new()
+            .fragment(
+                "common",
+                "digit ::= #\"[0-9]\";\n@preserve year ::= digit{4};",
+            )
+            .fragment("main", "start ::= year '-' digit digit;");
+        let grammar = builder.link().unwrap();
+        let mut engine = kbnf::engine::Engine::new(&grammar, vocab).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"2024-").unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert!(engine.try_accept_new_bytes(b"x").is_err());
+        assert_eq!(
+            engine.try_accept_new_bytes(b"10").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        let year = engine.parse_tree().unwrap();
+        let year = year.find("year").unwrap();
+        assert_eq!((year.start, year.end), (0, 4));
+        let error = builder
+            .clone()
+            .fragment("other", "digit ::= #\"[0-9a-f]\";")
+            .link()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            kbnf::grammar::CreateGrammarError::DuplicateNonterminalError { ref nonterminal, ref first, ref second }
+                if nonterminal == "digit" && first == "common" && second == "other"
+        ));
+        assert!(matches!(
+            builder.fragment("main", "").link().unwrap_err(),
+            kbnf::grammar::CreateGrammarError::DuplicateFragmentError(ref name) if name == "main"
+        ));
+    }
+
+    #[test]
+    fn state_fingerprint() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let fingerprint = || {
+            let mut engine = kbnf::engine::Engine::new(
+                "start ::= (item ',')* item ';'; item ::= 'ab' | #\"[a-z]+\" | 'a' item;",
+                vocab.clone(),
+            )
+            .unwrap();
+            engine.try_accept_new_bytes(b"ab,").unwrap();
+            engine.try_accept_new_bytes(b"a").unwrap();
+            engine.compute_allowed_token_ids();
+            let fingerprint = engine.state_fingerprint();
+            assert_eq!(fingerprint, engine.state_fingerprint());
+            engine.try_accept_new_bytes(b"b").unwrap();
+            assert_ne!(fingerprint, engine.state_fingerprint());
+            fingerprint
+        };
+        // Each engine has its own hash maps with different random states.
+        let fingerprint_1 = fingerprint();
+        assert!(fingerprint_1.contains("finished: false"));
+        assert_eq!(fingerprint_1, fingerprint());
+    }
+
+    #[test]
+    fn unanchored_regex() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new("start ::= #u\"ab\" '!';", vocab).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"xyab").unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"!").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab!").unwrap(),
+            AcceptTokenResult::Finished
+        );
+        engine.reset();
+        // The "!" before any occurrence of the regex is skipped as well.
+        assert_eq!(
+            engine.try_accept_new_bytes(b"xay!").unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert!(!engine.can_finish());
+        engine.try_accept_new_bytes(b"ab").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"!").unwrap(),
+            AcceptTokenResult::Finished
+        );
+    }
+
+    #[test]
+    fn allow_empty_match() {
+        let input = "start::=value; value::=('\"' #'[a-z]+' '\"') | '';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let eos_token_id = get_token_id_from_str(&vocab, "z").unwrap();
+        let quote_token_id = get_token_id_from_str(&vocab, "\"").unwrap();
+        let config = kbnf::config::Config {
+            eos_token_id: Some(eos_token_id),
+            ..Default::default()
+        };
+        // The empty string is not a match by default.
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        assert!(!engine.can_finish());
+        assert_eq!(
+            engine.try_accept_new_token(eos_token_id),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert!(matches!(
+            kbnf::engine::Engine::with_config("start::='';", vocab.clone(), config.clone()),
+            Err(kbnf::engine::CreateEngineError::EmptyGrammarError(_))
+        ));
+        let config = kbnf::config::Config {
+            allow_empty_match: true,
+            ..config
+        };
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        assert!(engine.can_finish());
+        assert!(!engine.is_finished());
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(eos_token_id as usize));
+        assert!(allowed.contains(quote_token_id as usize));
+        assert!(engine
+            .allowed_token_ids_until(usize::MAX)
+            .contains(&eos_token_id));
+        // Finishing via EOS
+        assert_eq!(
+            engine.try_accept_new_token(eos_token_id),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // Continuing with more input, where the empty match no longer applies
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_token(quote_token_id),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert!(!engine.can_finish());
+        engine.compute_allowed_token_ids();
+        assert!(!engine
+            .allowed_token_ids_from_last_computation()
+            .contains(eos_token_id as usize));
+        assert_eq!(
+            engine.try_accept_new_token(eos_token_id),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"abc\""),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.rollback(2).unwrap();
+        assert!(engine.can_finish());
+        // A grammar that only matches the empty string only allows the EOS token.
+        let mut engine =
+            kbnf::engine::Engine::with_config("start::='';", vocab.clone(), config).unwrap();
+        assert!(engine.can_finish());
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            vec![eos_token_id as usize]
+        );
+        assert_eq!(
+            engine.try_accept_new_token(quote_token_id),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_token(eos_token_id),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn vocabulary_report() {
+        let token_strings: AHashMap<u32, String> = [
+            (0, "a".to_string()),
+            (1, "b".to_string()),
+            (2, "ab".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let tokens = token_strings
+            .iter()
+            .map(|(k, v)| (*k, Token(v.as_bytes().to_vec().into_boxed_slice())))
+            .collect::<AHashMap<u32, _>>();
+        let (vocab, report) = Vocabulary::new_with_report(tokens, token_strings).unwrap();
+        assert!(report.uncovered_bytes.contains(&b'\n'));
+        assert!(!report.uncovered_bytes.contains(&b'a'));
+        assert!(!report.uncovered_bytes.contains(&b'b'));
+        assert_eq!(report.uncovered_bytes.len(), 248 - 2);
+        let mut engine =
+            kbnf::engine::Engine::new("start ::= 'a' '\n' 'b';", vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert!(engine.allowed_first_bytes().is_clear());
+        engine.compute_allowed_token_ids();
+        assert!(engine.allowed_token_ids_from_last_computation().is_clear());
+        // The branches not requiring the missing byte are unaffected.
+        let mut engine = kbnf::engine::Engine::new("start ::= 'a' (#'\n+' | 'b');", vocab).unwrap();
+        engine.try_accept_new_bytes(b"a").unwrap();
+        assert_eq!(
+            engine.allowed_first_bytes().ones().collect::<Vec<_>>(),
+            vec![b'b' as usize]
+        );
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn grammar_weight() {
+        let input = "start::='a' %2.0 | 'b' %0.5;";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let a = get_token_id_from_str(&vocab, "a").unwrap() as usize;
+        let b = get_token_id_from_str(&vocab, "b").unwrap() as usize;
+        let c = get_token_id_from_str(&vocab, "c").unwrap() as usize;
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        engine.compute_allowed_token_ids();
+        engine.mask_logits(&mut logits).unwrap();
+        engine.bias_logits_by_grammar_prior(&mut logits).unwrap();
+        assert_eq!(logits[a], 1.0 + 2.0f32.ln());
+        assert!(logits[a] > 1.0);
+        assert_eq!(logits[b], 1.0 + 0.5f32.ln());
+        // The bias is layered on top of the mask.
+        assert_eq!(logits[c], f32::NEG_INFINITY);
+        assert_eq!(
+            logits.iter().filter(|x| x.is_finite()).count(),
+            engine
+                .allowed_token_ids_from_last_computation()
+                .count_ones(..)
+        );
+    }
+
+    #[test]
+    fn engine_pool() {
+        let input = "start::=#'[0-9]+' ';';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let prototype = kbnf::engine::Engine::new(input, vocab).unwrap();
+        let fingerprint = prototype.state_fingerprint();
+        let pool = kbnf::engine::EnginePool::new(prototype);
+        assert_eq!(pool.idle_len(), 0);
+        {
+            let mut first = pool.acquire();
+            let mut second = pool.acquire();
+            assert_eq!(
+                first.try_accept_new_bytes(b"123"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            first.compute_allowed_token_ids();
+            assert_eq!(
+                second.try_accept_new_bytes(b"4;"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        assert_eq!(pool.idle_len(), 2);
+        // The returned engines are reset before being handed out again.
+        let mut engine = pool.acquire();
+        assert_eq!(pool.idle_len(), 1);
+        assert_eq!(engine.state_fingerprint(), fingerprint);
+        assert_eq!(
+            engine.try_accept_new_bytes(b"5;"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let detached = engine.detach();
+        assert!(detached.is_finished());
+        assert_eq!(pool.idle_len(), 1);
+        pool.clear();
+        assert_eq!(pool.idle_len(), 0);
+        assert_eq!(pool.acquire().state_fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn utf16_vocabulary() {
+        use kbnf::vocabulary::{CreateVocabularyError, UnpairedSurrogate};
+        let tokens: Vec<(u32, Vec<u16>)> = vec![
+            (0, "a".encode_utf16().collect()),
+            (1, "😀".encode_utf16().collect()),
+            (2, "b😀".encode_utf16().collect()),
+            // The high surrogate of 😀 without the low surrogate
+            (3, vec![0xD83D]),
+        ];
+        assert_eq!(tokens[1].1, vec![0xD83D, 0xDE00]);
+        let vocab =
+            Vocabulary::from_utf16_tokens(tokens.clone(), UnpairedSurrogate::Replace).unwrap();
+        assert_eq!(&*vocab.token(1).unwrap().0, &[0xF0, 0x9F, 0x98, 0x80]);
+        assert_eq!(&*vocab.token(2).unwrap().0, "b😀".as_bytes());
+        assert_eq!(&*vocab.token(3).unwrap().0, "\u{FFFD}".as_bytes());
+        assert_eq!(vocab.token_string(1), Some("😀"));
+        let mut engine = kbnf::engine::Engine::new("start::='a' '😀';", vocab).unwrap();
+        engine.try_accept_new_token(0).unwrap();
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert!(matches!(
+            Vocabulary::from_utf16_tokens(tokens, UnpairedSurrogate::Error),
+            Err(CreateVocabularyError::UnpairedSurrogate(3, 0xD83D))
+        ));
+    }
+
+    #[test]
+    fn detailed_errors() {
+        let input = "start::='ab' #'[0-9]+' 'c';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let token_id = get_token_id_from_str(&vocab, "abc").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_token(token_id),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert!(engine.last_rejection().is_none());
+        let config = kbnf::config::Config {
+            detailed_errors: true,
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab, config).unwrap();
+        assert_eq!(
+            engine.try_accept_new_token(token_id),
+            Err(AcceptTokenError::Rejected)
+        );
+        let rejection = engine.last_rejection().unwrap();
+        assert_eq!(rejection.byte_index, 2);
+        assert_eq!(
+            rejection.allowed_first_bytes.ones().collect::<Vec<_>>(),
+            (b'0'..=b'9').map(|x| x as usize).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab12x"),
+            Err(AcceptTokenError::Rejected)
+        );
+        let rejection = engine.last_rejection().unwrap();
+        assert_eq!(rejection.byte_index, 4);
+        assert!(rejection.allowed_first_bytes.contains(b'c' as usize));
+        assert!(rejection.allowed_first_bytes.contains(b'0' as usize));
+        // The states are not updated by the rejections.
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab1"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert!(engine.last_rejection().is_none());
+    }
+
+    #[test]
+    fn dead_rules() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let gbnf = "root ::= \"a\" | loop\nloop ::= loop \"y\"\ndead ::= \"x\"{2,3} other\nother ::= \"z\"";
+        let grammar =
+            kbnf::Grammar::<u8>::from_gbnf(gbnf, &vocab, kbnf::Config::default().regex_config)
+                .unwrap();
+        assert_eq!(grammar.unreachable_nonterminals(), vec!["dead", "other"]);
+        assert_eq!(grammar.nonproductive_nonterminals(), vec!["loop"]);
+        let deserialized = kbnf::Grammar::<u8>::from_bytes(&grammar.to_bytes()).unwrap();
+        assert_eq!(
+            deserialized.unreachable_nonterminals(),
+            vec!["dead", "other"]
+        );
+        assert_eq!(deserialized.nonproductive_nonterminals(), vec!["loop"]);
+        let grammar = kbnf::Grammar::<u8>::from_gbnf(
+            "root ::= \"a\" | \"b\"",
+            &vocab,
+            kbnf::Config::default().regex_config,
+        )
+        .unwrap();
+        assert!(grammar.unreachable_nonterminals().is_empty());
+        assert!(grammar.nonproductive_nonterminals().is_empty());
+    }
+
+    #[test]
+    fn completion_hook() {
+        let input = "start::='[' item (',' item)* ']'; @preserve item::='a'|'bc';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // The offsets are byte offsets even though the Earley sets are compacted.
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let grammar = engine.grammar();
+        let completions = Arc::new(Mutex::new(Vec::new()));
+        let hook_completions = completions.clone();
+        engine.set_completion_hook(Box::new(move |nonterminal_id, start, end| {
+            hook_completions.lock().unwrap().push((
+                grammar.nonterminal_str(nonterminal_id).unwrap().to_string(),
+                start,
+                end,
+            ));
+        }));
+        assert_eq!(
+            engine.try_accept_new_bytes(b"[a,bc"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        // The speculative acceptance does not invoke the hook.
+        engine.compute_allowed_token_ids();
+        assert!(engine.try_accept_new_bytes(b"x").is_err());
+        // The clones do not carry the hook.
+        let len = completions.lock().unwrap().len();
+        let mut clone = engine.clone();
+        assert_eq!(
+            clone.try_accept_new_bytes(b",a]"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let mut fresh = engine.clone_fresh();
+        assert_eq!(
+            fresh.try_accept_new_bytes(b"[a]"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(completions.lock().unwrap().len(), len);
+        assert_eq!(
+            engine.try_accept_new_bytes(b",a"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"]"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let completions = completions.lock().unwrap();
+        let items: Vec<_> = completions
+            .iter()
+            .filter(|(name, _, _)| name == "item")
+            .map(|(_, start, end)| (*start, *end))
+            .collect();
+        assert_eq!(items, vec![(1, 2), (3, 5), (6, 7)]);
+        assert!(completions.contains(&("start".to_string(), 0, 8)));
+        // The Leo optimization completes the right recursion implicitly.
+        let mut engine =
+            kbnf::engine::Engine::new("start::=('x' start)|('x' ';');", vocab).unwrap();
+        let grammar = engine.grammar();
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let hook_spans = spans.clone();
+        engine.set_completion_hook(Box::new(move |nonterminal_id, start, end| {
+            if grammar.nonterminal_str(nonterminal_id) == Some("start") {
+                hook_spans.lock().unwrap().push((start, end));
+            }
+        }));
+        assert_eq!(
+            engine.try_accept_new_bytes(b"xxx;"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        spans.lock().unwrap().sort_unstable();
+        assert_eq!(*spans.lock().unwrap(), vec![(0, 4), (1, 4), (2, 4)]);
+        engine.clear_completion_hook();
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"x;"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(spans.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn compaction_interval() {
+        let input = "start::=C'\n';C::='c'|#'c' C;";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let token_id = vocab
+            .token_id(&Token("c".as_bytes().to_vec().into_boxed_slice()))
+            .unwrap();
+        let engine_with_interval = |compaction_enabled, compaction_interval| {
+            let config = kbnf::config::Config {
+                engine_config: EngineConfig {
+                    cache_enabled: false,
+                    compaction_enabled,
+                    compaction_interval,
+                    max_cache_entries: None,
+                    max_earley_set_size: None,
+                    max_total_items: None,
+                    deterministic: false,
+                },
+                ..Default::default()
+            };
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap()
+        };
+        let mut uncompacted = engine_with_interval(false, 1);
+        let mut engines = [0, 1, 3, 4].map(|x| engine_with_interval(true, x));
+        for i in 0..12 {
+            assert_eq!(
+                uncompacted.try_accept_new_token(token_id),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            uncompacted.compute_allowed_token_ids();
+            for engine in engines.iter_mut() {
+                assert_eq!(
+                    engine.try_accept_new_token(token_id),
+                    Ok(AcceptTokenResult::Ongoing)
+                );
+                engine.compute_allowed_token_ids();
+                assert_eq!(
+                    engine.allowed_token_ids_from_last_computation(),
+                    uncompacted.allowed_token_ids_from_last_computation(),
+                    "{i}"
+                );
+            }
+            // The interval of 4 catches up with the interval of 1 every 4 bytes.
+            let [_, every_byte, _, every_four_bytes] = &engines;
+            // Nothing can be compacted after the first byte.
+            assert_eq!(
+                every_four_bytes.state_fingerprint() == every_byte.state_fingerprint(),
+                i == 0 || i % 4 == 3,
+                "{i}"
+            );
+        }
+    }
+
+    #[test]
+    fn state_id_width() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            expected_output_length: 1000,
+            ..Default::default()
+        };
+        let engine =
+            kbnf::engine::Engine::with_config("start::='abc';", vocab.clone(), config.clone())
+                .unwrap();
+        assert_eq!(engine.state_id_width(), 2);
+        // The terminal is too long for 16-bit state IDs.
+        let terminal = "ab".repeat(40000);
+        let mut engine = kbnf::engine::Engine::with_config(
+            &format!("start::='{terminal}' ';';"),
+            vocab.clone(),
+            config,
+        )
+        .unwrap();
+        assert_eq!(engine.state_id_width(), 4);
+        assert_eq!(
+            engine.try_accept_new_bytes(terminal.as_bytes()),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b";"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let engine = kbnf::engine::Engine::new("start::='abc';", vocab).unwrap();
+        assert_eq!(engine.state_id_width(), 4);
+    }
+
+    #[test]
+    fn builtin_regexes() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let accepts = |name: &str, input: &str| {
+            let mut engine = kbnf::engine::Engine::new(
+                &format!("start ::= #builtin({name}) ';';"),
+                vocab.clone(),
+            )
+            .unwrap();
+            engine.try_accept_new_bytes(input.as_bytes()) == Ok(AcceptTokenResult::Finished)
+        };
+        for (name, input, expected) in [
+            ("uuid", "123e4567-e89b-12d3-a456-426614174000;", true),
+            ("uuid", "123E4567-E89B-12D3-A456-426614174000;", true),
+            ("uuid", "123e4567-e89b-12d3-a456-42661417400;", false),
+            ("uuid", "123e4567e89b12d3a456426614174000;", false),
+            ("uuid", "g23e4567-e89b-12d3-a456-426614174000;", false),
+            ("date", "2024-02-29;", true),
+            ("date", "2024-13-01;", false),
+            ("time", "23:59:60.5;", true),
+            ("time", "24:00:00;", false),
+            ("datetime", "2024-02-29T23:59:59Z;", true),
+            ("datetime", "2024-02-29t23:59:59.123-08:00;", true),
+            ("datetime", "2024-02-29 23:59:59;", false),
+            ("ipv4", "192.168.0.1;", true),
+            ("ipv4", "256.1.1.1;", false),
+            ("ipv4", "01.1.1.1;", false),
+            ("email", "a.b+c@example.com;", true),
+            ("email", "a..b@example.com;", false),
+            ("email", "a@localhost;", false),
+        ] {
+            assert_eq!(accepts(name, input), expected, "{name} {input}");
+        }
+        let error =
+            kbnf::engine::Engine::new("start ::= #builtin(url);", vocab.clone()).unwrap_err();
+        assert!(error.to_string().contains("uuid"), "{error}");
+    }
+
+    #[test]
+    fn clone_fresh() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start ::= #'[0-9]+' ',' ('你好' | 'hello');";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        engine.try_accept_new_bytes(b"12,").unwrap();
+        engine.compute_allowed_token_ids();
+        let expected = engine.allowed_token_ids_from_last_computation().clone();
+        let mut fresh = engine.clone_fresh();
+        assert!(fresh.accepted_bytes().is_empty());
+        assert_eq!(fresh.cache_len(), engine.cache_len());
+        fresh.compute_allowed_token_ids();
+        let mut new_engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        new_engine.compute_allowed_token_ids();
+        assert_eq!(
+            fresh.allowed_token_ids_from_last_computation(),
+            new_engine.allowed_token_ids_from_last_computation()
+        );
+        // The original engine keeps its states.
+        assert_eq!(engine.accepted_bytes(), b"12,");
+        assert_eq!(engine.allowed_token_ids_from_last_computation(), &expected);
+        fresh.try_accept_new_bytes(b"12,").unwrap();
+        fresh.compute_allowed_token_ids();
+        assert_eq!(fresh.allowed_token_ids_from_last_computation(), &expected);
+        // The fresh engine uses the shared cache of the original engine.
+        let shared_cache = kbnf::engine::SharedCache::new();
+        let engine = kbnf::engine::Engine::with_shared_cache(
+            input,
+            vocab.clone(),
+            kbnf::config::Config::default(),
+            &shared_cache,
+        )
+        .unwrap();
+        let mut fresh = engine.clone_fresh();
+        fresh.compute_allowed_token_ids();
+        assert_eq!(shared_cache.len(), 1);
+        assert_eq!(fresh.cache_len(), 0);
+    }
+
+    #[test]
+    fn explain_rejection() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let token_id = get_token_id_from_str(&vocab, "abd").unwrap();
+        let engine = kbnf::engine::Engine::new("start ::= 'abc';", vocab.clone()).unwrap();
+        let explanation = engine.explain_rejection(token_id).unwrap();
+        assert_eq!(explanation.byte_index, 2);
+        assert_eq!(explanation.rejected_byte, b'd');
+        assert_eq!(explanation.expectations.len(), 1);
+        let expectation = &explanation.expectations[0];
+        assert_eq!(expectation.nonterminal, "start");
+        assert!(expectation.node.contains("abc"));
+        assert_eq!(
+            expectation.expected_bytes.ones().collect::<Vec<_>>(),
+            vec![b'c' as usize]
+        );
+        // The states are not modified, and the accepted tokens are not explained.
+        assert!(engine.accepted_bytes().is_empty());
+        let token_id = get_token_id_from_str(&vocab, "ab").unwrap();
+        assert!(engine.explain_rejection(token_id).is_none());
+        let input = "start ::= 'a' (#'[0-9]+' | 'bc' | #substrs'xyz');";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"a").unwrap();
+        let token_id = get_token_id_from_str(&vocab, "q").unwrap();
+        let explanation = engine.explain_rejection(token_id).unwrap();
+        assert_eq!(explanation.byte_index, 0);
+        let mut expected_bytes: Vec<_> = explanation
+            .expectations
+            .iter()
+            .flat_map(|x| x.expected_bytes.ones())
+            .collect();
+        expected_bytes.sort_unstable();
+        assert_eq!(
+            expected_bytes,
+            (b'0'..=b'9')
+                .chain([b'b', b'x', b'y', b'z'])
+                .map(|x| x as usize)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(engine.accepted_bytes(), b"a");
+    }
+
+    #[test]
+    fn dot_matches_bytes() {
+        let token_strings: AHashMap<u32, String> = [(1, "\\xff".to_string()), (2, "a".to_string())]
+            .into_iter()
+            .collect();
+        let tokens = [(1, vec![0xFF]), (2, b"a".to_vec())]
+            .into_iter()
+            .map(|(k, v)| (k, Token(v.into_boxed_slice())))
+            .collect::<AHashMap<u32, _>>();
+        let vocab = Vocabulary::new(tokens, token_strings).unwrap();
+        let input = "start ::= #\".+\";";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        let config = kbnf::config::Config {
+            regex_config: kbnf::config::RegexConfig {
+                dot_matches_bytes: true,
+                ..kbnf::config::Config::default().regex_config
+            },
+            ..Default::default()
+        };
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(engine.try_accept_new_token(1).is_ok());
+        // A negated class matches a single byte as well.
+        let mut engine = kbnf::engine::Engine::with_config(
+            "start ::= #\"[^a]\" 'a';",
+            vocab.clone(),
+            config.clone(),
+        )
+        .unwrap();
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+        // The Unicode classes are not supported in byte mode.
+        assert!(
+            kbnf::engine::Engine::with_config("start ::= #\"\\\\p{L}+\";", vocab, config).is_err()
+        );
+    }
+
+    #[test]
+    fn bounded_substrings() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let accept = |input: &str, bytes: &[u8]| {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            engine.try_accept_new_bytes(bytes)
+        };
+        let input = "start ::= #substrs(2,3)\"abcde\" '\\n';";
+        assert_eq!(accept(input, b"a"), Ok(AcceptTokenResult::Ongoing));
+        // A single character is shorter than the minimum length.
+        assert!(accept(input, b"a\n").is_err());
+        assert_eq!(accept(input, b"ab\n"), Ok(AcceptTokenResult::Finished));
+        assert_eq!(accept(input, b"bcd\n"), Ok(AcceptTokenResult::Finished));
+        assert!(accept(input, b"abcd").is_err());
+        assert!(accept(input, b"ac").is_err());
+        let input = "start ::= #substrs(3,)\"abcde\" '\\n';";
+        assert_eq!(accept(input, b"abcde\n"), Ok(AcceptTokenResult::Finished));
+        assert!(accept(input, b"ab\n").is_err());
+        let input = "start ::= #substrs(0,2)\"abcde\" '\\n';";
+        assert_eq!(accept(input, b"\n"), Ok(AcceptTokenResult::Finished));
+        assert_eq!(accept(input, b"de\n"), Ok(AcceptTokenResult::Finished));
+        assert!(accept(input, b"cde").is_err());
+        let mut engine =
+            kbnf::engine::Engine::new("start ::= #substrs(2)\"abcde\" '\\n';", vocab.clone())
+                .unwrap();
+        engine.try_accept_new_bytes(b"c").unwrap();
+        engine.compute_allowed_token_ids();
+        assert!(engine.allowed_token_ids_from_last_computation().contains(
+            vocab
+                .token_id(&Token(b"d".to_vec().into_boxed_slice()))
+                .unwrap() as usize
+        ));
+        assert!(!engine.allowed_token_ids_from_last_computation().contains(
+            vocab
+                .token_id(&Token(b"\n".to_vec().into_boxed_slice()))
+                .unwrap() as usize
+        ));
+        for input in [
+            "start ::= #substrs(6)\"abcde\";",
+            "start ::= #substrs(3,2)\"abcde\";",
+            "start ::= #substrs(a)\"abcde\";",
+        ] {
+            assert!(matches!(
+                kbnf::engine::Engine::new(input, vocab.clone()),
+                Err(kbnf::engine::CreateEngineError::GrammarError(
+                    kbnf::grammar::CreateGrammarError::PreprocessingError(_)
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn prefill() {
+        let input = "start::=#'[0-9]+' ',' ('A'|'B');";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        for compaction_enabled in [false, true] {
+            let config = kbnf::config::Config {
+                engine_config: EngineConfig {
+                    cache_enabled: false,
+                    compaction_enabled,
+                    compaction_interval: 1,
+                    max_cache_entries: None,
+                    max_earley_set_size: None,
+                    max_total_items: None,
+                    deterministic: false,
+                },
+                ..Default::default()
+            };
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            let mut expected =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            assert_eq!(engine.prefill(b"12"), Ok(AcceptTokenResult::Ongoing));
+            expected.try_accept_new_bytes(b"12").unwrap();
+            assert_eq!(format!("{:?}", engine), format!("{:?}", expected));
+            // The whole prefill is reverted whether the last byte or an earlier one is rejected.
+            for bytes in [&b"3,C"[..], b"3C,A"] {
+                assert_eq!(engine.prefill(bytes), Err(AcceptTokenError::Rejected));
+                assert_eq!(format!("{:?}", engine), format!("{:?}", expected));
+            }
+            assert_eq!(engine.prefill(b"3,A"), Ok(AcceptTokenResult::Finished));
+            expected.try_accept_new_bytes(b"3,A").unwrap();
+            assert_eq!(format!("{:?}", engine), format!("{:?}", expected));
+            assert_eq!(engine.prefill(b"A"), Err(AcceptTokenError::Finished));
+            engine.rollback(1).unwrap();
+            assert_eq!(engine.accepted_bytes(), b"12");
+            assert_eq!(
+                engine.try_accept_new_bytes(b",B"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+    }
+
+    #[test]
+    fn grammar_sample() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // xorshift64 keeps the samples deterministic.
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut rng = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for (input, max_len) in [
+            ("start ::= (\"a\"|\"b\")+ \"c\";", 20),
+            ("start ::= '(' start ')' | 'x';", 9),
+        ] {
+            let config = kbnf::config::Config::default();
+            let regex_config = config.regex_config;
+            let syntax_grammar =
+                kbnf::utils::construct_kbnf_syntax_grammar(input, config.internal_config())
+                    .unwrap();
+            let grammar: kbnf::grammar::Grammar<u8> =
+                kbnf::grammar::Grammar::new(syntax_grammar, &vocab, regex_config).unwrap();
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            for _ in 0..100 {
+                let sample = grammar.sample(&mut rng, max_len).unwrap();
+                assert!(sample.len() <= max_len);
+                assert_eq!(
+                    engine.try_accept_new_bytes(&sample),
+                    Ok(AcceptTokenResult::Finished),
+                    "{}",
+                    String::from_utf8_lossy(&sample)
+                );
+                engine.reset();
+            }
+        }
+        // No limit on the length does not overflow.
+        let input = "start ::= 'a' ('b' | 'cd')?;";
+        let config = kbnf::config::Config::default();
+        let regex_config = config.regex_config;
+        let syntax_grammar =
+            kbnf::utils::construct_kbnf_syntax_grammar(input, config.internal_config()).unwrap();
+        let grammar: kbnf::grammar::Grammar<u8> =
+            kbnf::grammar::Grammar::new(syntax_grammar, &vocab, regex_config).unwrap();
+        for _ in 0..10 {
+            let sample = grammar.sample(&mut rng, usize::MAX).unwrap();
+            assert!([&b"a"[..], b"ab", b"acd"].contains(&sample.as_slice()));
+        }
+    }
+
+    #[test]
+    fn mask_logits_buffer() {
+        /// Every other logit of the buffer, like a strided view of a two-column array.
+        struct Strided<'a>(&'a mut [f32]);
+        impl kbnf::engine_like::LogitsMut for Strided<'_> {
+            fn len(&self) -> usize {
+                self.0.len().div_ceil(2)
+            }
+            fn get(&self, index: usize) -> f32 {
+                self.0[index * 2]
+            }
+            fn set_neg_infinity(&mut self, index: usize) {
+                self.0[index * 2] = f32::NEG_INFINITY;
+            }
+        }
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let vocab_size = vocab.vocab_size();
+        for input in ["start::='a';", "start::=#'[^0-9]+';"] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            engine.compute_allowed_token_ids();
+            let mut expected: Vec<f32> = (0..vocab_size + 10).map(|x| x as f32).collect();
+            engine.mask_logits(&mut expected).unwrap();
+            let mut interleaved: Vec<f32> = (0..(vocab_size + 10) * 2)
+                .map(|x| if x % 2 == 0 { (x / 2) as f32 } else { 1.0 })
+                .collect();
+            let mut strided = Strided(&mut interleaved);
+            assert_eq!(kbnf::engine_like::LogitsMut::get(&strided, 3), 3.0);
+            engine.mask_logits_buffer(&mut strided).unwrap();
+            for (i, logit) in interleaved.iter().copied().enumerate() {
+                if i % 2 == 0 {
+                    assert_eq!(logit, expected[i / 2]);
+                } else {
+                    assert_eq!(logit, 1.0);
+                }
+            }
+            assert_eq!(
+                engine.mask_logits_buffer(&mut Strided(&mut interleaved[..vocab_size])),
+                Err(kbnf::engine_like::MaskLogitsError::InvalidLogitsLength)
+            );
+            let mut logits: Vec<f32> = (0..vocab_size + 10).map(|x| x as f32).collect();
+            engine.mask_logits_buffer(&mut logits).unwrap();
+            assert_eq!(logits, expected);
+        }
+    }
+
+    #[test]
+    fn resource_limits() {
+        // Every split of the input is a derivation, so the Earley sets grow with the input.
+        let input = "start::=a ';'; a::=a a|'x';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        for (compaction_enabled, max_earley_set_size, max_total_items) in [
+            (false, Some(30), None),
+            (true, Some(30), None),
+            (false, None, Some(200)),
+            (true, None, Some(200)),
+        ] {
+            let config = kbnf::config::Config {
+                engine_config: EngineConfig {
+                    cache_enabled: false,
+                    compaction_enabled,
+                    compaction_interval: 1,
+                    max_cache_entries: None,
+                    max_earley_set_size,
+                    max_total_items,
+                    deterministic: false,
+                },
+                ..Default::default()
+            };
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            let mut accepted = 0;
+            let error = loop {
+                let state = format!("{:?}", engine);
+                match engine.try_accept_new_bytes(b"x") {
+                    Ok(_) => accepted += 1,
+                    Err(error) => {
+                        // The states are reverted, so nothing is accepted.
+                        assert_eq!(format!("{:?}", engine), state);
+                        break error;
+                    }
+                }
+                assert!(accepted < 100, "the limit is never exceeded");
+            };
+            assert_eq!(error, AcceptTokenError::ResourceLimitExceeded);
+            assert!(accepted > 0);
+            assert_eq!(engine.accepted_bytes(), "x".repeat(accepted).as_bytes());
+            let state = format!("{:?}", engine);
+            assert_eq!(
+                engine.try_accept_new_str("xx"),
+                Err(AcceptStrError::ResourceLimitExceeded)
+            );
+            assert_eq!(
+                engine.try_accept_new_token(get_token_id_from_str(&vocab, "x").unwrap()),
+                Err(AcceptTokenError::ResourceLimitExceeded)
+            );
+            assert_eq!(format!("{:?}", engine), state);
+            // The engine is still usable after the limit is exceeded.
+            engine.compute_allowed_token_ids();
+            assert!(
+                engine.is_finished()
+                    || !engine.allowed_token_ids_from_last_computation().is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn config_presets() {
+        let input = "start::=#'[a-z ]+' ',' (#'[0-9]+'|'none') ';';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut expected = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for config in [
+            kbnf::config::Config::for_large_vocab(),
+            kbnf::config::Config::low_memory(),
+            kbnf::config::Config::max_throughput(),
+        ] {
+            expected.reset();
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            for s in ["hello world", ",", "42", ";"] {
+                engine.compute_allowed_token_ids();
+                expected.compute_allowed_token_ids();
+                assert_eq!(
+                    engine.allowed_token_ids_from_last_computation(),
+                    expected.allowed_token_ids_from_last_computation()
+                );
+                engine.try_accept_new_str(s).unwrap();
+                expected.try_accept_new_str(s).unwrap();
+            }
+            assert!(engine.is_finished());
+        }
+    }
+
+    #[test]
+    fn deserialize_config_without_new_fields() {
+        // The fields added since the first release take their default values when they are missing.
+        let config: kbnf::config::Config = serde_json::from_str(
+            r#"{
+                "regex_config": {
+                    "max_memory_usage": null,
+                    "fsa_type": "Dfa",
+                    "min_tokens_required_for_eager_regex_cache": 1000
+                },
+                "engine_config": { "cache_enabled": true, "compaction_enabled": true },
+                "start_nonterminal": "start",
+                "expected_output_length": 4294967295,
+                "compression_config": { "min_terminals": 5 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config, kbnf::config::Config::default());
+        let serialized = serde_json::to_string(&config).unwrap();
+        assert_eq!(
+            serde_json::from_str::<kbnf::config::Config>(&serialized).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn accept_token_str() {
+        let tokens: AHashMap<u32, Token> =
+            [(1, &b"a"[..]), (2, b"hello"), (3, b" world"), (4, b"\n")]
+                .into_iter()
+                .map(|(k, v)| (k, Token(v.into())))
+                .collect();
+        // The token string of a byte-level BPE tokenizer differs from the bytes of the token.
+        let token_strings: AHashMap<u32, String> = [
+            (1, "a".to_string()),
+            (2, "hello".to_string()),
+            (3, "\u{120}world".to_string()),
+            (4, "\n".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let vocab = Vocabulary::new(tokens, token_strings).unwrap();
+        assert_eq!(vocab.token_id_from_str("hello"), Some(2));
+        assert_eq!(vocab.token_id_from_str(" world"), Some(3));
+        assert_eq!(vocab.token_id_from_str("\u{120}world"), Some(3));
+        assert_eq!(vocab.token_id_from_str("world"), None);
+        let mut engine =
+            kbnf::engine::Engine::new("start::='hello' ' world' '\n';", vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_token_str("hello"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_token_str("hi"),
+            Err(AcceptTokenError::UnknownTokenStr)
+        );
+        assert_eq!(
+            engine.try_accept_token_str("a"),
+            Err(AcceptTokenError::Rejected)
+        );
+        let mut logits = vec![0.0; vocab.vocab_size()];
+        assert_eq!(
+            engine.update_logits_for_token_str("hi", &mut logits),
+            Err(kbnf::engine_like::UpdateLogitsError::UnknownTokenStr)
+        );
+        assert_eq!(
+            engine.update_logits_for_token_str("\u{120}world", &mut logits),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(logits[4], 0.0);
+        assert_eq!(logits[2], f32::NEG_INFINITY);
+        assert_eq!(
+            engine.try_accept_token_str("\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(engine.accepted_bytes(), b"hello world\n");
+    }
+
+    #[test]
+    fn speculative_token() {
+        let input = "start::=#'[0-9]+' ',' ('A'|'B');";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            engine_config: EngineConfig {
+                cache_enabled: false,
+                compaction_enabled: true,
+                compaction_interval: 1,
+                max_cache_entries: None,
+                max_earley_set_size: None,
+                max_total_items: None,
+                deterministic: false,
+            },
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        engine.try_accept_new_bytes(b"1").unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation().clone();
+        let before = format!("{:?}", engine);
+        let comma = get_token_id_from_str(&vocab, ",").unwrap();
+        let mut token = engine.try_accept_new_token_speculative(comma).unwrap();
+        assert_eq!(token.result(), AcceptTokenResult::Ongoing);
+        token.compute_allowed_token_ids();
+        assert!(token
+            .engine()
+            .allowed_token_ids_from_last_computation()
+            .contains(get_token_id_from_str(&vocab, "A").unwrap() as usize));
+        token.abort();
+        assert_eq!(engine.allowed_token_ids_from_last_computation(), &allowed);
+        assert_eq!(format!("{:?}", engine), before);
+        // A rejected token returns no guard and changes nothing.
+        assert!(matches!(
+            engine.try_accept_new_token_speculative(get_token_id_from_str(&vocab, "A").unwrap()),
+            Err(AcceptTokenError::Rejected)
+        ));
+        assert_eq!(format!("{:?}", engine), before);
+        drop(engine.try_accept_new_token_speculative(comma).unwrap());
+        assert_eq!(format!("{:?}", engine), before);
+        let token = engine.try_accept_new_token_speculative(comma).unwrap();
+        assert_eq!(token.commit(), AcceptTokenResult::Ongoing);
+        assert_eq!(engine.accepted_bytes(), b"1,");
+        assert_eq!(
+            engine.try_accept_new_bytes(b"B"),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn json() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=#json '\\n';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert!(format!("{:?}", engine).contains("#json"));
+        let accepts = |engine: &mut kbnf::Engine, input: &[u8]| {
+            engine.reset();
+            engine.try_accept_new_bytes(input).is_ok() && engine.can_finish()
+        };
+        for (input, expected) in [
+            ("{}\n", true),
+            ("[]\n", true),
+            ("0\n", true),
+            ("-12.50e+3\n", true),
+            ("1E9\n", true),
+            ("true\n", true),
+            ("null\n", true),
+            ("\"\"\n", true),
+            (
+                "\"a\\\"b\\\\c\\/\\b\\f\\n\\r\\t\\u00e9\\uD83D\\uDE00\"\n",
+                true,
+            ),
+            ("\"你好🎉\"\n", true),
+            (
+                "{\"a\": [1, {\"b\": null}, [], {}], \"c\" :\t\"d\",\r\n\"e\": false}\n",
+                true,
+            ),
+            ("[[[[\"deep\"]]]]\n", true),
+            ("\"abc\n", false),
+            ("\"tab\tin string\"\n", false),
+            ("\"\\x\"\n", false),
+            ("\"\\u12g4\"\n", false),
+            ("[1,]\n", false),
+            ("[1 2]\n", false),
+            ("{\"a\": 1,}\n", false),
+            ("{\"a\" 1}\n", false),
+            ("{1: 1}\n", false),
+            ("[1}\n", false),
+            ("{\"a\": 1]\n", false),
+            ("[[]\n", false),
+            ("[]]\n", false),
+            ("01\n", false),
+            ("-\n", false),
+            ("1.\n", false),
+            (".5\n", false),
+            ("1e\n", false),
+            ("tru\n", false),
+            ("nul1\n", false),
+            (" 1\n", true),
+            ("1 \n", true),
+            (" \t{\"a\": 1} \r\n", true),
+            ("1 2\n", false),
+            ("\n", false),
+        ] {
+            assert_eq!(
+                accepts(&mut engine, input.as_bytes()),
+                expected,
+                "{input:?}"
+            );
+        }
+        let nested = |depth: usize| format!("{}{}\n", "[".repeat(depth), "]".repeat(depth));
+        assert!(accepts(&mut engine, nested(24).as_bytes()));
+        assert!(!accepts(&mut engine, nested(25).as_bytes()));
+        // Only the bytes that can continue the value are allowed.
+        engine.reset();
+        engine.try_accept_new_bytes(b"{\"a\": [1").unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_first_bytes();
+        for byte in b"0123456789.eE,] " {
+            assert!(allowed.contains(*byte as usize), "{}", *byte as char);
+        }
+        for byte in b"}\"a" {
+            assert!(!allowed.contains(*byte as usize), "{}", *byte as char);
+        }
+        assert!(engine
+            .allowed_token_ids_from_last_computation()
+            .contains(get_token_id_from_str(&vocab, "]}").unwrap() as usize));
+        assert_eq!(
+            engine.try_accept_new_token(get_token_id_from_str(&vocab, "]}").unwrap()),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn engine_grammar() {
+        let input = "start::=#'[0-9]+' ',' ('A'|'B');";
+        let vocab = Arc::new(read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap());
+        let mut engine = kbnf::engine::Engine::new(input, (*vocab).clone()).unwrap();
+        let kbnf::engine::EngineGrammar::U16(grammar) = engine.grammar() else {
+            panic!("the grammar should use u16 IDs");
+        };
+        assert_eq!(
+            grammar.nonterminal_str(grammar.get_start_nonterminal_id()),
+            Some("start")
+        );
+        let mut base = kbnf::engine_base::EngineBase::<u16, u16, u32, u32, u32>::new(
+            vocab,
+            grammar.clone(),
+            kbnf::config::Config::default().engine_config,
+        )
+        .unwrap();
+        // The new engine shares the compiled grammar instead of recompiling it.
+        assert!(Arc::ptr_eq(&base.grammar(), &grammar));
+        for bytes in [&b"12"[..], b",", b"B"] {
+            engine.compute_allowed_token_ids();
+            base.compute_allowed_token_ids();
+            assert_eq!(
+                engine.allowed_token_ids_from_last_computation(),
+                base.allowed_token_ids_from_last_computation()
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(bytes),
+                base.try_accept_new_bytes(bytes)
+            );
+        }
+        assert!(base.is_finished());
+    }
+
+    #[test]
+    fn implicit_whitespace() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            implicit_whitespace: Some("[ \\t\\n]".to_string()),
+            ..Default::default()
+        };
+        let accepts = |engine: &mut kbnf::Engine, input: &[u8]| {
+            engine.reset();
+            engine.try_accept_new_bytes(input).is_ok() && engine.can_finish()
+        };
+        let input = "start ::= \"a\" \"b\";";
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        let mut plain = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for (input, expected) in [
+            (b"ab".as_slice(), true),
+            (b"a  \n b", true),
+            (b"a\tb", true),
+            (b" ab", false),
+            (b"ab ", false),
+            (b"a \r b", false),
+        ] {
+            assert_eq!(accepts(&mut engine, input), expected, "{input:?}");
+            assert_eq!(accepts(&mut plain, input), input == b"ab", "{input:?}");
+        }
+        // The whitespace is skipped between the repetitions and inside the nested nonterminals,
+        // but never inside a terminal.
+        let input = "start ::= item+ ';'; item ::= 'x' ('=' 'yz')?;";
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        for (input, expected) in [
+            (b"x;".as_slice(), true),
+            (b"x x = yz\nx;", true),
+            (b"x =yz ;", true),
+            (b"x = y z;", false),
+            (b"x x", false),
+        ] {
+            assert_eq!(accepts(&mut engine, input), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn allowed_token_ids_snapshot() {
+        let input = "start::=('a'|'b'|'c')+ ';';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let snapshot = engine.allowed_token_ids_snapshot();
+        assert_eq!(snapshot.len(), engine.allowed_token_count());
+        assert!(!snapshot.is_empty());
+        assert!(snapshot
+            .iter()
+            .map(|x| x as usize)
+            .eq(engine.allowed_token_ids_from_last_computation().ones()));
+        let a = get_token_id_from_str(&vocab, "a").unwrap();
+        assert!(snapshot.contains(a));
+        assert!(!snapshot.contains(get_token_id_from_str(&vocab, ";").unwrap()));
+        assert!(!snapshot.contains(u32::MAX));
+        // The snapshot does not borrow the engine, so the engine can accept tokens while iterating it.
+        let mut accepted = 0;
+        for token_id in &snapshot {
+            if engine.try_accept_new_token(token_id).is_ok() {
+                accepted += 1;
+            }
+            engine.reset();
+        }
+        assert_eq!(accepted, snapshot.len());
+        engine.try_accept_new_token(a).unwrap();
+        engine.compute_allowed_token_ids();
+        assert_ne!(engine.allowed_token_ids_snapshot(), snapshot);
+        assert!(snapshot.into_iter().any(|x| x == a));
+    }
+
+    #[test]
+    fn normalize_newlines() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            normalize_newlines: true,
+            ..Default::default()
+        };
+        let accepts = |engine: &mut kbnf::Engine, input: &[u8]| {
+            engine.reset();
+            engine.try_accept_new_bytes(input).is_ok() && engine.can_finish()
+        };
+        let input = "start ::= \"abc\\n\" #\"x\\n+y\" '\\n';";
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        let mut plain = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for (input, expected) in [
+            (b"abc\nx\ny\n".as_slice(), true),
+            (b"abc\r\nx\r\n\r\ny\r\n", true),
+            (b"abc\rx\r\r\ny\r", true),
+            (b"abc\r\rx\ny\n", false),
+            (b"abc\n\rx\ny\n", false),
+            (b"abc\r\nxy\r\n", false),
+        ] {
+            assert_eq!(accepts(&mut engine, input), expected, "{input:?}");
+            assert_eq!(
+                accepts(&mut plain, input),
+                !input.contains(&b'\r') && expected,
+                "{input:?}"
+            );
+        }
+        // The accepted bytes are kept as they are.
+        engine.reset();
+        engine.try_accept_new_bytes(b"abc\r").unwrap();
+        engine.compute_allowed_token_ids();
+        for token in ["\n", "x"] {
+            assert!(
+                engine
+                    .allowed_token_ids_from_last_computation()
+                    .contains(get_token_id_from_str(&vocab, token).unwrap() as usize),
+                "{token:?}"
+            );
+        }
+        assert!(!engine
+            .allowed_token_ids_from_last_computation()
+            .contains(get_token_id_from_str(&vocab, "\r").unwrap() as usize));
+        engine
+            .try_accept_new_token(get_token_id_from_str(&vocab, "\n").unwrap())
+            .unwrap();
+        assert_eq!(engine.accepted_bytes(), b"abc\r\n");
+        // The newlines in character classes are not affected.
+        let mut engine =
+            kbnf::engine::Engine::with_config("start ::= #\"[\\n]\" 'a';", vocab.clone(), config)
+                .unwrap();
+        assert!(accepts(&mut engine, b"\na"));
+        assert!(!accepts(&mut engine, b"\ra"));
+    }
+    #[test]
+    fn dedupe_equivalent_regexes() {
+        let input = r#"start::=#"[0-9]+" 'a' | #'[0-9]+' 'b' | #"[0-9][0-9]*" 'c';"#;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let kbnf::engine::EngineGrammar::U16(grammar) = engine.grammar() else {
+            panic!("the grammar should use u16 IDs");
+        };
+        let regex_ids: std::collections::HashSet<_> = (0..3u16)
+            .map(|production| {
+                match grammar.node(grammar.get_start_nonterminal_id(), 0u16, production) {
+                    kbnf::grammar::HIRNode::RegexString(id) => *id,
+                    node => panic!("unexpected node {node:?}"),
+                }
+            })
+            .collect();
+        // The equivalent regexes share one DFA.
+        assert_eq!(regex_ids.len(), 1);
+        let regex_id = regex_ids.into_iter().next().unwrap();
+        assert!(matches!(
+            grammar.regex_str(regex_id),
+            Some("\\A(?:[0-9]+)\\z" | "\\A(?:[0-9][0-9]*)\\z")
+        ));
+        assert_eq!(
+            engine.try_accept_new_token(get_token_id_from_str(&vocab, "12").unwrap()),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        engine.compute_allowed_token_ids();
+        for token in ["a", "b", "c", "3"] {
+            let token_id = get_token_id_from_str(&vocab, token).unwrap();
+            assert!(engine
+                .allowed_token_ids_from_last_computation()
+                .contains(token_id as usize));
+        }
+    }
+    #[test]
+    fn try_accept_new_tokens() {
+        let input = "start::='abcd' 'e';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let token_ids: Vec<u32> = ["ab", "cd", "bad"]
+            .into_iter()
+            .map(|token| get_token_id_from_str(&vocab, token).unwrap())
+            .collect();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation().clone();
+        let fresh = engine.clone();
+        assert_eq!(
+            engine.try_accept_new_tokens(&token_ids),
+            Err((2, AcceptTokenError::Rejected))
+        );
+        // The engine reverts to the start state, including the allowed token IDs.
+        assert_eq!(engine.allowed_token_ids_from_last_computation(), &allowed);
+        assert_eq!(format!("{engine:?}"), format!("{fresh:?}"));
+        assert_eq!(
+            engine.try_accept_new_tokens(&[]),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_tokens(&token_ids[..2]),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_tokens(&[get_token_id_from_str(&vocab, "e").unwrap()]),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+    #[test]
+    fn token_trie_traversal() {
+        let mut vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let next_token_id = vocab.vocab_size() as u32;
+        // The added tokens share prefixes with each other and the existing tokens, and some contain 0xFF.
+        for (i, token) in [&b"abcdef"[..], b"abcdeg", b"abc\xFF", b"\xFFabc", b"abc"]
+            .into_iter()
+            .enumerate()
+        {
+            vocab
+                .add_token(
+                    next_token_id + i as u32,
+                    Token(token.into()),
+                    String::from_utf8_lossy(token).into_owned(),
+                )
+                .unwrap();
+        }
+        let config = kbnf::config::Config {
+            regex_config: kbnf::config::RegexConfig {
+                min_tokens_required_for_eager_regex_cache: None,
+                ..kbnf::config::Config::default().regex_config
+            },
+            ..Default::default()
+        };
+        for input in [
+            "start::=#'[a-f]+' '\\n';",
+            "start::='abcde' ('f'|'g'|'h') 'x'|'ab' 'cd';",
+            "start::=#ex'abc|b' '\\n';",
+        ] {
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            engine.compute_allowed_token_ids();
+            let allowed: Vec<usize> = engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect();
+            // Every token is accepted one by one and reverted to find the allowed tokens.
+            let expected: Vec<usize> = (0..vocab.vocab_size() as u32)
+                .filter(|&token_id| engine.try_accept_new_token_speculative(token_id).is_ok())
+                .map(|token_id| token_id as usize)
+                .collect();
+            assert_eq!(allowed, expected, "{input}");
+            assert_eq!(
+                engine.allowed_token_ids_until(3),
+                expected[..3].iter().map(|&x| x as u32).collect::<Vec<_>>()
+            );
+        }
+    }
+    #[test]
+    fn nullability_and_first_bytes() {
+        let input = r#"start ::= "a"? "b" x; x ::= "c"? | #"[0-9]+";"#;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let engine = kbnf::engine::Engine::new(input, vocab).unwrap();
+        let kbnf::engine::EngineGrammar::U16(grammar) = engine.grammar() else {
+            panic!("the grammar should use u16 IDs");
+        };
+        let nonterminal = |name: &str| {
+            (0..grammar.nonterminals_size())
+                .map(|x| kbnf::grammar::NonterminalID(x as u16))
+                .find(|&x| grammar.nonterminal_str(x) == Some(name))
+                .unwrap()
+        };
+        let first_bytes = |name: &str| {
+            grammar
+                .first_bytes_of_nonterminal(nonterminal(name))
+                .ones()
+                .map(|x| x as u8 as char)
+                .collect::<String>()
+        };
+        assert!(!grammar.is_nullable(nonterminal("start")));
+        assert_eq!(first_bytes("start"), "ab");
+        assert!(grammar.is_nullable(nonterminal("x")));
+        assert_eq!(first_bytes("x"), "0123456789c");
+        let deserialized = kbnf::grammar::Grammar::<u16>::from_bytes(&grammar.to_bytes()).unwrap();
+        for name in ["start", "x"] {
+            let nonterminal = nonterminal(name);
+            assert_eq!(
+                deserialized.is_nullable(nonterminal),
+                grammar.is_nullable(nonterminal)
+            );
+            assert_eq!(
+                deserialized.first_bytes_of_nonterminal(nonterminal),
+                grammar.first_bytes_of_nonterminal(nonterminal)
+            );
+        }
+    }
+    #[test]
+    fn prefilter_regex() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // A necessary condition of the grammar prunes the bytes without changing the allowed tokens.
+        let input = r#"start ::= "[" num ("," num)* "]"; num ::= #"[0-9]+";"#;
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let config = kbnf::config::Config {
+            prefilter_regex: Some(r"\[[0-9,]*\]?".to_string()),
+            ..Default::default()
+        };
+        let mut prefiltered =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        for token in ["[", "12", ",", "3", "]"] {
+            engine.compute_allowed_token_ids();
+            prefiltered.compute_allowed_token_ids();
+            assert_eq!(
+                engine.allowed_token_ids_from_last_computation(),
+                prefiltered.allowed_token_ids_from_last_computation()
+            );
+            let token_id = get_token_id_from_str(&vocab, token).unwrap();
+            assert_eq!(
+                engine.try_accept_new_token(token_id),
+                prefiltered.try_accept_new_token(token_id)
+            );
+        }
+        assert!(prefiltered.is_finished());
+        // A stricter regex rejects the bytes that the grammar accepts.
+        let input = r#"start ::= #"[a-z]+" "\n";"#;
+        let config = kbnf::config::Config {
+            prefilter_regex: Some("[a-c]*".to_string()),
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        let allowed_token_ids = engine.allowed_token_ids_until(usize::MAX);
+        engine.compute_allowed_token_ids();
+        let allowed: Vec<u32> = engine
+            .allowed_token_ids_from_last_computation()
+            .ones()
+            .map(|x| x as u32)
+            .collect();
+        assert_eq!(allowed, allowed_token_ids);
+        assert!(!allowed.is_empty());
+        for token_id in allowed {
+            let token = vocab.token(token_id).unwrap();
+            assert!(token.0.iter().all(|byte| (b'a'..=b'c').contains(byte)));
+        }
+        assert_eq!(
+            engine.allowed_first_bytes().ones().collect::<Vec<_>>(),
+            vec![b'a' as usize, b'b' as usize, b'c' as usize]
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"abd"),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\n"),
+            Err(AcceptTokenError::Rejected)
+        );
+        // The state of the prefilter is restored with the Earley sets.
+        {
+            let mut transaction = engine.transaction();
+            transaction.try_accept_new_bytes(b"c").unwrap();
+        }
+        engine.rollback(1).unwrap();
+        assert_eq!(
+            engine.shortest_completion(4),
+            None,
+            "the newline is never accepted by the prefilter"
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"cab"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"d"),
+            Err(AcceptTokenError::Rejected)
+        );
+        let config = kbnf::config::Config {
+            prefilter_regex: Some("[a-".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            kbnf::engine::Engine::with_config(input, vocab, config),
+            Err(kbnf::engine::CreateEngineError::InvalidPrefilterRegexError(
+                _
+            ))
+        ));
+    }
+    #[test]
+    fn to_kbnf_source_round_trip() {
+        let input = r#"
+start ::= item+ (#json | #char "!")? "\n";
+item ::= ("\"q\\" | #"[0-9]+" | #e"[a-c]+;" | "~" #ex"x|yy" | #substrs"hello" | #substrs(2,3)"world"
+    | #lenprefixed(#"[0-9]+", #"[a-z]*") | #'[^;x]' ";" | "\xFF"{2}) " "?;
+"#;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let source = match engine.grammar() {
+            kbnf::engine::EngineGrammar::U8(grammar) => grammar.to_kbnf_source(),
+            kbnf::engine::EngineGrammar::U16(grammar) => grammar.to_kbnf_source(),
+        };
+        let mut round_trip = kbnf::engine::Engine::new(&source, vocab).unwrap();
+        let mut engine = engine;
+        let inputs: [&[u8]; 14] = [
+            b"\"q\\\n",
+            b"123 abc;\n",
+            b"zz hello ell wor!\n",
+            b"~ab\n",
+            b"~ayy\n",
+            b"3abc a;\n",
+            b"q;\n",
+            b"x;\n",
+            b"\xFF\xFF\n",
+            b"\xFF\n",
+            b"12[1,2]\n",
+            b"12\xC3\xA9!\n",
+            b"wo\n",
+            b"w\n",
+        ];
+        for input in inputs {
+            let mut result = vec![];
+            for engine in [&mut engine, &mut round_trip] {
+                engine.reset();
+                let accepted = engine.try_accept_new_bytes(input);
+                result.push((format!("{accepted:?}"), engine.can_finish()));
+            }
+            assert_eq!(result[0], result[1], "{}", String::from_utf8_lossy(input));
+        }
+    }
+    #[test]
+    fn sparse_token_ids() {
+        let token_strings: AHashMap<u32, String> = [
+            (0, "a".to_string()),
+            (2, "b".to_string()),
+            (5, "ab".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let tokens = token_strings
+            .iter()
+            .map(|(k, v)| (*k, Token(v.as_bytes().to_vec().into_boxed_slice())))
+            .collect::<AHashMap<u32, _>>();
+        let vocab = Vocabulary::new(tokens, token_strings).unwrap();
+        assert_eq!(vocab.vocab_size(), 6);
+        assert_eq!(vocab.token(3), None);
+        let mut engine = kbnf::engine::Engine::new("start ::= #\"[ab]+\" \"\\n\";", vocab).unwrap();
+        for token_id in [None, Some(0), Some(5)] {
+            if let Some(token_id) = token_id {
+                engine.try_accept_new_token(token_id).unwrap();
+            }
+            engine.compute_allowed_token_ids();
+            assert_eq!(
+                engine
+                    .allowed_token_ids_from_last_computation()
+                    .ones()
+                    .collect::<Vec<_>>(),
+                vec![0, 2, 5]
+            );
+            let mut logits = [0.0; 6];
+            engine.mask_logits(&mut logits).unwrap();
+            for token_id in [1, 3, 4] {
+                assert_eq!(logits[token_id], f32::NEG_INFINITY);
+            }
+            for token_id in [0, 2, 5] {
+                assert_eq!(logits[token_id], 0.0);
+            }
+            assert!(engine.mask_logits(&mut [0.0; 5]).is_err());
+        }
+        assert!(matches!(
+            engine.try_accept_new_token(3),
+            Err(AcceptTokenError::UnknownTokenID)
+        ));
+    }
+    #[test]
+    fn compute_allowed_token_ids_deadline() {
+        use kbnf::engine_like::ComputeOutcome;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = r#"start ::= #"[a-z]+" "\n";"#;
+        let mut reference = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        reference.compute_allowed_token_ids();
+        let expected = reference.allowed_token_ids_from_last_computation().clone();
+        // The eager regex cache would allow the tokens before the deadline is checked.
+        let config = kbnf::config::Config {
+            regex_config: kbnf::config::RegexConfig {
+                min_tokens_required_for_eager_regex_cache: None,
+                ..kbnf::config::Config::default().regex_config
+            },
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab, config).unwrap();
+        // The deadline has already passed, so nothing is tested and the partial result is not cached.
+        for _ in 0..2 {
+            let outcome = engine.compute_allowed_token_ids_deadline(std::time::Instant::now());
+            assert_eq!(outcome, ComputeOutcome::TimedOut);
+            let allowed = engine.allowed_token_ids_from_last_computation();
+            assert!(allowed.is_subset(&expected));
+            assert!(allowed.count_ones(..) < expected.count_ones(..));
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(3600);
+        assert_eq!(
+            engine.compute_allowed_token_ids_deadline(deadline),
+            ComputeOutcome::Complete
+        );
+        assert_eq!(engine.allowed_token_ids_from_last_computation(), &expected);
+        // The complete result is cached, which is used even if the deadline has passed.
+        assert_eq!(
+            engine.compute_allowed_token_ids_deadline(std::time::Instant::now()),
+            ComputeOutcome::Complete
+        );
+        assert_eq!(engine.allowed_token_ids_from_last_computation(), &expected);
+    }
+    #[test]
+    fn regex_continuation_and_early_end() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let b = get_token_id_from_str(&vocab, "b").unwrap() as usize;
+        let exclamation = get_token_id_from_str(&vocab, "!").unwrap() as usize;
+        let mut normal =
+            kbnf::engine::Engine::new(r#"start ::= #"(.|\n)*\n\n" "!";"#, vocab.clone()).unwrap();
+        let mut early =
+            kbnf::engine::Engine::new(r#"start ::= #e"(.|\n)*\n\n" "!";"#, vocab.clone()).unwrap();
+        // A single newline is not a match, so both regexes continue.
+        for engine in [&mut normal, &mut early] {
+            assert_eq!(
+                engine.try_accept_new_bytes(b"a\nb\n\n!"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        // At the first match, the normal regex is both continued and completed,
+        // while the early-end regex is only completed.
+        for engine in [&mut normal, &mut early] {
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"a\n\n"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            engine.compute_allowed_token_ids();
+            assert!(engine
+                .allowed_token_ids_from_last_computation()
+                .contains(exclamation));
+        }
+        assert!(normal.allowed_token_ids_from_last_computation().contains(b));
+        assert!(!early.allowed_token_ids_from_last_computation().contains(b));
+        // So an interior "\n\n" is only accepted by the normal regex.
+        assert_eq!(
+            normal.try_accept_new_bytes(b"b\n\n!"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(
+            early.try_accept_new_bytes(b"b"),
+            Err(AcceptTokenError::Rejected)
+        );
+        // `.` does not match a newline, so neither regex can continue after "\n\n".
+        for input in [
+            r#"start ::= #".*\n\n" "!";"#,
+            r#"start ::= #e".*\n\n" "!";"#,
+        ] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"a\n\n"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"b"),
+                Err(AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"!"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        // The engine finishes as soon as the start nonterminal matches,
+        // so a regex at the end of the grammar ends at its first match either way.
+        for input in [
+            r#"start ::= #"(.|\n)*\n\n";"#,
+            r#"start ::= #e"(.|\n)*\n\n";"#,
+        ] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"a\n\n"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"b"),
+                Err(AcceptTokenError::Finished)
+            );
+        }
+    }
+
+    #[test]
+    fn parameterized_rules() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = r#"
+start ::= pair<digit, letter> ";" pair<letter, list<digit>> "\n";
+pair<A, B> ::= A "," B;
+list<T> ::= T | T "+" list<T>;
+digit ::= #"[0-9]";
+letter ::= #"[a-z]";
+"#;
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        // Drive the engine token by token like a generation loop.
+        for token in ["1", ",", "a", ";", "b", ",", "2", "+", "3", "\n"] {
+            let token_id = get_token_id_from_str(&vocab, token).unwrap();
+            engine.compute_allowed_token_ids();
+            assert!(engine
+                .allowed_token_ids_from_last_computation()
+                .contains(token_id as usize));
+            engine.try_accept_new_token(token_id).unwrap();
+        }
+        assert!(engine.is_finished());
+        for rejected in ["a,", "1,a;1", "1,a;b,c", "1,a;b,2+\n"] {
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(rejected.as_bytes()),
+                Err(AcceptTokenError::Rejected),
+                "{rejected}"
+            );
+        }
+        for input in [
+            // The argument grows on every recursion, so the expansion never ends.
+            "start ::= nested<digit>; nested<T> ::= T | nested<pair<T, T>>; pair<A, B> ::= A B; digit ::= '0';",
+            "start ::= pair<digit>; pair<A, B> ::= A B; digit ::= '0';",
+            "start ::= digit<digit>; digit ::= '0';",
+            "start ::= pair<digit, digit; pair<A, B> ::= A B; digit ::= '0';",
+            "start ::= pair<digit, digit>; pair<A, A> ::= A A; digit ::= '0';",
+            "start ::= w<'1'>; w<T> ::= T;",
+            "start ::= pair<digit, \"1\">; pair<A, B> ::= A B; digit ::= '0';",
+        ] {
+            assert!(
+                matches!(
+                    kbnf::engine::Engine::new(input, vocab.clone()),
+                    Err(kbnf::engine::CreateEngineError::GrammarError(
+                        kbnf::grammar::CreateGrammarError::PreprocessingError(_)
+                    ))
+                ),
+                "{input}"
+            );
+        }
+        // The literal arguments are reported as such rather than as unclosed arguments.
+        let error = kbnf::engine::Engine::new("start ::= w<'1'>; w<T> ::= T;", vocab.clone())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("the argument '1' of w is not a nonterminal."),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn is_dead() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine =
+            kbnf::engine::Engine::new(r#"start ::= "ab" | "abc";"#, vocab.clone()).unwrap();
+        assert!(!engine.is_dead());
+        // Neither finished nor able to finish, but still alive as "b" is required.
+        engine.try_accept_new_bytes(b"a").unwrap();
+        assert!(!engine.is_finished());
+        assert!(!engine.can_finish());
+        assert!(!engine.is_dead());
+        // A rejected byte leaves the engine alive.
+        assert_eq!(
+            engine.try_accept_new_bytes(b"c"),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert!(!engine.is_dead());
+        // Finished, while "c" could still follow in the states.
+        assert_eq!(
+            engine.try_accept_new_bytes(b"b"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(!engine.is_dead());
+        let mut engine = kbnf::engine::Engine::new(r#"start ::= "ab";"#, vocab).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.is_finished());
+        assert!(engine.is_dead());
+        engine.reset();
+        assert!(!engine.is_dead());
+    }
+
+    #[test]
+    fn deterministic() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        // The ambiguity puts many items into the same Earley sets.
+        let input = "start ::= x y | y x | x x | a; a ::= x a | a x | x; x ::= 'a' | 'a' 'a' | b; b ::= 'a' | c; c ::= 'a'; y ::= 'a' | x;";
+        let mut config = kbnf::config::Config::default();
+        config.engine_config.cache_enabled = false;
+        config.engine_config.compaction_enabled = false;
+        config.engine_config.deterministic = true;
+        let outputs: Vec<_> = (0..4)
+            .map(|_| {
+                let mut engine =
+                    kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone())
+                        .unwrap();
+                engine.try_accept_new_bytes(b"aa").unwrap();
+                engine.compute_allowed_token_ids();
+                engine.compute_allowed_token_ids();
+                format!("{engine:#?}")
+            })
+            .collect();
+        assert!(outputs.iter().all(|x| *x == outputs[0]));
+    }
+
+    #[test]
+    fn in_progress_items() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(
+            r#"start ::= "Hello, World!\n" | number "\n"; number ::= #"[0-9]+";"#,
+            vocab.clone(),
+        )
+        .unwrap();
+        // The freshly predicted nodes have not matched any bytes.
+        assert!(engine.in_progress_items().is_empty());
+        let token_id = get_token_id_from_str(&vocab, "Hel").unwrap();
+        engine.try_accept_new_token(token_id).unwrap();
+        let items = engine.in_progress_items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].nonterminal, "start");
+        assert_eq!(items[0].kind, kbnf::engine_like::NodeKind::Terminal);
+        assert_eq!(items[0].state_id, 3);
+        engine.try_accept_new_bytes(b"lo, World!").unwrap();
+        assert_eq!(engine.in_progress_items()[0].state_id, 13);
+        engine.reset();
+        engine.try_accept_new_bytes(b"12").unwrap();
+        let items = engine.in_progress_items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].kind, kbnf::engine_like::NodeKind::Regex);
+        let state_id = items[0].state_id;
+        engine.try_accept_new_bytes(b"3").unwrap();
+        assert_eq!(engine.in_progress_items()[0].state_id, state_id);
+        // No node is in the middle of matching after the whole output is matched.
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.in_progress_items().is_empty());
+    }
+
+    #[test]
+    fn vocabulary_duplicated_tokens() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        for token_id in 1..vocab.vocab_size() as u32 {
+            let token = vocab.token(token_id).unwrap();
+            assert_eq!(vocab.token_id(token), Some(token_id));
+        }
+        // The smallest ID is matched to a duplicated token.
+        let token_strings: AHashMap<u32, String> = [(0, "a"), (1, "b"), (2, "a")]
+            .into_iter()
+            .map(|(k, v)| (k, v.to_string()))
+            .collect();
+        let tokens = token_strings
+            .iter()
+            .map(|(k, v)| (*k, Token(v.as_bytes().to_vec().into_boxed_slice())))
+            .collect::<AHashMap<u32, _>>();
+        let vocab = Vocabulary::new(tokens, token_strings).unwrap();
+        assert_eq!(
+            vocab.token_id(&Token(b"a".to_vec().into_boxed_slice())),
+            Some(0)
+        );
+        assert_eq!(
+            vocab.token(2),
+            Some(&Token(b"a".to_vec().into_boxed_slice()))
+        );
+        assert_eq!(
+            vocab.token_id(&Token(b"c".to_vec().into_boxed_slice())),
+            None
+        );
+    }
+
+    #[test]
+    fn max_recursion_depth() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            max_recursion_depth: Some(3),
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(
+            r#"start ::= ("{" start "}")?;"#,
+            vocab.clone(),
+            config.clone(),
+        )
+        .unwrap();
+        engine.try_accept_new_bytes(b"{{{").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"{"),
+            Err(AcceptTokenError::Rejected)
+        );
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(!allowed.contains(get_token_id_from_str(&vocab, "{").unwrap() as usize));
+        assert!(allowed.contains(get_token_id_from_str(&vocab, "}").unwrap() as usize));
+        assert_eq!(
+            engine.try_accept_new_bytes(b"}}}"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // The alternations that do not nest, like `'x'`, add no depth, so the brackets nest as deep as the braces.
+        let accepts = |grammar: &str, input: &[u8]| {
+            let mut engine =
+                kbnf::engine::Engine::with_config(grammar, vocab.clone(), config.clone()).unwrap();
+            engine.try_accept_new_bytes(input).is_ok() && engine.can_finish()
+        };
+        let json = "start ::= value; value ::= obj | 'x'; obj ::= '[' value ']';";
+        assert!(accepts(json, b"[[[x]]]"));
+        assert!(!accepts(json, b"[[[[x]]]]"));
+        let json = "start ::= value; value ::= arr | 'x'; arr ::= '[' (value (',' value)*)? ']';";
+        assert!(accepts(json, b"[[[x,x],[]],x]"));
+        assert!(accepts(json, b"[[[]]]"));
+        assert!(!accepts(json, b"[[[[]]]]"));
+        assert!(!accepts(json, b"[[[[x]]]]"));
+        // The elements of a list nest one level deeper than the list, however long the list is.
+        let mut engine = kbnf::engine::Engine::with_config(
+            r#"start ::= "[" (start ("," start)*)? "]" | "a";"#,
+            vocab.clone(),
+            config.clone(),
+        )
+        .unwrap();
+        engine
+            .try_accept_new_bytes(b"[a,[a,a,a],[[],a],a,a")
+            .unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"[[["),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"]"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // The recursion through multiple nonterminals is bounded as well.
+        let grammar = r#"start ::= "(" x; x ::= start ")" | "a)";"#;
+        let mut engine =
+            kbnf::engine::Engine::with_config(grammar, vocab.clone(), config.clone()).unwrap();
+        engine.try_accept_new_bytes(b"(((").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"("),
+            Err(AcceptTokenError::Rejected)
+        );
+        assert!(accepts(grammar, b"(((a)))"));
+        // The right recursion is not bounded.
+        let mut engine = kbnf::engine::Engine::with_config(
+            r#"start ::= "a" start | "b";"#,
+            vocab.clone(),
+            config,
+        )
+        .unwrap();
+        engine.try_accept_new_bytes(b"aaaaaaaa").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"b"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let config = kbnf::config::Config {
+            max_recursion_depth: Some(1),
+            ..Default::default()
+        };
+        assert!(kbnf::engine::Engine::with_config(
+            r#"start ::= "(" x ")"; x ::= "[" start "]" | "a";"#,
+            vocab.clone(),
+            config.clone(),
+        )
+        .is_ok());
+        assert!(matches!(
+            kbnf::engine::Engine::with_config(
+                r#"start ::= "(" x ")"; x ::= "[" start "]" | "[]";"#,
+                vocab,
+                config,
+            ),
+            Err(kbnf::engine::CreateEngineError::GrammarError(
+                kbnf::grammar::CreateGrammarError::RecursionTooDeepError(1)
+            ))
+        ));
+    }
+
+    #[test]
+    fn from_grammar() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine =
+            kbnf::engine::Engine::new(r#"start ::= "true" | "false" | "null";"#, vocab.clone())
+                .unwrap();
+        let grammar = engine.grammar();
+        let config = kbnf::config::Config {
+            eos_token_id: Some(0),
+            ..Default::default()
+        };
+        let mut first =
+            kbnf::engine::Engine::from_grammar(grammar.clone(), vocab.clone(), config.clone())
+                .unwrap();
+        let mut second =
+            kbnf::engine::Engine::from_grammar(grammar, vocab.clone(), config).unwrap();
+        // The engines share the compiled grammar instead of compiling it again.
+        let kbnf::engine::EngineGrammar::U16(grammar) = engine.grammar() else {
+            panic!("the grammar should use u16 ids");
+        };
+        for engine in [&first, &second] {
+            let kbnf::engine::EngineGrammar::U16(x) = engine.grammar() else {
+                panic!("the grammar should use u16 ids");
+            };
+            assert!(Arc::ptr_eq(&grammar, &x));
+        }
+        engine.compute_allowed_token_ids();
+        first.compute_allowed_token_ids();
+        second.compute_allowed_token_ids();
+        assert_eq!(
+            first.allowed_token_ids_from_last_computation(),
+            engine.allowed_token_ids_from_last_computation()
+        );
+        assert_eq!(
+            first.allowed_token_ids_from_last_computation(),
+            second.allowed_token_ids_from_last_computation()
+        );
+        first.try_accept_new_bytes(b"true").unwrap();
+        assert_eq!(
+            first.try_accept_new_token(0),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // The engines do not share their states.
+        assert_eq!(
+            second.try_accept_new_bytes(b"nu"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        // A deserialized grammar works as well.
+        let grammar = kbnf::grammar::Grammar::<u16>::from_bytes(&grammar.to_bytes()).unwrap();
+        let mut engine = kbnf::engine::Engine::from_grammar(
+            kbnf::engine::EngineGrammar::U16(Arc::new(grammar)),
+            vocab,
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"false"),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn finished_is_terminal() {
+        let input = "start::='a' 'b';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let a = get_token_id_from_str(&vocab, "a").unwrap();
+        let b = get_token_id_from_str(&vocab, "b").unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.try_accept_new_token(a).unwrap();
+        assert_eq!(
+            engine.try_accept_new_token(b),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(
+            engine.try_accept_new_token(a),
+            Err(AcceptTokenError::Finished)
+        );
+        let mut config = kbnf::config::Config {
+            finished_is_terminal: false,
+            ..Default::default()
+        };
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        engine.try_accept_new_token(a).unwrap();
+        assert_eq!(
+            engine.try_accept_new_token(b),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let bytes = engine.accepted_bytes().to_vec();
+        // The accepts after finishing are no-ops, even if the token is rejected by the grammar.
+        assert_eq!(
+            engine.try_accept_new_token(a),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"b"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(
+            engine.try_accept_new_str("ab"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert_eq!(
+            engine.try_accept_new_tokens(&[a, b]),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(engine.is_finished());
+        assert_eq!(engine.accepted_bytes(), bytes);
+        // Every logit is masked without the EOS token ID.
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        assert_eq!(
+            engine.update_logits(a, &mut logits),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(logits.iter().all(|x| *x == f32::NEG_INFINITY));
+        // Only the EOS token is left after finishing, with or without `mask_after_finish`.
+        let eos_token_id = 0;
+        config.eos_token_id = Some(eos_token_id);
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        engine.try_accept_new_token(a).unwrap();
+        let mut logits = vec![1.0; vocab.vocab_size()];
+        assert_eq!(
+            engine.update_logits(b, &mut logits),
+            Ok(AcceptTokenResult::Finished)
+        );
+        for (token_id, logit) in logits.iter().copied().enumerate() {
+            if token_id == eos_token_id as usize {
+                assert_eq!(logit, 1.0);
+            } else {
+                assert_eq!(logit, f32::NEG_INFINITY);
+            }
+        }
+        config.mask_after_finish = true;
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        engine.try_accept_new_bytes(b"ab").unwrap();
+        for token_id in [a, eos_token_id] {
+            let mut logits = vec![1.0; vocab.vocab_size()];
+            assert_eq!(
+                engine.update_logits(token_id, &mut logits),
+                Ok(AcceptTokenResult::Finished)
+            );
+            for (token_id, logit) in logits.iter().copied().enumerate() {
+                if token_id == eos_token_id as usize {
+                    assert_eq!(logit, 1.0);
+                } else {
+                    assert_eq!(logit, f32::NEG_INFINITY);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn accept_metrics() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            collect_accept_metrics: true,
+            ..Default::default()
+        };
+        let mut linear = kbnf::engine::Engine::with_config(
+            r#"start ::= "a"+ "b";"#,
+            vocab.clone(),
+            config.clone(),
+        )
+        .unwrap();
+        let mut ambiguous = kbnf::engine::Engine::with_config(
+            r#"start ::= s "b"; s ::= s s | "a";"#,
+            vocab.clone(),
+            config.clone(),
+        )
+        .unwrap();
+        linear.try_accept_new_bytes(b"aaaaaaaa").unwrap();
+        ambiguous.try_accept_new_bytes(b"aaaaaaaa").unwrap();
+        let linear_metrics = linear.last_accept_metrics();
+        let ambiguous_metrics = ambiguous.last_accept_metrics();
+        assert_eq!(linear_metrics.bytes_scanned, 8);
+        assert_eq!(ambiguous_metrics.bytes_scanned, 8);
+        // Every split of the bytes into `s s` is tracked by the ambiguous grammar.
+        assert!(ambiguous_metrics.items_created > 2 * linear_metrics.items_created);
+        assert!(ambiguous_metrics.completions > 2 * linear_metrics.completions);
+        assert_eq!(linear_metrics.cache_hit, None);
+        linear.compute_allowed_token_ids();
+        assert_eq!(linear.last_accept_metrics().cache_hit, Some(false));
+        linear.compute_allowed_token_ids();
+        assert_eq!(linear.last_accept_metrics().cache_hit, Some(true));
+        // The rejected byte is scanned without creating any Earley sets.
+        assert_eq!(
+            linear.try_accept_new_bytes(b"c"),
+            Err(AcceptTokenError::Rejected)
+        );
+        let metrics = linear.last_accept_metrics();
+        assert_eq!(metrics.bytes_scanned, 1);
+        assert_eq!(metrics.items_created, 0);
+        // The compaction removes the Earley sets no longer needed.
+        assert_eq!(
+            linear.try_accept_new_bytes(b"b"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        assert!(linear.last_accept_metrics().earley_sets_compacted > 0);
+        let mut engine =
+            kbnf::engine::Engine::new(r#"start ::= "a"+ "b";"#, vocab.clone()).unwrap();
+        engine.try_accept_new_bytes(b"aaa").unwrap();
+        assert_eq!(
+            engine.last_accept_metrics(),
+            kbnf::engine_like::AcceptMetrics::default()
+        );
+    }
+    #[test]
+    fn captures() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine = kbnf::engine::Engine::new(
+            r#"start ::= "Year: " #"(?P<year>[0-9]{4})" ", code: " #"[0-9]{4}";"#,
+            vocab.clone(),
+        )
+        .unwrap();
+        for token in ["Year", ":", " "] {
+            engine
+                .try_accept_new_token(get_token_id_from_str(&vocab, token).unwrap())
+                .unwrap();
+        }
+        engine.try_accept_new_bytes(b"2024, code: 1234").unwrap();
+        // The equivalent regex without groups captures nothing.
+        assert_eq!(
+            engine.captures(),
+            AHashMap::from_iter([("year".to_string(), b"2024".to_vec())])
+        );
+        // An incomplete match captures nothing.
+        engine.reset();
+        engine.try_accept_new_bytes(b"Year: 2024").unwrap();
+        assert!(engine.captures().is_empty());
+        // The last match of a name wins, and the groups not participating in the match are omitted.
+        let mut engine = kbnf::engine::Engine::new(
+            r#"start ::= (#"(?P<digit>[0-9])|(?P<letter>[a-z])" ",")+;"#,
+            vocab.clone(),
+        )
+        .unwrap();
+        engine.try_accept_new_bytes(b"1,2,").unwrap();
+        assert_eq!(
+            engine.captures(),
+            AHashMap::from_iter([("digit".to_string(), b"2".to_vec())])
+        );
+        // The internal group marking a partial regex is not a capture.
+        let mut engine = kbnf::engine::Engine::new(
+            r#"start ::= #p"(?P<year>[0-9]{4})-[0-9]{2}" #p"[a-z]+";"#,
+            vocab.clone(),
+        )
+        .unwrap();
+        engine.try_accept_new_bytes(b"2024-10abc").unwrap();
+        assert_eq!(
+            engine.captures(),
+            AHashMap::from_iter([("year".to_string(), b"2024".to_vec())])
+        );
+        let mut engine = kbnf::engine::Engine::new(r#"start ::= #p"[0-9]+";"#, vocab).unwrap();
+        engine.try_accept_new_bytes(b"12").unwrap();
+        assert!(engine.captures().is_empty());
+    }
+    #[test]
+    fn heal_last_token() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let hel = get_token_id_from_str(&vocab, "Hel").unwrap();
+        let hello = get_token_id_from_str(&vocab, "Hello").unwrap();
+        let world = get_token_id_from_str(&vocab, " world").unwrap();
+        let mut engine =
+            kbnf::engine::Engine::new(r#"start ::= "Hello world";"#, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.heal_last_token(),
+            Err(kbnf::engine_like::RollbackError::NotEnoughAcceptedTokens)
+        );
+        engine.try_accept_new_token(hel).unwrap();
+        let result = engine.heal_last_token().unwrap();
+        assert_eq!(result.removed_bytes, b"Hel");
+        assert!(result.candidate_token_ids.contains(&hello));
+        assert!(result.candidate_token_ids.contains(&hel));
+        assert!(!result.candidate_token_ids.contains(&world));
+        assert!(engine.accepted_bytes().is_empty());
+        // The allowed token IDs are not restricted by default.
+        assert!(engine.allowed_token_count() > result.candidate_token_ids.len());
+        let config = kbnf::config::Config {
+            token_healing: true,
+            ..Default::default()
+        };
+        let mut engine =
+            kbnf::engine::Engine::with_config(r#"start ::= "Hello world";"#, vocab, config)
+                .unwrap();
+        engine.try_accept_new_token(hel).unwrap();
+        let result = engine.heal_last_token().unwrap();
+        assert_eq!(
+            engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect::<Vec<_>>(),
+            result
+                .candidate_token_ids
+                .iter()
+                .map(|&x| x as usize)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            engine.try_accept_new_token(hello),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+    }
+    #[test]
+    fn grammar_prior_cache() {
+        let input = "start::=('A' %0.9 | 'B' %0.1) ('C' %0.5 | 'D' %0.5);";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut config = kbnf::config::Config::default();
+        config.engine_config.cache_enabled = false;
+        let mut uncached = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let biased = |engine: &kbnf::engine::Engine| {
+            let mut logits = vec![0.0; vocab.vocab_size()];
+            engine.bias_logits_by_grammar_prior(&mut logits).unwrap();
+            logits
+        };
+        let expected = biased(&uncached);
+        assert_eq!(biased(&engine), expected);
+        // The cached passes are reused after a reset and by the clones.
+        engine.reset();
+        assert_eq!(biased(&engine), expected);
+        assert_eq!(biased(&engine.clone()), expected);
+        engine.clear_cache();
+        assert_eq!(biased(&engine), expected);
+        uncached.try_accept_new_bytes(b"A").unwrap();
+        engine.try_accept_new_bytes(b"A").unwrap();
+        assert_eq!(biased(&engine), biased(&uncached));
+    }
+
+    #[test]
+    fn checkpoint_restores_diagnostics() {
+        let input = "start::='ab' #'[0-9]+' 'c';";
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config {
+            detailed_errors: true,
+            collect_accept_metrics: true,
+            ..Default::default()
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab, config).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab12x"),
+            Err(AcceptTokenError::Rejected)
+        );
+        let rejection = engine.last_rejection().cloned();
+        let metrics = engine.last_accept_metrics();
+        assert!(rejection.is_some());
+        let checkpoint = engine.checkpoint();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ab123"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert!(engine.last_rejection().is_none());
+        assert_ne!(engine.last_accept_metrics(), metrics);
+        engine.restore(&checkpoint).unwrap();
+        assert_eq!(engine.last_rejection().cloned(), rejection);
+        assert_eq!(engine.last_accept_metrics(), metrics);
+    }
 }